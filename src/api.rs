@@ -0,0 +1,352 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use log::{debug, error, info};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{timeout, timeout_at, Instant};
+use crate::config::InterceptConfig;
+use crate::context::ProxyContext;
+use crate::data::flow::parse_query;
+use crate::data::http::{reason_phrase, HttpMessage, HttpParser, MAX_BODY, MAX_HEAD};
+use crate::data::store::FlowStore;
+use crate::error::ProxyResult;
+use crate::filter::Filter;
+use crate::import::{import, parse};
+use crate::replay::{replay_many, ReplayRequest};
+
+//等待请求的默认超时时间，毫秒
+const WAIT_TIMEOUT: u64 = 30000;
+//等待请求最长的超时时间，毫秒，太大的值计算截止时间时会溢出
+const WAIT_LIMIT: u64 = 5 * 60 * 1000;
+//列表接口默认返回最近的多少条
+const LIST_LIMIT: usize = 100;
+//一次最多重放的次数
+const REPLAY_LIMIT: u64 = 1000;
+//SSE的心跳间隔，顺便检查客户端是否已经断开
+const KEEPALIVE: Duration = Duration::from_secs(15);
+//读取请求的超时时间，连接上来不发数据的客户端不能一直占着
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/*
+   本地控制接口，所有请求都需要带上令牌：Authorization: Bearer <token>，或者URL参数?token=<token>
+   GET    /api/status                                   监听地址、记录数、解密规则
+   GET    /api/flows?filter=&since=&limit=&body=        查询记录，默认不带body
+   GET    /api/flows/{id}                               查询一条记录，带body
+   DELETE /api/flows                                    清空记录
+   POST   /api/flows/{id}/replay?count=&concurrency=    重放一条记录的请求，后台发送，结果是新的记录
+   POST   /api/import                                   导入HAR文件或者cURL命令，body是文件内容，cURL命令不能引用本机的文件
+   POST   /api/playback/reset                           回放模式重新从第一个记录开始回放
+   GET    /api/intercept                                查询解密规则
+   PUT    /api/intercept                                修改解密规则，body：{"enabled":true,"include":[],"exclude":[]}
+   GET    /api/wait?filter=&since=&timeout=             等待一个匹配的请求完成，超时返回408
+   GET    /api/events?filter=&body=                     SSE，实时推送新完成的记录
+ */
+pub struct ApiServer {
+    store: Arc<FlowStore>,
+    ctx: Arc<ProxyContext>,
+    listeners: Vec<String>,
+    token: String,
+}
+
+//端口由调用的地方提前绑定，绑定失败时可以直接返回错误
+pub async fn start_api(listener: TcpListener, token: String, store: Arc<FlowStore>, ctx: Arc<ProxyContext>, listeners: Vec<String>) -> ProxyResult<()> {
+    info!("控制接口已启动：http://{}/api/status", listener.local_addr()?);
+    let api = Arc::new(ApiServer { store, ctx, listeners, token });
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let api = api.clone();
+        tokio::spawn(async move {
+            api.handle(stream).await.unwrap_or_else(|e| debug!("控制接口{}：{}", peer, e.to_string()));
+        });
+    }
+}
+
+//每个连接只处理一个请求，请求头不能超过MAX_HEAD，整个请求不能超过MAX_HEAD+MAX_BODY(导入的HAR文件在body里)
+async fn read_request(stream: &mut TcpStream) -> ProxyResult<HttpMessage> {
+    let mut parser = HttpParser::new(true, Arc::new(Mutex::new(VecDeque::new())));
+    let mut bs = vec![0; 8192];
+    //头部结束之前读到的数据
+    let mut head: Vec<u8> = vec![];
+    let mut head_done = false;
+    let mut received = 0;
+    let read = async {
+        loop {
+            let len = stream.read(&mut bs).await?;
+            if len == 0 { return Err("控制接口请求不完整".into()); }
+            received += len;
+            //还没读到头部结束的空行时检查头部的长度，不是HTTP的数据也在这里结束
+            if !head_done {
+                head.extend(&bs[..len]);
+                head_done = head.windows(4).any(|b| b == b"\r\n\r\n");
+                if !head_done && head.len() > MAX_HEAD { return Err("控制接口请求头过长".into()); }
+            }
+            if received > MAX_HEAD + MAX_BODY { return Err("控制接口请求过大".into()); }
+            if let Some(request) = parser.feed(&bs[..len]).pop() { return Ok(request); }
+        }
+    };
+    timeout(READ_TIMEOUT, read).await.map_err(|_| "控制接口读取请求超时")?
+}
+
+//逐个字节比较完再返回结果，比较的时间和令牌在哪里不一样无关，避免通过响应时间猜出令牌
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn json_response(code: u16, value: &Value) -> Vec<u8> {
+    let body = value.to_string();
+    format!("HTTP/1.1 {} {}\r\nContent-Type: application/json; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            code, reason_phrase(code), body.len(), body).into_bytes()
+}
+
+fn error_json(msg: impl AsRef<str>) -> Value {
+    json!({ "error": msg.as_ref() })
+}
+
+struct Query(Vec<(String, String)>);
+
+impl Query {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    fn number(&self, key: &str, default: u64) -> u64 {
+        self.get(key).and_then(|v| v.parse().ok()).unwrap_or(default)
+    }
+
+    fn flag(&self, key: &str, default: bool) -> bool {
+        match self.get(key) {
+            Some(v) => v == "1" || v.eq_ignore_ascii_case("true"),
+            None => default,
+        }
+    }
+
+    fn filter(&self) -> Result<Filter, Value> {
+        Filter::parse(self.get("filter").unwrap_or("")).map_err(|e| error_json(format!("过滤表达式错误：{}", e.to_string())))
+    }
+}
+
+impl ApiServer {
+    fn authorized(&self, request: &HttpMessage, query: &Query) -> bool {
+        let bearer = request.head.header("Authorization").and_then(|v| v.strip_prefix("Bearer ")).map(|v| v.trim());
+        bearer.or(query.get("token")).is_some_and(|token| constant_time_eq(token.as_bytes(), self.token.as_bytes()))
+    }
+
+    async fn handle(&self, mut stream: TcpStream) -> ProxyResult<()> {
+        let request = read_request(&mut stream).await?;
+        let uri = request.head.uri().to_string();
+        let (path, query) = uri.split_once('?').unwrap_or((uri.as_str(), ""));
+        let query = Query(parse_query(query));
+        let method = request.head.method().to_uppercase();
+        debug!("控制接口请求：{} {}", method, path);
+        if !self.authorized(&request, &query) {
+            stream.write_all(&json_response(401, &error_json("令牌错误"))).await?;
+            return Ok(());
+        }
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let (code, value) = match (method.as_str(), segments.as_slice()) {
+            ("GET", ["api", "status"]) => (200, self.status()?),
+            ("GET", ["api", "flows"]) => self.list_flows(&query),
+            ("GET", ["api", "flows", id]) => match id.parse().ok().and_then(|id| self.store.get(id)) {
+                Some(flow) => (200, flow.to_json(query.flag("body", true))),
+                None => (404, error_json(format!("没有这条记录：{}", id))),
+            },
+            ("POST", ["api", "flows", id, "replay"]) => self.replay(id, &query),
+            ("POST", ["api", "import"]) => self.import(&request).await?,
+            ("DELETE", ["api", "flows"]) => {
+                self.store.clear();
+                (200, json!({ "ok": true }))
+            }
+            ("POST", ["api", "playback", "reset"]) => {
+                let playback = self.ctx.playback.read()?;
+                playback.reset();
+                (200, json!({ "ok": true, "entries": playback.len() }))
+            }
+            ("GET", ["api", "intercept"]) => (200, self.intercept()?),
+            ("PUT", ["api", "intercept"]) => self.set_intercept(&request)?,
+            ("GET", ["api", "wait"]) => self.wait(&query).await?,
+            ("GET", ["api", "events"]) => return self.events(stream, &query).await,
+            _ => (404, error_json(format!("没有这个接口：{} {}", method, path))),
+        };
+        stream.write_all(&json_response(code, &value)).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    fn status(&self) -> ProxyResult<Value> {
+        Ok(json!({
+            "listeners": self.listeners,
+            "flows": self.store.len(),
+            "intercept": self.intercept()?,
+        }))
+    }
+
+    fn list_flows(&self, query: &Query) -> (u16, Value) {
+        let filter = match query.filter() {
+            Ok(filter) => filter,
+            Err(e) => return (400, e),
+        };
+        let since = query.number("since", 0);
+        let limit = query.number("limit", LIST_LIMIT as u64) as usize;
+        let flows: Vec<_> = self.store.list(&filter).into_iter().filter(|flow| flow.id > since).collect();
+        let body = query.flag("body", false);
+        let flows: Vec<Value> = flows[flows.len().saturating_sub(limit)..].iter().map(|flow| flow.to_json(body)).collect();
+        (200, json!({ "flows": flows, "total": self.store.len() }))
+    }
+
+    fn replay(&self, id: &str, query: &Query) -> (u16, Value) {
+        let flow = match id.parse().ok().and_then(|id| self.store.get(id)) {
+            Some(flow) => flow,
+            None => return (404, error_json(format!("没有这条记录：{}", id))),
+        };
+        let count = query.number("count", 1).clamp(1, REPLAY_LIMIT) as usize;
+        //并发数太大时创建信号量会panic，超过次数的并发也没有意义
+        let concurrency = query.number("concurrency", 1).clamp(1, REPLAY_LIMIT) as usize;
+        let request = ReplayRequest::from_flow(&flow);
+        if let Err(e) = request.to_message() { return (400, error_json(e.to_string())); }
+        let ctx = self.ctx.clone();
+        tokio::spawn(async move {
+            replay_many(ctx, request, count, concurrency).await.unwrap_or_else(|e| error!("重放失败：{}", e.to_string()));
+        });
+        (202, json!({ "ok": true, "count": count, "concurrency": concurrency }))
+    }
+
+    async fn import(&self, request: &HttpMessage) -> ProxyResult<(u16, Value)> {
+        let flows = match parse(&String::from_utf8_lossy(&request.body), false) {
+            Ok(flows) => flows,
+            Err(e) => return Ok((400, error_json(e.to_string()))),
+        };
+        let count = import(&self.ctx, flows).await?;
+        info!("控制接口导入了{}条记录", count);
+        Ok((200, json!({ "ok": true, "count": count })))
+    }
+
+    fn intercept(&self) -> ProxyResult<Value> {
+        let rules = self.ctx.intercept.read()?;
+        Ok(json!({
+            "enabled": rules.enabled,
+            "include": rules.include.iter().map(|p| p.as_str()).collect::<Vec<_>>(),
+            "exclude": rules.exclude.iter().map(|p| p.as_str()).collect::<Vec<_>>(),
+        }))
+    }
+
+    fn set_intercept(&self, request: &HttpMessage) -> ProxyResult<(u16, Value)> {
+        let config = match serde_json::from_slice::<InterceptConfig>(&request.body) {
+            Ok(config) => config,
+            Err(e) => return Ok((400, error_json(format!("解密规则格式错误：{}", e)))),
+        };
+        if config.include.iter().chain(config.exclude.iter()).any(|p| p.trim().is_empty()) {
+            return Ok((400, error_json("解密规则的域名不能为空")));
+        }
+        *self.ctx.intercept.write()? = config.to_rules();
+        info!("控制接口修改了解密规则：启用{}，包含{:?}，排除{:?}", config.enabled, config.include, config.exclude);
+        Ok((200, self.intercept()?))
+    }
+
+    //先订阅再查已有的记录，避免在两者之间完成的请求被漏掉
+    async fn wait(&self, query: &Query) -> ProxyResult<(u16, Value)> {
+        let filter = match query.filter() {
+            Ok(filter) => filter,
+            Err(e) => return Ok((400, e)),
+        };
+        let since = query.number("since", 0);
+        let deadline = Instant::now() + Duration::from_millis(query.number("timeout", WAIT_TIMEOUT).min(WAIT_LIMIT));
+        let body = query.flag("body", true);
+        let mut rx = self.store.subscribe();
+        let found = self.store.list(&filter).into_iter().filter(|flow| flow.id > since).min_by_key(|flow| flow.id);
+        if let Some(flow) = found { return Ok((200, flow.to_json(body))); }
+        loop {
+            match timeout_at(deadline, rx.recv()).await {
+                Ok(Ok(flow)) if flow.id > since && filter.matches(&flow) => return Ok((200, flow.to_json(body))),
+                Ok(Ok(_)) | Ok(Err(RecvError::Lagged(_))) => continue,
+                Ok(Err(RecvError::Closed)) => return Err("抓包记录已关闭".into()),
+                Err(_) => return Ok((408, error_json("等待超时"))),
+            }
+        }
+    }
+
+    async fn events(&self, mut stream: TcpStream, query: &Query) -> ProxyResult<()> {
+        let filter = match query.filter() {
+            Ok(filter) => filter,
+            Err(e) => {
+                stream.write_all(&json_response(400, &e)).await?;
+                return Ok(());
+            }
+        };
+        let body = query.flag("body", false);
+        let mut rx = self.store.subscribe();
+        stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n").await?;
+        loop {
+            let data = match timeout(KEEPALIVE, rx.recv()).await {
+                Ok(Ok(flow)) if filter.matches(&flow) => format!("id: {}\nevent: flow\ndata: {}\n\n", flow.id, flow.to_json(body)),
+                Ok(Ok(_)) => continue,
+                //接收得太慢，中间有记录被丢掉了
+                Ok(Err(RecvError::Lagged(count))) => format!("event: lagged\ndata: {}\n\n", count),
+                Ok(Err(RecvError::Closed)) => return Ok(()),
+                Err(_) => ": ping\n\n".to_string(),
+            };
+            stream.write_all(data.as_bytes()).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_api {
+    use serde_json::Value;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use crate::data::flow::test_flow;
+    use crate::server::{ListenMode, ProxyServer};
+
+    async fn request(addr: std::net::SocketAddr, request: &[u8]) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        //控制接口提前断开时写入可能失败，只看返回的内容
+        let _ = stream.write_all(request).await;
+        let mut response = vec![];
+        let _ = stream.read_to_end(&mut response).await;
+        String::from_utf8(response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_api() {
+        let handle = ProxyServer::builder()
+            .listen("127.0.0.1:0", ListenMode::Http)
+            .mitm(false)
+            .api("127.0.0.1:0", "secret")
+            .start().await.unwrap();
+        let addr = handle.api().unwrap().0;
+        handle.store().push(test_flow(1, b"GET /a HTTP/1.1\r\nHost: a.com\r\n\r\n", b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"));
+        handle.store().push(test_flow(2, b"GET /b HTTP/1.1\r\nHost: b.com\r\n\r\n", b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n"));
+        //没有令牌、令牌错误(长度一样)都拒绝
+        for auth in ["", "Authorization: Bearer secreT\r\n", "Authorization: Bearer secret2\r\n"] {
+            let response = request(addr, format!("GET /api/flows HTTP/1.1\r\n{}\r\n", auth).as_bytes()).await;
+            assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+        }
+        let response = request(addr, b"GET /api/flows?token=secret&filter=status:404 HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        let body: Value = serde_json::from_str(response.split_once("\r\n\r\n").unwrap().1).unwrap();
+        assert_eq!(body["total"], 2);
+        assert_eq!(body["flows"].as_array().unwrap().len(), 1);
+        assert_eq!(body["flows"][0]["id"], 2);
+        let response = request(addr, b"GET /api/flows HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n").await;
+        let body: Value = serde_json::from_str(response.split_once("\r\n\r\n").unwrap().1).unwrap();
+        assert_eq!(body["flows"].as_array().unwrap().len(), 2);
+        //导入的cURL命令不能读取代理所在机器上的文件
+        let command = "curl -d @/etc/hostname example.com";
+        let response = request(addr, format!("POST /api/import?token=secret HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", command.len(), command).as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+        //超时时间和并发数太大时按上限处理，不能panic
+        let response = request(addr, b"GET /api/wait?token=secret&filter=status:404&timeout=18446744073709551615 HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        handle.store().push(test_flow(3, b"GET /c HTTP/1.1\r\nHost: 127.0.0.1:1\r\n\r\n", b""));
+        let response = request(addr, b"POST /api/flows/3/replay?token=secret&count=2000&concurrency=18446744073709551615 HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 202"), "{}", response);
+        let body: Value = serde_json::from_str(response.split_once("\r\n\r\n").unwrap().1).unwrap();
+        assert_eq!((body["count"].as_u64(), body["concurrency"].as_u64()), (Some(1000), Some(1000)));
+        //一直没有头部结束的数据不会无限缓存，超过长度后直接断开
+        assert!(request(addr, &vec![b'x'; 128 * 1024]).await.is_empty());
+        handle.shutdown();
+    }
+}
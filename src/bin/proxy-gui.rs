@@ -0,0 +1,43 @@
+use egui::ViewportBuilder;
+use proxy::breakpoint::{Breakpoints, BREAK_TIMEOUT};
+use proxy::config::ProxyConfig;
+use proxy::gui::ProxyView;
+use proxy::{init_log4rs, ProxyServer};
+
+//图形界面，参数和命令行版本一样
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = match ProxyConfig::load(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e.to_string());
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = init_log4rs(&config) {
+        eprintln!("初始化日志失败：{}", e.to_string());
+        std::process::exit(2);
+    }
+    //界面必须在主线程运行，代理在tokio的工作线程里运行
+    let runtime = tokio::runtime::Runtime::new().expect("创建tokio运行时失败");
+    //没有指定输出文件时不输出抓包记录，在界面上看
+    let output = !config.capture.file.is_empty();
+    //界面上设置的断点通过拦截处理暂停请求
+    let breakpoints = Breakpoints::new(BREAK_TIMEOUT);
+    let builder = ProxyServer::builder().config(config).output(output).handler(breakpoints.clone());
+    let handle = match runtime.block_on(builder.start()) {
+        Ok(handle) => handle,
+        Err(e) => {
+            eprintln!("{}", e.to_string());
+            std::process::exit(2);
+        }
+    };
+    let viewport = ViewportBuilder::default()
+        .with_title("Proxy").with_inner_size((1200.0, 600.0));
+    let native_options = eframe::NativeOptions { viewport, ..Default::default() };
+    let store = handle.store();
+    let context = handle.context();
+    let replayer = handle.replayer();
+    let snippet = handle.snippet_options();
+    eframe::run_native("Proxy", native_options, Box::new(|cc| ProxyView::new(cc, store, breakpoints, context, replayer, snippet))).unwrap();
+}
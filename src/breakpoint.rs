@@ -0,0 +1,291 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use log::{info, warn};
+use tokio::sync::oneshot;
+use crate::data::flow::Flow;
+use crate::data::http::{HttpHead, HttpMessage};
+use crate::error::ProxyResult;
+use crate::filter::Filter;
+use crate::handler::{ConnInfo, FlowHandler, HandlerAction};
+use crate::proxy::Direction;
+
+//断点默认的等待时间，超时后原样放行，避免忘记处理的断点让客户端一直等下去
+pub const BREAK_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BreakStage {
+    //请求发给服务器之前
+    Request,
+    //响应返回给客户端之前
+    Response,
+}
+
+#[derive(Clone, Debug)]
+pub struct BreakRule {
+    pub expr: String,
+    filter: Filter,
+    pub request: bool,
+    pub response: bool,
+}
+
+pub enum Resume {
+    //修改后放行
+    Edited(HttpMessage),
+    //原样放行
+    Unchanged,
+    //中止，断开连接
+    Abort,
+}
+
+//一个暂停中的请求或响应
+pub struct Paused {
+    pub id: u64,
+    pub stage: BreakStage,
+    pub conn: ConnInfo,
+    //请求断点只有请求，响应断点同时有请求和响应
+    pub flow: Flow,
+    //超过这个时间自动放行
+    pub deadline: Instant,
+    sender: Mutex<Option<oneshot::Sender<Resume>>>,
+}
+
+impl Paused {
+    //暂停的消息
+    pub fn message(&self) -> &HttpMessage {
+        match (self.stage, self.flow.response.as_ref()) {
+            (BreakStage::Response, Some(response)) => response,
+            _ => &self.flow.request,
+        }
+    }
+}
+
+/*
+   按过滤表达式设置断点，匹配的请求/响应暂停，在界面上修改后放行、原样放行或者中止
+   作为拦截处理注册到ProxyServerBuilder::handler，界面通过paused查询暂停中的消息，通过resume放行
+ */
+pub struct Breakpoints {
+    rules: RwLock<Vec<BreakRule>>,
+    paused: Mutex<Vec<Arc<Paused>>>,
+    timeout: Duration,
+    next_id: AtomicU64,
+}
+
+impl Breakpoints {
+    pub fn new(timeout: Duration) -> Arc<Breakpoints> {
+        Arc::new(Breakpoints { rules: RwLock::new(vec![]), paused: Mutex::new(vec![]), timeout, next_id: AtomicU64::new(1) })
+    }
+
+    pub fn add(&self, expr: &str, request: bool, response: bool) -> ProxyResult<()> {
+        let filter = Filter::parse(expr)?;
+        self.rules.write()?.push(BreakRule { expr: expr.to_string(), filter, request, response });
+        Ok(())
+    }
+
+    pub fn remove(&self, index: usize) {
+        if let Ok(mut rules) = self.rules.write() && index < rules.len() { rules.remove(index); }
+    }
+
+    pub fn rules(&self) -> Vec<BreakRule> {
+        self.rules.read().map(|rules| rules.clone()).unwrap_or_default()
+    }
+
+    //按暂停的先后顺序
+    pub fn paused(&self) -> Vec<Arc<Paused>> {
+        self.paused.lock().map(|paused| paused.clone()).unwrap_or_default()
+    }
+
+    //已经超时或者已经放行的返回false
+    pub fn resume(&self, id: u64, resume: Resume) -> bool {
+        let paused = self.paused().into_iter().find(|paused| paused.id == id);
+        let sender = paused.and_then(|paused| paused.sender.lock().ok()?.take());
+        match sender {
+            Some(sender) => sender.send(resume).is_ok(),
+            None => false,
+        }
+    }
+
+    fn matches(&self, stage: BreakStage, flow: &Flow) -> bool {
+        let rules = match self.rules.read() {
+            Ok(rules) => rules,
+            Err(_) => return false,
+        };
+        rules.iter().any(|rule| match stage {
+            BreakStage::Request => rule.request,
+            BreakStage::Response => rule.response,
+        } && rule.filter.matches(flow))
+    }
+
+    async fn hold(&self, stage: BreakStage, conn: &ConnInfo, flow: Flow) -> Resume {
+        let (sender, receiver) = oneshot::channel();
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        info!("断点暂停：{:?} {}", stage, flow.url());
+        let paused = Paused { id, stage, conn: conn.clone(), flow, deadline: Instant::now() + self.timeout, sender: Mutex::new(Some(sender)) };
+        if let Ok(mut list) = self.paused.lock() { list.push(Arc::new(paused)); }
+        let res = tokio::time::timeout(self.timeout, receiver).await;
+        if let Ok(mut list) = self.paused.lock() { list.retain(|paused| paused.id != id); }
+        match res {
+            Ok(Ok(resume)) => resume,
+            _ => {
+                warn!("断点{}超时，原样放行", id);
+                Resume::Unchanged
+            }
+        }
+    }
+}
+
+fn break_flow(conn: &ConnInfo, request: HttpMessage, response: Option<HttpMessage>) -> Flow {
+    Flow { id: 0, sid: conn.sid.clone(), client: conn.client.clone(), server: conn.server.clone(), tls: conn.tls, request, response, error: None, tags: vec![], remap: None, replay_of: None, timing: None }
+}
+
+#[async_trait]
+impl FlowHandler for Breakpoints {
+    //没有这个阶段可能匹配这个请求的断点时边接收边转发，界面一直注册着断点，不能让所有的响应都等接收完整
+    fn wants(&self, conn: &ConnInfo, direction: &Direction, request: &HttpHead) -> bool {
+        let rules = match self.rules.read() {
+            Ok(rules) => rules,
+            Err(_) => return false,
+        };
+        let flow = break_flow(conn, HttpMessage::new(request.clone(), vec![]), None);
+        rules.iter().any(|rule| match direction {
+            Direction::ClientToServer => rule.request,
+            Direction::ServerToClient => rule.response,
+        } && rule.filter.may_match(&flow))
+    }
+
+    async fn on_request(&self, conn: &ConnInfo, request: &mut HttpMessage) -> HandlerAction {
+        let flow = break_flow(conn, request.clone(), None);
+        if !self.matches(BreakStage::Request, &flow) { return HandlerAction::Continue; }
+        match self.hold(BreakStage::Request, conn, flow).await {
+            Resume::Edited(message) => *request = message,
+            Resume::Unchanged => {}
+            Resume::Abort => return HandlerAction::Drop,
+        }
+        HandlerAction::Continue
+    }
+
+    async fn on_response(&self, conn: &ConnInfo, request: &HttpMessage, response: &mut HttpMessage) -> HandlerAction {
+        let flow = break_flow(conn, request.clone(), Some(response.clone()));
+        if !self.matches(BreakStage::Response, &flow) { return HandlerAction::Continue; }
+        match self.hold(BreakStage::Response, conn, flow).await {
+            Resume::Edited(message) => *response = message,
+            Resume::Unchanged => {}
+            Resume::Abort => return HandlerAction::Drop,
+        }
+        HandlerAction::Continue
+    }
+}
+
+#[cfg(test)]
+mod test_breakpoint {
+    use std::time::Duration;
+    use crate::breakpoint::{Breakpoints, BreakStage, Resume};
+    use crate::data::http::HttpMessage;
+    use crate::handler::{ConnInfo, FlowHandler, HandlerAction};
+    use crate::proxy::Direction;
+
+    #[tokio::test]
+    async fn test_breakpoint() {
+        let breakpoints = Breakpoints::new(Duration::from_millis(200));
+        breakpoints.add("path:/login", true, false).unwrap();
+        assert!(breakpoints.add("path:(", true, false).is_err());
+        let conn = ConnInfo { sid: "1".to_string(), client: String::new(), server: "a.com:80".to_string(), tls: false };
+        let request = |uri: &str| HttpMessage::new(crate::data::http::HttpHead { line: format!("GET {} HTTP/1.1", uri), headers: vec![] }, vec![]);
+        //不匹配的直接放行
+        assert!(matches!(breakpoints.on_request(&conn, &mut request("/index")).await, HandlerAction::Continue));
+        //匹配的暂停，修改后放行
+        let waiter = breakpoints.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                if let Some(paused) = waiter.paused().pop() {
+                    assert_eq!(paused.stage, BreakStage::Request);
+                    let mut message = paused.message().clone();
+                    message.head.set_uri("/login2");
+                    assert!(waiter.resume(paused.id, Resume::Edited(message)));
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        });
+        let mut message = request("/login");
+        assert!(matches!(breakpoints.on_request(&conn, &mut message).await, HandlerAction::Continue));
+        task.await.unwrap();
+        assert_eq!(message.head.uri(), "/login2");
+        assert!(breakpoints.paused().is_empty());
+        //超时后原样放行
+        let mut message = request("/login");
+        assert!(matches!(breakpoints.on_request(&conn, &mut message).await, HandlerAction::Continue));
+        assert_eq!(message.head.uri(), "/login");
+        //响应没有设置断点
+        assert!(matches!(breakpoints.on_response(&conn, &request("/login"), &mut HttpMessage::response(200, "OK", "")).await, HandlerAction::Continue));
+        //只有可能匹配的请求需要接收完整
+        assert!(breakpoints.wants(&conn, &Direction::ClientToServer, &request("/login").head));
+        assert!(!breakpoints.wants(&conn, &Direction::ClientToServer, &request("/index").head));
+        assert!(!breakpoints.wants(&conn, &Direction::ServerToClient, &request("/login").head));
+    }
+
+    //没有断点、或者断点和规则都是别的地址的时候，流式的响应边接收边转发，不会等响应结束
+    #[tokio::test]
+    async fn test_streaming() {
+        streaming(false).await;
+        streaming(true).await;
+    }
+
+    async fn streaming(unrelated: bool) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::{TcpListener, TcpStream};
+        use tokio::sync::oneshot;
+        use tokio_stream::StreamExt;
+        use crate::config::{FaultConfig, RewriteConfig};
+        use crate::server::{ListenMode, ProxyServer};
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let (finish, finished) = oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut bs = vec![0; 1024];
+            let _ = stream.read(&mut bs).await.unwrap();
+            stream.write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nfirst\r\n").await.unwrap();
+            finished.await.unwrap();
+            stream.write_all(b"4\r\nlast\r\n0\r\n\r\n").await.unwrap();
+            let _ = stream.read(&mut bs).await;
+        });
+        let breakpoints = Breakpoints::new(Duration::from_secs(5));
+        let mut builder = ProxyServer::builder()
+            .listen("127.0.0.1:0", ListenMode::Http)
+            .mitm(false)
+            .handler(breakpoints.clone());
+        if unrelated {
+            breakpoints.add("path:/login", true, true).unwrap();
+            let rewrite = RewriteConfig { enabled: true, stage: "response".to_string(), host: "api.example.com".to_string(), ..RewriteConfig::default() };
+            let fault = FaultConfig { url: "http://api.example.com/*".to_string(), action: "corrupt".to_string(), bytes: 1, probability: 100.0, ..FaultConfig::default() };
+            builder = builder
+                .map_local("http://api.example.com/*", std::env::temp_dir().to_string_lossy())
+                .map_remote("http://api.example.com/v1/*", "http://127.0.0.1:1")
+                .rewrite(rewrite)
+                .fault(fault);
+        }
+        let handle = builder.start().await.unwrap();
+        let mut flows = handle.flows();
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+        let request = format!("GET http://{}/events HTTP/1.1\r\nHost: {}\r\n\r\n", backend_addr, backend_addr);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = vec![];
+        let read_until = async |stream: &mut TcpStream, response: &mut Vec<u8>, end: &[u8]| {
+            while !response.ends_with(end) {
+                let mut bs = [0; 1024];
+                let len = stream.read(&mut bs).await.unwrap();
+                assert!(len > 0);
+                response.extend_from_slice(&bs[..len]);
+            }
+        };
+        //服务器还没发完，客户端已经收到了第一块
+        tokio::time::timeout(Duration::from_secs(2), read_until(&mut stream, &mut response, b"first\r\n")).await.unwrap();
+        finish.send(()).unwrap();
+        read_until(&mut stream, &mut response, b"0\r\n\r\n").await;
+        let flow = flows.next().await.unwrap();
+        assert_eq!(flow.response.as_ref().unwrap().body, b"firstlast");
+        handle.shutdown();
+    }
+}
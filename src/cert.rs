@@ -1,8 +1,17 @@
+use crate::config::CaConfig;
 use crate::error::ProxyResult;
 use rcgen::KeyUsagePurpose::{CrlSign, KeyCertSign};
 use rcgen::{BasicConstraints, CertificateParams, DnType, DnValue, Ia5String, IsCa, PrintableString, SanType};
+use rustls::ServerConfig;
+use rustls_pemfile::Item;
+use rustls_pki_types::{PrivateKeyDer, ServerName};
+use std::io::BufReader;
+use std::net::IpAddr;
 use std::str::FromStr;
+use std::sync::Arc;
 use time::OffsetDateTime;
+use tokio_rustls::TlsAcceptor;
+use log::trace;
 
 //生成一个根证书
 pub fn current_time() -> ProxyResult<i64> {
@@ -40,8 +49,13 @@ pub fn gen_ca() -> ProxyResult<()> {
 //接下来我们实现可以为每个域名生成一个证书
 pub fn gen_cert_for_sni(sni: impl AsRef<str>, ca: &str, key: &str) -> ProxyResult<(String, String)> {
     let mut params = CertificateParams::default();
-    //为某个SNI实现证书签发
-    params.subject_alt_names.push(SanType::DnsName(Ia5String::from_str(sni.as_ref())?));
+    //为某个SNI实现证书签发，socks客户端没有SNI时只能用IP地址签发，IPv6地址可能带着方括号
+    let sni = sni.as_ref();
+    let sni = sni.strip_prefix('[').and_then(|sni| sni.strip_suffix(']')).unwrap_or(sni);
+    match sni.parse::<IpAddr>() {
+        Ok(ip) => params.subject_alt_names.push(SanType::IpAddress(ip)),
+        Err(_) => params.subject_alt_names.push(SanType::DnsName(Ia5String::from_str(sni)?)),
+    }
     params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
     params.not_before = OffsetDateTime::from_unix_timestamp(current_time()?)?;
     //这里我们的根证书有效时长只有一年
@@ -64,10 +78,65 @@ pub fn gen_cert_for_sni(sni: impl AsRef<str>, ca: &str, key: &str) -> ProxyResul
 }
 
 
+//SNI是客户端发过来的，要拼到缓存文件的路径里，只接受合法的域名和IP地址，返回证书使用的名字
+fn cert_name(sni: &str) -> ProxyResult<String> {
+    let name = sni.strip_prefix('[').and_then(|name| name.strip_suffix(']')).unwrap_or(sni);
+    if let Ok(ip) = name.parse::<IpAddr>() { return Ok(ip.to_string()); }
+    match ServerName::try_from(name) {
+        Ok(ServerName::DnsName(_)) => Ok(name.to_lowercase()),
+        _ => Err(format!("{}不是合法的域名，不生成证书", sni).into()),
+    }
+}
+
+//这里需要实现一个TlsAcceptor才能解密
+pub fn gen_acceptor_for_sni(sni: impl AsRef<str>, ca: &CaConfig) -> ProxyResult<TlsAcceptor> {
+    let sni = cert_name(sni.as_ref())?;
+    //这里先要生成证书
+    //在top命令中我们看到我们的程序在建立连接的时候cpu占用很高。这个是证书生成时占用的，这里我们做一个证书缓存
+    //IPv6地址的冒号在windows上不能用在文件名里
+    let file = sni.replace(':', "_");
+    let crt_path = format!("{}/{}.pem", ca.cache_dir, file);
+    let key_path = format!("{}/{}.key", ca.cache_dir, file);
+    let (sni_bs, key_bs) = if std::fs::exists(crt_path.as_str())? {
+        let sni_bs = std::fs::read(crt_path.as_str())?;
+        let key_bs = std::fs::read(key_path.as_str())?;
+        (sni_bs, key_bs)
+    } else {
+        trace!("正在为{}生成证书",sni);
+        let (pem, key) = gen_cert_for_sni(&sni, &ca.cert, &ca.key)?;
+        let sni_bs = pem.into_bytes();
+        let key_bs = key.into_bytes();
+        std::fs::write(crt_path.as_str(), sni_bs.as_slice())?;
+        std::fs::write(key_path.as_str(), key_bs.as_slice())?;
+        (sni_bs, key_bs)
+    };
+
+
+    let mut reader = BufReader::new(sni_bs.as_slice());
+    let item = rustls_pemfile::read_one(&mut reader).transpose().ok_or("读取证书失败")??;
+    let sni_cert = match item {
+        Item::X509Certificate(cert) => cert,
+        _ => return Err("不支持的证书".into()),
+    };
+    let mut reader = BufReader::new(key_bs.as_slice());
+    let item = rustls_pemfile::read_one(&mut reader).transpose().ok_or("读取证书密钥失败")??;
+    let sni_key = match item {
+        Item::Pkcs1Key(key) => PrivateKeyDer::Pkcs1(key),
+        Item::Pkcs8Key(key) => PrivateKeyDer::Pkcs8(key),
+        Item::Sec1Key(key) => PrivateKeyDer::Sec1(key),
+        _ => return Err("不支持的证书密钥类型".into()),
+    };
+    let config = ServerConfig::builder_with_protocol_versions(&rustls::ALL_VERSIONS)
+        .with_no_client_auth().with_single_cert(vec![sni_cert], sni_key)?;
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    Ok(acceptor)
+}
+
+
 //然后我们做一个模块化测试
 #[cfg(test)]
 mod test_gen_cert {
-    use crate::cert::{gen_ca, gen_cert_for_sni};
+    use crate::cert::{cert_name, gen_ca, gen_cert_for_sni};
 
     #[test]
     fn test_gen_cert() {
@@ -76,4 +145,15 @@ mod test_gen_cert {
         std::fs::write("1.pem", pem.as_bytes()).unwrap();
         std::fs::write("1.key", key.as_bytes()).unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_cert_name() {
+        assert_eq!(cert_name("WWW.Baidu.com").unwrap(), "www.baidu.com");
+        assert_eq!(cert_name("[::1]").unwrap(), "::1");
+        assert_eq!(cert_name("127.0.0.1").unwrap(), "127.0.0.1");
+        //不能用来写缓存目录外面的文件
+        for sni in ["../../etc/a", "a/b.com", "a..com", "", "a b.com"] {
+            assert!(cert_name(sni).is_err(), "{}", sni);
+        }
+    }
+}
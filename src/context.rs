@@ -0,0 +1,119 @@
+use std::sync::{Arc, RwLock};
+use log::trace;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::Sender;
+use crate::config::CaConfig;
+use crate::error::ProxyResult;
+use crate::data::flow::{request_url, Capture};
+use crate::data::http::HttpHead;
+use crate::data::timing::ConnTiming;
+use crate::handler::{ConnInfo, Handlers};
+use crate::proxy::Direction;
+use crate::reverse::ReverseRoutes;
+use crate::rule::fault::FaultRules;
+use crate::rule::intercept::InterceptRules;
+use crate::rule::map_local::MapLocalRules;
+use crate::rule::map_remote::MapRemoteRules;
+use crate::rule::playback::PlaybackRules;
+use crate::rule::rewrite::{RewriteRules, RewriteStage};
+use crate::rule::url_host;
+use crate::throttle::ThrottleRules;
+use crate::upstream::UpstreamRules;
+
+//所有监听端口共享的状态，每个连接都持有一份引用
+pub struct ProxyContext {
+    pub sender: Sender<Capture>,
+    //根证书和签发证书的缓存目录
+    pub ca: CaConfig,
+    //运行时可能会修改，这里用读写锁
+    pub intercept: RwLock<InterceptRules>,
+    pub upstream: RwLock<UpstreamRules>,
+    pub reverse: RwLock<ReverseRoutes>,
+    pub map_local: RwLock<MapLocalRules>,
+    pub map_remote: RwLock<MapRemoteRules>,
+    pub rewrite: RwLock<RewriteRules>,
+    pub fault: RwLock<FaultRules>,
+    pub playback: RwLock<PlaybackRules>,
+    pub throttle: RwLock<ThrottleRules>,
+    //拦截处理，启动后不能修改
+    pub handlers: Handlers,
+}
+
+impl ProxyContext {
+    pub fn new(sender: Sender<Capture>, ca: CaConfig, handlers: Handlers) -> Arc<ProxyContext> {
+        Arc::new(ProxyContext {
+            sender,
+            ca,
+            handlers,
+            intercept: RwLock::new(InterceptRules::new()),
+            upstream: RwLock::new(UpstreamRules::new()),
+            reverse: RwLock::new(ReverseRoutes::new()),
+            map_local: RwLock::new(MapLocalRules::new()),
+            map_remote: RwLock::new(MapRemoteRules::new()),
+            rewrite: RwLock::new(RewriteRules::new()),
+            fault: RwLock::new(FaultRules::new()),
+            playback: RwLock::new(PlaybackRules::new()),
+            throttle: RwLock::new(ThrottleRules::new()),
+        })
+    }
+
+    //所有向外的连接都走这里，根据域名选择直连或者上级代理
+    pub async fn connect(&self, host: &str, port: u16) -> ProxyResult<TcpStream> {
+        self.connect_timed(host, port, &mut ConnTiming::default()).await
+    }
+
+    //和connect一样，同时记录DNS解析和TCP连接的时间
+    pub async fn connect_timed(&self, host: &str, port: u16, timing: &mut ConnTiming) -> ProxyResult<TcpStream> {
+        //读写锁不能跨await持有，这里先复制一份
+        let upstream = self.upstream.read()?.select(host).clone();
+        trace!("连接{}:{}，上级代理：{}", host, port, upstream);
+        upstream.connect_timed(host, port, timing).await
+    }
+
+    //有拦截处理、本地映射、远程映射、改写、故障注入规则或者在回放模式时，按消息转发，连接开始的时候决定
+    //具体每个消息是否要接收完整再转发见buffered
+    pub fn forward(&self) -> bool {
+        !self.handlers.is_empty() || self.has_rules()
+    }
+
+    //有任何一种规则时按消息转发，规则在运行时可能会添加
+    pub fn has_rules(&self) -> bool {
+        let map_local = self.map_local.read().map(|rules| !rules.is_empty()).unwrap_or(false);
+        let map_remote = self.map_remote.read().map(|rules| !rules.is_empty()).unwrap_or(false);
+        let rewrite = self.rewrite.read().map(|rules| !rules.is_empty()).unwrap_or(false);
+        let fault = self.fault.read().map(|rules| !rules.is_empty()).unwrap_or(false);
+        let playback = self.playback.read().map(|rules| rules.enabled()).unwrap_or(false);
+        map_local || map_remote || rewrite || fault || playback
+    }
+
+    //按消息转发时，这个方向的消息是否要接收完整再转发，request是这个消息对应的请求的头部
+    //只看这个阶段可能匹配这个URL的规则，其他域名的规则不影响SSE、下载这些边接收边转发
+    pub fn buffered(&self, conn: &ConnInfo, direction: &Direction, request: &HttpHead) -> bool {
+        let url = request_url(request, conn.tls, &conn.server);
+        let rules = match direction {
+            Direction::ClientToServer => self.request_rules(conn, request, &url),
+            Direction::ServerToClient => self.response_rules(conn, request, &url),
+        };
+        //读写锁出错时按需要处理
+        rules.unwrap_or(true) || self.handlers.iter().any(|handler| handler.wants(conn, direction, request))
+    }
+
+    //本地映射、远程映射、回放和请求方向的故障只在请求方向生效
+    fn request_rules(&self, conn: &ConnInfo, request: &HttpHead, url: &str) -> ProxyResult<bool> {
+        let playback = self.playback.read()?;
+        Ok(self.map_local.read()?.select(url).is_some()
+            || self.map_remote.read()?.select(url).is_some()
+            || (playback.enabled() && playback.has_host(url_host(url).as_str()))
+            || self.fault.read()?.matches(request, url, true)
+            || self.rewrite.read()?.matches(RewriteStage::Request, &conn.server, request))
+    }
+
+    fn response_rules(&self, conn: &ConnInfo, request: &HttpHead, url: &str) -> ProxyResult<bool> {
+        Ok(self.fault.read()?.matches(request, url, false) || self.rewrite.read()?.matches(RewriteStage::Response, &conn.server, request))
+    }
+
+    //这个域名有本地映射、远程映射规则或者回放记录，需要看到第一个请求后才能决定连接哪里
+    pub fn connect_later(&self, host: &str) -> ProxyResult<bool> {
+        Ok(self.map_local.read()?.has_host(host) || self.map_remote.read()?.has_host(host) || self.playback.read()?.has_host(host))
+    }
+}
@@ -0,0 +1,528 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use log::trace;
+use serde_json::{json, Value};
+use time::{OffsetDateTime, UtcOffset};
+use crate::data::http::{HttpHead, HttpMessage};
+use crate::data::timing::{phases, ConnTiming};
+use crate::error::ProxyResult;
+use crate::rule::map_remote::REMAPPED_TAG;
+use crate::replay::REPLAY_TAG;
+use crate::proxy::Direction;
+
+//抓包通道里传递的数据，每个连接用sid区分
+pub enum Capture {
+    //一个完整的HTTP消息
+    Message {
+        sid: String,
+        //客户端地址
+        client: String,
+        //连接的目标服务器地址
+        server: String,
+        tls: bool,
+        direction: Direction,
+        message: HttpMessage,
+    },
+    //给这个连接上还没有响应的请求加一个标记，如：mapped，请求方向是最后一个请求，响应方向是最早的请求
+    Tag { sid: String, direction: Direction, tag: String },
+    //最后一个还没有响应的请求被远程映射改写了地址
+    Remap { sid: String, remap: Remap },
+    //连接建立过程中各个阶段的时间，记录在这个连接上的下一个请求里，已经有还没有时间的请求时记录在最后一个请求里(如：重放)
+    Timing { sid: String, timing: ConnTiming },
+    //这个连接是重放的请求，source是原来的记录编号
+    Replay { sid: String, source: Option<u64> },
+    //导入的HAR、cURL命令，已经是完整的记录，只需要编号
+    Import { flow: Box<Flow> },
+    //连接断开了，还没有响应的请求不会再有响应
+    Closed { sid: String },
+    //和Closed一样，带上具体的错误
+    Failed { sid: String, error: String },
+}
+
+//一次请求和它的响应
+#[derive(Clone, Debug)]
+pub struct Flow {
+    //按收到请求的顺序编号，从1开始
+    pub id: u64,
+    pub sid: String,
+    pub client: String,
+    pub server: String,
+    pub tls: bool,
+    pub request: HttpMessage,
+    pub response: Option<HttpMessage>,
+    pub error: Option<String>,
+    //代理自己处理过的请求的标记，如：mapped(本地映射)
+    pub tags: Vec<String>,
+    pub remap: Option<Remap>,
+    //重放的请求，原来的记录编号
+    pub replay_of: Option<u64>,
+    //连接上的第一个请求才有，复用连接的请求为None
+    pub timing: Option<ConnTiming>,
+}
+
+//远程映射改写过的请求：客户端请求的URL和实际发送的URL
+#[derive(Clone, Debug)]
+pub struct Remap {
+    pub original: String,
+    pub rewritten: String,
+}
+
+impl Flow {
+    pub fn method(&self) -> &str {
+        self.request.head.method()
+    }
+
+    pub fn scheme(&self) -> &str {
+        match self.tls {
+            true => "https",
+            false => "http",
+        }
+    }
+
+    //普通HTTP代理的请求行是完整的URL，解密后的HTTPS和透明代理只有路径，需要从Host里取
+    pub fn host(&self) -> String {
+        let uri = self.request.head.uri();
+        if let Some((_, rest)) = uri.split_once("://") {
+            return rest.split('/').next().unwrap_or("").to_string();
+        }
+        match self.request.head.header("Host") {
+            Some(host) => host.to_string(),
+            None => self.server.clone(),
+        }
+    }
+
+    pub fn path(&self) -> String {
+        let uri = self.request.head.uri();
+        match uri.split_once("://") {
+            Some((_, rest)) => rest.find('/').map(|pos| rest[pos..].to_string()).unwrap_or("/".to_string()),
+            None => uri.to_string(),
+        }
+    }
+
+    //远程映射过的请求是实际发送的URL
+    pub fn url(&self) -> String {
+        match &self.remap {
+            Some(remap) => remap.rewritten.clone(),
+            None => request_url(&self.request.head, self.tls, &self.server),
+        }
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        self.response.as_ref()?.head.status()
+    }
+
+    pub fn content_type(&self) -> &str {
+        self.response.as_ref().and_then(|r| r.head.header("Content-Type")).unwrap_or("")
+    }
+
+    //从开始发送请求到接收完响应的时间
+    pub fn duration(&self) -> Option<Duration> {
+        self.response.as_ref()?.end.duration_since(self.request.start).ok()
+    }
+
+    //URL里的查询参数，和表单格式的请求body
+    pub fn params(&self) -> Vec<(String, String)> {
+        let path = self.path();
+        let mut params = match path.split_once('?') {
+            Some((_, query)) => parse_query(query),
+            None => vec![],
+        };
+        let content_type = self.request.head.header("Content-Type").unwrap_or("");
+        if content_type.contains("application/x-www-form-urlencoded") {
+            params.extend(parse_query(&String::from_utf8_lossy(&self.request.decoded_body())));
+        }
+        params
+    }
+
+    //请求的Cookie和响应的Set-Cookie
+    pub fn cookies(&self) -> (Vec<(String, String)>, Vec<String>) {
+        let request = self.request.head.headers.iter().filter(|(k, _)| k.eq_ignore_ascii_case("Cookie"))
+            .flat_map(|(_, v)| v.split(';'))
+            .filter_map(|c| c.split_once('=').map(|(k, v)| (k.trim().to_string(), v.trim().to_string())))
+            .collect();
+        let response = self.response.iter().flat_map(|r| r.head.headers.iter())
+            .filter(|(k, _)| k.eq_ignore_ascii_case("Set-Cookie")).map(|(_, v)| v.clone()).collect();
+        (request, response)
+    }
+
+    pub fn to_json(&self, body: bool) -> Value {
+        json!({
+            "id": self.id,
+            "sid": self.sid,
+            "client": self.client,
+            "server": self.server,
+            "scheme": self.scheme(),
+            "method": self.method(),
+            "url": self.url(),
+            "started_at": unix_millis(self.request.start),
+            "duration_ms": self.duration().map(|d| d.as_millis() as u64),
+            "request": message_json(&self.request, body),
+            "response": self.response.as_ref().map(|r| message_json(r, body)),
+            "error": self.error,
+            "tags": self.tags,
+            "original_url": self.remap.as_ref().map(|r| r.original.clone()),
+            "replay_of": self.replay_of,
+            "timings": phases(self).iter().map(|(kind, phase)| (kind.key().to_string(), json!(phase.duration().as_secs_f64() * 1000.0))).collect::<serde_json::Map<_, _>>(),
+        })
+    }
+
+    //to_json的逆操作，读取--format json输出的记录，没有输出body时body是空的
+    pub fn from_json(value: &Value) -> ProxyResult<Flow> {
+        let mut request = message_from_json(&value["request"])?;
+        let start = UNIX_EPOCH + Duration::from_millis(value["started_at"].as_u64().unwrap_or(0));
+        (request.start, request.end) = (start, start);
+        let response = match value["response"].is_null() {
+            true => None,
+            false => {
+                let mut response = message_from_json(&value["response"])?;
+                response.start = start;
+                response.end = start + Duration::from_millis(value["duration_ms"].as_u64().unwrap_or(0));
+                Some(response)
+            }
+        };
+        let text = |key: &str| value[key].as_str().unwrap_or("").to_string();
+        Ok(Flow {
+            id: value["id"].as_u64().unwrap_or(0),
+            sid: text("sid"),
+            client: text("client"),
+            server: text("server"),
+            tls: value["scheme"].as_str() == Some("https"),
+            request,
+            response,
+            error: value["error"].as_str().map(|e| e.to_string()),
+            tags: value["tags"].as_array().map(|tags| tags.iter().filter_map(|t| t.as_str()).map(|t| t.to_string()).collect()).unwrap_or_default(),
+            remap: value["original_url"].as_str().map(|original| Remap { original: original.to_string(), rewritten: text("url") }),
+            replay_of: value["replay_of"].as_u64(),
+            timing: None,
+        })
+    }
+
+    //一行的摘要：编号 时间 状态码 方法 URL 类型 大小 耗时
+    pub fn summary(&self) -> String {
+        let status = match (self.status(), &self.error) {
+            (Some(status), _) => status.to_string(),
+            (None, Some(_)) => "ERR".to_string(),
+            (None, None) => "---".to_string(),
+        };
+        let size = self.response.as_ref().map(|r| format_size(r.body_size)).unwrap_or("-".to_string());
+        let duration = self.duration().map(|d| format!("{}ms", d.as_millis())).unwrap_or("-".to_string());
+        let content_type = self.content_type().split(';').next().unwrap_or("").trim();
+        let mut line = format!("#{} {} {} {} {} {} {} {}", self.id, local_time(self.request.start), status, self.method(),
+                               self.url(), if content_type.is_empty() { "-" } else { content_type }, size, duration);
+        if let Some(remap) = &self.remap { line.push_str(&format!(" <- {}", remap.original)); }
+        if let Some(id) = self.replay_of { line.push_str(&format!(" <- #{}", id)); }
+        if !self.tags.is_empty() { line.push_str(&format!(" [{}]", self.tags.join(","))); }
+        if let Some(error) = &self.error { line.push_str(&format!(" ({})", error)); }
+        line
+    }
+}
+
+//请求的完整URL，还没有组装成Flow的时候也可以使用
+pub fn request_url(head: &HttpHead, tls: bool, server: &str) -> String {
+    let uri = head.uri();
+    if uri.contains("://") { return uri.to_string(); }
+    let scheme = if tls { "https" } else { "http" };
+    format!("{}://{}{}", scheme, head.header("Host").unwrap_or(server), uri)
+}
+
+fn message_json(message: &HttpMessage, body: bool) -> Value {
+    let mut value = json!({
+        "line": message.head.line,
+        "headers": message.head.headers,
+        "body_size": message.body_size,
+    });
+    if body && !message.body.is_empty() {
+        //文本直接输出，二进制数据用base64
+        let (text, encoding) = match std::str::from_utf8(&message.body) {
+            Ok(text) => (text.to_string(), "utf8"),
+            Err(_) => (STANDARD.encode(&message.body), "base64"),
+        };
+        value["body"] = json!(text);
+        value["body_encoding"] = json!(encoding);
+        value["body_truncated"] = json!(message.body.len() < message.body_size);
+    }
+    value
+}
+
+fn message_from_json(value: &Value) -> ProxyResult<HttpMessage> {
+    let line = value["line"].as_str().ok_or("记录格式错误，没有请求行或者状态行")?;
+    let headers = value["headers"].as_array().map(|headers| headers.iter().filter_map(|header| {
+        Some((header[0].as_str()?.to_string(), header[1].as_str()?.to_string()))
+    }).collect()).unwrap_or_default();
+    let body = value["body"].as_str().unwrap_or("");
+    let body = match value["body_encoding"].as_str() {
+        Some("base64") => STANDARD.decode(body)?,
+        _ => body.as_bytes().to_vec(),
+    };
+    let mut message = HttpMessage::new(HttpHead { line: line.to_string(), headers }, body);
+    message.body_size = value["body_size"].as_u64().map(|size| size as usize).unwrap_or(message.body_size);
+    Ok(message)
+}
+
+//a=1&b=%E4%B8%AD这种格式，+当作空格
+pub fn parse_query(query: &str) -> Vec<(String, String)> {
+    query.split('&').filter(|p| !p.is_empty()).map(|pair| {
+        let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+        (url_decode(k), url_decode(v))
+    }).collect()
+}
+
+//查询参数和表单里的+是空格
+pub fn url_decode(value: &str) -> String {
+    percent_decode(&value.replace('+', " "))
+}
+
+//只解码%xx，URL里用户名、密码的+是它本身
+pub fn percent_decode(value: &str) -> String {
+    let bs = value.as_bytes();
+    let mut res = Vec::with_capacity(bs.len());
+    let mut i = 0;
+    while i < bs.len() {
+        match bs[i] {
+            b'%' if i + 2 < bs.len() && bs[i + 1].is_ascii_hexdigit() && bs[i + 2].is_ascii_hexdigit() => {
+                let hex = |b: u8| (b as char).to_digit(16).unwrap_or(0) as u8;
+                res.push(hex(bs[i + 1]) * 16 + hex(bs[i + 2]));
+                i += 2;
+            }
+            b => res.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&res).to_string()
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+//本地时间的时:分:秒.毫秒
+pub fn local_time(time: SystemTime) -> String {
+    let millis = unix_millis(time);
+    let time = OffsetDateTime::from_unix_timestamp((millis / 1000) as i64).unwrap_or(OffsetDateTime::UNIX_EPOCH);
+    let time = time.to_offset(UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC));
+    format!("{:02}:{:02}:{:02}.{:03}", time.hour(), time.minute(), time.second(), millis % 1000)
+}
+
+pub fn format_size(size: usize) -> String {
+    match size {
+        0..1024 => format!("{} B", size),
+        1024..1048576 => format!("{:.1} KB", size as f64 / 1024.0),
+        _ => format!("{:.1} MB", size as f64 / 1048576.0),
+    }
+}
+
+//把抓包通道里的请求和响应按连接配对成Flow，HTTP1同一个连接上的响应和请求顺序一致
+pub struct FlowAssembler {
+    next_id: u64,
+    pending: HashMap<String, VecDeque<Flow>>,
+    //还没有请求的连接的时间
+    timings: HashMap<String, ConnTiming>,
+}
+
+impl Default for FlowAssembler {
+    fn default() -> Self {
+        FlowAssembler::new()
+    }
+}
+
+impl FlowAssembler {
+    pub fn new() -> FlowAssembler {
+        FlowAssembler { next_id: 1, pending: HashMap::new(), timings: HashMap::new() }
+    }
+
+    //返回这次完成的Flow
+    pub fn push(&mut self, capture: Capture) -> Vec<Flow> {
+        match capture {
+            Capture::Message { sid, client, server, tls, direction: Direction::ClientToServer, message } => {
+                let timing = self.timings.remove(&sid);
+                let flow = Flow { id: self.next_id, sid: sid.clone(), client, server, tls, request: message, response: None, error: None, tags: vec![], remap: None, replay_of: None, timing };
+                self.next_id += 1;
+                self.pending.entry(sid).or_default().push_back(flow);
+                vec![]
+            }
+            Capture::Message { sid, direction: Direction::ServerToClient, message, .. } => {
+                //100 Continue这种不是最终的响应
+                if matches!(message.head.status(), Some(status) if status < 200 && status != 101) { return vec![]; }
+                let flow = self.pending.get_mut(&sid).and_then(|flows| flows.pop_front());
+                let mut flow = match flow {
+                    Some(flow) => flow,
+                    None => {
+                        trace!("{}收到了没有对应请求的响应：{}", sid, message.head.line);
+                        return vec![];
+                    }
+                };
+                if self.pending.get(&sid).map(|flows| flows.is_empty()).unwrap_or(false) { self.pending.remove(&sid); }
+                flow.response = Some(message);
+                vec![flow]
+            }
+            Capture::Tag { sid, direction, tag } => {
+                let flow = self.pending.get_mut(&sid).and_then(|flows| match direction {
+                    Direction::ClientToServer => flows.back_mut(),
+                    Direction::ServerToClient => flows.front_mut(),
+                });
+                if let Some(flow) = flow { flow.tags.push(tag); }
+                vec![]
+            }
+            Capture::Timing { sid, timing } => {
+                match self.pending.get_mut(&sid).and_then(|flows| flows.back_mut()).filter(|flow| flow.timing.is_none()) {
+                    Some(flow) => flow.timing = Some(timing),
+                    None => { self.timings.insert(sid, timing); }
+                }
+                vec![]
+            }
+            Capture::Remap { sid, remap } => {
+                if let Some(flow) = self.pending.get_mut(&sid).and_then(|flows| flows.back_mut()) {
+                    flow.tags.push(REMAPPED_TAG.to_string());
+                    flow.remap = Some(remap);
+                }
+                vec![]
+            }
+            Capture::Replay { sid, source } => {
+                if let Some(flow) = self.pending.get_mut(&sid).and_then(|flows| flows.back_mut()) {
+                    flow.tags.push(REPLAY_TAG.to_string());
+                    flow.replay_of = source;
+                }
+                vec![]
+            }
+            Capture::Import { mut flow } => {
+                flow.id = self.next_id;
+                self.next_id += 1;
+                vec![*flow]
+            }
+            Capture::Closed { sid } => self.close(&sid, "连接已断开，没有收到响应"),
+            Capture::Failed { sid, error } => self.close(&sid, &error),
+        }
+    }
+
+    fn close(&mut self, sid: &str, error: &str) -> Vec<Flow> {
+        self.timings.remove(sid);
+        let flows = self.pending.remove(sid).unwrap_or_default();
+        flows.into_iter().map(|mut flow| {
+            flow.error = Some(error.to_string());
+            flow
+        }).collect()
+    }
+}
+
+//测试时用原始数据构造一个Flow
+#[cfg(test)]
+pub fn test_flow(id: u64, request: &[u8], response: &[u8]) -> Flow {
+    use std::sync::{Arc, Mutex};
+    use crate::data::http::HttpParser;
+    let heads = Arc::new(Mutex::new(VecDeque::new()));
+    let request = HttpParser::new(true, heads.clone()).feed(request).remove(0);
+    let response = HttpParser::new(false, heads).feed(response).pop();
+    Flow {
+        id,
+        sid: id.to_string(),
+        client: "127.0.0.1:50000".to_string(),
+        server: request.head.header("Host").unwrap_or("").to_string(),
+        tls: true,
+        request,
+        response,
+        error: None,
+        tags: vec![],
+        remap: None,
+        replay_of: None,
+        timing: None,
+    }
+}
+
+#[cfg(test)]
+mod test_flow {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use crate::data::flow::{test_flow, Capture, FlowAssembler};
+    use crate::data::http::{HttpHead, HttpMessage, HttpParser, Parsed, MAX_BODY};
+    use crate::proxy::Direction;
+
+    #[test]
+    fn test_parser() {
+        let heads = Arc::new(Mutex::new(VecDeque::new()));
+        let mut request = HttpParser::new(true, heads.clone());
+        let mut response = HttpParser::new(false, heads);
+        assert_eq!(request.feed(b"GET / HTTP/1.1\r\nHost: a.com\r\n\r\nHEAD / HTTP/1.1\r\nHost: a.com\r\n\r\n").len(), 2);
+        //分成好几个包的chunked响应
+        assert!(response.feed(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel").is_empty());
+        let messages = response.feed(b"lo\r\n6;ext=1\r\n world\r\n0\r\n\r\n");
+        assert_eq!(messages[0].body, b"hello world");
+        //HEAD请求的响应有Content-Length但是没有body
+        let messages = response.feed(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n");
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].body_size, 0);
+        //没有长度的响应读到连接断开
+        let mut response = HttpParser::new(false, Arc::new(Mutex::new(VecDeque::new())));
+        assert!(response.feed(b"HTTP/1.0 200 OK\r\n\r\nabc").is_empty());
+        assert_eq!(response.finish().unwrap().body, b"abc");
+        //不是HTTP的数据
+        let mut request = HttpParser::new(true, Arc::new(Mutex::new(VecDeque::new())));
+        assert!(request.feed(b"\x16\x03\x01\x00\x05hello\r\n\r\n").is_empty());
+        assert!(request.feed(b"GET / HTTP/1.1\r\n\r\n").is_empty());
+    }
+
+    #[test]
+    fn test_forward() {
+        let mut request = HttpParser::new(true, Arc::new(Mutex::new(VecDeque::new()))).forward(true);
+        //升级成websocket之后的数据原样转发
+        let parsed = request.parse(b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\n\r\n\x81\x05");
+        assert!(matches!(&parsed[0], Parsed::Message(m) if m.head.uri() == "/ws"));
+        assert!(matches!(&parsed[1], Parsed::Raw(bs) if bs == b"\x81\x05"));
+        assert!(matches!(&request.parse(b"hello")[0], Parsed::Raw(bs) if bs == b"hello"));
+        //chunked的body去掉编码后重新设置长度
+        let mut response = HttpParser::new(false, Arc::new(Mutex::new(VecDeque::new()))).forward(true);
+        let mut parsed = response.parse(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n");
+        let message = parsed.pop().unwrap().into_message().unwrap();
+        assert_eq!(message.to_bytes(), b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+        //body太大时边接收边转发，转发的数据和接收的一样
+        let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 10);
+        let mut data = head.into_bytes();
+        data.extend(vec![b'a'; MAX_BODY + 10]);
+        let mut raw = vec![];
+        let mut forwarded = None;
+        for chunk in data.chunks(MAX_BODY / 2) {
+            for parsed in response.parse(chunk) {
+                match parsed {
+                    Parsed::Raw(bs) => raw.extend(bs),
+                    Parsed::Forwarded(message) => forwarded = Some(message),
+                    Parsed::Message(_) => panic!("body太大的消息不应该等待接收完整"),
+                }
+            }
+        }
+        assert_eq!(raw, data);
+        assert_eq!(forwarded.unwrap().body_size, MAX_BODY + 10);
+        //修改过的body
+        let mut message = HttpMessage::response(200, "OK", "abc");
+        message.body = b"abcdef".to_vec();
+        assert!(String::from_utf8(message.to_bytes()).unwrap().contains("Content-Length: 6"));
+    }
+
+    #[test]
+    fn test_assembler() {
+        let flow = test_flow(1, b"GET /a?b=1 HTTP/1.1\r\nHost: a.com\r\n\r\n", b"HTTP/1.1 404 Not Found\r\nContent-Length: 2\r\n\r\nno");
+        assert_eq!(flow.url(), "https://a.com/a?b=1");
+        assert_eq!(flow.status(), Some(404));
+        let (head, _) = HttpHead::parse(b"GET http://b.com:8080/x HTTP/1.1\r\n\r\n").unwrap();
+        let mut request = flow.request.clone();
+        request.head = head;
+        let mut assembler = FlowAssembler::new();
+        let capture = |direction, message| Capture::Message {
+            sid: "1".to_string(),
+            client: "".to_string(),
+            server: "".to_string(),
+            tls: false,
+            direction,
+            message,
+        };
+        assert!(assembler.push(capture(Direction::ClientToServer, request.clone())).is_empty());
+        assert!(assembler.push(capture(Direction::ClientToServer, request)).is_empty());
+        let flows = assembler.push(capture(Direction::ServerToClient, flow.response.clone().unwrap()));
+        assert_eq!(flows[0].id, 1);
+        assert_eq!(flows[0].host(), "b.com:8080");
+        assert_eq!(flows[0].path(), "/x");
+        let flows = assembler.push(Capture::Closed { sid: "1".to_string() });
+        assert_eq!(flows[0].id, 2);
+        assert!(flows[0].error.is_some());
+    }
+}
@@ -1,6 +1,16 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 use reqrio::{Buffer, Response};
 use crate::error::ProxyResult;
 
+//头部超过这个长度还没结束的，当作不是HTTP数据
+pub const MAX_HEAD: usize = 64 * 1024;
+//body只保存这么多，超出的部分只计算长度
+pub const MAX_BODY: usize = 16 * 1024 * 1024;
+
 pub type Request = Response;
 
 
@@ -23,4 +33,509 @@ impl HttpStream {
             false => Ok(self.response.extend(buffer)?)
         }
     }
-}
\ No newline at end of file
+}
+
+//HTTP1的请求头/响应头，结构见README，第一行是请求行或者状态行
+#[derive(Clone, Debug)]
+pub struct HttpHead {
+    pub line: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl HttpHead {
+    //头部还没接收完整时返回None，成功时同时返回头部的长度(包括最后的空行)
+    pub fn parse(bs: &[u8]) -> Option<(HttpHead, usize)> {
+        let end = bs.windows(4).position(|b| b == b"\r\n\r\n")? + 4;
+        let text = String::from_utf8_lossy(&bs[..end - 4]).to_string();
+        let mut lines = text.split("\r\n");
+        let line = lines.next()?.to_string();
+        let headers = lines.filter_map(|l| l.split_once(':')).map(|(k, v)| (k.trim().to_string(), v.trim().to_string())).collect();
+        Some((HttpHead { line, headers }, end))
+    }
+
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v.as_str())
+    }
+
+    //替换已有的同名头(只保留一个)，没有时添加到最后
+    pub fn set_header(&mut self, key: &str, value: impl ToString) {
+        match self.headers.iter().position(|(k, _)| k.eq_ignore_ascii_case(key)) {
+            Some(pos) => {
+                self.headers[pos].1 = value.to_string();
+                let mut index = 0;
+                self.headers.retain(|(k, _)| {
+                    index += 1;
+                    index - 1 == pos || !k.eq_ignore_ascii_case(key)
+                });
+            }
+            None => self.headers.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    pub fn remove_header(&mut self, key: &str) {
+        self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+    }
+
+    //请求行的第二段，如：GET /index.html HTTP/1.1中的/index.html
+    pub fn uri(&self) -> &str {
+        self.line.split(' ').nth(1).unwrap_or("")
+    }
+
+    //请求行的第一段，如：GET
+    pub fn method(&self) -> &str {
+        self.line.split(' ').next().unwrap_or("")
+    }
+
+    //状态行的第二段，如：HTTP/1.1 200 OK中的200
+    pub fn status(&self) -> Option<u16> {
+        self.line.split(' ').nth(1)?.parse().ok()
+    }
+
+    pub fn set_uri(&mut self, uri: &str) {
+        let mut parts: Vec<&str> = self.line.splitn(3, ' ').collect();
+        if parts.len() < 2 { return; }
+        parts[1] = uri;
+        self.line = parts.join(" ");
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bs = self.line.clone().into_bytes();
+        bs.extend(b"\r\n");
+        for (k, v) in &self.headers {
+            bs.extend(format!("{}: {}\r\n", k, v).into_bytes());
+        }
+        bs.extend(b"\r\n");
+        bs
+    }
+}
+
+//常用状态码的描述，代理自己生成响应时使用
+pub fn reason_phrase(code: u16) -> &'static str {
+    match code {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
+
+//生成一个简单的文本响应，用于代理自己返回错误信息
+pub fn text_response(code: u16, reason: &str, body: impl AsRef<str>) -> Vec<u8> {
+    let body = body.as_ref();
+    format!("HTTP/1.1 {} {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", code, reason, body.len(), body).into_bytes()
+}
+
+//一个完整的HTTP消息，body是去掉chunked编码后的数据
+#[derive(Clone, Debug)]
+pub struct HttpMessage {
+    pub head: HttpHead,
+    pub body: Vec<u8>,
+    //body的实际长度，超过MAX_BODY的部分没有保存
+    pub body_size: usize,
+    //收到第一个字节和最后一个字节的时间
+    pub start: SystemTime,
+    pub end: SystemTime,
+}
+
+impl HttpMessage {
+    pub fn new(head: HttpHead, body: Vec<u8>) -> HttpMessage {
+        let now = SystemTime::now();
+        HttpMessage { head, body_size: body.len(), body, start: now, end: now }
+    }
+
+    //拦截处理里直接返回给客户端的响应，其他的头可以通过head.set_header添加
+    pub fn response(code: u16, reason: &str, body: impl Into<Vec<u8>>) -> HttpMessage {
+        let body = body.into();
+        let head = HttpHead { line: format!("HTTP/1.1 {} {}", code, reason), headers: vec![("Content-Length".to_string(), body.len().to_string())] };
+        HttpMessage::new(head, body)
+    }
+
+    //转发时重新组装报文，chunked的body已经去掉了编码，body被修改过时需要重新设置长度
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut head = self.head.clone();
+        let chunked = head.header("Transfer-Encoding").map(|v| v.to_lowercase().contains("chunked")).unwrap_or(false);
+        if chunked {
+            head.remove_header("Transfer-Encoding");
+            head.set_header("Content-Length", self.body.len());
+        } else if self.body.len() != self.body_size || (head.header("Content-Length").is_none() && !self.body.is_empty()) {
+            head.set_header("Content-Length", self.body.len());
+        }
+        let mut bs = head.to_bytes();
+        bs.extend(&self.body);
+        bs
+    }
+
+    //按Content-Encoding解压后的body，不支持的编码或者解压失败时返回原始数据
+    pub fn decoded_body(&self) -> Vec<u8> {
+        let encoding = self.head.header("Content-Encoding").unwrap_or("").trim().to_lowercase();
+        let mut body = vec![];
+        let res = match encoding.as_str() {
+            "gzip" | "x-gzip" => flate2::read::GzDecoder::new(self.body.as_slice()).read_to_end(&mut body),
+            "deflate" => flate2::read::ZlibDecoder::new(self.body.as_slice()).read_to_end(&mut body),
+            _ => return self.body.clone(),
+        };
+        match res {
+            Ok(_) => body,
+            Err(_) => self.body.clone(),
+        }
+    }
+
+    //没有压缩或者是支持解压的编码，decoded_body返回的是原始内容
+    pub fn can_decode(&self) -> bool {
+        let encoding = self.head.header("Content-Encoding").unwrap_or("").trim().to_lowercase();
+        matches!(encoding.as_str(), "" | "identity" | "gzip" | "x-gzip" | "deflate")
+    }
+
+    //修改解压后的body，按原来的Content-Encoding重新压缩，同时修改长度
+    pub fn set_decoded_body(&mut self, body: Vec<u8>) {
+        let encoding = self.head.header("Content-Encoding").unwrap_or("").trim().to_lowercase();
+        let encoded = match encoding.as_str() {
+            "gzip" | "x-gzip" => {
+                let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(&body).and_then(|_| encoder.finish())
+            }
+            "deflate" => {
+                let mut encoder = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(&body).and_then(|_| encoder.finish())
+            }
+            _ => Ok(body.clone()),
+        };
+        self.body = match encoded {
+            Ok(encoded) => encoded,
+            //压缩失败时直接发送没有压缩的数据
+            Err(_) => {
+                self.head.remove_header("Content-Encoding");
+                body
+            }
+        };
+        self.body_size = self.body.len();
+        self.head.remove_header("Transfer-Encoding");
+        self.head.set_header("Content-Length", self.body.len());
+    }
+
+    //预览：JSON格式化，文本原样显示，二进制只显示长度
+    pub fn preview(&self) -> String {
+        let body = self.decoded_body();
+        if body.is_empty() { return String::new(); }
+        if let Ok(json) = serde_json::from_slice::<serde_json::Value>(&body) {
+            return serde_json::to_string_pretty(&json).unwrap_or_default();
+        }
+        match String::from_utf8(body) {
+            Ok(text) => text,
+            Err(e) => format!("[{}字节的二进制数据]", e.as_bytes().len()),
+        }
+    }
+
+    //原始报文，二进制的body只显示长度
+    pub fn raw(&self) -> String {
+        let mut raw = String::from_utf8_lossy(&self.head.to_bytes()).to_string();
+        match std::str::from_utf8(&self.body) {
+            Ok(body) => raw.push_str(body),
+            Err(_) => raw.push_str(&format!("[{}字节的二进制数据]", self.body_size)),
+        }
+        if self.body.len() < self.body_size { raw.push_str(&format!("\n[body过大，只保存了前{}字节]", self.body.len())); }
+        raw
+    }
+}
+
+//转发模式下解析的结果，按数据的顺序排列
+pub enum Parsed {
+    //完整的消息，还没有转发，可以修改后再转发
+    Message(HttpMessage),
+    //body太大，已经边接收边转发了，只用来抓包
+    Forwarded(HttpMessage),
+    //需要原样转发的数据，如：升级成websocket后的数据、太大的body
+    Raw(Vec<u8>),
+}
+
+impl Parsed {
+    pub fn into_message(self) -> Option<HttpMessage> {
+        match self {
+            Parsed::Message(message) | Parsed::Forwarded(message) => Some(message),
+            Parsed::Raw(_) => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum BodyState {
+    //还在读取头部
+    Head,
+    //Content-Length，剩余的长度
+    Length(usize),
+    //chunked编码，等待长度行
+    ChunkSize,
+    //chunked编码，当前块剩余的长度
+    ChunkData(usize),
+    //块数据后面的\r\n
+    ChunkEnd,
+    //最后一个块后面的trailer，以空行结束
+    Trailer,
+    //没有长度的响应，读到连接断开为止
+    UntilClose,
+    //升级成了其他协议(如websocket)或者不是HTTP数据，后面的数据不再解析
+    Passthrough,
+}
+
+/*
+   从TCP数据流里拆分出一个个HTTP消息，数据可能被拆成任意大小的包
+   body的长度按照以下顺序判断：
+   1. HEAD请求的响应、1xx、204、304没有body
+   2. Transfer-Encoding: chunked
+   3. Content-Length
+   4. 请求没有body，响应读到连接断开
+   转发模式下(有拦截处理时)消息接收完整后才转发，同时记录原始数据：
+   不是HTTP的数据、body超过MAX_BODY的消息、不需要接收完整(BufferPolicy返回false)的消息原样转发，不会丢掉任何数据
+ */
+pub struct HttpParser {
+    request: bool,
+    state: BodyState,
+    buf: Vec<u8>,
+    current: Option<HttpMessage>,
+    //请求方向记录请求的头部，响应方向按顺序取出来判断HEAD请求和决定是否接收完整，两个方向共用一个
+    heads: Arc<Mutex<VecDeque<HttpHead>>>,
+    forward: bool,
+    //当前消息已经解析过的原始数据，只有转发模式下记录
+    raw: Vec<u8>,
+    //当前消息的body太大，正在边接收边转发
+    streaming: bool,
+    //转发模式下每个消息是否接收完整再转发，没有设置时都接收完整
+    policy: Option<BufferPolicy>,
+}
+
+//转发模式下每个消息的头部解析后调用，参数是这个消息对应的请求头部，返回false时这个消息从头部开始就边接收边转发
+pub type BufferPolicy = Box<dyn Fn(&HttpHead) -> bool + Send + Sync>;
+
+impl HttpParser {
+    pub fn new(request: bool, heads: Arc<Mutex<VecDeque<HttpHead>>>) -> HttpParser {
+        HttpParser { request, state: BodyState::Head, buf: vec![], current: None, heads, forward: false, raw: vec![], streaming: false, policy: None }
+    }
+
+    pub fn forward(mut self, forward: bool) -> HttpParser {
+        self.forward = forward;
+        self
+    }
+
+    //只影响后面开始的消息，正在接收的消息不变
+    pub fn set_policy(&mut self, policy: BufferPolicy) {
+        self.policy = Some(policy);
+    }
+
+    pub fn heads(&self) -> Arc<Mutex<VecDeque<HttpHead>>> {
+        self.heads.clone()
+    }
+
+    //返回这次数据里接收完整的消息
+    pub fn feed(&mut self, bs: &[u8]) -> Vec<HttpMessage> {
+        self.parse(bs).into_iter().filter_map(Parsed::into_message).collect()
+    }
+
+    pub fn parse(&mut self, bs: &[u8]) -> Vec<Parsed> {
+        let mut parsed = vec![];
+        self.buf.extend(bs);
+        loop {
+            match self.state {
+                BodyState::Head => {
+                    if self.buf.is_empty() { break; }
+                    let (head, len) = match HttpHead::parse(&self.buf) {
+                        Some(res) => res,
+                        None if self.buf.len() > MAX_HEAD => {
+                            self.passthrough();
+                            continue;
+                        }
+                        None => break,
+                    };
+                    if !head.line.contains("HTTP/") {
+                        self.passthrough();
+                        continue;
+                    }
+                    self.take(len);
+                    let request = self.request_head(&head);
+                    self.state = self.body_state(&head, request.as_ref());
+                    //没有对应请求的响应不知道要不要处理，接收完整
+                    if self.forward && let (Some(policy), Some(request)) = (&self.policy, &request) && !policy(request) { self.streaming = true; }
+                    let now = SystemTime::now();
+                    self.current = Some(HttpMessage { head, body: vec![], body_size: 0, start: now, end: now });
+                    //101的响应本身是完整的，后面的数据就不是HTTP了
+                    if let BodyState::Head | BodyState::Passthrough = self.state { parsed.extend(self.complete()); }
+                }
+                BodyState::Length(remain) => {
+                    let len = remain.min(self.buf.len());
+                    let data = self.take(len);
+                    self.push_body(&data);
+                    match remain - len {
+                        0 => parsed.extend(self.complete()),
+                        remain => {
+                            self.state = BodyState::Length(remain);
+                            break;
+                        }
+                    }
+                }
+                BodyState::ChunkSize => {
+                    let pos = match self.buf.windows(2).position(|b| b == b"\r\n") {
+                        Some(pos) => pos,
+                        None => break,
+                    };
+                    let line = String::from_utf8_lossy(&self.take(pos + 2)[..pos]).to_string();
+                    let size = line.split(';').next().unwrap_or("").trim();
+                    match usize::from_str_radix(size, 16) {
+                        Ok(0) => self.state = BodyState::Trailer,
+                        Ok(size) => self.state = BodyState::ChunkData(size),
+                        Err(_) => self.passthrough(),
+                    }
+                }
+                BodyState::ChunkData(remain) => {
+                    let len = remain.min(self.buf.len());
+                    let data = self.take(len);
+                    self.push_body(&data);
+                    match remain - len {
+                        0 => self.state = BodyState::ChunkEnd,
+                        remain => {
+                            self.state = BodyState::ChunkData(remain);
+                            break;
+                        }
+                    }
+                }
+                BodyState::ChunkEnd => {
+                    if self.buf.len() < 2 { break; }
+                    self.take(2);
+                    self.state = BodyState::ChunkSize;
+                }
+                BodyState::Trailer => {
+                    let pos = match self.buf.windows(2).position(|b| b == b"\r\n") {
+                        Some(pos) => pos,
+                        None => break,
+                    };
+                    self.take(pos + 2);
+                    if pos == 0 { parsed.extend(self.complete()); }
+                }
+                BodyState::UntilClose => {
+                    let data = self.take(self.buf.len());
+                    self.push_body(&data);
+                    break;
+                }
+                BodyState::Passthrough => {
+                    //转发模式下剩下的数据原样转发，否则直接丢掉
+                    let mut data = mem::take(&mut self.raw);
+                    data.extend(mem::take(&mut self.buf));
+                    if self.forward && !data.is_empty() { parsed.push(Parsed::Raw(data)); }
+                    break;
+                }
+            }
+        }
+        if self.streaming && !self.raw.is_empty() { parsed.push(Parsed::Raw(mem::take(&mut self.raw))); }
+        parsed
+    }
+
+    //连接断开时调用，读到连接断开为止的响应这时才算完整
+    pub fn finish(&mut self) -> Option<HttpMessage> {
+        self.close().into_iter().find_map(Parsed::into_message)
+    }
+
+    pub fn close(&mut self) -> Vec<Parsed> {
+        let mut parsed = match self.state {
+            BodyState::UntilClose => self.complete(),
+            _ => vec![],
+        };
+        //没有接收完整的数据也要转发出去
+        let mut data = mem::take(&mut self.raw);
+        data.extend(mem::take(&mut self.buf));
+        if self.forward && !data.is_empty() { parsed.push(Parsed::Raw(data)); }
+        parsed
+    }
+
+    //从缓冲区取出已经解析的数据，转发模式下同时记录下来
+    fn take(&mut self, len: usize) -> Vec<u8> {
+        let data: Vec<u8> = self.buf.drain(..len).collect();
+        if self.forward { self.raw.extend(&data); }
+        data
+    }
+
+    //请求方向记录下请求的头部，响应方向取出对应的请求，1xx不是最终的响应，不消耗请求
+    fn request_head(&self, head: &HttpHead) -> Option<HttpHead> {
+        let mut heads = self.heads.lock().ok()?;
+        match (self.request, head.status()) {
+            (true, _) => {
+                heads.push_back(head.clone());
+                Some(head.clone())
+            }
+            (false, Some(status)) if status < 200 => None,
+            (false, _) => heads.pop_front(),
+        }
+    }
+
+    fn body_state(&self, head: &HttpHead, request: Option<&HttpHead>) -> BodyState {
+        match head.status() {
+            Some(101) if !self.request => return BodyState::Passthrough,
+            Some(status) if !self.request && status < 200 => return BodyState::Head,
+            _ => {}
+        }
+        let head_request = request.is_some_and(|request| request.method().eq_ignore_ascii_case("HEAD"));
+        if !self.request && (head_request || matches!(head.status(), Some(204 | 304))) {
+            return BodyState::Head;
+        }
+        let chunked = head.header("Transfer-Encoding").map(|v| v.to_lowercase().contains("chunked")).unwrap_or(false);
+        let length = head.header("Content-Length").and_then(|v| v.trim().parse::<usize>().ok());
+        match (chunked, length, self.request) {
+            (true, _, _) => BodyState::ChunkSize,
+            (false, Some(0), _) => BodyState::Head,
+            (false, Some(len), _) => BodyState::Length(len),
+            (false, None, true) => BodyState::Head,
+            (false, None, false) => BodyState::UntilClose,
+        }
+    }
+
+    fn push_body(&mut self, data: &[u8]) {
+        if let Some(message) = self.current.as_mut() {
+            let keep = MAX_BODY.saturating_sub(message.body.len()).min(data.len());
+            message.body.extend(&data[..keep]);
+            message.body_size += data.len();
+            if self.forward && message.body_size > MAX_BODY { self.streaming = true; }
+        }
+    }
+
+    fn complete(&mut self) -> Vec<Parsed> {
+        if !matches!(self.state, BodyState::Passthrough) { self.state = BodyState::Head; }
+        let raw = mem::take(&mut self.raw);
+        let streaming = mem::replace(&mut self.streaming, false);
+        let mut message = match self.current.take() {
+            Some(message) => message,
+            None => return vec![],
+        };
+        message.end = SystemTime::now();
+        //请求升级协议(如websocket)后，客户端后面发送的就不是HTTP数据了
+        if self.request && message.head.header("Upgrade").is_some() { self.state = BodyState::Passthrough; }
+        match streaming {
+            true => vec![Parsed::Raw(raw), Parsed::Forwarded(message)],
+            false => vec![Parsed::Message(message)],
+        }
+    }
+
+    //不是HTTP数据，当前消息已经解析的原始数据保留下来，在转发模式下原样转发
+    fn passthrough(&mut self) {
+        self.state = BodyState::Passthrough;
+        self.current = None;
+        self.streaming = false;
+    }
+}
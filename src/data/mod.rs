@@ -1,5 +1,10 @@
 pub mod http;
+pub mod flow;
+pub mod store;
+pub mod timing;
+pub mod tree;
 pub mod ui;
+pub mod websocket;
 
 use std::fmt::{Display, Formatter, Write};
 
@@ -0,0 +1,109 @@
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use log::error;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::Receiver;
+use tokio_stream::Stream;
+use tokio_stream::wrappers::BroadcastStream;
+use crate::data::flow::{Capture, Flow, FlowAssembler};
+use crate::filter::Filter;
+use crate::headless::FlowWriter;
+
+//界面(GUI、TUI)和控制接口共用的抓包记录，按完成的顺序保存
+pub struct FlowStore {
+    flows: RwLock<VecDeque<Arc<Flow>>>,
+    //最多保存多少个，超过时删除最早的，为0时不限制
+    limit: usize,
+    //新完成的Flow，需要实时接收的地方订阅这个
+    sender: broadcast::Sender<Arc<Flow>>,
+}
+
+impl FlowStore {
+    pub fn new(limit: usize) -> Arc<FlowStore> {
+        let (sender, _) = broadcast::channel(1024);
+        Arc::new(FlowStore { flows: RwLock::new(VecDeque::new()), limit, sender })
+    }
+
+    pub fn push(&self, flow: impl Into<Arc<Flow>>) {
+        let flow = flow.into();
+        if let Ok(mut flows) = self.flows.write() {
+            flows.push_back(flow.clone());
+            while self.limit > 0 && flows.len() > self.limit { flows.pop_front(); }
+        }
+        //没有订阅者的时候会返回错误，不用处理
+        let _ = self.sender.send(flow);
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut flows) = self.flows.write() { flows.clear(); }
+    }
+
+    pub fn len(&self) -> usize {
+        self.flows.read().map(|flows| flows.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<Flow>> {
+        //id是按请求的顺序编号的，保存是按完成的顺序，不一定有序，从后往前找
+        self.flows.read().ok()?.iter().rev().find(|flow| flow.id == id).cloned()
+    }
+
+    //按过滤表达式筛选，返回的是引用计数，不会复制body
+    pub fn list(&self, filter: &Filter) -> Vec<Arc<Flow>> {
+        match self.flows.read() {
+            Ok(flows) => flows.iter().filter(|flow| filter.matches(flow)).cloned().collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Flow>> {
+        self.sender.subscribe()
+    }
+
+    pub fn stream(&self) -> FlowStream {
+        FlowStream(BroadcastStream::new(self.subscribe()))
+    }
+}
+
+//新完成的Flow的异步流，给嵌入使用的程序接收，接收太慢时中间的记录会被跳过
+pub struct FlowStream(BroadcastStream<Arc<Flow>>);
+
+impl Stream for FlowStream {
+    type Item = Arc<Flow>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match Pin::new(&mut self.0).poll_next(cx) {
+                Poll::Ready(Some(Ok(flow))) => return Poll::Ready(Some(flow)),
+                Poll::Ready(Some(Err(_))) => continue,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+//每个Flow完成时调用，在接收抓包数据的任务里执行，不要做耗时的操作
+pub type FlowCallback = Box<dyn Fn(&Flow) + Send + Sync>;
+
+//从抓包通道接收数据，配对成Flow后保存，需要输出到stdout或者文件时同时输出
+pub async fn receive_flows(mut rx: Receiver<Capture>, store: Arc<FlowStore>, writer: Option<FlowWriter>, callbacks: Vec<FlowCallback>) {
+    let mut assembler = FlowAssembler::new();
+    let (sender, thread) = writer.map(FlowWriter::spawn).unzip();
+    while let Some(capture) = rx.recv().await {
+        for flow in assembler.push(capture) {
+            let flow = Arc::new(flow);
+            if let Some(sender) = &sender { sender.send(flow.clone()).unwrap_or_else(|_| error!("输出抓包记录的线程已退出")); }
+            for callback in &callbacks { callback(&flow); }
+            store.push(flow);
+        }
+    }
+    //等输出线程把剩下的记录写完
+    drop(sender);
+    if let Some(thread) = thread { let _ = tokio::task::spawn_blocking(move || thread.join()).await; }
+}
@@ -0,0 +1,144 @@
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, SystemTime};
+use crate::data::flow::Flow;
+
+//一个阶段的开始和结束时间
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Phase {
+    pub start: SystemTime,
+    pub end: SystemTime,
+}
+
+impl Phase {
+    //从start开始到现在
+    pub fn since(start: SystemTime) -> Phase {
+        Phase { start, end: SystemTime::now() }
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.end.duration_since(self.start).unwrap_or_default()
+    }
+}
+
+/*
+    一个连接建立过程中各个阶段的时间，连接上的第一个请求记录这些时间，复用连接的请求没有
+    accept   客户端连接进来的时间，到开始处理CONNECT(或者socks握手、第一个请求)为止
+    connect  CONNECT请求或者socks握手，到告诉客户端隧道已建立为止，里面可能包括了连接服务器
+    dns      解析服务器(有上级代理时是上级代理)的地址
+    tcp      和服务器建立TCP连接，有上级代理时包括上级代理的握手
+    tls      和服务器的TLS握手
+ */
+#[derive(Clone, Debug, Default)]
+pub struct ConnTiming {
+    pub accept: Option<SystemTime>,
+    pub connect: Option<Phase>,
+    pub dns: Option<Phase>,
+    pub tcp: Option<Phase>,
+    pub tls: Option<Phase>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PhaseKind {
+    Accept,
+    Connect,
+    Dns,
+    Tcp,
+    Tls,
+    //从收到请求的第一个字节到转发完
+    Send,
+    //请求发送完到收到响应的第一个字节(TTFB)
+    Wait,
+    //接收响应
+    Receive,
+}
+
+impl PhaseKind {
+    pub fn kinds() -> Vec<PhaseKind> {
+        vec![PhaseKind::Accept, PhaseKind::Connect, PhaseKind::Dns, PhaseKind::Tcp, PhaseKind::Tls, PhaseKind::Send, PhaseKind::Wait, PhaseKind::Receive]
+    }
+
+    //JSON输出里使用的名称，和HAR的timings一样
+    pub fn key(&self) -> &'static str {
+        match self {
+            PhaseKind::Accept => "accept",
+            PhaseKind::Connect => "connect",
+            PhaseKind::Dns => "dns",
+            PhaseKind::Tcp => "tcp",
+            PhaseKind::Tls => "ssl",
+            PhaseKind::Send => "send",
+            PhaseKind::Wait => "wait",
+            PhaseKind::Receive => "receive",
+        }
+    }
+}
+
+impl Display for PhaseKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PhaseKind::Accept => f.write_str("接受连接"),
+            PhaseKind::Connect => f.write_str("建立隧道"),
+            PhaseKind::Dns => f.write_str("DNS解析"),
+            PhaseKind::Tcp => f.write_str("TCP连接"),
+            PhaseKind::Tls => f.write_str("TLS握手"),
+            PhaseKind::Send => f.write_str("发送请求"),
+            PhaseKind::Wait => f.write_str("等待响应"),
+            PhaseKind::Receive => f.write_str("接收响应"),
+        }
+    }
+}
+
+//一条记录的各个阶段，按开始时间排序，没有的阶段不返回
+pub fn phases(flow: &Flow) -> Vec<(PhaseKind, Phase)> {
+    let mut phases = vec![];
+    if let Some(timing) = &flow.timing {
+        let conn = [(PhaseKind::Connect, timing.connect), (PhaseKind::Dns, timing.dns), (PhaseKind::Tcp, timing.tcp), (PhaseKind::Tls, timing.tls)];
+        if let Some(accept) = timing.accept {
+            //接受连接到下一个阶段开始
+            let next = conn.iter().filter_map(|(_, phase)| phase.map(|p| p.start)).chain([flow.request.start]).min().unwrap_or(accept);
+            phases.push((PhaseKind::Accept, Phase { start: accept, end: next.max(accept) }));
+        }
+        phases.extend(conn.into_iter().filter_map(|(kind, phase)| phase.map(|phase| (kind, phase))));
+    }
+    let request = &flow.request;
+    phases.push((PhaseKind::Send, Phase { start: request.start, end: request.end.max(request.start) }));
+    if let Some(response) = &flow.response {
+        phases.push((PhaseKind::Wait, Phase { start: request.end, end: response.start.max(request.end) }));
+        phases.push((PhaseKind::Receive, Phase { start: response.start, end: response.end.max(response.start) }));
+    }
+    phases.sort_by_key(|(_, phase)| phase.start);
+    phases
+}
+
+#[cfg(test)]
+mod test_timing {
+    use std::time::{Duration, SystemTime};
+    use crate::data::flow::{test_flow, Flow};
+    use crate::data::timing::{phases, ConnTiming, Phase, PhaseKind};
+
+    #[test]
+    fn test_timing() {
+        let at = |ms: u64| SystemTime::UNIX_EPOCH + Duration::from_millis(ms);
+        let mut flow = test_flow(1, b"GET / HTTP/1.1\r\nHost: a.com\r\n\r\n", b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+        (flow.request.start, flow.request.end) = (at(100), at(101));
+        let response = flow.response.as_mut().unwrap();
+        (response.start, response.end) = (at(150), at(170));
+        let kinds = |flow: &Flow| phases(flow).iter().map(|(kind, phase)| (*kind, phase.duration().as_millis())).collect::<Vec<_>>();
+        //复用的连接只有请求的阶段
+        assert_eq!(kinds(&flow), [(PhaseKind::Send, 1), (PhaseKind::Wait, 49), (PhaseKind::Receive, 20)]);
+        flow.timing = Some(ConnTiming {
+            accept: Some(at(0)),
+            connect: Some(Phase { start: at(2), end: at(60) }),
+            dns: Some(Phase { start: at(3), end: at(13) }),
+            tcp: Some(Phase { start: at(13), end: at(50) }),
+            tls: Some(Phase { start: at(70), end: at(99) }),
+        });
+        assert_eq!(kinds(&flow), [
+            (PhaseKind::Accept, 2), (PhaseKind::Connect, 58), (PhaseKind::Dns, 10), (PhaseKind::Tcp, 37),
+            (PhaseKind::Tls, 29), (PhaseKind::Send, 1), (PhaseKind::Wait, 49), (PhaseKind::Receive, 20),
+        ]);
+        //还没有响应、时间顺序不对的不会是负数
+        flow.response = None;
+        flow.request.end = at(90);
+        assert_eq!(kinds(&flow).last(), Some(&(PhaseKind::Send, 0)));
+    }
+}
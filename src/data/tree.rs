@@ -0,0 +1,105 @@
+use std::collections::BTreeMap;
+use crate::data::flow::Flow;
+
+/*
+    按域名和路径把记录分组成一棵树，每个节点统计下面所有记录的个数、大小和错误数：
+    https://a.com        (12, 34.5 KB)
+      api                (10)
+        login            (1, 1个错误)
+        user             (9)
+    http://cdn.b.com     (30)
+    节点的路径是从根开始的每一段，第一段是scheme://host，查询参数不参与分组
+ */
+#[derive(Clone, Debug, Default)]
+pub struct TreeNode {
+    pub name: String,
+    pub path: Vec<String>,
+    pub count: usize,
+    //请求和响应的body大小
+    pub bytes: usize,
+    //连接出错或者状态码>=400
+    pub errors: usize,
+    pub children: BTreeMap<String, TreeNode>,
+}
+
+impl TreeNode {
+    pub fn build<'a>(flows: impl IntoIterator<Item = &'a Flow>) -> TreeNode {
+        let mut root = TreeNode::default();
+        for flow in flows { root.add(flow); }
+        root
+    }
+
+    fn add(&mut self, flow: &Flow) {
+        let bytes = flow.request.body_size + flow.response.as_ref().map(|r| r.body_size).unwrap_or(0);
+        let error = flow.error.is_some() || flow.status().map(|s| s >= 400).unwrap_or(false);
+        let segments = tree_path(flow);
+        let mut node = self;
+        node.add_stats(bytes, error);
+        for (index, segment) in segments.iter().enumerate() {
+            node = node.children.entry(segment.clone()).or_insert_with(|| TreeNode { name: segment.clone(), path: segments[..=index].to_vec(), ..TreeNode::default() });
+            node.add_stats(bytes, error);
+        }
+    }
+
+    fn add_stats(&mut self, bytes: usize, error: bool) {
+        self.count += 1;
+        self.bytes += bytes;
+        if error { self.errors += 1; }
+    }
+}
+
+//第一段是scheme://host，后面是路径的每一段，以/结尾的路径最后一段是/
+pub fn tree_path(flow: &Flow) -> Vec<String> {
+    let path = flow.path();
+    let path = path.split(['?', '#']).next().unwrap_or("");
+    let mut segments = vec![format!("{}://{}", flow.scheme(), flow.host().to_lowercase())];
+    let parts: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    for (index, part) in parts.iter().enumerate() {
+        match part.is_empty() {
+            true if index == parts.len() - 1 => segments.push("/".to_string()),
+            true => {}
+            false => segments.push(part.to_string()),
+        }
+    }
+    segments
+}
+
+//记录在选中的节点下面
+pub fn in_node(flow: &Flow, node: &[String]) -> bool {
+    tree_path(flow).starts_with(node)
+}
+
+#[cfg(test)]
+mod test_tree {
+    use crate::data::flow::test_flow;
+    use crate::data::tree::{in_node, tree_path, TreeNode};
+
+    #[test]
+    fn test_tree() {
+        let flow = |id, uri: &str, host: &str, status: &str| {
+            let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", uri, host);
+            test_flow(id, request.as_bytes(), format!("HTTP/1.1 {}\r\nContent-Length: 2\r\n\r\nok", status).as_bytes())
+        };
+        let flows = [
+            flow(1, "/api/user?id=1", "A.com", "200 OK"),
+            flow(2, "/api/user?id=2", "a.com", "200 OK"),
+            flow(3, "/api/login", "a.com", "401 Unauthorized"),
+            flow(4, "/", "a.com", "200 OK"),
+            flow(5, "/app.js", "cdn.b.com", "200 OK"),
+        ];
+        assert_eq!(tree_path(&flows[0]), ["https://a.com", "api", "user"]);
+        assert_eq!(tree_path(&flows[3]), ["https://a.com", "/"]);
+        let root = TreeNode::build(&flows);
+        assert_eq!((root.count, root.bytes, root.errors), (5, 10, 1));
+        assert_eq!(root.children.keys().collect::<Vec<_>>(), ["https://a.com", "https://cdn.b.com"]);
+        let host = &root.children["https://a.com"];
+        assert_eq!((host.count, host.errors), (4, 1));
+        let api = &host.children["api"];
+        assert_eq!((api.count, api.errors, api.path.as_slice()), (3, 1, ["https://a.com".to_string(), "api".to_string()].as_slice()));
+        assert_eq!(api.children["user"].count, 2);
+        //选中节点过滤记录
+        let selected: Vec<u64> = flows.iter().filter(|flow| in_node(flow, &api.path)).map(|flow| flow.id).collect();
+        assert_eq!(selected, [1, 2, 3]);
+        assert!(flows.iter().all(|flow| in_node(flow, &[])));
+    }
+}
@@ -1,4 +1,6 @@
 use std::fmt::{Display, Formatter};
+use crate::data::flow::Flow;
+use crate::data::timing::phases;
 
 #[derive(Eq, PartialEq, Clone)]
 pub enum ProxyTab {
@@ -8,10 +10,11 @@ pub enum ProxyTab {
     Cookie,
     ReqRaw,
     RespRaw,
+    Timing,
 }
 impl ProxyTab {
     pub fn tabs() -> Vec<ProxyTab> {
-        vec![ProxyTab::Header, ProxyTab::Param, ProxyTab::PreView,  ProxyTab::Cookie, ProxyTab::ReqRaw, ProxyTab::RespRaw]
+        vec![ProxyTab::Header, ProxyTab::Param, ProxyTab::PreView,  ProxyTab::Cookie, ProxyTab::ReqRaw, ProxyTab::RespRaw, ProxyTab::Timing]
     }
 }
 
@@ -24,6 +27,67 @@ impl Display for ProxyTab {
             ProxyTab::Cookie => f.write_str("Cookie"),
             ProxyTab::ReqRaw => f.write_str("原始请求"),
             ProxyTab::RespRaw => f.write_str("原始响应"),
+            ProxyTab::Timing => f.write_str("时间"),
         }
     }
-}
\ No newline at end of file
+}
+
+//详情页每个标签页显示的内容，GUI和TUI共用
+pub enum TabContent {
+    //分组的键值对，如：总揽、请求标头、响应标头
+    Sections(Vec<(String, Vec<(String, String)>)>),
+    Text(String),
+}
+
+impl ProxyTab {
+    pub fn content(&self, flow: &Flow) -> TabContent {
+        match self {
+            ProxyTab::Header => {
+                let mut overview = vec![
+                    ("请求URL".to_string(), flow.url()),
+                    ("请求方法".to_string(), flow.method().to_string()),
+                    ("状态码".to_string(), flow.status().map(|s| s.to_string()).unwrap_or("-".to_string())),
+                    ("目标地址".to_string(), flow.server.clone()),
+                    ("客户端地址".to_string(), flow.client.clone()),
+                    ("耗时".to_string(), flow.duration().map(|d| format!("{}ms", d.as_millis())).unwrap_or("-".to_string())),
+                ];
+                if let Some(error) = &flow.error { overview.push(("错误".to_string(), error.clone())); }
+                let response = flow.response.as_ref().map(|r| r.head.headers.clone()).unwrap_or_default();
+                TabContent::Sections(vec![
+                    ("总揽".to_string(), overview),
+                    ("请求标头".to_string(), flow.request.head.headers.clone()),
+                    ("响应标头".to_string(), response),
+                ])
+            }
+            ProxyTab::Param => TabContent::Sections(vec![("负载".to_string(), flow.params())]),
+            ProxyTab::Cookie => {
+                let (request, response) = flow.cookies();
+                let response = response.iter().map(|c| {
+                    let (name, value) = c.split_once('=').unwrap_or((c, ""));
+                    (name.trim().to_string(), value.to_string())
+                }).collect();
+                TabContent::Sections(vec![("请求Cookie".to_string(), request), ("响应Cookie".to_string(), response)])
+            }
+            ProxyTab::PreView => TabContent::Text(flow.response.as_ref().map(|r| r.preview()).unwrap_or_default()),
+            ProxyTab::ReqRaw => TabContent::Text(flow.request.raw()),
+            ProxyTab::RespRaw => match (&flow.response, &flow.error) {
+                (Some(response), _) => TabContent::Text(response.raw()),
+                (None, Some(error)) => TabContent::Text(error.clone()),
+                (None, None) => TabContent::Text(String::new()),
+            },
+            //复用连接的请求没有建立连接的阶段
+            ProxyTab::Timing => {
+                let phases = phases(flow).iter().map(|(kind, phase)| (kind.to_string(), format!("{:.1}ms", phase.duration().as_secs_f64() * 1000.0))).collect();
+                let total = flow.duration().map(|d| format!("{}ms", d.as_millis())).unwrap_or("-".to_string());
+                let conn = match flow.timing.is_some() {
+                    true => "新建的连接".to_string(),
+                    false => "复用的连接".to_string(),
+                };
+                TabContent::Sections(vec![
+                    ("时间".to_string(), phases),
+                    ("连接".to_string(), vec![("连接编号".to_string(), flow.sid.clone()), ("连接".to_string(), conn), ("总耗时".to_string(), total)]),
+                ])
+            }
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use std::mem;
+use crate::data::http::MAX_BODY;
+
+/*
+   websocket的一帧：
+   +-+-+-+-+-------+-+-------------+-------------------------------+
+   |F|R|R|R| opcode|M| Payload len |    Extended payload length    |
+   |I|S|S|S|  (4)  |A|     (7)     |             (16/64)           |
+   |N|V|V|V|       |S|             |   (if payload len==126/127)   |
+   | |1|2|3|       |K|             |                               |
+   +-+-+-+-+-------+-+-------------+-------------------------------+
+   |     Masking-key (0 or 4 bytes)     |      Payload Data ...    |
+   +------------------------------------+--------------------------+
+   客户端发送的帧必须有掩码，服务器发送的没有
+ */
+#[derive(Clone, Debug)]
+pub struct WsFrame {
+    pub fin: bool,
+    //启用了压缩扩展时RSV1为1，这时payload是压缩过的数据
+    pub rsv: u8,
+    //0：后续帧，1：文本，2：二进制，8：关闭，9：ping，10：pong
+    pub opcode: u8,
+    pub mask: Option<[u8; 4]>,
+    //去掉掩码后的数据
+    pub payload: Vec<u8>,
+}
+
+impl WsFrame {
+    //没有压缩的文本帧
+    pub fn text(&self) -> Option<&str> {
+        if self.opcode != 1 || self.rsv != 0 { return None; }
+        std::str::from_utf8(&self.payload).ok()
+    }
+
+    //转发时重新组装，原来有掩码的用原来的掩码
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bs = vec![(self.fin as u8) << 7 | (self.rsv & 7) << 4 | (self.opcode & 0x0F)];
+        let mask = (self.mask.is_some() as u8) << 7;
+        let len = self.payload.len();
+        match len {
+            0..=125 => bs.push(mask | len as u8),
+            126..=0xFFFF => {
+                bs.push(mask | 126);
+                bs.extend((len as u16).to_be_bytes());
+            }
+            _ => {
+                bs.push(mask | 127);
+                bs.extend((len as u64).to_be_bytes());
+            }
+        }
+        match self.mask {
+            Some(key) => {
+                bs.extend(key);
+                bs.extend(self.payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+            }
+            None => bs.extend(&self.payload),
+        }
+        bs
+    }
+}
+
+//从数据流里拆分出一个个帧，帧的长度超过MAX_BODY时不再解析
+#[derive(Default)]
+pub struct WsParser {
+    buf: Vec<u8>,
+    oversized: bool,
+}
+
+impl WsParser {
+    pub fn new() -> WsParser {
+        WsParser::default()
+    }
+
+    pub fn feed(&mut self, bs: &[u8]) -> Vec<WsFrame> {
+        self.buf.extend(bs);
+        let mut frames = vec![];
+        while !self.oversized {
+            match self.parse_frame() {
+                Some(frame) => frames.push(frame),
+                None => break,
+            }
+        }
+        frames
+    }
+
+    pub fn oversized(&self) -> bool {
+        self.oversized
+    }
+
+    //还没有解析的数据
+    pub fn take_rest(&mut self) -> Vec<u8> {
+        mem::take(&mut self.buf)
+    }
+
+    fn parse_frame(&mut self) -> Option<WsFrame> {
+        if self.buf.len() < 2 { return None; }
+        let (b0, b1) = (self.buf[0], self.buf[1]);
+        let (len, mut pos) = match b1 & 0x7F {
+            126 => (u16::from_be_bytes(self.buf.get(2..4)?.try_into().ok()?) as u64, 4),
+            127 => (u64::from_be_bytes(self.buf.get(2..10)?.try_into().ok()?), 10),
+            len => (len as u64, 2),
+        };
+        if len > MAX_BODY as u64 {
+            self.oversized = true;
+            return None;
+        }
+        let mask = match b1 & 0x80 {
+            0 => None,
+            _ => {
+                let key: [u8; 4] = self.buf.get(pos..pos + 4)?.try_into().ok()?;
+                pos += 4;
+                Some(key)
+            }
+        };
+        let end = pos + len as usize;
+        if self.buf.len() < end { return None; }
+        let mut payload: Vec<u8> = self.buf[pos..end].to_vec();
+        if let Some(key) = mask {
+            payload.iter_mut().enumerate().for_each(|(i, b)| *b ^= key[i % 4]);
+        }
+        self.buf.drain(..end);
+        Some(WsFrame { fin: b0 & 0x80 != 0, rsv: (b0 >> 4) & 7, opcode: b0 & 0x0F, mask, payload })
+    }
+}
+
+#[cfg(test)]
+mod test_websocket {
+    use crate::data::websocket::{WsFrame, WsParser};
+
+    #[test]
+    fn test_frame() {
+        let frame = WsFrame { fin: true, rsv: 0, opcode: 1, mask: Some([1, 2, 3, 4]), payload: b"hello".to_vec() };
+        let long = WsFrame { fin: true, rsv: 0, opcode: 2, mask: None, payload: vec![7; 300] };
+        let mut bs = frame.to_bytes();
+        bs.extend(long.to_bytes());
+        assert_eq!(bs[1], 0x80 | 5);
+        let mut parser = WsParser::new();
+        //分成两次接收
+        let mut frames = parser.feed(&bs[..9]);
+        assert!(frames.is_empty());
+        frames.extend(parser.feed(&bs[9..]));
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].text(), Some("hello"));
+        assert_eq!(frames[1].payload.len(), 300);
+        assert_eq!(frames[0].to_bytes(), frame.to_bytes());
+        //超过MAX_BODY的帧不解析
+        let mut parser = WsParser::new();
+        assert!(parser.feed(&[0x82, 127, 0, 0, 0, 0, 0x10, 0, 0, 0]).is_empty());
+        assert!(parser.oversized());
+        assert_eq!(parser.take_rest().len(), 10);
+    }
+}
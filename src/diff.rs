@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+use serde_json::Value;
+use crate::data::flow::Flow;
+use crate::data::http::HttpMessage;
+
+//逐行比较时最多比较的行数乘积，超过时按行号一一对应，避免大文件占用太多内存
+const MAX_LCS: usize = 4_000_000;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DiffKind {
+    Same,
+    Changed,
+    //只有左边有
+    Removed,
+    //只有右边有
+    Added,
+}
+
+//并排显示的一行，左右两边没有的内容为空
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DiffRow {
+    pub kind: DiffKind,
+    pub left: String,
+    pub right: String,
+}
+
+impl DiffRow {
+    fn new(left: Option<String>, right: Option<String>) -> DiffRow {
+        let kind = match (&left, &right) {
+            (Some(l), Some(r)) if l == r => DiffKind::Same,
+            (Some(_), Some(_)) => DiffKind::Changed,
+            (Some(_), None) => DiffKind::Removed,
+            _ => DiffKind::Added,
+        };
+        DiffRow { kind, left: left.unwrap_or_default(), right: right.unwrap_or_default() }
+    }
+}
+
+/*
+    两个请求(或者两个响应)的比较结果：
+    line    请求是方法和完整的URL，响应是状态行
+    headers 按名称排序后比较，不区分顺序和大小写，同名的多个值按顺序合并
+    body    解压后比较，两边都是JSON时按路径比较，否则逐行比较
+ */
+pub struct MessageDiff {
+    pub line: DiffRow,
+    pub headers: Vec<DiffRow>,
+    pub body: Vec<DiffRow>,
+    pub json: bool,
+}
+
+impl MessageDiff {
+    pub fn is_same(&self) -> bool {
+        self.line.kind == DiffKind::Same && self.headers.iter().chain(self.body.iter()).all(|row| row.kind == DiffKind::Same)
+    }
+}
+
+pub fn diff_requests(left: &Flow, right: &Flow) -> MessageDiff {
+    let line = |flow: &Flow| format!("{} {}", flow.method(), flow.url());
+    diff_messages(line(left), Some(&left.request), line(right), Some(&right.request))
+}
+
+//还没有响应的记录当作空的响应
+pub fn diff_responses(left: &Flow, right: &Flow) -> MessageDiff {
+    let line = |flow: &Flow| flow.response.as_ref().map(|r| r.head.line.clone()).unwrap_or_default();
+    diff_messages(line(left), left.response.as_ref(), line(right), right.response.as_ref())
+}
+
+fn diff_messages(left_line: String, left: Option<&HttpMessage>, right_line: String, right: Option<&HttpMessage>) -> MessageDiff {
+    let headers = |message: Option<&HttpMessage>| message.map(|m| m.head.headers.clone()).unwrap_or_default();
+    let body = |message: Option<&HttpMessage>| message.map(|m| m.decoded_body()).unwrap_or_default();
+    let (left_body, right_body) = (body(left), body(right));
+    let json = |body: &[u8]| serde_json::from_slice::<Value>(body).ok();
+    let (body, json) = match (json(&left_body), json(&right_body)) {
+        (Some(l), Some(r)) => (diff_json(&l, &r), true),
+        _ => {
+            let text = |message: Option<&HttpMessage>| message.map(|m| m.preview()).unwrap_or_default();
+            (diff_lines(&text(left), &text(right)), false)
+        }
+    };
+    MessageDiff {
+        line: DiffRow::new(Some(left_line), Some(right_line)),
+        headers: diff_headers(&headers(left), &headers(right)),
+        body,
+        json,
+    }
+}
+
+pub fn diff_headers(left: &[(String, String)], right: &[(String, String)]) -> Vec<DiffRow> {
+    let group = |headers: &[(String, String)]| {
+        let mut map: BTreeMap<String, (String, Vec<String>)> = BTreeMap::new();
+        for (k, v) in headers {
+            map.entry(k.to_lowercase()).or_insert((k.clone(), vec![])).1.push(v.clone());
+        }
+        map
+    };
+    let (left, right) = (group(left), group(right));
+    let mut names: Vec<&String> = left.keys().chain(right.keys()).collect();
+    names.sort();
+    names.dedup();
+    let line = |map: &BTreeMap<String, (String, Vec<String>)>, name: &str| map.get(name).map(|(k, v)| format!("{}: {}", k, v.join(", ")));
+    names.into_iter().map(|name| {
+        let mut row = DiffRow::new(line(&left, name), line(&right, name));
+        //名称的大小写不同不算修改
+        if row.kind == DiffKind::Changed && row.left.eq_ignore_ascii_case(&row.right) && left[name].1 == right[name].1 { row.kind = DiffKind::Same; }
+        row
+    }).collect()
+}
+
+//按路径展开后比较，对象不区分键的顺序，数组按下标比较，如：$.data.items[0].id
+pub fn diff_json(left: &Value, right: &Value) -> Vec<DiffRow> {
+    let (mut left_paths, mut right_paths) = (BTreeMap::new(), BTreeMap::new());
+    flatten("$".to_string(), left, &mut left_paths);
+    flatten("$".to_string(), right, &mut right_paths);
+    let mut paths: Vec<&String> = left_paths.keys().chain(right_paths.keys()).collect();
+    paths.sort();
+    paths.dedup();
+    let line = |map: &BTreeMap<String, String>, path: &str| map.get(path).map(|v| format!("{}: {}", path, v));
+    paths.into_iter().map(|path| DiffRow::new(line(&left_paths, path), line(&right_paths, path))).collect()
+}
+
+fn flatten(path: String, value: &Value, paths: &mut BTreeMap<String, String>) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (k, v) in map { flatten(format!("{}.{}", path, k), v, paths); }
+        }
+        Value::Array(items) if !items.is_empty() => {
+            for (i, v) in items.iter().enumerate() { flatten(format!("{}[{}]", path, i), v, paths); }
+        }
+        value => { paths.insert(path, value.to_string()); }
+    }
+}
+
+/*
+    逐行比较，用最长公共子序列找出相同的行，其余的是删除和添加的行，
+    相邻的删除和添加合并成修改的行并排显示：
+    a   a       a | a
+    b   c   ->  b | c
+    d   d       d | d
+ */
+pub fn diff_lines(left: &str, right: &str) -> Vec<DiffRow> {
+    let left: Vec<&str> = left.lines().collect();
+    let right: Vec<&str> = right.lines().collect();
+    if left.len() * right.len() > MAX_LCS {
+        return (0..left.len().max(right.len())).map(|i| {
+            DiffRow::new(left.get(i).map(|l| l.to_string()), right.get(i).map(|r| r.to_string()))
+        }).collect();
+    }
+    //lcs[i][j]是left[i..]和right[j..]的最长公共子序列的长度
+    let mut lcs = vec![vec![0u32; right.len() + 1]; left.len() + 1];
+    for i in (0..left.len()).rev() {
+        for j in (0..right.len()).rev() {
+            lcs[i][j] = match left[i] == right[j] {
+                true => lcs[i + 1][j + 1] + 1,
+                false => lcs[i + 1][j].max(lcs[i][j + 1]),
+            };
+        }
+    }
+    let (mut rows, mut removed, mut added) = (vec![], vec![], vec![]);
+    let (mut i, mut j) = (0, 0);
+    while i < left.len() || j < right.len() {
+        if i < left.len() && j < right.len() && left[i] == right[j] {
+            pair(&mut rows, &mut removed, &mut added);
+            rows.push(DiffRow::new(Some(left[i].to_string()), Some(right[j].to_string())));
+            i += 1;
+            j += 1;
+        } else if j < right.len() && (i == left.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            added.push(right[j].to_string());
+            j += 1;
+        } else {
+            removed.push(left[i].to_string());
+            i += 1;
+        }
+    }
+    pair(&mut rows, &mut removed, &mut added);
+    rows
+}
+
+fn pair(rows: &mut Vec<DiffRow>, removed: &mut Vec<String>, added: &mut Vec<String>) {
+    let count = removed.len().max(added.len());
+    let (mut removed, mut added) = (removed.drain(..), added.drain(..));
+    for _ in 0..count { rows.push(DiffRow::new(removed.next(), added.next())); }
+}
+
+#[cfg(test)]
+mod test_diff {
+    use serde_json::json;
+    use crate::data::flow::test_flow;
+    use crate::diff::{diff_json, diff_lines, diff_requests, diff_responses, DiffKind};
+
+    #[test]
+    fn test_diff() {
+        let kinds = |rows: &[crate::diff::DiffRow]| rows.iter().map(|row| row.kind).collect::<Vec<_>>();
+        let rows = diff_lines("a\nb\nd\ne", "a\nc\nd\nf\ng");
+        assert_eq!(kinds(&rows), [DiffKind::Same, DiffKind::Changed, DiffKind::Same, DiffKind::Changed, DiffKind::Added]);
+        assert_eq!((rows[1].left.as_str(), rows[1].right.as_str()), ("b", "c"));
+        assert_eq!(kinds(&diff_lines("a\nb", "b")), [DiffKind::Removed, DiffKind::Same]);
+        //键的顺序不同不算修改
+        let rows = diff_json(&json!({"a": 1, "b": {"c": [1, 2]}, "d": {}}), &json!({"d": {}, "b": {"c": [1, 3], "e": null}, "a": 1}));
+        let rows: Vec<_> = rows.iter().map(|row| (row.kind, row.left.as_str(), row.right.as_str())).collect();
+        assert_eq!(rows, [
+            (DiffKind::Same, "$.a: 1", "$.a: 1"),
+            (DiffKind::Same, "$.b.c[0]: 1", "$.b.c[0]: 1"),
+            (DiffKind::Changed, "$.b.c[1]: 2", "$.b.c[1]: 3"),
+            (DiffKind::Added, "", "$.b.e: null"),
+            (DiffKind::Same, "$.d: {}", "$.d: {}"),
+        ]);
+        //浏览器和应用发出的请求，标头的顺序和大小写不同
+        let browser = test_flow(1, b"POST /login HTTP/1.1\r\nHost: a.com\r\nAccept: */*\r\nCookie: s=1\r\nContent-Length: 13\r\n\r\n{\"u\":1,\"p\":2}", b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+        let app = test_flow(2, b"POST /login HTTP/1.1\r\ncontent-length: 7\r\nHOST: a.com\r\nAccept: */*\r\n\r\n{\"u\":1}", b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 2\r\n\r\nno");
+        let diff = diff_requests(&browser, &app);
+        assert!(diff.json && diff.line.kind == DiffKind::Same && !diff.is_same());
+        let headers: Vec<_> = diff.headers.iter().map(|row| row.kind).collect();
+        assert_eq!(headers, [DiffKind::Same, DiffKind::Changed, DiffKind::Removed, DiffKind::Same]);
+        assert_eq!(kinds(&diff.body), [DiffKind::Removed, DiffKind::Same]);
+        let diff = diff_responses(&browser, &app);
+        assert!(!diff.json && diff.line.kind == DiffKind::Changed);
+        assert_eq!(kinds(&diff.body), [DiffKind::Changed]);
+        assert!(diff_requests(&browser, &browser).is_same());
+    }
+}
@@ -0,0 +1,443 @@
+use regex::{Regex, RegexBuilder};
+use crate::data::flow::Flow;
+use crate::error::ProxyResult;
+use crate::rule::wildcard_match;
+
+/*
+   过滤表达式，用于筛选要输出或者显示的Flow
+   条件：
+     字段:值      包含这个值(不区分大小写)，值里有*或?时按通配符匹配整个字段
+     字段~正则    正则匹配(不区分大小写)
+     单独的词     URL包含这个词
+   文本字段：host、method、url、path、type(响应的Content-Type)、header(请求和响应的每一行"名称: 值")、body、scheme、client
+   数字字段：status、size(响应body大小，支持k/m后缀)、time(耗时，毫秒，支持s后缀)
+     status:404、status:4xx、status:400-499、status>=400、size>1m、time>500
+   组合：and(&&)、or(||)、not(!)、括号，多个条件之间没有写and/or时按and处理
+     host:*.example.com method:POST
+     status:4xx or status:5xx
+     !type:image and (url~"/api/v\d+" || header:x-token)
+   值里面有空格、括号的时候用双引号括起来
+ */
+#[derive(Clone, Debug)]
+pub enum Filter {
+    //空表达式，全部匹配
+    All,
+    Not(Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Text(Field, String),
+    Regex(Field, Regex),
+    //数字字段在[min, max]范围内
+    Range(NumField, u64, u64),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Field {
+    Host,
+    Method,
+    Url,
+    Path,
+    Type,
+    Header,
+    Body,
+    Scheme,
+    Client,
+    Tag,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NumField {
+    Status,
+    Size,
+    Time,
+}
+
+enum Key {
+    Text(Field),
+    Num(NumField),
+}
+
+impl Key {
+    fn parse(key: &str) -> Option<Key> {
+        match key.to_lowercase().as_str() {
+            "host" | "domain" => Some(Key::Text(Field::Host)),
+            "method" => Some(Key::Text(Field::Method)),
+            "url" => Some(Key::Text(Field::Url)),
+            "path" => Some(Key::Text(Field::Path)),
+            "type" | "mime" => Some(Key::Text(Field::Type)),
+            "header" => Some(Key::Text(Field::Header)),
+            "body" => Some(Key::Text(Field::Body)),
+            "scheme" => Some(Key::Text(Field::Scheme)),
+            "client" => Some(Key::Text(Field::Client)),
+            "tag" => Some(Key::Text(Field::Tag)),
+            "status" | "code" => Some(Key::Num(NumField::Status)),
+            "size" => Some(Key::Num(NumField::Size)),
+            "time" => Some(Key::Num(NumField::Time)),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    Not,
+    And,
+    Or,
+    //key_end是第一个引号的位置，引号里的内容不会被当作字段名和运算符
+    Word { text: String, key_end: usize },
+}
+
+fn tokenize(expr: &str) -> ProxyResult<Vec<Token>> {
+    let chars: Vec<char> = expr.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            c if c.is_whitespace() => i += 1,
+            '(' => {
+                tokens.push(Token::LParen);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::RParen);
+                i += 1;
+            }
+            '!' => {
+                tokens.push(Token::Not);
+                i += 1;
+            }
+            '&' | '|' => {
+                let c = chars[i];
+                while i < chars.len() && chars[i] == c { i += 1; }
+                tokens.push(if c == '&' { Token::And } else { Token::Or });
+            }
+            _ => {
+                let mut text = String::new();
+                let mut key_end = None;
+                while i < chars.len() && !chars[i].is_whitespace() && chars[i] != '(' && chars[i] != ')' {
+                    if chars[i] != '"' {
+                        text.push(chars[i]);
+                        i += 1;
+                        continue;
+                    }
+                    key_end.get_or_insert(text.len());
+                    i += 1;
+                    loop {
+                        match chars.get(i) {
+                            None => return Err(format!("过滤表达式的引号没有结束：{}", expr).into()),
+                            Some('"') => break,
+                            //只转义引号和反斜杠本身，正则里的\d这种保持原样
+                            Some('\\') if matches!(chars.get(i + 1), Some('"' | '\\')) => {
+                                text.push(chars[i + 1]);
+                                i += 2;
+                            }
+                            Some(c) => {
+                                text.push(*c);
+                                i += 1;
+                            }
+                        }
+                    }
+                    i += 1;
+                }
+                let token = match (key_end, text.to_lowercase().as_str()) {
+                    (None, "and") => Token::And,
+                    (None, "or") => Token::Or,
+                    (None, "not") => Token::Not,
+                    _ => Token::Word { key_end: key_end.unwrap_or(text.len()), text },
+                };
+                tokens.push(token);
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        self.pos += 1;
+        self.tokens.get(self.pos - 1)
+    }
+
+    fn parse_or(&mut self) -> ProxyResult<Filter> {
+        let mut left = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            left = Filter::Or(Box::new(left), Box::new(self.parse_and()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> ProxyResult<Filter> {
+        let mut left = self.parse_unary()?;
+        loop {
+            match self.peek() {
+                Some(Token::And) => self.pos += 1,
+                //没有写and/or的相邻条件
+                Some(Token::Not | Token::LParen | Token::Word { .. }) => {}
+                _ => break,
+            }
+            left = Filter::And(Box::new(left), Box::new(self.parse_unary()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> ProxyResult<Filter> {
+        match self.next() {
+            Some(Token::Not) => Ok(Filter::Not(Box::new(self.parse_unary()?))),
+            Some(Token::LParen) => {
+                let filter = self.parse_or()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(filter),
+                    _ => Err("过滤表达式缺少右括号".into())
+                }
+            }
+            Some(Token::Word { text, key_end }) => parse_term(text, *key_end),
+            Some(token) => Err(format!("过滤表达式的{:?}前面缺少条件", token).into()),
+            None => Err("过滤表达式不完整".into()),
+        }
+    }
+}
+
+fn parse_term(text: &str, key_end: usize) -> ProxyResult<Filter> {
+    let head = &text[..key_end];
+    let pos = match head.find(|c: char| !c.is_ascii_alphanumeric() && c != '_') {
+        Some(pos) if pos > 0 => pos,
+        _ => return Ok(Filter::Text(Field::Url, text.to_lowercase())),
+    };
+    let (name, op) = (&head[..pos], head[pos..].chars().next().unwrap_or(' '));
+    let key = match (Key::parse(name), op) {
+        (Some(key), ':' | '~' | '>' | '<' | '=') => key,
+        //http://这种完整的URL、a=1这种查询参数当作单独的词
+        (None, ':') if text[pos..].starts_with("://") => return Ok(Filter::Text(Field::Url, text.to_lowercase())),
+        (None, ':' | '~') => return Err(format!("不支持的过滤字段：{}，可选：host、method、url、path、type、header、body、scheme、client、status、size、time", name).into()),
+        _ => return Ok(Filter::Text(Field::Url, text.to_lowercase())),
+    };
+    let value = match op {
+        ':' | '~' => &text[pos + 1..],
+        _ => &text[pos..],
+    };
+    match (key, op) {
+        (Key::Text(field), ':') => Ok(Filter::Text(field, value.to_lowercase())),
+        (Key::Text(field), '~') => Ok(Filter::Regex(field, RegexBuilder::new(value).case_insensitive(true).build()?)),
+        (Key::Text(_), _) => Err(format!("{}是文本字段，不能比较大小", name).into()),
+        (Key::Num(_), '~') => Err(format!("{}是数字字段，不支持正则", name).into()),
+        (Key::Num(field), _) => {
+            let (min, max) = parse_range(field, value).ok_or(format!("{}的值格式错误：{}", name, value))?;
+            Ok(Filter::Range(field, min, max))
+        }
+    }
+}
+
+//404、4xx、400-499、>=400、<1k这些格式都转换成一个闭区间
+fn parse_range(field: NumField, value: &str) -> Option<(u64, u64)> {
+    let value = value.trim();
+    for (op, min_max) in [(">=", 0), ("<=", 1), (">", 2), ("<", 3), ("=", 4)] {
+        if let Some(num) = value.strip_prefix(op) {
+            let num = parse_number(field, num)?;
+            return match min_max {
+                0 => Some((num, u64::MAX)),
+                1 => Some((0, num)),
+                2 => Some((num.checked_add(1)?, u64::MAX)),
+                3 => Some((0, num.checked_sub(1)?)),
+                _ => Some((num, num)),
+            };
+        }
+    }
+    //用get取子串，值是中文等多字节字符时不能直接切片
+    if field == NumField::Status && value.len() == 3 && value.get(1..).is_some_and(|s| s.eq_ignore_ascii_case("xx")) {
+        let class = value.get(..1)?.parse::<u64>().ok()?;
+        return Some((class * 100, class * 100 + 99));
+    }
+    match value.split_once('-') {
+        Some((min, max)) => Some((parse_number(field, min)?, parse_number(field, max)?)),
+        None => parse_number(field, value).map(|num| (num, num)),
+    }
+}
+
+fn parse_number(field: NumField, value: &str) -> Option<u64> {
+    let value = value.trim().to_lowercase();
+    let (num, unit) = match field {
+        NumField::Status => (value.as_str(), 1),
+        NumField::Size => match value.trim_end_matches('b') {
+            v if v.ends_with('k') => (&v[..v.len() - 1], 1024),
+            v if v.ends_with('m') => (&v[..v.len() - 1], 1024 * 1024),
+            v if v.ends_with('g') => (&v[..v.len() - 1], 1024 * 1024 * 1024),
+            v => (v, 1),
+        },
+        NumField::Time => match value.strip_suffix("ms") {
+            Some(v) => (v, 1),
+            None => match value.strip_suffix('s') {
+                Some(v) => (v, 1000),
+                None => (value.as_str(), 1),
+            },
+        },
+    };
+    let num = num.trim().parse::<f64>().ok()?;
+    if num < 0.0 { return None; }
+    Some((num * unit as f64) as u64)
+}
+
+impl Filter {
+    pub fn parse(expr: impl AsRef<str>) -> ProxyResult<Filter> {
+        let tokens = tokenize(expr.as_ref())?;
+        if tokens.is_empty() { return Ok(Filter::All); }
+        let mut parser = Parser { tokens, pos: 0 };
+        let filter = parser.parse_or()?;
+        match parser.peek() {
+            None => Ok(filter),
+            Some(token) => Err(format!("过滤表达式的{:?}是多余的", token).into()),
+        }
+    }
+
+    pub fn matches(&self, flow: &Flow) -> bool {
+        match self {
+            Filter::All => true,
+            Filter::Not(filter) => !filter.matches(flow),
+            Filter::And(left, right) => left.matches(flow) && right.matches(flow),
+            Filter::Or(left, right) => left.matches(flow) || right.matches(flow),
+            Filter::Text(field, value) => {
+                let wildcard = value.contains(['*', '?']);
+                field_values(flow, *field).iter().any(|text| {
+                    let text = text.to_lowercase();
+                    match wildcard {
+                        true => wildcard_match(value.as_bytes(), text.as_bytes()),
+                        false => text.contains(value.as_str()),
+                    }
+                })
+            }
+            Filter::Regex(field, regex) => field_values(flow, *field).iter().any(|text| regex.is_match(text)),
+            Filter::Range(field, min, max) => {
+                let num = match field {
+                    NumField::Status => flow.status().map(|s| s as u64),
+                    NumField::Size => flow.response.as_ref().map(|r| r.body_size as u64),
+                    NumField::Time => flow.duration().map(|d| d.as_millis() as u64),
+                };
+                num.map(|num| num >= *min && num <= *max).unwrap_or(false)
+            }
+        }
+    }
+
+    //只有请求头部的时候判断是否可能匹配，body、响应、标记这些还不知道的条件当作可能匹配
+    pub fn may_match(&self, flow: &Flow) -> bool {
+        self.partial(flow).unwrap_or(true)
+    }
+
+    //返回None表示现在还不能确定
+    fn partial(&self, flow: &Flow) -> Option<bool> {
+        match self {
+            Filter::Not(filter) => filter.partial(flow).map(|matched| !matched),
+            Filter::And(left, right) => match (left.partial(flow), right.partial(flow)) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            },
+            Filter::Or(left, right) => match (left.partial(flow), right.partial(flow)) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            },
+            Filter::Text(field, _) | Filter::Regex(field, _) if field.in_head() => Some(self.matches(flow)),
+            Filter::All => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl Field {
+    //请求头部解析出来就能确定的字段
+    fn in_head(&self) -> bool {
+        matches!(self, Field::Host | Field::Method | Field::Url | Field::Path | Field::Scheme | Field::Client)
+    }
+}
+
+fn field_values(flow: &Flow, field: Field) -> Vec<String> {
+    match field {
+        //匹配域名时不带端口
+        Field::Host => {
+            let host = flow.host();
+            let name = host.rsplit_once(':').filter(|(_, port)| port.parse::<u16>().is_ok()).map(|(name, _)| name.to_string());
+            vec![name.unwrap_or(host)]
+        }
+        Field::Method => vec![flow.method().to_string()],
+        Field::Url => vec![flow.url()],
+        Field::Path => vec![flow.path()],
+        Field::Type => vec![flow.content_type().to_string()],
+        Field::Header => {
+            let response = flow.response.iter().flat_map(|r| r.head.headers.iter());
+            flow.request.head.headers.iter().chain(response).map(|(k, v)| format!("{}: {}", k, v)).collect()
+        }
+        Field::Body => {
+            let response = flow.response.iter().map(|r| String::from_utf8_lossy(&r.body).to_string());
+            std::iter::once(String::from_utf8_lossy(&flow.request.body).to_string()).chain(response).collect()
+        }
+        Field::Scheme => vec![flow.scheme().to_string()],
+        Field::Client => vec![flow.client.clone()],
+        Field::Tag => flow.tags.clone(),
+    }
+}
+
+#[cfg(test)]
+mod test_filter {
+    use crate::data::flow::test_flow;
+    use crate::filter::Filter;
+
+    #[test]
+    fn test_filter() {
+        let mut response = b"HTTP/1.1 404 Not Found\r\nContent-Type: application/json\r\nContent-Length: 2048\r\n\r\n".to_vec();
+        response.extend([b'x'; 2048]);
+        let get = test_flow(1, b"GET /api/v2/users?id=5 HTTP/1.1\r\nHost: api.example.com\r\nX-Token: abc\r\n\r\n", &response);
+        let post = test_flow(2, b"POST /upload HTTP/1.1\r\nHost: img.test.org:8443\r\nContent-Length: 5\r\n\r\nhello",
+                             b"HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: 3\r\n\r\npng");
+        let cases = [
+            ("", true, true),
+            ("host:*.example.com", true, false),
+            ("host:img.test.org", false, true),
+            ("method:post", false, true),
+            ("status:4xx or status:5xx", true, false),
+            ("status>=200 status<300", false, true),
+            ("size>1k", true, false),
+            ("!type:image and (url~\"/api/v\\d+\" || header:x-token)", true, false),
+            ("body:hello", false, true),
+            ("id=5", true, false),
+            ("not (upload)", true, false),
+        ];
+        for (expr, a, b) in cases {
+            let filter = Filter::parse(expr).unwrap();
+            assert_eq!((filter.matches(&get), filter.matches(&post)), (a, b), "{}", expr);
+        }
+        //status:中这种多字节字符的值不能panic
+        for expr in ["status:abc", "status:中", "status:中x", "size:>中", "foo:bar", "(host:a", "and host:a", "url:\"a"] {
+            assert!(Filter::parse(expr).is_err(), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_may_match() {
+        //只有请求头部，还不知道的条件当作可能匹配
+        let head = test_flow(1, b"GET /login HTTP/1.1\r\nHost: a.com\r\n\r\n", b"");
+        let cases = [
+            ("path:/login", true),
+            ("path:/index", false),
+            ("status:4xx", true),
+            ("path:/index and status:4xx", false),
+            ("path:/index or status:4xx", true),
+            ("!path:/login", false),
+            ("!status:4xx", true),
+            ("host:b.com or body:x", true),
+        ];
+        for (expr, may) in cases {
+            assert_eq!(Filter::parse(expr).unwrap().may_match(&head), may, "{}", expr);
+        }
+    }
+}
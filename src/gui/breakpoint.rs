@@ -0,0 +1,208 @@
+use std::sync::Arc;
+use std::time::Instant;
+use egui::{Color32, Context, Label, ScrollArea, TextEdit, Ui, Window};
+use crate::breakpoint::{BreakStage, Breakpoints, Paused, Resume};
+use crate::data::http::HttpMessage;
+
+//正在编辑的暂停消息
+struct Editor {
+    id: u64,
+    stage: BreakStage,
+    //请求：方法、URL、版本；响应：整个状态行放在line里
+    method: String,
+    url: String,
+    version: String,
+    line: String,
+    headers: String,
+    body: String,
+    //解压后的原始body，没有修改时原样放行
+    original_body: String,
+    //二进制的body不能修改
+    binary: bool,
+}
+
+impl Editor {
+    fn new(paused: &Paused) -> Editor {
+        let message = paused.message();
+        let parts: Vec<&str> = message.head.line.splitn(3, ' ').collect();
+        let headers = message.head.headers.iter().map(|(k, v)| format!("{}: {}", k, v)).collect::<Vec<_>>().join("\n");
+        let (body, binary) = match String::from_utf8(message.decoded_body()) {
+            Ok(body) => (body, false),
+            Err(e) => (format!("[{}字节的二进制数据，不能修改]", e.as_bytes().len()), true),
+        };
+        Editor {
+            id: paused.id,
+            stage: paused.stage,
+            method: parts.first().unwrap_or(&"").to_string(),
+            url: parts.get(1).unwrap_or(&"").to_string(),
+            version: parts.get(2).unwrap_or(&"HTTP/1.1").to_string(),
+            line: message.head.line.clone(),
+            headers,
+            original_body: body.clone(),
+            body,
+            binary,
+        }
+    }
+
+    fn edited(&self, paused: &Paused) -> HttpMessage {
+        let mut message = paused.message().clone();
+        message.head.line = match self.stage {
+            BreakStage::Request => format!("{} {} {}", self.method.trim(), self.url.trim(), self.version),
+            BreakStage::Response => self.line.trim().to_string(),
+        };
+        message.head.headers = self.headers.lines().filter_map(|l| l.split_once(':')).map(|(k, v)| (k.trim().to_string(), v.trim().to_string())).collect();
+        //修改过的body是明文，去掉压缩的头
+        if !self.binary && self.body != self.original_body {
+            message.body = self.body.clone().into_bytes();
+            message.head.remove_header("Content-Encoding");
+        }
+        message
+    }
+}
+
+/*
+    ---------------------------------------
+    | 表达式[           ] □请求 □响应 [添加] |
+    | path:/login   请求        [删除]     |
+    ---------------------------------------
+    | #1 请求 GET http://a.com/login 58秒 |
+    ---------------------------------------
+    | [GET] [http://a.com/login] HTTP/1.1 |
+    | 标头                                 |
+    | body                                |
+    | [修改后放行] [原样放行] [中止]          |
+    ---------------------------------------
+ */
+pub struct BreakpointView {
+    breakpoints: Arc<Breakpoints>,
+    open: bool,
+    expr: String,
+    request: bool,
+    response: bool,
+    error: String,
+    editor: Option<Editor>,
+}
+
+impl BreakpointView {
+    pub fn new(breakpoints: Arc<Breakpoints>) -> BreakpointView {
+        BreakpointView { breakpoints, open: false, expr: String::new(), request: true, response: false, error: String::new(), editor: None }
+    }
+
+    pub fn show_button(&mut self, ui: &mut Ui) {
+        let count = self.breakpoints.paused().len();
+        let text = match count {
+            0 => "断点".to_string(),
+            count => format!("断点({})", count),
+        };
+        ui.selectable_label(self.open, text).clicked().then(|| self.open = !self.open);
+    }
+
+    pub fn show(&mut self, ctx: &Context) {
+        let paused = self.breakpoints.paused();
+        //有新暂停的消息时自动打开
+        if !paused.is_empty() && self.editor.is_none() { self.open = true; }
+        if self.editor.as_ref().map(|editor| !paused.iter().any(|p| p.id == editor.id)).unwrap_or(false) { self.editor = None; }
+        let mut open = self.open;
+        Window::new("断点").open(&mut open).default_size([700.0, 500.0]).show(ctx, |ui| {
+            self.show_rules(ui);
+            ui.separator();
+            self.show_paused(ui, &paused);
+            if let Some(current) = self.editor.as_ref().and_then(|editor| paused.iter().find(|p| p.id == editor.id)) {
+                ui.separator();
+                self.show_editor(ui, current);
+            }
+        });
+        self.open = open;
+    }
+
+    fn show_rules(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.add(TextEdit::singleline(&mut self.expr).hint_text("过滤表达式，如：host:*.example.com path:/login").desired_width(300.0));
+            ui.checkbox(&mut self.request, "请求");
+            ui.checkbox(&mut self.response, "响应");
+            if ui.button("添加").clicked() {
+                match self.breakpoints.add(&self.expr, self.request, self.response) {
+                    Ok(()) => {
+                        self.expr.clear();
+                        self.error.clear();
+                    }
+                    Err(e) => self.error = e.to_string(),
+                }
+            }
+        });
+        if !self.error.is_empty() { ui.colored_label(Color32::RED, &self.error); }
+        for (index, rule) in self.breakpoints.rules().iter().enumerate() {
+            ui.horizontal(|ui| {
+                let stage = match (rule.request, rule.response) {
+                    (true, true) => "请求、响应",
+                    (true, false) => "请求",
+                    (false, true) => "响应",
+                    (false, false) => "-",
+                };
+                ui.label(if rule.expr.is_empty() { "全部" } else { rule.expr.as_str() });
+                ui.label(stage);
+                ui.button("删除").clicked().then(|| self.breakpoints.remove(index));
+            });
+        }
+    }
+
+    fn show_paused(&mut self, ui: &mut Ui, paused: &[Arc<Paused>]) {
+        if paused.is_empty() {
+            ui.label("没有暂停的请求");
+            return;
+        }
+        let now = Instant::now();
+        for item in paused {
+            let stage = match item.stage {
+                BreakStage::Request => "请求",
+                BreakStage::Response => "响应",
+            };
+            let remain = item.deadline.saturating_duration_since(now).as_secs();
+            let text = format!("#{} {} {} {} {}秒后自动放行", item.id, stage, item.flow.method(), item.flow.url(), remain);
+            let selected = self.editor.as_ref().map(|editor| editor.id == item.id).unwrap_or(false);
+            ui.selectable_label(selected, text).clicked().then(|| self.editor = Some(Editor::new(item)));
+        }
+    }
+
+    fn show_editor(&mut self, ui: &mut Ui, paused: &Paused) {
+        let editor = match self.editor.as_mut() {
+            Some(editor) => editor,
+            None => return,
+        };
+        match editor.stage {
+            BreakStage::Request => {
+                ui.horizontal(|ui| {
+                    ui.add(TextEdit::singleline(&mut editor.method).desired_width(70.0));
+                    ui.add(TextEdit::singleline(&mut editor.url).desired_width(450.0));
+                    ui.label(&editor.version);
+                });
+            }
+            BreakStage::Response => {
+                ui.add(TextEdit::singleline(&mut editor.line).desired_width(520.0));
+            }
+        }
+        ScrollArea::vertical().id_salt("breakpoint_editor").max_height(360.0).show(ui, |ui| {
+            ui.label("标头");
+            ui.add(TextEdit::multiline(&mut editor.headers).code_editor().desired_rows(8).desired_width(f32::INFINITY));
+            ui.label("body");
+            match editor.binary {
+                true => {
+                    ui.add(Label::new(&editor.body));
+                }
+                false => {
+                    ui.add(TextEdit::multiline(&mut editor.body).code_editor().desired_rows(8).desired_width(f32::INFINITY));
+                }
+            }
+        });
+        let mut resume = None;
+        ui.horizontal(|ui| {
+            ui.button("修改后放行").clicked().then(|| resume = Some(Resume::Edited(editor.edited(paused))));
+            ui.button("原样放行").clicked().then(|| resume = Some(Resume::Unchanged));
+            ui.button("中止").clicked().then(|| resume = Some(Resume::Abort));
+        });
+        if let Some(resume) = resume {
+            self.breakpoints.resume(paused.id, resume);
+            self.editor = None;
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use egui::{Color32, ComboBox, Context, DragValue, Grid, ScrollArea, TextEdit, Ui, Window};
+use crate::data::flow::Flow;
+use crate::replay::{ReplayRequest, Replayer};
+
+const METHODS: [&str; 7] = ["GET", "POST", "PUT", "DELETE", "PATCH", "HEAD", "OPTIONS"];
+
+/*
+    ---------------------------------------
+    | 来自#12                              |
+    | [POST] [https://a.com/login       ] |
+    | 标头                                 |
+    | body                                |
+    | 次数[1] 并发[1] [发送] [清空]          |
+    ---------------------------------------
+ */
+pub struct ComposerView {
+    replayer: Replayer,
+    open: bool,
+    source: Option<u64>,
+    method: String,
+    url: String,
+    headers: String,
+    body: String,
+    //原始的body，没有修改时原样发送，保留压缩
+    raw_body: Vec<u8>,
+    original_body: String,
+    //二进制的body不能修改
+    binary: bool,
+    count: usize,
+    concurrency: usize,
+    message: String,
+    error: bool,
+}
+
+impl ComposerView {
+    pub fn new(replayer: Replayer) -> ComposerView {
+        ComposerView {
+            replayer,
+            open: false,
+            source: None,
+            method: "GET".to_string(),
+            url: String::new(),
+            headers: String::new(),
+            body: String::new(),
+            raw_body: vec![],
+            original_body: String::new(),
+            binary: false,
+            count: 1,
+            concurrency: 1,
+            message: String::new(),
+            error: false,
+        }
+    }
+
+    pub fn show_button(&mut self, ui: &mut Ui) {
+        ui.selectable_label(self.open, "编辑请求").clicked().then(|| self.open = !self.open);
+    }
+
+    //直接重放一次，出错时才打开窗口显示
+    pub fn replay(&mut self, flow: &Flow) {
+        let request = ReplayRequest::from_flow(flow);
+        if let Err(e) = self.replayer.send(request, 1, 1) {
+            self.show_error(e.to_string());
+            self.open = true;
+        }
+    }
+
+    //把记录的请求放到编辑窗口里
+    pub fn edit(&mut self, flow: &Flow) {
+        let request = ReplayRequest::from_flow(flow);
+        let (body, binary) = match String::from_utf8(flow.request.decoded_body()) {
+            Ok(body) => (body, false),
+            Err(e) => (format!("[{}字节的二进制数据，不能修改]", e.as_bytes().len()), true),
+        };
+        self.source = request.source;
+        self.method = request.method;
+        self.url = request.url;
+        self.headers = request.headers.iter().map(|(k, v)| format!("{}: {}", k, v)).collect::<Vec<_>>().join("\n");
+        self.raw_body = request.body;
+        self.original_body = body.clone();
+        self.body = body;
+        self.binary = binary;
+        self.message.clear();
+        self.open = true;
+    }
+
+    fn request(&self) -> ReplayRequest {
+        let mut headers: Vec<(String, String)> = self.headers.lines().filter_map(|l| l.split_once(':')).map(|(k, v)| (k.trim().to_string(), v.trim().to_string())).collect();
+        //修改过的body是明文，去掉压缩的头
+        let body = match !self.binary && self.body != self.original_body {
+            true => {
+                headers.retain(|(k, _)| !k.eq_ignore_ascii_case("Content-Encoding"));
+                self.body.clone().into_bytes()
+            }
+            false => self.raw_body.clone(),
+        };
+        ReplayRequest { method: self.method.clone(), url: self.url.clone(), headers, body, source: self.source }
+    }
+
+    fn show_error(&mut self, message: String) {
+        self.message = message;
+        self.error = true;
+    }
+
+    pub fn show(&mut self, ctx: &Context) {
+        let mut open = self.open;
+        Window::new("编辑请求").open(&mut open).default_size([600.0, 500.0]).show(ctx, |ui| {
+            if let Some(source) = self.source { ui.label(format!("来自#{}", source)); }
+            ui.horizontal(|ui| {
+                ComboBox::from_id_salt("composer_method").selected_text(&self.method).show_ui(ui, |ui| {
+                    for method in METHODS { ui.selectable_value(&mut self.method, method.to_string(), method); }
+                });
+                ui.add(TextEdit::singleline(&mut self.url).hint_text("https://www.example.com/api").desired_width(f32::INFINITY));
+            });
+            ui.label("标头");
+            ScrollArea::vertical().id_salt("composer_headers").max_height(150.0).show(ui, |ui| {
+                ui.add(TextEdit::multiline(&mut self.headers).hint_text("Name: value").desired_width(f32::INFINITY));
+            });
+            ui.label("body");
+            ScrollArea::vertical().id_salt("composer_body").max_height(200.0).show(ui, |ui| {
+                ui.add_enabled(!self.binary, TextEdit::multiline(&mut self.body).desired_width(f32::INFINITY));
+            });
+            Grid::new("composer_send").num_columns(6).show(ui, |ui| {
+                ui.label("次数");
+                ui.add(DragValue::new(&mut self.count).range(1..=1000));
+                ui.label("并发");
+                ui.add(DragValue::new(&mut self.concurrency).range(1..=100));
+                if ui.button("发送").clicked() {
+                    match self.replayer.send(self.request(), self.count, self.concurrency) {
+                        Ok(()) => {
+                            self.message = format!("已发送{}次，结果在抓包记录里", self.count);
+                            self.error = false;
+                        }
+                        Err(e) => self.show_error(e.to_string()),
+                    }
+                }
+                if ui.button("清空").clicked() {
+                    let replayer = self.replayer.clone();
+                    *self = ComposerView::new(replayer);
+                    self.open = true;
+                }
+            });
+            if !self.message.is_empty() {
+                let color = if self.error { Color32::RED } else { Color32::DARK_GREEN };
+                ui.colored_label(color, &self.message);
+            }
+        });
+        self.open = open && self.open;
+    }
+}
@@ -0,0 +1,132 @@
+use std::sync::Arc;
+use egui::{Color32, Context, Grid, RichText, ScrollArea, Ui, Window};
+use crate::data::flow::Flow;
+use crate::diff::{diff_requests, diff_responses, DiffKind, DiffRow, MessageDiff};
+
+/*
+    在记录列表里按住Ctrl点击(或者右键菜单)先后选择两条记录，并排比较：
+    ---------------------------------------------
+    | [请求][响应] [x]只显示不同的  [交换]          |
+    | #3 POST https://a.com/login | #7 POST ... |
+    | 标头                                       |
+    | cookie: s=1                 |             |
+    | body(JSON)                                |
+    | $.u: 1                      | $.u: 2      |
+    ---------------------------------------------
+ */
+pub struct DiffView {
+    open: bool,
+    left: Option<Arc<Flow>>,
+    right: Option<Arc<Flow>>,
+    response: bool,
+    changed_only: bool,
+    //比较的结果，记录和请求/响应不变时不用每次刷新都重新比较
+    diff: Option<((u64, u64, bool), MessageDiff)>,
+}
+
+impl DiffView {
+    pub fn new() -> DiffView {
+        DiffView { open: false, left: None, right: None, response: false, changed_only: false, diff: None }
+    }
+
+    pub fn show_button(&mut self, ui: &mut Ui) {
+        let text = match (&self.left, &self.right) {
+            (Some(left), None) => format!("对比#{}", left.id),
+            _ => "对比".to_string(),
+        };
+        ui.selectable_label(self.open, text).clicked().then(|| self.open = !self.open);
+    }
+
+    //第一次选择的在左边，第二次选择的在右边并打开窗口，再选择时重新开始
+    pub fn pick(&mut self, flow: Arc<Flow>) {
+        match (&self.left, &self.right) {
+            (Some(left), None) if left.id != flow.id => {
+                self.right = Some(flow);
+                self.open = true;
+            }
+            (Some(left), None) if left.id == flow.id => self.left = None,
+            _ => (self.left, self.right) = (Some(flow), None),
+        }
+    }
+
+    pub fn is_picked(&self, id: u64) -> bool {
+        self.left.iter().chain(self.right.iter()).any(|flow| flow.id == id)
+    }
+
+    //右键菜单里的选项
+    pub fn menu(&mut self, ui: &mut Ui, flow: &Arc<Flow>) {
+        let text = match &self.left {
+            Some(left) if self.right.is_none() && left.id != flow.id => format!("和#{}对比", left.id),
+            Some(left) if self.right.is_none() && left.id == flow.id => "取消对比".to_string(),
+            _ => "选择对比".to_string(),
+        };
+        if ui.button(text).clicked() {
+            self.pick(flow.clone());
+            ui.close_menu();
+        }
+    }
+
+    fn show_diff(&self, ui: &mut Ui, left: &Flow, right: &Flow, diff: &MessageDiff) {
+        if diff.is_same() { ui.colored_label(Color32::DARK_GREEN, "完全相同"); }
+        //第一行始终显示，知道比较的是哪两条记录
+        let line = DiffRow { kind: diff.line.kind, left: format!("#{} {}", left.id, diff.line.left), right: format!("#{} {}", right.id, diff.line.right) };
+        show_rows(ui, "diff_line", &[line], false);
+        ui.separator();
+        ui.label("标头");
+        show_rows(ui, "diff_headers", &diff.headers, self.changed_only);
+        ui.separator();
+        ui.label(if diff.json { "body(JSON)" } else { "body" });
+        show_rows(ui, "diff_body", &diff.body, self.changed_only);
+    }
+
+    pub fn show(&mut self, ctx: &Context) {
+        let mut open = self.open;
+        Window::new("对比").open(&mut open).default_size([800.0, 600.0]).show(ctx, |ui| {
+            let (left, right) = match (self.left.clone(), self.right.clone()) {
+                (Some(left), Some(right)) => (left, right),
+                (Some(left), None) => {
+                    ui.label(format!("已选择#{}，在记录列表里按住Ctrl点击另一条记录", left.id));
+                    return;
+                }
+                _ => {
+                    ui.label("在记录列表里按住Ctrl点击(或者右键菜单)先后选择两条记录");
+                    return;
+                }
+            };
+            ui.horizontal(|ui| {
+                ui.selectable_value(&mut self.response, false, "请求");
+                ui.selectable_value(&mut self.response, true, "响应");
+                ui.separator();
+                ui.checkbox(&mut self.changed_only, "只显示不同的");
+                ui.button("交换").clicked().then(|| (self.left, self.right) = (self.right.take(), self.left.take()));
+            });
+            let (left, right) = (self.left.clone().unwrap_or(left), self.right.clone().unwrap_or(right));
+            let key = (left.id, right.id, self.response);
+            let diff = match self.diff.take() {
+                Some((k, diff)) if k == key => diff,
+                _ if self.response => diff_responses(&left, &right),
+                _ => diff_requests(&left, &right),
+            };
+            ScrollArea::both().auto_shrink([false; 2]).show(ui, |ui| self.show_diff(ui, &left, &right, &diff));
+            self.diff = Some((key, diff));
+        });
+        self.open = open && self.open;
+    }
+}
+
+//changed_only为true时不显示相同的行
+fn show_rows(ui: &mut Ui, id: &str, rows: &[DiffRow], changed_only: bool) {
+    Grid::new(id).num_columns(2).striped(true).show(ui, |ui| {
+        for row in rows.iter().filter(|row| !changed_only || row.kind != DiffKind::Same) {
+            let (left, right) = match row.kind {
+                DiffKind::Same => (Color32::TRANSPARENT, Color32::TRANSPARENT),
+                DiffKind::Changed => (Color32::LIGHT_YELLOW, Color32::LIGHT_YELLOW),
+                DiffKind::Removed => (Color32::from_rgb(255, 210, 210), Color32::TRANSPARENT),
+                DiffKind::Added => (Color32::TRANSPARENT, Color32::from_rgb(210, 255, 210)),
+            };
+            ui.label(RichText::new(&row.left).monospace().background_color(left));
+            ui.label(RichText::new(&row.right).monospace().background_color(right));
+            ui.end_row();
+        }
+    });
+}
@@ -0,0 +1,73 @@
+use std::sync::Arc;
+use egui::{Color32, Context, ScrollArea, TextEdit, Ui, Window};
+use crate::context::ProxyContext;
+use crate::error::ProxyResult;
+use crate::import::{import_blocking, load, parse};
+
+/*
+    ---------------------------------------
+    | 文件[customer.har          ] [导入]  |
+    | 或者粘贴HAR内容、cURL命令：             |
+    | curl 'https://...' -H ...           |
+    | [导入粘贴的内容]                       |
+    ---------------------------------------
+ */
+pub struct ImportView {
+    ctx: Arc<ProxyContext>,
+    open: bool,
+    path: String,
+    text: String,
+    message: String,
+    error: bool,
+}
+
+impl ImportView {
+    pub fn new(ctx: Arc<ProxyContext>) -> ImportView {
+        ImportView { ctx, open: false, path: String::new(), text: String::new(), message: String::new(), error: false }
+    }
+
+    pub fn show_button(&mut self, ui: &mut Ui) {
+        ui.selectable_label(self.open, "导入").clicked().then(|| self.open = !self.open);
+    }
+
+    fn import(&mut self, res: ProxyResult<usize>) {
+        match res {
+            Ok(count) => {
+                self.message = format!("导入了{}条记录", count);
+                self.error = false;
+            }
+            Err(e) => {
+                self.message = e.to_string();
+                self.error = true;
+            }
+        }
+    }
+
+    pub fn show(&mut self, ctx: &Context) {
+        let mut open = self.open;
+        Window::new("导入").open(&mut open).default_size([500.0, 400.0]).show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("文件");
+                ui.add(TextEdit::singleline(&mut self.path).hint_text("customer.har"));
+                if ui.button("导入").clicked() {
+                    let res = load(self.path.trim()).and_then(|flows| import_blocking(&self.ctx, flows));
+                    self.import(res);
+                }
+            });
+            ui.label("或者粘贴HAR内容、cURL命令：");
+            ScrollArea::vertical().max_height(250.0).show(ui, |ui| {
+                ui.add(TextEdit::multiline(&mut self.text).hint_text("curl 'https://www.example.com/api' -H 'Accept: */*'").desired_width(f32::INFINITY));
+            });
+            if ui.button("导入粘贴的内容").clicked() {
+                let res = parse(&self.text, true).and_then(|flows| import_blocking(&self.ctx, flows));
+                if res.is_ok() { self.text.clear(); }
+                self.import(res);
+            }
+            if !self.message.is_empty() {
+                let color = if self.error { Color32::RED } else { Color32::DARK_GREEN };
+                ui.colored_label(color, &self.message);
+            }
+        });
+        self.open = open && self.open;
+    }
+}
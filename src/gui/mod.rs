@@ -1,28 +1,60 @@
-use crate::data::ui::ProxyTab;
+mod breakpoint;
+mod composer;
+mod diff;
+mod import;
+mod rewrite;
+mod throttle;
+mod tree;
+mod waterfall;
+
+use crate::data::ui::{ProxyTab, TabContent};
 use crate::data::FilterMode;
 use eframe::emath::Align;
 use eframe::epaint::text::TextWrapMode;
 use eframe::{App, Frame};
-use egui::{include_image, Button, CentralPanel, Color32, Context, FontData, Id, Label, Layout, ScrollArea, Sense, Ui, UiBuilder, Visuals, Widget};
+use egui::{include_image, Button, CentralPanel, Color32, Context, FontData, Id, Label, Layout, ScrollArea, Sense, StrokeKind, Ui, UiBuilder, Visuals, Widget};
 use std::error::Error;
-use reqrio::Response;
-use crate::data::http::Request;
-
-pub struct HttpMessage {
-    request: Request,
-    response: Response,
-}
+use std::sync::Arc;
+use std::time::Duration;
+use crate::data::flow::{format_size, local_time, Flow};
+use crate::data::store::FlowStore;
+use crate::filter::Filter;
+use crate::breakpoint::Breakpoints;
+use crate::gui::breakpoint::BreakpointView;
+use crate::context::ProxyContext;
+use crate::gui::rewrite::RewriteView;
+use crate::gui::throttle::ThrottleView;
+use crate::gui::composer::ComposerView;
+use crate::gui::diff::DiffView;
+use crate::gui::waterfall::WaterfallView;
+use crate::gui::tree::TreeView;
+use crate::gui::import::ImportView;
+use crate::replay::Replayer;
+use crate::snippet::{generate, SnippetKind, SnippetOptions};
+use log::error;
 
 pub struct ProxyView {
-    data: Vec<HttpMessage>,
+    //和终端界面共用的抓包记录
+    store: Arc<FlowStore>,
+    data: Vec<Arc<Flow>>,
     current_item: Option<usize>,
     working: bool,
     filter_mode: FilterMode,
     view_tab: ProxyTab,
+    breakpoint: BreakpointView,
+    rewrite: RewriteView,
+    throttle: ThrottleView,
+    composer: ComposerView,
+    import: ImportView,
+    diff: DiffView,
+    waterfall: WaterfallView,
+    tree: TreeView,
+    //复制为cURL等命令时使用的代理地址和根证书
+    snippet: SnippetOptions,
 }
 
 impl ProxyView {
-    pub fn new(ctx: &eframe::CreationContext) -> Result<Box<dyn App>, Box<dyn Error + Send + Sync + 'static>> {
+    pub fn new(ctx: &eframe::CreationContext, store: Arc<FlowStore>, breakpoints: Arc<Breakpoints>, proxy: Arc<ProxyContext>, replayer: Replayer, snippet: SnippetOptions) -> Result<Box<dyn App>, Box<dyn Error + Send + Sync + 'static>> {
         //修改默认字体，确保支持中文
         let mut fonts = egui::FontDefinitions::default();
         let font_bytes = include_bytes!("../../res/font/simfang.ttf");
@@ -36,11 +68,21 @@ impl ProxyView {
         //安装图片加载器
         egui_extras::install_image_loaders(&ctx.egui_ctx);
         Ok(Box::new(ProxyView {
+            store,
             data: vec![],
             current_item: None,
             working: false,
             filter_mode: FilterMode::None,
             view_tab: ProxyTab::Header,
+            breakpoint: BreakpointView::new(breakpoints),
+            rewrite: RewriteView::new(proxy.clone()),
+            import: ImportView::new(proxy.clone()),
+            throttle: ThrottleView::new(proxy),
+            composer: ComposerView::new(replayer),
+            diff: DiffView::new(),
+            waterfall: WaterfallView::new(),
+            tree: TreeView::new(),
+            snippet,
         }))
     }
 
@@ -55,9 +97,17 @@ impl ProxyView {
             ui.add(btn).clicked().then(|| {});
             let btn = Button::image_and_text(include_image!("../../res/imgs/export.png"), "导出");
             ui.add(btn).clicked().then(|| {});
+            self.tree.show_button(ui);
             for mode in FilterMode::modes() {
                 ui.selectable_label(self.filter_mode == mode, mode.to_string()).clicked().then(|| self.filter_mode = mode);
             }
+            self.breakpoint.show_button(ui);
+            self.rewrite.show_button(ui);
+            self.throttle.show_button(ui);
+            self.composer.show_button(ui);
+            self.import.show_button(ui);
+            self.diff.show_button(ui);
+            self.waterfall.show_button(ui);
         });
     }
 
@@ -70,7 +120,7 @@ impl ProxyView {
         item_layout_rect.max.y = item_layout_rect.max.y - 2.0;
         let builder = UiBuilder::new().max_rect(item_layout_rect);
         ui.allocate_new_ui(builder, |ui| {
-            let datum = &self.data[index];
+            let datum = self.data[index].clone();
             ui.vertical(|ui| {
                 //这里的样式我们后面再更换
                 if let Some(current_item) = self.current_item {
@@ -78,22 +128,35 @@ impl ProxyView {
                         ui.painter().rect_filled(item_rect, 0.2, Color32::LIGHT_BLUE);
                     }
                 }
+                //选择了对比的记录
+                if self.diff.is_picked(datum.id) {
+                    ui.painter().rect_stroke(item_rect, 0.2, (1.5, Color32::DARK_BLUE), StrokeKind::Inside);
+                }
                 let resp = ui.interact(item_rect, Id::from(format!("item_{}", index)), Sense::click_and_drag());
                 if resp.hovered() {
                     ui.painter().rect_filled(item_rect, 0.2, Color32::LIGHT_YELLOW);
                 }
-                if resp.clicked() {
+                //按住Ctrl点击时选择对比的记录
+                if resp.clicked() && ui.input(|i| i.modifiers.command) {
+                    self.diff.pick(datum.clone());
+                } else if resp.clicked() {
                     self.current_item = Some(index);
                 }
+                resp.context_menu(|ui| {
+                    self.diff.menu(ui, &datum);
+                    ui.separator();
+                    copy_menu(ui, &datum, &self.snippet);
+                });
                 //保证不自动换行
-                let url = Label::new("https://docs.rs/eframe/latest/eframe/").wrap_mode(TextWrapMode::Extend).truncate();
+                let url = Label::new(datum.url()).wrap_mode(TextWrapMode::Extend).truncate();
                 ui.add(url);
                 ui.horizontal(|ui| {
-                    ui.label(index.to_string());
-                    ui.label(200.to_string());
-                    ui.label("文档");
-                    ui.label("08:00");
-                    ui.label("1.6 Kb");
+                    ui.label(datum.id.to_string());
+                    ui.label(datum.status().map(|s| s.to_string()).unwrap_or("-".to_string()));
+                    ui.label(datum.content_type().split(';').next().unwrap_or(""));
+                    ui.label(local_time(datum.request.start));
+                    ui.label(datum.response.as_ref().map(|r| format_size(r.body_size)).unwrap_or("-".to_string()));
+                    for tag in &datum.tags { ui.label(format!("[{}]", tag)); }
                 });
             });
         });
@@ -137,37 +200,36 @@ impl ProxyView {
         });
     }
 
-    fn show_headers(&mut self, ui: &mut Ui) {
-        ui.horizontal(|ui| {
-            ui.set_height(30.0);
-            let rect = ui.max_rect();
-            ui.painter().rect_filled(rect, 0.0, Color32::LIGHT_BLUE);
-            ui.label("总揽");
-        });
-        self.show_header_item(ui, "请求URL", r#"data:image/svg+xml,<svg width="18" height="18" viewBox="0 0 12 12" %09enable-background="new 0 0 12 12" xmlns="http://www.w3.org/2000/svg" fill="none">%09<circle r="5.25" cx="6" cy="6" stroke-width="1.25" stroke="black"/>%09<text x="6" y="7" style="font:8px sans-serif;font-weight:1000" text-anchor="middle" %09%09dominant-baseline="middle" fill="black">?%3C/text%3E%3C/svg%3Edfjkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkk"#);
-        self.show_header_item(ui, "请求方法", "POST");
-        self.show_header_item(ui, "状态码", "200");
-        self.show_header_item(ui, "目标地址", "127.0.0.1:443");
+    fn show_section(&self, ui: &mut Ui, title: &str) {
         ui.horizontal(|ui| {
             ui.set_height(30.0);
             let rect = ui.max_rect();
             ui.painter().rect_filled(rect, 0.0, Color32::LIGHT_BLUE);
-            ui.label("请求标头");
+            ui.label(title);
         });
-        let datum = &self.data[self.current_item.unwrap_or(0)];
-        // for (key, value) in datum.request().header().keys() {
-        //     self.show_header_item(ui, key, value);
-        // }
-        ui.horizontal(|ui| {
-            ui.set_height(30.0);
-            let rect = ui.max_rect();
-            ui.painter().rect_filled(rect, 0.0, Color32::LIGHT_BLUE);
-            ui.label("响应标头");
-        });
-        // for (key, value) in datum.response().header().keys() {
-        //     self.show_header_item(ui, key, value);
-        // }
     }
+
+    //标头、负载、Cookie是分组的键值对，预览和原始数据是文本
+    fn show_content(&mut self, ui: &mut Ui) {
+        let datum = match self.current_item.and_then(|index| self.data.get(index)) {
+            Some(datum) => datum.clone(),
+            None => return,
+        };
+        match self.view_tab.content(&datum) {
+            TabContent::Sections(sections) => {
+                for (title, items) in sections {
+                    self.show_section(ui, &title);
+                    for (key, value) in items {
+                        self.show_header_item(ui, key, value);
+                    }
+                }
+            }
+            TabContent::Text(text) => {
+                ui.add(Label::new(text).wrap_mode(TextWrapMode::Wrap));
+            }
+        }
+    }
+
     fn show_root_middle_right(&mut self, ui: &mut Ui) {
         /*
            |标头|负载|预览|Cookie|原始请求|原始响应|
@@ -184,34 +246,61 @@ impl ProxyView {
                 for tab in ProxyTab::tabs() {
                     ui.selectable_label(self.view_tab == tab, tab.to_string()).clicked().then(|| self.view_tab = tab);
                 }
+                //重放当前的请求，结果是新的抓包记录
+                if let Some(flow) = self.current_item.and_then(|index| self.data.get(index)).cloned() {
+                    ui.separator();
+                    ui.button("重放").clicked().then(|| self.composer.replay(&flow));
+                    ui.button("编辑重放").clicked().then(|| self.composer.edit(&flow));
+                    ui.menu_button("复制为", |ui| copy_menu(ui, &flow, &self.snippet));
+                }
             });
             let area = ScrollArea::vertical().auto_shrink([false; 2]).id_salt("root_middle_right_scroll")
                 .max_height(app_height);
             area.show(ui, |ui| {
-                match self.view_tab {
-                    ProxyTab::Header => { ui.vertical(|ui| self.show_headers(ui)); }
-                    ProxyTab::PreView => {}
-                    ProxyTab::Param => {}
-                    ProxyTab::Cookie => {}
-                    ProxyTab::ReqRaw => {}
-                    ProxyTab::RespRaw => {}
-                }
+                ui.vertical(|ui| self.show_content(ui));
             });
         });
     }
 }
 
+//生成命令或者代码复制到剪贴板，引用的body和上传的文件保存到临时目录
+fn copy_menu(ui: &mut Ui, flow: &Flow, options: &SnippetOptions) {
+    for kind in SnippetKind::kinds() {
+        if !ui.button(format!("复制为{}", kind)).clicked() { continue; }
+        let snippet = generate(kind, flow, options);
+        if let Err(e) = snippet.save() { error!("保存body失败：{}", e.to_string()); }
+        ui.ctx().copy_text(snippet.code);
+        ui.close_menu();
+    }
+}
+
 impl App for ProxyView {
     fn update(&mut self, ctx: &Context, _frame: &mut Frame) {
+        //抓包记录是在其他线程里添加的，定时刷新一下，按编号找回刷新前选中的记录
+        let current = self.current_item.and_then(|index| self.data.get(index)).map(|flow| flow.id);
+        let flows = self.store.list(&Filter::All);
+        self.data = self.tree.filter(&flows);
+        self.current_item = current.and_then(|id| self.data.iter().position(|flow| flow.id == id));
+        ctx.request_repaint_after(Duration::from_millis(200));
         CentralPanel::default().show(ctx, |ui| {
             self.show_root_top(ui);
             let app_height = ui.max_rect().height();
             ui.horizontal(|ui| {
                 ui.set_height(app_height);
+                if self.tree.is_open() { self.tree.show(ui); }
                 self.show_root_middle_left(ui);
                 if self.current_item.is_none() { return; }
                 self.show_root_middle_right(ui);
             })
         });
+        self.breakpoint.show(ctx);
+        self.rewrite.show(ctx);
+        self.composer.show(ctx);
+        self.import.show(ctx);
+        self.diff.show(ctx);
+        //在时间线上点击的记录在详情里显示
+        if let Some(id) = self.waterfall.show(ctx, &self.data) {
+            self.current_item = self.data.iter().position(|flow| flow.id == id);
+        }
     }
 }
\ No newline at end of file
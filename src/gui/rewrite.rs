@@ -0,0 +1,214 @@
+use std::sync::Arc;
+use egui::{Color32, ComboBox, Context, Grid, ScrollArea, TextEdit, Ui, Window};
+use crate::config::{BodyReplace, RewriteConfig};
+use crate::context::ProxyContext;
+use crate::rule::rewrite::RewriteRule;
+
+//编辑中的规则，头和body的替换每行一条
+struct Form {
+    //修改已有的规则时是它的位置
+    index: Option<usize>,
+    name: String,
+    stage: String,
+    host: String,
+    path: String,
+    method: String,
+    content_type: String,
+    //每行一个头的名称
+    remove_headers: String,
+    //每行一个，Name: value
+    set_headers: String,
+    add_headers: String,
+    //每行一个，正则 => 替换的内容
+    body: String,
+}
+
+impl Form {
+    fn new() -> Form {
+        Form::from_config(None, &RewriteConfig::default())
+    }
+
+    fn from_config(index: Option<usize>, config: &RewriteConfig) -> Form {
+        let headers = |headers: &std::collections::BTreeMap<String, String>| headers.iter().map(|(k, v)| format!("{}: {}", k, v)).collect::<Vec<_>>().join("\n");
+        Form {
+            index,
+            name: config.name.clone(),
+            stage: config.stage.clone(),
+            host: config.host.clone(),
+            path: config.path.clone(),
+            method: config.method.clone(),
+            content_type: config.content_type.clone(),
+            remove_headers: config.remove_headers.join("\n"),
+            set_headers: headers(&config.set_headers),
+            add_headers: headers(&config.add_headers),
+            body: config.body.iter().map(|b| format!("{} => {}", b.find, b.replace)).collect::<Vec<_>>().join("\n"),
+        }
+    }
+
+    fn to_config(&self) -> RewriteConfig {
+        let headers = |text: &str| text.lines().filter_map(|l| l.split_once(':')).map(|(k, v)| (k.trim().to_string(), v.trim().to_string())).collect();
+        RewriteConfig {
+            name: self.name.trim().to_string(),
+            enabled: true,
+            stage: self.stage.clone(),
+            host: self.host.trim().to_string(),
+            path: self.path.trim().to_string(),
+            method: self.method.trim().to_string(),
+            content_type: self.content_type.trim().to_string(),
+            remove_headers: self.remove_headers.lines().map(|l| l.trim().to_string()).filter(|l| !l.is_empty()).collect(),
+            set_headers: headers(&self.set_headers),
+            add_headers: headers(&self.add_headers),
+            body: self.body.lines().filter(|l| !l.trim().is_empty()).map(|l| {
+                let (find, replace) = l.split_once(" => ").unwrap_or((l, ""));
+                BodyReplace { find: find.to_string(), replace: replace.to_string() }
+            }).collect(),
+        }
+    }
+}
+
+/*
+    ---------------------------------------------
+    | ☑ 名称  响应 *.example.com /api  [编辑][删除] |
+    ---------------------------------------------
+    | 阶段 [响应]  域名[    ] 路径[    ]           |
+    | 方法[   ]  类型[    ]                       |
+    | 删除头 | 替换头 | 添加头 | body替换            |
+    | [保存] [新建]                                |
+    ---------------------------------------------
+ */
+pub struct RewriteView {
+    ctx: Arc<ProxyContext>,
+    open: bool,
+    form: Form,
+    error: String,
+}
+
+impl RewriteView {
+    pub fn new(ctx: Arc<ProxyContext>) -> RewriteView {
+        RewriteView { ctx, open: false, form: Form::new(), error: String::new() }
+    }
+
+    pub fn show_button(&mut self, ui: &mut Ui) {
+        let count = self.ctx.rewrite.read().map(|rules| rules.rules.len()).unwrap_or(0);
+        let text = match count {
+            0 => "改写".to_string(),
+            count => format!("改写({})", count),
+        };
+        ui.selectable_label(self.open, text).clicked().then(|| self.open = !self.open);
+    }
+
+    pub fn show(&mut self, ctx: &Context) {
+        let mut open = self.open;
+        Window::new("改写规则").open(&mut open).default_size([700.0, 500.0]).show(ctx, |ui| {
+            self.show_rules(ui);
+            ui.separator();
+            self.show_form(ui);
+        });
+        self.open = open;
+    }
+
+    fn show_rules(&mut self, ui: &mut Ui) {
+        //转发每个消息时都要读规则，这里只复制一份显示，启用、删除的时候才写
+        let configs: Vec<RewriteConfig> = match self.ctx.rewrite.read() {
+            Ok(rules) => rules.rules.iter().map(|rule| rule.config.clone()).collect(),
+            Err(_) => return,
+        };
+        if configs.is_empty() { ui.label("没有改写规则，新的规则只对之后建立的连接生效"); }
+        let (mut toggle, mut remove) = (None, None);
+        ScrollArea::vertical().id_salt("rewrite_rules").max_height(150.0).show(ui, |ui| {
+            for (index, config) in configs.iter().enumerate() {
+                ui.horizontal(|ui| {
+                    let mut enabled = config.enabled;
+                    if ui.checkbox(&mut enabled, "").changed() { toggle = Some((index, enabled)); }
+                    ui.label(if config.name.is_empty() { format!("#{}", index + 1) } else { config.name.clone() });
+                    ui.label(&config.stage);
+                    ui.label(if config.host.is_empty() { "*" } else { config.host.as_str() });
+                    ui.label(&config.path);
+                    if ui.button("编辑").clicked() { self.form = Form::from_config(Some(index), config); }
+                    ui.button("删除").clicked().then(|| remove = Some(index));
+                });
+            }
+        });
+        if toggle.is_none() && remove.is_none() { return; }
+        let mut rules = match self.ctx.rewrite.write() {
+            Ok(rules) => rules,
+            Err(_) => return,
+        };
+        if let Some((index, enabled)) = toggle && let Some(rule) = rules.rules.get_mut(index) { rule.config.enabled = enabled; }
+        if let Some(index) = remove.filter(|index| *index < rules.rules.len()) {
+            rules.rules.remove(index);
+            self.form = Form::new();
+        }
+    }
+
+    fn show_form(&mut self, ui: &mut Ui) {
+        let form = &mut self.form;
+        Grid::new("rewrite_form").num_columns(4).show(ui, |ui| {
+            ui.label("名称");
+            ui.text_edit_singleline(&mut form.name);
+            ui.label("阶段");
+            ComboBox::from_id_salt("rewrite_stage").selected_text(if form.stage == "request" { "请求" } else { "响应" }).show_ui(ui, |ui| {
+                ui.selectable_value(&mut form.stage, "request".to_string(), "请求");
+                ui.selectable_value(&mut form.stage, "response".to_string(), "响应");
+            });
+            ui.end_row();
+            ui.label("域名");
+            ui.add(TextEdit::singleline(&mut form.host).hint_text("*.example.com"));
+            ui.label("路径");
+            ui.add(TextEdit::singleline(&mut form.path).hint_text("/api，支持通配符"));
+            ui.end_row();
+            ui.label("方法");
+            ui.add(TextEdit::singleline(&mut form.method).hint_text("GET"));
+            ui.label("类型");
+            ui.add(TextEdit::singleline(&mut form.content_type).hint_text("json"));
+            ui.end_row();
+        });
+        ui.columns(4, |columns| {
+            columns[0].label("删除头");
+            columns[0].add(TextEdit::multiline(&mut form.remove_headers).hint_text("Set-Cookie").desired_rows(4));
+            columns[1].label("替换头");
+            columns[1].add(TextEdit::multiline(&mut form.set_headers).hint_text("Cache-Control: no-cache").desired_rows(4));
+            columns[2].label("添加头");
+            columns[2].add(TextEdit::multiline(&mut form.add_headers).hint_text("X-Debug: 1").desired_rows(4));
+            columns[3].label("body替换");
+            columns[3].add(TextEdit::multiline(&mut form.body).hint_text("\"vip\":false => \"vip\":true").desired_rows(4));
+        });
+        if !self.error.is_empty() { ui.colored_label(Color32::RED, &self.error); }
+        ui.horizontal(|ui| {
+            let text = if self.form.index.is_some() { "保存" } else { "添加" };
+            if ui.button(text).clicked() { self.save(); }
+            if ui.button("新建").clicked() {
+                self.form = Form::new();
+                self.error.clear();
+            }
+        });
+    }
+
+    fn save(&mut self) {
+        let rule = match RewriteRule::new(self.form.to_config()) {
+            Ok(rule) => rule,
+            Err(e) => {
+                self.error = e.to_string();
+                return;
+            }
+        };
+        let mut rules = match self.ctx.rewrite.write() {
+            Ok(rules) => rules,
+            Err(e) => {
+                self.error = e.to_string();
+                return;
+            }
+        };
+        match self.form.index.filter(|index| *index < rules.rules.len()) {
+            //保留原来的启用状态
+            Some(index) => {
+                let mut rule = rule;
+                rule.config.enabled = rules.rules[index].config.enabled;
+                rules.rules[index] = rule;
+            }
+            None => rules.rules.push(rule),
+        }
+        self.form = Form::new();
+        self.error.clear();
+    }
+}
@@ -0,0 +1,43 @@
+use std::sync::Arc;
+use egui::{ComboBox, Ui};
+use crate::context::ProxyContext;
+
+//工具栏上切换网络环境，切换后已有的连接也马上生效
+pub struct ThrottleView {
+    ctx: Arc<ProxyContext>,
+}
+
+impl ThrottleView {
+    pub fn new(ctx: Arc<ProxyContext>) -> ThrottleView {
+        ThrottleView { ctx }
+    }
+
+    pub fn show_button(&mut self, ui: &mut Ui) {
+        //转发数据时每次读取都要读锁，这里只复制一份显示，点击的时候才写
+        let (enabled, forced, names) = match self.ctx.throttle.read() {
+            Ok(rules) => (rules.enabled, rules.forced.clone(), rules.profiles.iter().map(|profile| profile.name.clone()).collect::<Vec<_>>()),
+            Err(_) => return,
+        };
+        let current = match (enabled, &forced) {
+            (false, _) => "不限速".to_string(),
+            (true, Some(name)) => name.clone(),
+            (true, None) => "按配置".to_string(),
+        };
+        //点击后的enabled和forced
+        let mut clicked = None;
+        ComboBox::from_id_salt("throttle").selected_text(format!("网络：{}", current)).show_ui(ui, |ui| {
+            if ui.selectable_label(!enabled, "不限速").clicked() { clicked = Some((false, forced.clone())); }
+            //按监听地址、域名和默认的配置，没有匹配的不限速
+            if ui.selectable_label(enabled && forced.is_none(), "按配置").clicked() { clicked = Some((true, None)); }
+            //选择的网络环境所有连接都使用
+            for name in names {
+                let selected = enabled && forced.as_ref() == Some(&name);
+                if ui.selectable_label(selected, &name).clicked() { clicked = Some((true, Some(name))); }
+            }
+        });
+        if let Some((enabled, forced)) = clicked && let Ok(mut rules) = self.ctx.throttle.write() {
+            rules.enabled = enabled;
+            rules.forced = forced;
+        }
+    }
+}
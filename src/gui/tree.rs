@@ -0,0 +1,94 @@
+use std::sync::Arc;
+use egui::collapsing_header::CollapsingState;
+use egui::{Color32, Id, RichText, ScrollArea, Ui};
+use crate::data::flow::{format_size, Flow};
+use crate::data::tree::{tree_path, TreeNode};
+
+/*
+    记录列表左边按域名和路径分组的树，选中一个节点时列表里只显示这个节点下面的记录：
+    -------------------------------
+    | 全部 120                     |
+    | ▼ https://a.com 12 · 34.5 KB |
+    |   ▶ api 10 · 2个错误          |
+    |     index.html 1 · 5.0 KB    |
+    | ▶ http://cdn.b.com 30 · 1 MB |
+    -------------------------------
+ */
+pub struct TreeView {
+    open: bool,
+    selected: Vec<String>,
+    //每帧都要用，记录没有变化(个数和最后一个的编号都一样)时不重新分组
+    key: Option<(usize, u64)>,
+    root: Arc<TreeNode>,
+    //每个记录的分组路径，和记录的顺序一致
+    paths: Vec<Vec<String>>,
+}
+
+impl TreeView {
+    pub fn new() -> TreeView {
+        TreeView { open: false, selected: vec![], key: None, root: Arc::default(), paths: vec![] }
+    }
+
+    pub fn show_button(&mut self, ui: &mut Ui) {
+        ui.selectable_label(self.open, "按域名分组").clicked().then(|| self.open = !self.open);
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    //返回选中的节点下面的记录，关闭时不过滤，也不用分组
+    pub fn filter(&mut self, flows: &[Arc<Flow>]) -> Vec<Arc<Flow>> {
+        if !self.open { return flows.to_vec(); }
+        self.refresh(flows);
+        if self.selected.is_empty() { return flows.to_vec(); }
+        flows.iter().zip(&self.paths).filter(|(_, path)| path.starts_with(&self.selected)).map(|(flow, _)| flow.clone()).collect()
+    }
+
+    //抓包记录只会添加、淘汰最早的或者清空，个数和最后一个的编号都没变时就是没有变化
+    fn refresh(&mut self, flows: &[Arc<Flow>]) {
+        let key = (flows.len(), flows.last().map(|flow| flow.id).unwrap_or(0));
+        if self.key == Some(key) { return; }
+        self.key = Some(key);
+        self.root = Arc::new(TreeNode::build(flows.iter().map(|flow| flow.as_ref())));
+        self.paths = flows.iter().map(|flow| tree_path(flow)).collect();
+    }
+
+    pub fn show(&mut self, ui: &mut Ui) {
+        let root = self.root.clone();
+        ui.vertical(|ui| {
+            ui.set_width(260.0);
+            ScrollArea::both().id_salt("flow_tree").auto_shrink([false; 2]).show(ui, |ui| {
+                let all = format!("全部 {}", root.count);
+                ui.selectable_label(self.selected.is_empty(), all).clicked().then(|| self.selected.clear());
+                for node in root.children.values() { self.show_node(ui, node); }
+            });
+        });
+    }
+
+    fn show_node(&mut self, ui: &mut Ui, node: &TreeNode) {
+        let mut text = format!("{} {} · {}", node.name, node.count, format_size(node.bytes));
+        if node.errors > 0 { text.push_str(&format!(" · {}个错误", node.errors)); }
+        let text = match node.errors > 0 {
+            true => RichText::new(text).color(Color32::DARK_RED),
+            false => RichText::new(text),
+        };
+        let selected = self.selected == node.path;
+        if node.children.is_empty() {
+            //和有子节点的对齐
+            ui.horizontal(|ui| {
+                ui.add_space(ui.spacing().indent);
+                ui.selectable_label(selected, text).clicked().then(|| self.selected = node.path.clone());
+            });
+            return;
+        }
+        let id = Id::new("flow_tree").with(&node.path);
+        CollapsingState::load_with_default_open(ui.ctx(), id, false)
+            .show_header(ui, |ui| {
+                ui.selectable_label(selected, text).clicked().then(|| self.selected = node.path.clone());
+            })
+            .body(|ui| {
+                for child in node.children.values() { self.show_node(ui, child); }
+            });
+    }
+}
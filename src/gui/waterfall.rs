@@ -0,0 +1,166 @@
+use std::sync::Arc;
+use std::time::SystemTime;
+use egui::{pos2, vec2, Align2, Color32, Context, DragValue, FontId, Rect, RichText, ScrollArea, Sense, Ui, Window};
+use crate::data::flow::Flow;
+use crate::data::timing::{phases, PhaseKind};
+
+const ROW_HEIGHT: f32 = 18.0;
+//左边显示编号和URL的宽度
+const LABEL_WIDTH: f32 = 280.0;
+
+/*
+    所有记录画在同一个时间轴上，按连接分组，和浏览器开发者工具的瀑布图一样：
+    -------------------------------------------------------------
+    | 最近[100]条 [x]按连接分组    0ms     500ms     1000ms        |
+    | 127.0.0.1:51234 -> a.com:443                                |
+    |   #3 GET /index.html   ▒▒██▓▓░░░░████                       |
+    |   #4 GET /app.js                     ░░░███                 |
+    -------------------------------------------------------------
+ */
+pub struct WaterfallView {
+    open: bool,
+    limit: usize,
+    group: bool,
+}
+
+//分组后的一行，连接行显示客户端和服务器地址
+enum Row {
+    Conn(String),
+    Flow(Arc<Flow>),
+}
+
+impl WaterfallView {
+    pub fn new() -> WaterfallView {
+        WaterfallView { open: false, limit: 100, group: true }
+    }
+
+    pub fn show_button(&mut self, ui: &mut Ui) {
+        ui.selectable_label(self.open, "时间线").clicked().then(|| self.open = !self.open);
+    }
+
+    //按连接第一次出现的顺序分组，连接里按编号排列
+    fn rows(&self, flows: &[Arc<Flow>]) -> Vec<Row> {
+        let flows = &flows[flows.len().saturating_sub(self.limit)..];
+        if !self.group { return flows.iter().map(|flow| Row::Flow(flow.clone())).collect(); }
+        let mut groups: Vec<(&str, Vec<Arc<Flow>>)> = vec![];
+        for flow in flows {
+            match groups.iter_mut().find(|(sid, _)| *sid == flow.sid) {
+                Some((_, group)) => group.push(flow.clone()),
+                None => groups.push((&flow.sid, vec![flow.clone()])),
+            }
+        }
+        groups.into_iter().flat_map(|(_, group)| {
+            let title = format!("{} -> {}  {}个请求", group[0].client, group[0].server, group.len());
+            [Row::Conn(title)].into_iter().chain(group.into_iter().map(Row::Flow))
+        }).collect()
+    }
+
+    //返回点击的记录编号，在详情里显示
+    pub fn show(&mut self, ctx: &Context, flows: &[Arc<Flow>]) -> Option<u64> {
+        let mut open = self.open;
+        let mut clicked = None;
+        Window::new("时间线").open(&mut open).default_size([900.0, 500.0]).show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("最近");
+                ui.add(DragValue::new(&mut self.limit).range(1..=1000));
+                ui.label("条");
+                ui.checkbox(&mut self.group, "按连接分组");
+                ui.separator();
+                for kind in PhaseKind::kinds() {
+                    ui.label(RichText::new("■").color(phase_color(kind)));
+                    ui.label(kind.to_string());
+                }
+            });
+            let rows = self.rows(flows);
+            let all: Vec<_> = rows.iter().filter_map(|row| match row {
+                Row::Flow(flow) => Some(phases(flow)),
+                Row::Conn(_) => None,
+            }).flatten().collect();
+            let start = all.iter().map(|(_, phase)| phase.start).min().unwrap_or(SystemTime::now());
+            let end = all.iter().map(|(_, phase)| phase.end).max().unwrap_or(start);
+            let total = ms(start, end).max(1.0);
+            let axis = Axis { start, total };
+            axis.show_ticks(ui);
+            ScrollArea::vertical().auto_shrink([false; 2]).show_rows(ui, ROW_HEIGHT, rows.len(), |ui, range| {
+                for row in &rows[range] {
+                    if let Some(id) = axis.show_row(ui, row) { clicked = Some(id); }
+                }
+            });
+        });
+        self.open = open && self.open;
+        clicked
+    }
+}
+
+//时间轴：最早的开始时间和总时长(毫秒)
+struct Axis {
+    start: SystemTime,
+    total: f64,
+}
+
+impl Axis {
+    fn x(&self, bar: Rect, time: SystemTime) -> f32 {
+        bar.left() + (ms(self.start, time) / self.total) as f32 * bar.width()
+    }
+
+    fn show_ticks(&self, ui: &mut Ui) {
+        let (rect, _) = ui.allocate_exact_size(vec2(ui.available_width(), ROW_HEIGHT), Sense::hover());
+        let bar = Rect::from_min_max(pos2(rect.left() + LABEL_WIDTH, rect.top()), rect.max);
+        let painter = ui.painter();
+        for i in 0..=4 {
+            let x = bar.left() + bar.width() * i as f32 / 4.0;
+            let align = if i == 4 { Align2::RIGHT_CENTER } else { Align2::LEFT_CENTER };
+            painter.text(pos2(x, rect.center().y), align, format!("{:.0}ms", self.total * i as f64 / 4.0), FontId::proportional(11.0), Color32::GRAY);
+        }
+    }
+
+    fn show_row(&self, ui: &mut Ui, row: &Row) -> Option<u64> {
+        let (rect, resp) = ui.allocate_exact_size(vec2(ui.available_width(), ROW_HEIGHT), Sense::click());
+        let painter = ui.painter_at(rect);
+        let flow = match row {
+            Row::Conn(title) => {
+                painter.rect_filled(rect, 0.0, Color32::from_gray(235));
+                painter.text(pos2(rect.left() + 2.0, rect.center().y), Align2::LEFT_CENTER, title, FontId::proportional(12.0), Color32::DARK_GRAY);
+                return None;
+            }
+            Row::Flow(flow) => flow,
+        };
+        if resp.hovered() { painter.rect_filled(rect, 0.0, Color32::LIGHT_YELLOW); }
+        let label = format!("  #{} {} {}", flow.id, flow.method(), flow.path());
+        let label_rect = Rect::from_min_size(rect.min, vec2(LABEL_WIDTH - 4.0, ROW_HEIGHT));
+        let color = if flow.error.is_some() { Color32::RED } else { Color32::BLACK };
+        ui.painter_at(label_rect).text(pos2(rect.left(), rect.center().y), Align2::LEFT_CENTER, label, FontId::proportional(12.0), color);
+        let bar = Rect::from_min_max(pos2(rect.left() + LABEL_WIDTH, rect.top() + 3.0), pos2(rect.right(), rect.bottom() - 3.0));
+        let phases = phases(flow);
+        //连接的阶段有重叠(建立隧道包括连接服务器)，按开始时间先后画，后面的盖住前面的
+        for (kind, phase) in &phases {
+            let (left, right) = (self.x(bar, phase.start), self.x(bar, phase.end));
+            let rect = Rect::from_min_max(pos2(left, bar.top()), pos2(right.max(left + 1.0), bar.bottom()));
+            painter.rect_filled(rect, 0.0, phase_color(*kind));
+        }
+        let resp = resp.on_hover_ui(|ui| {
+            ui.label(format!("#{} {}", flow.id, flow.url()));
+            for (kind, phase) in &phases {
+                ui.label(format!("{}：{:.1}ms", kind, phase.duration().as_secs_f64() * 1000.0));
+            }
+        });
+        resp.clicked().then_some(flow.id)
+    }
+}
+
+fn ms(start: SystemTime, time: SystemTime) -> f64 {
+    time.duration_since(start).unwrap_or_default().as_secs_f64() * 1000.0
+}
+
+fn phase_color(kind: PhaseKind) -> Color32 {
+    match kind {
+        PhaseKind::Accept => Color32::from_gray(190),
+        PhaseKind::Connect => Color32::from_rgb(200, 170, 230),
+        PhaseKind::Dns => Color32::from_rgb(60, 170, 170),
+        PhaseKind::Tcp => Color32::from_rgb(240, 150, 40),
+        PhaseKind::Tls => Color32::from_rgb(160, 80, 200),
+        PhaseKind::Send => Color32::from_rgb(120, 120, 120),
+        PhaseKind::Wait => Color32::from_rgb(60, 180, 75),
+        PhaseKind::Receive => Color32::from_rgb(50, 110, 220),
+    }
+}
@@ -0,0 +1,93 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use crate::data::http::{HttpHead, HttpMessage};
+use crate::data::websocket::WsFrame;
+use crate::proxy::Direction;
+
+//一个连接的基本信息，每个回调都会带上
+#[derive(Clone, Debug)]
+pub struct ConnInfo {
+    pub sid: String,
+    pub client: String,
+    pub server: String,
+    //是否是解密后的HTTPS
+    pub tls: bool,
+}
+
+pub enum HandlerAction {
+    //继续转发(可能已经被修改过)，交给下一个处理
+    Continue,
+    //不再转发，直接把这个响应返回给客户端
+    Respond(HttpMessage),
+    //丢弃，断开连接
+    Drop,
+}
+
+/*
+   拦截处理，通过ProxyServerBuilder::handler注册，多个时按注册的顺序调用，返回的不是Continue时后面的不再调用
+   注册了拦截处理后，消息接收完整才会转发，以下情况不会调用on_request/on_response，直接转发：
+   1. body超过16MB的消息，边接收边转发
+   2. 不解密的HTTPS和不认识的协议
+   3. 所有拦截处理的wants都返回false的消息，边接收边转发，SSE、下载这些不会被缓存
+   不支持管道化(pipelining)，被拦截的请求的响应直接发给客户端
+ */
+#[async_trait]
+pub trait FlowHandler: Send + Sync {
+    //每个消息开始接收时调用，request是这个消息对应的请求的头部(响应方向也是请求的)
+    //返回false表示这个消息不需要处理，不用等接收完整
+    fn wants(&self, _conn: &ConnInfo, _direction: &Direction, _request: &HttpHead) -> bool {
+        true
+    }
+
+    //开始转发数据之前调用，Respond会把响应发给客户端后断开
+    async fn on_connect(&self, _conn: &ConnInfo) -> HandlerAction {
+        HandlerAction::Continue
+    }
+
+    async fn on_request(&self, _conn: &ConnInfo, _request: &mut HttpMessage) -> HandlerAction {
+        HandlerAction::Continue
+    }
+
+    //Respond时用返回的响应替换服务器的响应
+    async fn on_response(&self, _conn: &ConnInfo, _request: &HttpMessage, _response: &mut HttpMessage) -> HandlerAction {
+        HandlerAction::Continue
+    }
+
+    //websocket的每一帧，分片的消息会分成多次调用，返回false时丢弃这一帧
+    async fn on_websocket_message(&self, _conn: &ConnInfo, _direction: &Direction, _frame: &mut WsFrame) -> bool {
+        true
+    }
+
+    //连接出错时调用，这时连接已经断开了
+    async fn on_error(&self, _conn: &ConnInfo, _error: &str) {}
+}
+
+//同一个拦截处理注册到代理后，其他地方(如界面)还需要使用它的时候
+#[async_trait]
+impl<T: FlowHandler + ?Sized> FlowHandler for Arc<T> {
+    fn wants(&self, conn: &ConnInfo, direction: &Direction, request: &HttpHead) -> bool {
+        (**self).wants(conn, direction, request)
+    }
+
+    async fn on_connect(&self, conn: &ConnInfo) -> HandlerAction {
+        (**self).on_connect(conn).await
+    }
+
+    async fn on_request(&self, conn: &ConnInfo, request: &mut HttpMessage) -> HandlerAction {
+        (**self).on_request(conn, request).await
+    }
+
+    async fn on_response(&self, conn: &ConnInfo, request: &HttpMessage, response: &mut HttpMessage) -> HandlerAction {
+        (**self).on_response(conn, request, response).await
+    }
+
+    async fn on_websocket_message(&self, conn: &ConnInfo, direction: &Direction, frame: &mut WsFrame) -> bool {
+        (**self).on_websocket_message(conn, direction, frame).await
+    }
+
+    async fn on_error(&self, conn: &ConnInfo, error: &str) {
+        (**self).on_error(conn, error).await
+    }
+}
+
+pub type Handlers = Arc<Vec<Arc<dyn FlowHandler>>>;
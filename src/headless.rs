@@ -0,0 +1,164 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Sender};
+use std::thread::JoinHandle;
+use log::{error, info};
+use crate::data::flow::Flow;
+use crate::error::{ProxyError, ProxyResult};
+use crate::filter::Filter;
+
+//无界面模式下每个完成的Flow的输出格式
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum OutputFormat {
+    //一行一个JSON对象(JSON Lines)
+    Json,
+    //一行的摘要
+    Summary,
+}
+
+impl FromStr for OutputFormat {
+    type Err = ProxyError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" | "jsonl" => Ok(OutputFormat::Json),
+            "summary" => Ok(OutputFormat::Summary),
+            _ => Err(format!("不支持的输出格式：{}，可选：json、summary", s).into())
+        }
+    }
+}
+
+//输出到stdout或者文件，文件超过指定大小时滚动：proxy.jsonl -> proxy.jsonl.1 -> proxy.jsonl.2 ...
+pub struct FlowWriter {
+    format: OutputFormat,
+    filter: Filter,
+    //是否输出body
+    body: bool,
+    //为空时输出到stdout
+    path: String,
+    file: Option<File>,
+    size: u64,
+    //为0时不滚动
+    rotate_size: u64,
+    //保留几个旧文件
+    rotate_keep: usize,
+}
+
+impl FlowWriter {
+    pub fn new(format: OutputFormat, filter: Filter, body: bool, path: String, rotate_size: u64, rotate_keep: usize) -> ProxyResult<FlowWriter> {
+        let mut writer = FlowWriter { format, filter, body, path, file: None, size: 0, rotate_size, rotate_keep };
+        writer.open()?;
+        Ok(writer)
+    }
+
+    fn open(&mut self) -> ProxyResult<()> {
+        if self.path.is_empty() { return Ok(()); }
+        if let Some(dir) = Path::new(&self.path).parent() { std::fs::create_dir_all(dir)?; }
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = file.metadata()?.len();
+        self.file = Some(file);
+        Ok(())
+    }
+
+    //windows上打开着的文件不能改名，先关闭；改名失败时也要重新打开，不能让后面的记录输出到stdout
+    fn rotate(&mut self) -> ProxyResult<()> {
+        self.file = None;
+        match self.shift() {
+            Ok(()) => info!("抓包文件{}已滚动", self.path),
+            Err(e) => error!("抓包文件{}滚动失败，继续写入原来的文件：{}", self.path, e.to_string()),
+        }
+        self.open()
+    }
+
+    fn shift(&self) -> ProxyResult<()> {
+        if self.rotate_keep == 0 {
+            std::fs::remove_file(&self.path)?;
+            return Ok(());
+        }
+        //从最旧的开始往后挪，最旧的一个会被覆盖
+        for index in (1..self.rotate_keep).rev() {
+            let from = format!("{}.{}", self.path, index);
+            if Path::new(&from).exists() { std::fs::rename(&from, format!("{}.{}", self.path, index + 1))?; }
+        }
+        std::fs::rename(&self.path, format!("{}.1", self.path))?;
+        Ok(())
+    }
+
+    //写文件是阻塞的操作，在单独的线程里输出，不影响接收抓包数据，发送端关闭后线程结束
+    pub fn spawn(mut self) -> (Sender<Arc<Flow>>, JoinHandle<()>) {
+        let (sender, receiver) = channel::<Arc<Flow>>();
+        let handle = std::thread::spawn(move || {
+            for flow in receiver {
+                self.write(&flow).unwrap_or_else(|e| error!("输出抓包记录失败：{}", e.to_string()));
+            }
+        });
+        (sender, handle)
+    }
+
+    pub fn write(&mut self, flow: &Flow) -> ProxyResult<()> {
+        if !self.filter.matches(flow) { return Ok(()); }
+        let mut line = match self.format {
+            OutputFormat::Json => flow.to_json(self.body).to_string(),
+            OutputFormat::Summary => flow.summary(),
+        };
+        line.push('\n');
+        if self.rotate_size > 0 && self.size > 0 && self.size + line.len() as u64 > self.rotate_size { self.rotate()?; }
+        //之前重新打开失败了，再试一次
+        if self.file.is_none() { self.open()?; }
+        match self.file.as_mut() {
+            Some(file) => file.write_all(line.as_bytes())?,
+            None => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(line.as_bytes())?;
+                stdout.flush()?;
+            }
+        }
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_headless {
+    use std::path::Path;
+    use crate::data::flow::test_flow;
+    use crate::filter::Filter;
+    use crate::headless::{FlowWriter, OutputFormat};
+
+    //文件里每行摘要开头的编号
+    fn ids(path: &str) -> Vec<u64> {
+        std::fs::read_to_string(path).unwrap_or_default().lines()
+            .map(|line| line[1..line.find(' ').unwrap()].parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = std::env::temp_dir().join(format!("proxy-rotate-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("flows.log").to_string_lossy().to_string();
+        let mut writer = FlowWriter::new(OutputFormat::Summary, Filter::All, false, path.clone(), 150, 2).unwrap();
+        for id in 1..=20 {
+            writer.write(&test_flow(id, b"GET /a HTTP/1.1\r\nHost: a.com\r\n\r\n", b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")).unwrap();
+        }
+        //只保留两个旧文件，越旧的编号越小，最早的记录已经删掉了
+        let (current, first, second) = (ids(&path), ids(&format!("{}.1", path)), ids(&format!("{}.2", path)));
+        assert!(!Path::new(&format!("{}.3", path)).exists());
+        assert_eq!(current.last(), Some(&20));
+        assert!(!first.is_empty() && !second.is_empty());
+        assert!(second.last() < first.first() && first.last() < current.first());
+        assert!(second[0] > 1);
+        for file in [&path, &format!("{}.1", path), &format!("{}.2", path)] {
+            assert!(std::fs::metadata(file).unwrap().len() <= 150);
+        }
+        //改名失败时还是写到原来的文件里
+        std::fs::remove_file(format!("{}.1", path)).unwrap();
+        std::fs::create_dir_all(dir.join("flows.log.1").join("busy")).unwrap();
+        for id in 21..=30 {
+            let flow = test_flow(id, b"GET /a HTTP/1.1\r\nHost: a.com\r\n\r\n", b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+            let _ = writer.write(&flow);
+        }
+        assert_eq!(ids(&path).last(), Some(&30));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
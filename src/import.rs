@@ -0,0 +1,497 @@
+use std::fs;
+use std::time::{Duration, SystemTime};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use regex::Regex;
+use serde_json::Value;
+use time::{Date, Month, PrimitiveDateTime, Time, UtcOffset};
+use uuid::Uuid;
+use crate::context::ProxyContext;
+use crate::data::flow::{Capture, Flow};
+use crate::data::http::{HttpHead, HttpMessage};
+use crate::error::ProxyResult;
+use crate::reverse::Backend;
+
+//导入的记录在抓包记录里的标记
+pub const IMPORT_TAG: &str = "imported";
+//导入的记录在抓包记录里的客户端地址
+pub const IMPORT_CLIENT: &str = "import";
+
+/*
+    把别人抓的HAR文件(1.2)或者复制的cURL命令导入成抓包记录，和实时抓到的一样可以查看、过滤、重放：
+    HAR里的响应body是解压后的内容，导入时去掉Content-Encoding，
+    cURL命令只有请求，没有响应；
+    cURL命令里的-d @文件、-F name=@文件会读取本机的文件，只有界面上本地导入时允许，API导入的不允许(allow_files)
+ */
+pub fn parse(text: &str, allow_files: bool) -> ProxyResult<Vec<Flow>> {
+    match text.trim_start().starts_with('{') {
+        true => parse_har(text),
+        false => parse_curl(text, allow_files),
+    }
+}
+
+//按文件内容判断格式
+pub fn load(path: &str) -> ProxyResult<Vec<Flow>> {
+    let text = fs::read_to_string(path).map_err(|e| format!("读取{}失败：{}", path, e))?;
+    parse(&text, true)
+}
+
+//交给抓包通道编号和保存，返回导入的个数
+pub async fn import(ctx: &ProxyContext, flows: Vec<Flow>) -> ProxyResult<usize> {
+    let count = flows.len();
+    for flow in flows { ctx.sender.send(Capture::Import { flow: Box::new(flow) }).await?; }
+    Ok(count)
+}
+
+//界面线程里使用，不能在tokio的运行时里调用
+pub fn import_blocking(ctx: &ProxyContext, flows: Vec<Flow>) -> ProxyResult<usize> {
+    let count = flows.len();
+    for flow in flows { ctx.sender.blocking_send(Capture::Import { flow: Box::new(flow) })?; }
+    Ok(count)
+}
+
+//id由抓包通道重新编号
+fn new_flow(url: &str, request: HttpMessage, response: Option<HttpMessage>) -> ProxyResult<Flow> {
+    let (scheme, rest) = url.split_once("://").ok_or(format!("URL必须带协议：{}", url))?;
+    let pos = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let backend = Backend::parse(format!("{}://{}", scheme, &rest[..pos]))?;
+    Ok(Flow {
+        id: 0,
+        sid: Uuid::new_v4().to_string(),
+        client: IMPORT_CLIENT.to_string(),
+        server: format!("{}:{}", backend.host, backend.port),
+        tls: backend.tls,
+        request,
+        response,
+        error: None,
+        tags: vec![IMPORT_TAG.to_string()],
+        remap: None,
+        replay_of: None,
+        timing: None,
+    })
+}
+
+//请求行里只有路径，完整的URL由Host组成
+fn request_head(method: &str, url: &str, version: &str, headers: Vec<(String, String)>) -> HttpHead {
+    let rest = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    let rest = rest.split('#').next().unwrap_or("");
+    let pos = rest.find(['/', '?']).unwrap_or(rest.len());
+    let uri = match &rest[pos..] {
+        "" => "/".to_string(),
+        path if path.starts_with('?') => format!("/{}", path),
+        path => path.to_string(),
+    };
+    let mut head = HttpHead { line: format!("{} {} {}", method.to_uppercase(), uri, version), headers };
+    if head.header("Host").is_none() { head.headers.insert(0, ("Host".to_string(), rest[..pos].to_string())); }
+    head
+}
+
+//HTTP/2的请求没有HTTP/1的版本号，统一显示成HTTP/2.0
+fn http_version(version: &str) -> String {
+    match version.trim().to_uppercase().as_str() {
+        "" | "UNKNOWN" => "HTTP/1.1".to_string(),
+        "H2" | "HTTP/2" | "HTTP/2.0" => "HTTP/2.0".to_string(),
+        "H3" | "HTTP/3" | "HTTP/3.0" => "HTTP/3.0".to_string(),
+        version => version.to_string(),
+    }
+}
+
+//HAR里的头是[{"name":"","value":""}]，去掉HTTP/2的伪头部
+fn har_headers(value: &Value) -> Vec<(String, String)> {
+    value.as_array().map(|headers| headers.iter().filter_map(|header| {
+        let name = header["name"].as_str()?;
+        if name.starts_with(':') { return None; }
+        Some((name.to_string(), header["value"].as_str().unwrap_or("").to_string()))
+    }).collect()).unwrap_or_default()
+}
+
+//postData和content的text，encoding为base64时是二进制数据
+fn har_body(value: &Value) -> ProxyResult<Vec<u8>> {
+    let text = value["text"].as_str().unwrap_or("");
+    Ok(match value["encoding"].as_str() {
+        Some("base64") => STANDARD.decode(text)?,
+        _ => text.as_bytes().to_vec(),
+    })
+}
+
+//2024-01-02T03:04:05.678+08:00，没有时区时按UTC
+fn parse_time(value: &str) -> ProxyResult<SystemTime> {
+    let regex = Regex::new(r"^(\d{4})-(\d{2})-(\d{2})T(\d{2}):(\d{2}):(\d{2})(?:\.(\d{1,9}))?(Z|([+-])(\d{2}):?(\d{2}))?$")?;
+    let caps = regex.captures(value.trim()).ok_or(format!("时间格式错误：{}", value))?;
+    let number = |index: usize| caps.get(index).map(|m| m.as_str().parse::<i32>().unwrap_or(0)).unwrap_or(0);
+    let millis = caps.get(7).map(|m| format!("{:0<3}", m.as_str())[..3].parse::<u16>().unwrap_or(0)).unwrap_or(0);
+    let date = Date::from_calendar_date(number(1), Month::try_from(number(2) as u8)?, number(3) as u8)?;
+    let time = Time::from_hms_milli(number(4) as u8, number(5) as u8, number(6) as u8, millis)?;
+    let sign = if caps.get(9).map(|m| m.as_str()) == Some("-") { -1 } else { 1 };
+    let offset = UtcOffset::from_hms((sign * number(10)) as i8, (sign * number(11)) as i8, 0)?;
+    let time = PrimitiveDateTime::new(date, time).assume_offset(offset);
+    Ok(SystemTime::UNIX_EPOCH + Duration::from_millis(time.unix_timestamp_nanos().max(0) as u64 / 1_000_000))
+}
+
+pub fn parse_har(text: &str) -> ProxyResult<Vec<Flow>> {
+    let har: Value = serde_json::from_str(text).map_err(|e| format!("HAR文件格式错误：{}", e))?;
+    let entries = har["log"]["entries"].as_array().ok_or("HAR文件里没有log.entries")?;
+    let mut flows = vec![];
+    for (index, entry) in entries.iter().enumerate() {
+        let flow = har_entry(entry).map_err(|e| format!("HAR文件第{}个请求：{}", index + 1, e.to_string()))?;
+        flows.push(flow);
+    }
+    Ok(flows)
+}
+
+fn har_entry(entry: &Value) -> ProxyResult<Flow> {
+    let request = &entry["request"];
+    let url = request["url"].as_str().ok_or("没有URL")?;
+    let method = request["method"].as_str().ok_or("没有请求方法")?;
+    let start = match entry["startedDateTime"].as_str() {
+        Some(time) => parse_time(time)?,
+        None => SystemTime::now(),
+    };
+    //总耗时，-1表示没有
+    let total = Duration::from_micros((entry["time"].as_f64().unwrap_or(0.0).max(0.0) * 1000.0).round() as u64);
+    let head = request_head(method, url, &http_version(request["httpVersion"].as_str().unwrap_or("")), har_headers(&request["headers"]));
+    let mut request = HttpMessage::new(head, har_body(&request["postData"])?);
+    request.start = start;
+    request.end = start;
+    let response = &entry["response"];
+    //浏览器里被取消、被拦截的请求状态码是0
+    let status = response["status"].as_u64().unwrap_or(0);
+    let mut error = None;
+    let response = match status {
+        0 => {
+            error = Some(entry["response"]["_error"].as_str().unwrap_or("导入的请求没有响应").to_string());
+            None
+        }
+        status => {
+            let line = format!("{} {} {}", http_version(response["httpVersion"].as_str().unwrap_or("")), status, response["statusText"].as_str().unwrap_or(""));
+            let mut head = HttpHead { line: line.trim_end().to_string(), headers: har_headers(&response["headers"]) };
+            //body已经解压过了
+            head.remove_header("Content-Encoding");
+            let mut response = HttpMessage::new(head, har_body(&response["content"])?);
+            if response.head.header("Content-Length").is_some() { response.head.set_header("Content-Length", response.body.len()); }
+            response.start = start;
+            response.end = start + total;
+            Some(response)
+        }
+    };
+    let mut flow = new_flow(url, request, response)?;
+    flow.error = error;
+    Ok(flow)
+}
+
+//按shell的规则分割参数，支持单引号、双引号、$'...'和行尾的\续行
+fn shell_words(text: &str) -> ProxyResult<Vec<String>> {
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if let Some(word) = word.take() { words.push(word); }
+            }
+            '\\' => match chars.next() {
+                Some('\n') => {}
+                //Windows换行
+                Some('\r') if chars.peek() == Some(&'\n') => { chars.next(); }
+                Some(c) => word.get_or_insert_default().push(c),
+                None => {}
+            },
+            '\'' => {
+                let word = word.get_or_insert_default();
+                loop {
+                    match chars.next().ok_or("单引号没有结束")? {
+                        '\'' => break,
+                        c => word.push(c),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_default();
+                loop {
+                    match chars.next().ok_or("双引号没有结束")? {
+                        '"' => break,
+                        '\\' => match chars.next().ok_or("双引号没有结束")? {
+                            c @ ('"' | '\\' | '$' | '`') => word.push(c),
+                            '\n' => {}
+                            c => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                        },
+                        c => word.push(c),
+                    }
+                }
+            }
+            //Chrome复制的命令里有特殊字符时用$'...'
+            '$' if chars.peek() == Some(&'\'') => {
+                chars.next();
+                let word = word.get_or_insert_default();
+                let mut bytes = vec![];
+                loop {
+                    match chars.next().ok_or("单引号没有结束")? {
+                        '\'' => break,
+                        '\\' => match chars.next().ok_or("单引号没有结束")? {
+                            'n' => bytes.push(b'\n'),
+                            'r' => bytes.push(b'\r'),
+                            't' => bytes.push(b'\t'),
+                            'x' => {
+                                let hex: String = (0..2).filter_map(|_| chars.next_if(|c| c.is_ascii_hexdigit())).collect();
+                                bytes.push(u8::from_str_radix(&hex, 16).map_err(|_| "$'...'里的\\x格式错误")?);
+                            }
+                            'u' => {
+                                let hex: String = (0..4).filter_map(|_| chars.next_if(|c| c.is_ascii_hexdigit())).collect();
+                                let c = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32).ok_or("$'...'里的\\u格式错误")?;
+                                bytes.extend(c.to_string().as_bytes());
+                            }
+                            c => bytes.extend(c.to_string().as_bytes()),
+                        },
+                        c => bytes.extend(c.to_string().as_bytes()),
+                    }
+                }
+                word.push_str(&String::from_utf8_lossy(&bytes));
+            }
+            c => word.get_or_insert_default().push(c),
+        }
+    }
+    if let Some(word) = word { words.push(word); }
+    Ok(words)
+}
+
+//可以粘贴多条命令，每个curl开始一条
+pub fn parse_curl(text: &str, allow_files: bool) -> ProxyResult<Vec<Flow>> {
+    let words = shell_words(text)?;
+    let mut commands: Vec<Vec<String>> = vec![];
+    for word in words {
+        match word.as_str() {
+            "curl" | "curl.exe" => commands.push(vec![]),
+            _ => match commands.last_mut() {
+                Some(command) => command.push(word),
+                None => return Err("不是cURL命令，需要以curl开头".into()),
+            },
+        }
+    }
+    if commands.is_empty() { return Err("没有cURL命令".into()); }
+    commands.iter().map(|args| curl_flow(args, allow_files)).collect()
+}
+
+//需要参数、但是和请求无关的选项
+const CURL_SKIP: [&str; 24] = ["-o", "--output", "-x", "--proxy", "--cacert", "--capath", "-m", "--max-time", "--connect-timeout", "-w", "--write-out",
+    "--resolve", "--connect-to", "-E", "--cert", "--key", "-U", "--proxy-user", "--retry", "-c", "--cookie-jar", "--limit-rate", "--interface", "-r"];
+
+//命令里引用的文件
+fn read_file(path: &str, allow_files: bool) -> ProxyResult<Vec<u8>> {
+    if !allow_files { return Err(format!("不允许读取本机的文件：{}", path).into()); }
+    Ok(fs::read(path).map_err(|e| format!("读取{}失败：{}", path, e))?)
+}
+
+fn curl_flow(args: &[String], allow_files: bool) -> ProxyResult<Flow> {
+    let mut url = None;
+    let mut method = None;
+    let mut headers: Vec<(String, String)> = vec![];
+    //文件内容可能不是文本，按字节保存
+    let mut data: Vec<Vec<u8>> = vec![];
+    let mut form: Vec<(String, bool)> = vec![];
+    let mut get = false;
+    let mut compressed = false;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        //-XPOST这种值和选项写在一起的
+        let short = arg.starts_with('-') && !arg.starts_with("--") && arg.len() > 2;
+        let (option, attached) = match short && arg.chars().nth(1).map(|c| "XHdAbeuF".contains(c)).unwrap_or(false) {
+            true => (&arg[..2], Some(arg[2..].to_string())),
+            false => (arg.as_str(), None),
+        };
+        let mut value = || attached.clone().or_else(|| iter.next().cloned()).ok_or(format!("{}需要参数", option));
+        match option {
+            "-X" | "--request" => method = Some(value()?),
+            "-H" | "--header" => {
+                let header = value()?;
+                match header.split_once(':') {
+                    Some((k, v)) => headers.push((k.trim().to_string(), v.trim().to_string())),
+                    //Name;表示值为空的头
+                    None => if let Some(k) = header.strip_suffix(';') { headers.push((k.trim().to_string(), String::new())); },
+                }
+            }
+            "-d" | "--data" | "--data-ascii" | "--data-binary" => {
+                let value = value()?;
+                data.push(match value.strip_prefix('@') {
+                    //和curl一样，只有--data-binary保留文件里的换行
+                    Some(path) => match read_file(path, allow_files)? {
+                        content if option == "--data-binary" => content,
+                        content => content.into_iter().filter(|b| *b != b'\r' && *b != b'\n').collect(),
+                    },
+                    None => value.into_bytes(),
+                });
+            }
+            "--data-raw" => data.push(value()?.into_bytes()),
+            "--data-urlencode" => {
+                let value = value()?;
+                data.push(match value.split_once('=') {
+                    Some((name, content)) => format!("{}={}", name, url_encode(content)),
+                    None => url_encode(&value),
+                }.into_bytes());
+            }
+            "--json" => {
+                data.push(value()?.into_bytes());
+                headers.push(("Content-Type".to_string(), "application/json".to_string()));
+                headers.push(("Accept".to_string(), "application/json".to_string()));
+            }
+            "-F" | "--form" => form.push((value()?, false)),
+            "--form-string" => form.push((value()?, true)),
+            "-A" | "--user-agent" => headers.push(("User-Agent".to_string(), value()?)),
+            "-e" | "--referer" => headers.push(("Referer".to_string(), value()?)),
+            "-b" | "--cookie" => {
+                //不带=的是cookie文件，没法导入
+                let cookie = value()?;
+                if cookie.contains('=') { headers.push(("Cookie".to_string(), cookie)); }
+            }
+            "-u" | "--user" => headers.push(("Authorization".to_string(), format!("Basic {}", STANDARD.encode(value()?)))),
+            "-I" | "--head" => method = Some("HEAD".to_string()),
+            "-G" | "--get" => get = true,
+            "--compressed" => compressed = true,
+            "--url" => url = Some(value()?),
+            option if CURL_SKIP.contains(&option) => { value()?; }
+            option if option.starts_with('-') => {}
+            _ => url = Some(arg.clone()),
+        }
+    }
+    let mut url = url.ok_or("cURL命令里没有URL")?;
+    if !url.contains("://") { url = format!("http://{}", url); }
+    let has_header = |headers: &[(String, String)], key: &str| headers.iter().any(|(k, _)| k.eq_ignore_ascii_case(key));
+    if compressed && !has_header(&headers, "Accept-Encoding") { headers.push(("Accept-Encoding".to_string(), "deflate, gzip".to_string())); }
+    let mut body = vec![];
+    if !form.is_empty() {
+        let boundary = format!("------------------------{}", Uuid::new_v4().simple());
+        body = multipart_body(&form, &boundary, allow_files)?;
+        headers.retain(|(k, _)| !k.eq_ignore_ascii_case("Content-Type"));
+        headers.push(("Content-Type".to_string(), format!("multipart/form-data; boundary={}", boundary)));
+    } else if !data.is_empty() {
+        let data = data.join(&b'&');
+        match get {
+            //-G把数据放到URL里
+            true => url = format!("{}{}{}", url, if url.contains('?') { "&" } else { "?" }, String::from_utf8_lossy(&data)),
+            false => {
+                if !has_header(&headers, "Content-Type") { headers.push(("Content-Type".to_string(), "application/x-www-form-urlencoded".to_string())); }
+                body = data;
+            }
+        }
+    }
+    let method = method.unwrap_or(if body.is_empty() { "GET" } else { "POST" }.to_string());
+    if !body.is_empty() { headers.push(("Content-Length".to_string(), body.len().to_string())); }
+    let head = request_head(&method, &url, "HTTP/1.1", headers);
+    new_flow(&url, HttpMessage::new(head, body), None)
+}
+
+//name=value，name=@文件[;type=][;filename=]，name=<文件(文件内容作为值)
+fn multipart_body(form: &[(String, bool)], boundary: &str, allow_files: bool) -> ProxyResult<Vec<u8>> {
+    let mut body = vec![];
+    for (field, string) in form {
+        let (name, value) = field.split_once('=').ok_or(format!("表单格式错误：{}", field))?;
+        let mut disposition = format!("form-data; name=\"{}\"", name);
+        let mut content_type = None;
+        let data = match value.strip_prefix('@') {
+            Some(file) if !string => {
+                let mut parts = file.split(';');
+                let path = parts.next().unwrap_or("").trim_matches('"');
+                let mut filename = path.rsplit(['/', '\\']).next().unwrap_or(path).to_string();
+                for part in parts {
+                    match part.split_once('=') {
+                        Some(("type", v)) => content_type = Some(v.to_string()),
+                        Some(("filename", v)) => filename = v.trim_matches('"').to_string(),
+                        _ => {}
+                    }
+                }
+                disposition.push_str(&format!("; filename=\"{}\"", filename));
+                content_type.get_or_insert("application/octet-stream".to_string());
+                read_file(path, allow_files)?
+            }
+            _ => match value.strip_prefix('<') {
+                Some(path) if !string => read_file(path, allow_files)?,
+                _ => value.as_bytes().to_vec(),
+            },
+        };
+        body.extend(format!("--{}\r\nContent-Disposition: {}\r\n", boundary, disposition).as_bytes());
+        if let Some(content_type) = content_type { body.extend(format!("Content-Type: {}\r\n", content_type).as_bytes()); }
+        body.extend(b"\r\n");
+        body.extend(data);
+        body.extend(b"\r\n");
+    }
+    body.extend(format!("--{}--\r\n", boundary).as_bytes());
+    Ok(body)
+}
+
+fn url_encode(value: &str) -> String {
+    value.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        b => format!("%{:02X}", b),
+    }).collect()
+}
+
+#[cfg(test)]
+mod test_import {
+    use std::time::{Duration, UNIX_EPOCH};
+    use crate::import::{parse, IMPORT_TAG};
+
+    #[test]
+    fn test_import() {
+        let har = r#"{"log": {"version": "1.2", "entries": [
+            {"startedDateTime": "2024-01-02T03:04:05.678+08:00", "time": 120.5,
+             "request": {"method": "POST", "url": "https://api.example.com/login?x=1", "httpVersion": "h2",
+                         "headers": [{"name": ":authority", "value": "api.example.com"}, {"name": "content-type", "value": "application/json"}],
+                         "postData": {"mimeType": "application/json", "text": "{\"a\":1}"}},
+             "response": {"status": 200, "statusText": "", "httpVersion": "h2",
+                          "headers": [{"name": "content-encoding", "value": "gzip"}, {"name": "content-type", "value": "image/png"}],
+                          "content": {"size": 4, "mimeType": "image/png", "text": "iVBORw==", "encoding": "base64"}}},
+            {"startedDateTime": "2024-01-02T03:04:06Z", "time": -1,
+             "request": {"method": "GET", "url": "http://www.example.com", "httpVersion": "HTTP/1.1", "headers": []},
+             "response": {"status": 0, "statusText": "", "httpVersion": "", "headers": [], "content": {"size": 0}, "_error": "net::ERR_BLOCKED_BY_CLIENT"}}
+        ]}}"#;
+        let flows = parse(har, false).unwrap();
+        assert_eq!(flows.len(), 2);
+        assert_eq!(flows[0].url(), "https://api.example.com/login?x=1");
+        assert_eq!(flows[0].request.head.line, "POST /login?x=1 HTTP/2.0");
+        assert_eq!(flows[0].request.body, b"{\"a\":1}");
+        assert_eq!(flows[0].request.start, UNIX_EPOCH + Duration::from_millis(1704135845678));
+        assert_eq!(flows[0].duration(), Some(Duration::from_micros(120500)));
+        let response = flows[0].response.as_ref().unwrap();
+        assert_eq!(response.head.line, "HTTP/2.0 200");
+        assert_eq!(response.body, b"\x89PNG");
+        assert!(response.head.header("Content-Encoding").is_none());
+        assert_eq!((flows[1].url(), flows[1].error.as_deref()), ("http://www.example.com/".to_string(), Some("net::ERR_BLOCKED_BY_CLIENT")));
+        assert_eq!(flows[1].tags, vec![IMPORT_TAG]);
+        //Chrome复制的cURL命令
+        let curl = "curl 'https://api.example.com/users/1' \\\n  -X 'PUT' \\\n  -H 'authorization: Bearer abc' \\\n  -H $'x-note: it\\'s' \\\n  --data-raw '{\"name\":\"b\"}' \\\n  --compressed\ncurl -G example.com/search -d q=1 --data-urlencode 'w=a b' -o out.html";
+        let flows = parse(curl, false).unwrap();
+        assert_eq!(flows.len(), 2);
+        assert_eq!(flows[0].request.head.line, "PUT /users/1 HTTP/1.1");
+        assert_eq!(flows[0].request.head.header("x-note"), Some("it's"));
+        assert_eq!(flows[0].request.head.header("Accept-Encoding"), Some("deflate, gzip"));
+        assert_eq!(flows[0].request.head.header("Content-Length"), Some("12"));
+        assert_eq!((flows[0].server.as_str(), flows[0].tls), ("api.example.com:443", true));
+        assert!(flows[0].response.is_none() && flows[0].error.is_none());
+        assert_eq!(flows[1].url(), "http://example.com/search?q=1&w=a%20b");
+        let form = parse("curl -F title=hi --form-string 'note=@x' http://localhost:8080/upload", false).unwrap().remove(0);
+        let body = String::from_utf8(form.request.body.clone()).unwrap();
+        assert_eq!(form.method(), "POST");
+        assert!(form.request.head.header("Content-Type").unwrap().starts_with("multipart/form-data; boundary="));
+        assert!(body.contains("name=\"title\"\r\n\r\nhi\r\n") && body.contains("name=\"note\"\r\n\r\n@x\r\n"));
+        //API导入的命令不能读取本机的文件
+        let path = std::env::temp_dir().join(format!("import-{}.txt", std::process::id()));
+        std::fs::write(&path, "secret").unwrap();
+        for command in [format!("curl -d @{} example.com", path.display()), format!("curl -F f=@{} example.com", path.display()), format!("curl -F 'f=<{}' example.com", path.display())] {
+            assert!(parse(&command, false).is_err());
+            let body = parse(&command, true).unwrap().remove(0).request.body;
+            assert!(String::from_utf8_lossy(&body).contains("secret"));
+        }
+        //--data-binary原样发送文件内容，-d去掉换行
+        std::fs::write(&path, b"\xff\xfe\r\n\x00\x89").unwrap();
+        let binary = parse(&format!("curl --data-binary @{} -d a=1 example.com", path.display()), true).unwrap().remove(0);
+        assert_eq!(binary.request.body, b"\xff\xfe\r\n\x00\x89&a=1");
+        assert_eq!(binary.request.head.header("Content-Length"), Some("10"));
+        let ascii = parse(&format!("curl -d @{} example.com", path.display()), true).unwrap().remove(0);
+        assert_eq!(ascii.request.body, b"\xff\xfe\x00\x89");
+        std::fs::remove_file(path).unwrap();
+        assert!(parse("wget http://example.com", false).is_err());
+        assert!(parse("curl -X POST", false).is_err());
+    }
+}
@@ -0,0 +1,80 @@
+mod api;
+mod cert;
+mod socks5;
+mod sniff;
+mod transparent;
+mod proxy;
+pub mod breakpoint;
+pub mod config;
+pub mod error;
+pub mod server;
+pub mod context;
+pub mod rule;
+pub mod upstream;
+pub mod throttle;
+pub mod replay;
+pub mod snippet;
+pub mod diff;
+pub mod reverse;
+pub mod data;
+pub mod filter;
+pub mod handler;
+pub mod import;
+pub mod headless;
+#[cfg(feature = "gui")]
+pub mod gui;
+#[cfg(feature = "tui")]
+pub mod tui;
+
+use log4rs::append::console::{ConsoleAppender, Target};
+use log4rs::append::file::FileAppender;
+use log4rs::Config;
+use log4rs::config::{Appender, Logger, Root};
+use log4rs::encode::pattern::PatternEncoder;
+use log::LevelFilter;
+use crate::config::ProxyConfig;
+use crate::error::ProxyResult;
+
+pub use crate::data::flow::Flow;
+pub use crate::data::store::{FlowStore, FlowStream};
+pub use crate::handler::{ConnInfo, FlowHandler, HandlerAction};
+pub use crate::proxy::Direction;
+pub use crate::server::{ListenMode, ProxyHandle, ProxyServer, ProxyServerBuilder};
+
+//命令行和图形界面共用，嵌入使用时由调用的程序自己初始化日志
+pub fn init_log4rs(config: &ProxyConfig) -> ProxyResult<()> {
+    let coder = PatternEncoder::new("{h({d(%Y-%m-%d %H:%M:%S)} [{f}:{L}] {l:<6})} {M}:{m}{n}");
+    //stdout留给抓包记录，日志输出到stderr
+    let stdout = ConsoleAppender::builder().encoder(Box::new(coder.clone())).target(Target::Stderr).build();
+    let mut builder = Config::builder()
+        .appender(Appender::builder().build("stdout", Box::new(stdout)))
+        .logger(Logger::builder().build("rustls", LevelFilter::Error));
+    //终端界面模式下输出到控制台会把界面弄乱，只输出到文件
+    let mut root = match config.tui {
+        true => Root::builder(),
+        false => Root::builder().appender("stdout"),
+    };
+    //日志文件为空时只输出到控制台
+    if !config.log.file.is_empty() {
+        let requests = FileAppender::builder().encoder(Box::new(coder)).build(&config.log.file)?;
+        builder = builder.appender(Appender::builder().build("requests", Box::new(requests)));
+        root = root.appender("requests");
+    }
+    log4rs::init_config(builder.build(root.build(config.log_level()))?)?;
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn regex_find(rex: &str, context: &str) -> ProxyResult<Vec<String>> {
+    let regx = regex::RegexBuilder::new(rex).build()?;
+    let mut res = vec![];
+    for re in regx.captures_iter(context) {
+        let mut r = vec![];
+        for index in 0..re.len() {
+            r.push(re[index].to_string());
+        }
+        if r.len() > 1 { r.remove(0); }
+        res.extend(r);
+    };
+    Ok(res)
+}
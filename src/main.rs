@@ -1,155 +1,49 @@
-mod cert;
-mod error;
-// mod socks5;
-mod proxy;
-mod data;
-mod gui;
-
-use std::collections::HashMap;
-use std::io::BufReader;
-use std::sync::Arc;
-use std::time::Duration;
-use egui::ViewportBuilder;
-use log4rs::append::console::ConsoleAppender;
-use log4rs::append::file::FileAppender;
-use log4rs::Config;
-use log4rs::config::{Appender, Logger, Root};
-use log4rs::encode::pattern::PatternEncoder;
-use log::{debug, error, info, trace, LevelFilter};
-use reqrio::Response;
-use rustls::ServerConfig;
-use rustls_pemfile::Item;
-use rustls_pki_types::PrivateKeyDer;
-use tokio::net::TcpListener;
-use tokio::sync;
-use tokio::sync::mpsc::Receiver;
-use tokio::time::sleep;
-use tokio_rustls::TlsAcceptor;
-use crate::error::ProxyResult;
-use crate::gui::ProxyView;
-use crate::proxy::{Direction, ProxyStream};
-// fn main() {
-//     let viewport = ViewportBuilder::default()
-//         .with_title("Proxy").with_inner_size((1200.0, 6000.0));
-//     let mut native_options = eframe::NativeOptions::default();
-//     native_options.viewport=viewport;
-//     eframe::run_native("Proxy", native_options, Box::new(|cc| ProxyView::new(cc))).unwrap();
-// }
+use log::error;
+use proxy::config::{self, ProxyConfig};
+use proxy::error::ProxyResult;
+use proxy::{init_log4rs, ProxyServer};
+#[cfg(feature = "tui")]
+use proxy::tui;
 
 #[tokio::main]
 async fn main() {
-    tokio::spawn(async {
-        init_log4rs().unwrap();
-        start_server().await.unwrap();
-    });
-    sleep(Duration::from_secs(100000)).await;
-    // start_socks5_server().await.unwrap()
-}
-
-fn init_log4rs() -> ProxyResult<()> {
-    let coder = PatternEncoder::new("{h({d(%Y-%m-%d %H:%M:%S)} [{f}:{L}] {l:<6})} {M}:{m}{n}");
-    let stdout = ConsoleAppender::builder().encoder(Box::new(coder.clone())).build();
-    let requests = FileAppender::builder().encoder(Box::new(coder)).build("target/log/proxy.log")?;
-    let config = Config::builder()
-        .appender(Appender::builder().build("stdout", Box::new(stdout)))
-        .appender(Appender::builder().build("requests", Box::new(requests)))
-        .logger(Logger::builder().build("rustls", LevelFilter::Error))
-        .build(Root::builder().appender("stdout").appender("requests").build(LevelFilter::Trace))?;
-
-    log4rs::init_config(config)?;
-    Ok(())
-}
-
-
-//我们先在本地启动一个服务，监听7090端口
-async fn start_server() -> ProxyResult<()> {
-    let (sx, rx) = sync::mpsc::channel(1024);
-    tokio::spawn(async move {
-        receive_data(rx).await;
-    });
-    info!("在本地0.0.0.0:7090建立一个Tcp端口监听服务");
-    let listen = TcpListener::bind("0.0.0.0:7090").await?;
-    loop {
-        //接受一个新连接
-        let (stream, addr) = listen.accept().await?;
-        debug!("来自{}的新连接",addr);
-        //启动一个线程，避免造成其他连接阻塞，影响网络体验
-        let sender = sx.clone();
-        tokio::spawn(async move {
-            ProxyStream::new(stream, sender).start().await.unwrap_or_else(|e| error!("{}",e.to_string()));
-        });
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", config::USAGE);
+        return;
     }
-}
-//到目前为止，我们没有做区分stream
-async fn receive_once(rx: &mut Receiver<(Direction, Response)>) -> Option<()> {
-    let (direction, data) = rx.recv().await?;
-    match direction {
-        Direction::ClientToServer => println!("{}", data.header()),
-        Direction::ServerToClient => println!("{}", data.header()),
+    //配置有问题时直接退出，不要启动到一半才发现
+    let config = match ProxyConfig::load(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e.to_string());
+            std::process::exit(2);
+        }
+    };
+    if config.tui && !cfg!(feature = "tui") {
+        eprintln!("编译时没有启用终端界面，使用cargo build --features tui重新编译");
+        std::process::exit(2);
     }
-    None
-}
-
-async fn receive_data(mut rx: Receiver<(Direction, Response)>) {
-    loop {
-        let _ = receive_once(&mut rx).await;
+    if let Err(e) = init_log4rs(&config) {
+        eprintln!("初始化日志失败：{}", e.to_string());
+        std::process::exit(2);
     }
+    start_server(config).await.unwrap_or_else(|e| error!("{}", e.to_string()));
 }
 
-
-#[allow(dead_code)]
-fn regex_find(rex: &str, context: &str) -> ProxyResult<Vec<String>> {
-    let regx = regex::RegexBuilder::new(rex).build()?;
-    let mut res = vec![];
-    for re in regx.captures_iter(context) {
-        let mut r = vec![];
-        for index in 0..re.len() {
-            r.push(re[index].to_string());
-        }
-        if r.len() > 1 { r.remove(0); }
-        res.extend(r);
-    };
-    Ok(res)
+//按配置启动所有的监听端口，所有端口共用一个抓包通道
+async fn start_server(config: ProxyConfig) -> ProxyResult<()> {
+    let handle = ProxyServer::builder().config(config.clone()).start().await?;
+    //没有配置令牌时是随机生成的，需要告诉使用者
+    if let Some((_, token)) = handle.api().filter(|_| config.api.token.is_empty()) {
+        eprintln!("控制接口令牌：{}", token);
+    }
+    #[cfg(feature = "tui")]
+    if config.tui {
+        let listeners: Vec<String> = handle.addrs().iter().map(|(addr, mode)| format!("{}://{}", mode, addr)).collect();
+        let store = handle.store();
+        //终端界面会阻塞线程，按q退出后整个程序退出
+        return tokio::task::spawn_blocking(move || tui::run(store, listeners.join(" "))).await?;
+    }
+    handle.wait().await
 }
-
-
-//这里需要实现一个TlsAcceptor才能解密
-fn gen_acceptor_for_sni(sni: impl AsRef<str>) -> ProxyResult<TlsAcceptor> {
-    //这里先要生成证书
-    //在top命令中我们看到我们的程序在建立连接的时候cpu占用很高。这个是证书生成时占用的，这里我们做一个证书缓存
-    let crt_path = format!("target/tmp/certs/{}.pem", sni.as_ref());
-    let key_path = format!("target/tmp/certs/{}.key", sni.as_ref());
-    let (sni_bs, key_bs) = if std::fs::exists(crt_path.as_str())? {
-        let sni_bs = std::fs::read(crt_path.as_str())?;
-        let key_bs = std::fs::read(key_path.as_str())?;
-        (sni_bs, key_bs)
-    } else {
-        trace!("正在为{}生成证书",sni.as_ref());
-        let (pem, key) = cert::gen_cert_for_sni(sni.as_ref(), "sca.pem", "sca.key")?;
-        let sni_bs = pem.into_bytes();
-        let key_bs = key.into_bytes();
-        std::fs::write(crt_path.as_str(), sni_bs.as_slice())?;
-        std::fs::write(key_path.as_str(), key_bs.as_slice())?;
-        (sni_bs, key_bs)
-    };
-
-
-    let mut reader = BufReader::new(sni_bs.as_slice());
-    let item = rustls_pemfile::read_one(&mut reader).transpose().ok_or("读取证书失败")??;
-    let sni_cert = match item {
-        Item::X509Certificate(cert) => cert,
-        _ => return Err("不支持的证书".into()),
-    };
-    let mut reader = BufReader::new(key_bs.as_slice());
-    let item = rustls_pemfile::read_one(&mut reader).transpose().ok_or("读取证书密钥失败")??;
-    let sni_key = match item {
-        Item::Pkcs1Key(key) => PrivateKeyDer::Pkcs1(key),
-        Item::Pkcs8Key(key) => PrivateKeyDer::Pkcs8(key),
-        Item::Sec1Key(key) => PrivateKeyDer::Sec1(key),
-        _ => return Err("不支持的证书密钥类型".into()),
-    };
-    let config = ServerConfig::builder_with_protocol_versions(&rustls::ALL_VERSIONS)
-        .with_no_client_auth().with_single_cert(vec![sni_cert], sni_key)?;
-    let acceptor = TlsAcceptor::from(Arc::new(config));
-    Ok(acceptor)
-}
\ No newline at end of file
@@ -1,27 +1,61 @@
+use std::collections::VecDeque;
 use std::fmt::Display;
 use std::mem;
-use std::sync::Arc;
-use log::{error, trace};
-use reqrio::{tokio, Buffer, Method, Response};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use log::{debug, error, trace};
+use reqrio::{tokio, Buffer, Method};
 use reqrio::tokio::io::AsyncWriteExt;
 use reqrio::tokio::net::TcpStream;
 use rustls::{ClientConfig, RootCertStore};
-use rustls_pki_types::{DnsName, ServerName};
+use rustls_pki_types::ServerName;
 use tokio::io::{AsyncReadExt, ReadHalf, WriteHalf};
+use tokio::sync::mpsc;
 use tokio::sync::mpsc::Sender;
-use tokio::task::{JoinError, JoinHandle};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
 use tokio_rustls::TlsConnector;
 use uuid::Uuid;
 use crate::error::{ProxyError, ProxyResult};
-use crate::{gen_acceptor_for_sni, regex_find};
-use crate::data::http::Request;
+use crate::cert::gen_acceptor_for_sni;
+use crate::regex_find;
+use crate::data::flow::{request_url, Capture, Remap};
+use crate::data::http::{text_response, BufferPolicy, HttpHead, HttpMessage, HttpParser, Parsed};
+use crate::data::timing::{ConnTiming, Phase};
+use crate::data::websocket::WsParser;
+use crate::context::ProxyContext;
+use crate::handler::{ConnInfo, HandlerAction};
+use crate::replay::exchange;
+use crate::reverse::{forward_backend, Backend};
+use crate::rule::fault::{FaultAction, FAULT_TAG};
+use crate::rule::map_local::MAPPED_TAG;
+use crate::rule::playback::PLAYBACK_TAG;
+use crate::rule::rewrite::{RewriteStage, REWRITTEN_TAG};
+use crate::server::ListenMode;
+use crate::sniff::{parse_sni, Protocol};
+use crate::throttle::{NetworkProfile, Throttle, THROTTLE_CHUNK};
+use crate::upstream::split_host_port;
 
-#[derive(Clone)]
+//判断协议时最多等待的次数，每次10毫秒
+const SNIFF_RETRY: usize = 50;
+//隧道建立后等待客户端发送第一个字节的时间，超时的当作服务器先发数据的协议直接转发
+const TUNNEL_SNIFF_WAIT: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Direction {
     ClientToServer,
     ServerToClient,
 }
 
+//和真实服务器建立TLS连接时使用，证书按系统内置的根证书校验
+pub(crate) fn tls_connector() -> TlsConnector {
+    let mut root_ca = RootCertStore::empty();
+    root_ca.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let client_config = ClientConfig::builder().with_root_certificates(root_ca).with_no_client_auth();
+    TlsConnector::from(Arc::new(client_config))
+}
+
 impl Display for Direction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -32,21 +66,401 @@ impl Display for Direction {
 }
 
 pub struct ProxyParam {
-    sid: String,
-    sender: Sender<(Direction, Response)>,
-    buffer: Buffer,
-    data: Request,
-    direction: Direction,
+    pub(crate) sid: String,
+    pub(crate) sender: Sender<Capture>,
+    pub(crate) buffer: Buffer,
+    pub(crate) parser: HttpParser,
+    pub(crate) direction: Direction,
+    //客户端地址和目标服务器地址，抓包记录里使用
+    pub(crate) client: String,
+    pub(crate) server: String,
+    //是否是解密后的HTTPS
+    pub(crate) tls: bool,
+    //已经从客户端读取、还没有转发的数据，开始转发时先处理
+    pub(crate) pending: Vec<u8>,
+    //消息接收完整后再转发，有拦截处理、本地映射或者改写规则时使用
+    pub(crate) forward: bool,
+    pub(crate) ctx: Arc<ProxyContext>,
+    //连接进来的监听地址，按监听地址选择网络环境
+    pub(crate) listener: Option<SocketAddr>,
+    //模拟慢速网络，每个方向一个
+    throttle: Throttle,
+    //远程映射改写了当前请求的地址，抓包时记录下来
+    remap: Option<Remap>,
+    //已经转发的请求，调用on_response时使用，两个方向共用
+    requests: Arc<Mutex<VecDeque<HttpMessage>>>,
+    //升级成websocket后按帧解析
+    ws: Option<WsParser>,
+    //连接建立过程中各个阶段的时间，开始转发时发送到抓包通道
+    pub(crate) timing: ConnTiming,
+    //故障注入了reset，断开时客户端要收到RST而不是正常关闭
+    reset: bool,
+}
+
+//有拦截处理时请求方向通知响应方向，T是客户端连接的类型
+enum Inject<T> {
+    //请求被拦截，把这个响应发给客户端
+    Response(HttpMessage),
+    //请求被丢弃，断开客户端连接
+    Close,
+    //故障注入reset，把客户端连接读的一半交给响应方向，合起来设置SO_LINGER为0后关闭
+    Reset(ReadHalf<T>),
+}
+
+//reset需要拿到底层的TCP连接设置SO_LINGER，解密的连接取TLS下面的那一层
+pub(crate) trait TcpSocket {
+    fn tcp(&self) -> &TcpStream;
+}
+
+impl TcpSocket for TcpStream {
+    fn tcp(&self) -> &TcpStream {
+        self
+    }
+}
+
+impl TcpSocket for tokio_rustls::server::TlsStream<TcpStream> {
+    fn tcp(&self) -> &TcpStream {
+        self.get_ref().0
+    }
+}
+
+impl TcpSocket for tokio_rustls::client::TlsStream<TcpStream> {
+    fn tcp(&self) -> &TcpStream {
+        self.get_ref().0
+    }
 }
 
-impl Clone for ProxyParam {
-    fn clone(&self) -> Self {
+impl ProxyParam {
+    //服务器到客户端方向使用的参数，两个方向的解析器共用请求方法队列
+    fn response(&self) -> ProxyParam {
         ProxyParam {
             sid: self.sid.clone(),
             sender: self.sender.clone(),
             buffer: Buffer::new(),
-            data: Request::new(),
-            direction: self.direction.clone(),
+            parser: HttpParser::new(false, self.parser.heads()).forward(self.forward),
+            direction: Direction::ServerToClient,
+            client: self.client.clone(),
+            server: self.server.clone(),
+            tls: self.tls,
+            pending: vec![],
+            forward: self.forward,
+            ctx: self.ctx.clone(),
+            listener: self.listener,
+            throttle: Throttle::new(),
+            remap: None,
+            requests: self.requests.clone(),
+            ws: None,
+            timing: ConnTiming::default(),
+            reset: false,
+        }
+    }
+
+    fn conn(&self) -> ConnInfo {
+        ConnInfo { sid: self.sid.clone(), client: self.client.clone(), server: self.server.clone(), tls: self.tls }
+    }
+
+    //把解析出来的完整消息发送到抓包通道
+    pub(crate) async fn capture(&mut self, bs: &[u8]) -> ProxyResult<()> {
+        for message in self.parser.feed(bs) { self.send(message).await?; }
+        Ok(())
+    }
+
+    //当前使用的网络环境，每次读取数据时重新选择，界面上切换后已有的连接也马上生效
+    fn network(&self) -> ProxyResult<Option<NetworkProfile>> {
+        Ok(self.ctx.throttle.read()?.select(self.listener, &self.server))
+    }
+
+    //这个方向的每个消息是否要接收完整再转发，解析出头部后按URL检查规则和拦截处理，规则修改后马上生效
+    fn policy(&self) -> BufferPolicy {
+        let (ctx, conn, direction) = (self.ctx.clone(), self.conn(), self.direction.clone());
+        Box::new(move |request| ctx.buffered(&conn, &direction, request))
+    }
+
+    //有网络环境时分块限速发送
+    async fn write_throttled<O>(&mut self, writer: &mut O, data: &[u8]) -> ProxyResult<()>
+    where
+        O: AsyncWriteExt + Unpin,
+    {
+        match self.network()? {
+            None => writer.write_all(data).await?,
+            Some(profile) => for chunk in data.chunks(THROTTLE_CHUNK) {
+                self.throttle.wait(&profile, &self.direction, chunk.len()).await;
+                writer.write_all(chunk).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, message: HttpMessage) -> ProxyResult<()> {
+        self.send_as(self.direction.clone(), message).await?;
+        if let Some(remap) = self.remap.take() { self.sender.send(Capture::Remap { sid: self.sid.clone(), remap }).await?; }
+        Ok(())
+    }
+
+    //给当前的请求加一个标记，请求方向在发送请求之后调用，响应方向在发送响应之前调用
+    async fn tag(&self, tag: &str) -> ProxyResult<()> {
+        self.sender.send(Capture::Tag { sid: self.sid.clone(), direction: self.direction.clone(), tag: tag.to_string() }).await?;
+        Ok(())
+    }
+
+    //匹配本地映射规则的请求用本地文件生成响应，回放模式下用保存的会话响应，同时返回抓包记录的标记
+    fn map_local(&self, request: &HttpMessage) -> ProxyResult<Option<(HttpMessage, &'static str)>> {
+        let url = request_url(&request.head, self.tls, &self.server);
+        if let Some(rule) = self.ctx.map_local.read()?.select(&url) {
+            trace!("{}映射到本地文件{}", url, rule.path.display());
+            return Ok(Some((rule.respond(request, &url), MAPPED_TAG)));
+        }
+        let playback = self.ctx.playback.read()?;
+        if !playback.enabled() { return Ok(None); }
+        Ok(playback.respond(request, &url).map(|response| (response, PLAYBACK_TAG)))
+    }
+
+    //按概率决定这个请求是否注入故障，on_request区分请求方向和响应方向生效的故障
+    fn fault(&self, request: &HttpHead, on_request: bool) -> ProxyResult<Option<FaultAction>> {
+        let rules = self.ctx.fault.read()?;
+        if rules.is_empty() { return Ok(None); }
+        let url = request_url(request, self.tls, &self.server);
+        let fault = rules.roll(request, &url, on_request);
+        if let Some(fault) = &fault { debug!("{}注入故障：{:?}", url, fault); }
+        Ok(fault)
+    }
+
+    //按请求的URL检查远程映射规则，匹配时改写请求的地址和Host，返回要连接的地址和抓包时记录的改写
+    fn remap(&self, head: &mut HttpHead) -> ProxyResult<Option<(Backend, Remap)>> {
+        let url = request_url(head, self.tls, &self.server);
+        let rule = match self.ctx.map_remote.read()?.select(&url).cloned() {
+            Some(rule) => rule,
+            None => return Ok(None),
+        };
+        let rewritten = rule.rewrite_url(&url);
+        debug!("远程映射：{} -> {}", url, rewritten);
+        head.set_uri(&rule.rewrite_uri(&url));
+        if !rule.preserve_host { head.set_header("Host", rule.to.host_header()); }
+        //和反向代理一样每个连接只转发一个请求，后面的请求重新连接时再按规则选择
+        head.set_header("Connection", "close");
+        Ok(Some((rule.to, Remap { original: url, rewritten })))
+    }
+
+    //第一个请求决定连接哪里，匹配时改写pending里的请求，返回要连接的地址
+    //头部不完整时这里不处理，转发的时候还会按每个请求检查一次
+    fn map_remote(&mut self) -> ProxyResult<Option<Backend>> {
+        let (mut head, head_len) = match HttpHead::parse(&self.pending) {
+            Some(res) => res,
+            None => return Ok(None),
+        };
+        let (backend, remap) = match self.remap(&mut head)? {
+            Some(res) => res,
+            None => return Ok(None),
+        };
+        let mut pending = head.to_bytes();
+        pending.extend(&self.pending[head_len..]);
+        self.pending = pending;
+        self.remap = Some(remap);
+        Ok(Some(backend))
+    }
+
+    //同一个连接上后面的请求匹配远程映射时，这个连接已经连着别的服务器了，单独连接映射的地址取回响应
+    async fn exchange_remote(&mut self, request: &mut HttpMessage) -> ProxyResult<Option<HttpMessage>> {
+        //第一个请求已经在连接前改写过了
+        if self.remap.is_some() { return Ok(None); }
+        let (backend, remap) = match self.remap(&mut request.head)? {
+            Some(res) => res,
+            None => return Ok(None),
+        };
+        self.remap = Some(remap);
+        let mut response = match exchange(&self.ctx, &backend, request, &mut ConnTiming::default()).await {
+            Ok(response) => response,
+            Err(e) => HttpMessage::response(502, "Bad Gateway", format!("远程映射连接{}:{}失败：{}", backend.host, backend.port, e.to_string())),
+        };
+        //客户端和代理之间的连接还要继续使用
+        response.head.remove_header("Connection");
+        Ok(Some(response))
+    }
+
+    async fn send_as(&self, direction: Direction, message: HttpMessage) -> ProxyResult<()> {
+        self.sender.send(Capture::Message {
+            sid: self.sid.clone(),
+            client: self.client.clone(),
+            server: self.server.clone(),
+            tls: self.tls,
+            direction,
+            message,
+        }).await?;
+        Ok(())
+    }
+
+    //调用拦截处理后转发一段数据，返回false时断开连接
+    async fn relay<O, T>(&mut self, writer: &mut O, parsed: Parsed, inject: &Option<Sender<Inject<T>>>) -> ProxyResult<bool>
+    where
+        O: AsyncWriteExt + Unpin,
+    {
+        let mut message = match parsed {
+            Parsed::Raw(bs) => return self.relay_raw(writer, bs).await,
+            //已经转发过了，只需要抓包
+            Parsed::Forwarded(message) => {
+                match self.direction {
+                    Direction::ClientToServer => self.remember(&message)?,
+                    //没有调用on_response，对应的请求也要取出来，后面的响应才能对上
+                    Direction::ServerToClient => if message.head.status().is_none_or(|status| status >= 200 || status == 101) {
+                        self.requests.lock()?.pop_front();
+                    }
+                }
+                self.upgrade(&message);
+                self.send(message).await?;
+                return Ok(true);
+            }
+            Parsed::Message(message) => message,
+        };
+        let conn = self.conn();
+        let (mut mapped, mut rewritten) = (None, false);
+        let mut fault = match self.direction {
+            Direction::ClientToServer => self.fault(&message.head, true)?,
+            Direction::ServerToClient => None,
+        };
+        let action = match self.direction {
+            //故障注入最先处理，断开连接或者返回错误时不再调用后面的
+            Direction::ClientToServer if fault.is_some() => match fault {
+                Some(FaultAction::Status(code)) => HandlerAction::Respond(FaultAction::respond(code)),
+                _ => HandlerAction::Drop,
+            },
+            //本地映射在拦截处理之前，映射的请求不再调用拦截处理；改写规则也在拦截处理之前，断点看到的是改写后的
+            Direction::ClientToServer => match self.map_local(&message)? {
+                Some((response, tag)) => {
+                    mapped = Some(tag);
+                    HandlerAction::Respond(response)
+                }
+                None => if let Some(response) = self.exchange_remote(&mut message).await? {
+                    HandlerAction::Respond(response)
+                } else {
+                    let head = message.head.clone();
+                    rewritten = self.ctx.rewrite.read()?.apply(RewriteStage::Request, &self.server, &head, &mut message);
+                    let mut action = HandlerAction::Continue;
+                    for handler in self.ctx.handlers.iter() {
+                        action = handler.on_request(&conn, &mut message).await;
+                        if !matches!(action, HandlerAction::Continue) { break; }
+                    }
+                    action
+                }
+            },
+            //1xx不是最终的响应，没有对应的请求
+            Direction::ServerToClient => match message.head.status() {
+                Some(status) if status < 200 && status != 101 => HandlerAction::Continue,
+                _ => {
+                    let request = self.requests.lock()?.pop_front();
+                    let mut action = HandlerAction::Continue;
+                    if let Some(request) = request {
+                        fault = self.fault(&request.head, false)?;
+                        rewritten = self.ctx.rewrite.read()?.apply(RewriteStage::Response, &self.server, &request.head, &mut message);
+                        for handler in self.ctx.handlers.iter() {
+                            action = handler.on_response(&conn, &request, &mut message).await;
+                            if !matches!(action, HandlerAction::Continue) { break; }
+                        }
+                    }
+                    action
+                }
+            }
+        };
+        match (action, self.direction.clone()) {
+            (HandlerAction::Continue, direction) => {
+                //响应方向的故障在改写和拦截处理之后，修改的是最终发给客户端的数据
+                let (bytes, close) = match &fault {
+                    Some(fault) => fault.apply(&mut message).await,
+                    None => (message.to_bytes(), false),
+                };
+                writer.write_all(&bytes).await?;
+                self.upgrade(&message);
+                self.remember(&message)?;
+                if rewritten && direction == Direction::ServerToClient { self.tag(REWRITTEN_TAG).await?; }
+                if fault.is_some() { self.tag(FAULT_TAG).await?; }
+                self.send(message).await?;
+                if rewritten && direction == Direction::ClientToServer { self.tag(REWRITTEN_TAG).await?; }
+                if close { return Ok(false); }
+            }
+            //请求不再发给服务器，响应方向不会收到这个请求的响应
+            (HandlerAction::Respond(response), Direction::ClientToServer) => {
+                self.parser.heads().lock()?.pop_back();
+                self.send(message).await?;
+                if let Some(tag) = mapped { self.tag(tag).await?; }
+                if fault.is_some() { self.tag(FAULT_TAG).await?; }
+                if rewritten { self.tag(REWRITTEN_TAG).await?; }
+                self.send_as(Direction::ServerToClient, response.clone()).await?;
+                if let Some(inject) = inject { inject.send(Inject::Response(response)).await?; }
+            }
+            (HandlerAction::Respond(response), Direction::ServerToClient) => {
+                writer.write_all(&response.to_bytes()).await?;
+                if rewritten { self.tag(REWRITTEN_TAG).await?; }
+                self.send(response).await?;
+            }
+            (HandlerAction::Drop, direction) => {
+                trace!("{}{}被拦截处理丢弃", direction, message.head.line);
+                if direction == Direction::ClientToServer { self.send(message).await?; }
+                if fault.is_some() { self.tag(FAULT_TAG).await?; }
+                //reset由调用的地方把客户端连接交给响应方向
+                self.reset = fault == Some(FaultAction::Reset);
+                if let Some(inject) = inject && !self.reset { inject.send(Inject::Close).await?; }
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    async fn relay_raw<O>(&mut self, writer: &mut O, bs: Vec<u8>) -> ProxyResult<bool>
+    where
+        O: AsyncWriteExt + Unpin,
+    {
+        let ws = match self.ws.as_mut() {
+            Some(ws) => ws,
+            None => {
+                writer.write_all(&bs).await?;
+                return Ok(true);
+            }
+        };
+        let frames = ws.feed(&bs);
+        //帧太大时不再解析，后面的数据原样转发
+        let rest = match ws.oversized() {
+            true => Some(ws.take_rest()),
+            false => None,
+        };
+        let conn = self.conn();
+        for mut frame in frames {
+            let mut keep = true;
+            for handler in self.ctx.handlers.iter() {
+                keep = handler.on_websocket_message(&conn, &self.direction, &mut frame).await;
+                if !keep { break; }
+            }
+            if keep { writer.write_all(&frame.to_bytes()).await?; }
+        }
+        if let Some(rest) = rest {
+            self.ws = None;
+            writer.write_all(&rest).await?;
+        }
+        Ok(true)
+    }
+
+    //转发的请求记下来，响应方向调用on_response时使用
+    fn remember(&self, message: &HttpMessage) -> ProxyResult<()> {
+        if self.direction == Direction::ClientToServer { self.requests.lock()?.push_back(message.clone()); }
+        Ok(())
+    }
+
+    //请求升级成websocket，或者服务器同意升级后，后面的数据按帧解析
+    fn upgrade(&mut self, message: &HttpMessage) {
+        let websocket = message.head.header("Upgrade").map(|v| v.eq_ignore_ascii_case("websocket")).unwrap_or(false);
+        let accepted = match self.direction {
+            Direction::ClientToServer => true,
+            Direction::ServerToClient => message.head.status() == Some(101),
+        };
+        if websocket && accepted { self.ws = Some(WsParser::new()); }
+    }
+}
+
+//本地映射的连接在需要服务器的时候才连接，server是host:port
+async fn connect_later(ctx: &ProxyContext, outbound: Option<TcpStream>, server: &str, timing: &mut ConnTiming) -> ProxyResult<TcpStream> {
+    match outbound {
+        Some(outbound) => Ok(outbound),
+        None => {
+            let (host, port) = split_host_port(server, 80)?;
+            ctx.connect_timed(&host, port, timing).await
         }
     }
 }
@@ -54,22 +468,39 @@ impl Clone for ProxyParam {
 //
 pub struct ProxyStream {
     //生成一个id以便区分流
-    inbound: TcpStream,
-    param: ProxyParam,
+    pub(crate) inbound: TcpStream,
+    pub(crate) param: ProxyParam,
+    pub(crate) ctx: Arc<ProxyContext>,
 }
 
 impl ProxyStream {
-    pub fn new(inbound: TcpStream, sender: Sender<(Direction, Response)>) -> ProxyStream {
+    pub fn new(inbound: TcpStream, ctx: Arc<ProxyContext>) -> ProxyStream {
+        let client = inbound.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+        let forward = ctx.forward();
         ProxyStream {
             inbound,
             param: ProxyParam {
                 sid: Uuid::new_v4().to_string(),
-                sender,
+                sender: ctx.sender.clone(),
                 //初始化一个缓冲区
                 buffer: Buffer::new(),
-                data: Request::new(),
+                parser: HttpParser::new(true, Arc::new(Mutex::new(VecDeque::new()))).forward(forward),
                 direction: Direction::ClientToServer,
+                client,
+                server: String::new(),
+                tls: false,
+                pending: vec![],
+                forward,
+                ctx: ctx.clone(),
+                listener: None,
+                throttle: Throttle::new(),
+                remap: None,
+                requests: Arc::new(Mutex::new(VecDeque::new())),
+                ws: None,
+                timing: ConnTiming { accept: Some(SystemTime::now()), ..ConnTiming::default() },
+                reset: false,
             },
+            ctx,
         }
     }
 
@@ -79,109 +510,364 @@ impl ProxyStream {
         O: AsyncWriteExt + Send + Unpin + 'static,
     {
         tokio::spawn(async move {
+            let pending = mem::take(&mut param.pending);
+            if !pending.is_empty() {
+                param.write_throttled(&mut writer, &pending).await?;
+                param.capture(&pending).await?;
+            }
             loop {
                 param.buffer.reset();
                 param.buffer.async_read(&mut reader).await?;
-                //及时把数据发送出去，减少延时
-                writer.write_all(param.buffer.filled()).await?;
                 if param.buffer.len() == 0 { break; } //读取长度为0时，此tcp连接已断开
-                if param.data.extend(&param.buffer)? {
-                    let response = mem::replace(&mut param.data, Response::new());
-                    param.sender.send((param.direction.clone(), response)).await?;
-                }
+                let data = param.buffer.filled().to_vec();
+                //及时把数据发送出去，减少延时
+                param.write_throttled(&mut writer, &data).await?;
+                param.capture(&data).await?;
+            }
+            //没有长度的响应到这里才算接收完整
+            if let Some(message) = param.parser.finish() { param.send(message).await?; }
+            Ok::<(), ProxyError>(())
+        })
+    }
 
+    //只转发不抓包，一个方向读完后关闭另一边的写，和copy_bidirectional一样
+    async fn pipe<I, O>(mut reader: ReadHalf<I>, mut writer: WriteHalf<O>, mut param: ProxyParam) -> JoinHandle<ProxyResult<()>>
+    where
+        I: AsyncReadExt + Send + Unpin + 'static,
+        O: AsyncWriteExt + Send + Unpin + 'static,
+    {
+        tokio::spawn(async move {
+            loop {
+                param.buffer.reset();
+                param.buffer.async_read(&mut reader).await?;
+                if param.buffer.len() == 0 { break; }
+                let data = param.buffer.filled().to_vec();
+                param.write_throttled(&mut writer, &data).await?;
             }
+            writer.shutdown().await?;
             Ok::<(), ProxyError>(())
         })
     }
 
-    async fn copy_io<I, O>(inbound: I, outbound: O, mut param: ProxyParam) -> ProxyResult<()>
+    //有拦截处理时使用，消息接收完整后调用拦截处理再转发，响应方向同时接收请求方向插入的响应
+    async fn relay<I, O>(mut reader: ReadHalf<I>, mut writer: WriteHalf<O>, mut param: ProxyParam, inject: Option<Sender<Inject<I>>>, mut injected: Option<mpsc::Receiver<Inject<O>>>) -> JoinHandle<ProxyResult<()>>
     where
-        I: AsyncReadExt + AsyncWriteExt + Send + Unpin + 'static,
-        O: AsyncReadExt + AsyncWriteExt + Send + Unpin + 'static,
+        I: AsyncReadExt + Send + Unpin + 'static,
+        O: AsyncWriteExt + TcpSocket + Send + Unpin + 'static,
     {
-        let res_func = |res: Result<ProxyResult<()>, JoinError>, direction: Direction| {
-            match res {
-                Ok(r) => match r {
-                    Ok(()) => {}
-                    Err(e) => error!("{}{}",direction,e.to_string())
+        tokio::spawn(async move {
+            let mut bs = vec![0; 16 * 1024];
+            let pending = mem::take(&mut param.pending);
+            param.parser.set_policy(param.policy());
+            let mut parsed = param.parser.parse(&pending);
+            loop {
+                for item in parsed {
+                    if !param.relay(&mut writer, item, &inject).await? {
+                        writer.shutdown().await?;
+                        if let Some(inject) = &inject && param.reset { inject.send(Inject::Reset(reader)).await?; }
+                        return Ok(());
+                    }
                 }
-                Err(e) => error!("{}{}",direction,e.to_string())
+                //有网络环境时每次只读一块，读到后按速率等待
+                let network = param.network()?;
+                let size = if network.is_some() { THROTTLE_CHUNK } else { bs.len() };
+                let len = tokio::select! {
+                    len = reader.read(&mut bs[..size]) => len?,
+                    item = async { injected.as_mut()?.recv().await }, if injected.is_some() => {
+                        match item {
+                            Some(Inject::Response(response)) => writer.write_all(&response.to_bytes()).await?,
+                            Some(Inject::Close) => {
+                                writer.shutdown().await?;
+                                return Ok(());
+                            }
+                            //不能shutdown，那样客户端会先收到FIN
+                            Some(Inject::Reset(client)) => {
+                                let client = client.unsplit(writer);
+                                //新版本的tokio把set_linger标记为弃用，因为关闭时会阻塞线程，时间为0时不会
+                                #[allow(deprecated)]
+                                client.tcp().set_linger(Some(Duration::ZERO))?;
+                                return Ok(());
+                            }
+                            //请求方向已经结束了
+                            None => injected = None,
+                        }
+                        parsed = vec![];
+                        continue;
+                    }
+                };
+                if len == 0 { break; } //读取长度为0时，此tcp连接已断开
+                if let Some(profile) = &network { param.throttle.wait(profile, &param.direction, len).await; }
+                parsed = param.parser.parse(&bs[..len]);
             }
-        };
+            for item in param.parser.close() { param.relay(&mut writer, item, &inject).await?; }
+            Ok::<(), ProxyError>(())
+        })
+    }
+
+    pub(crate) async fn copy_io<I, O>(mut inbound: I, outbound: O, mut param: ProxyParam) -> ProxyResult<()>
+    where
+        I: AsyncReadExt + AsyncWriteExt + TcpSocket + Send + Unpin + 'static,
+        O: AsyncReadExt + AsyncWriteExt + TcpSocket + Send + Unpin + 'static,
+    {
+        let conn = param.conn();
+        for handler in param.ctx.handlers.iter() {
+            match handler.on_connect(&conn).await {
+                HandlerAction::Continue => {}
+                HandlerAction::Respond(response) => {
+                    inbound.write_all(&response.to_bytes()).await?;
+                    inbound.shutdown().await?;
+                    return Ok(());
+                }
+                HandlerAction::Drop => return Ok(()),
+            }
+        }
+        param.sender.send(Capture::Timing { sid: param.sid.clone(), timing: mem::take(&mut param.timing) }).await?;
         let (inbound_reader, inbound_writer) = tokio::io::split(inbound);
         let (outbound_reader, outbound_writer) = tokio::io::split(outbound);
-        let rt1 = ProxyStream::copy(inbound_reader, outbound_writer, param.clone()).await;
-        param.direction = Direction::ServerToClient;
-        let rt2 = ProxyStream::copy(outbound_reader, inbound_writer, param).await;
+        let (sid, sender, handlers) = (param.sid.clone(), param.sender.clone(), param.ctx.handlers.clone());
+        let response_param = param.response();
+        let (rt1, rt2) = match !param.forward {
+            true => (ProxyStream::copy(inbound_reader, outbound_writer, param).await,
+                     ProxyStream::copy(outbound_reader, inbound_writer, response_param).await),
+            false => {
+                let (inject, injected) = mpsc::channel(16);
+                (ProxyStream::relay(inbound_reader, outbound_writer, param, Some(inject), None).await,
+                 ProxyStream::relay(outbound_reader, inbound_writer, response_param, None, Some(injected)).await)
+            }
+        };
         let (r1, r2) = tokio::join!(rt1,rt2);
-        res_func(r1, Direction::ClientToServer);
-        res_func(r2, Direction::ServerToClient);
+        for (res, direction) in [(r1, Direction::ClientToServer), (r2, Direction::ServerToClient)] {
+            let e = match res {
+                Ok(Ok(())) => continue,
+                Ok(Err(e)) => e.to_string(),
+                Err(e) => e.to_string(),
+            };
+            error!("{}{}", direction, e);
+            for handler in handlers.iter() { handler.on_error(&conn, &e).await; }
+        }
+        sender.send(Capture::Closed { sid }).await?;
         Ok(())
     }
 
+    //有映射规则的连接先不连接服务器，前面的请求都由本地文件响应时服务器可以不存在
+    //遇到需要服务器处理的请求时返回true，远程映射按这个请求决定连接哪里，这个请求和后面的数据放回pending，客户端断开时返回false
+    async fn answer_local<I>(inbound: &mut I, param: &mut ProxyParam) -> ProxyResult<bool>
+    where
+        I: AsyncReadExt + AsyncWriteExt + Unpin,
+    {
+        //单独的解析器，不影响后面转发时使用的请求方法队列
+        let mut parser = HttpParser::new(true, Arc::new(Mutex::new(VecDeque::new()))).forward(true);
+        let mut parsed = VecDeque::from(parser.parse(&mem::take(&mut param.pending)));
+        let mut bs = vec![0; 16 * 1024];
+        loop {
+            while let Some(item) = parsed.pop_front() {
+                let response = match &item {
+                    Parsed::Message(request) => param.map_local(request)?.map(|response| (request.clone(), response)),
+                    _ => None,
+                };
+                let (request, (response, tag)) = match response {
+                    Some(res) => res,
+                    None => {
+                        parsed.push_front(item);
+                        param.pending = parsed.into_iter().chain(parser.close()).flat_map(|item| match item {
+                            Parsed::Message(message) => message.to_bytes(),
+                            Parsed::Raw(bs) => bs,
+                            Parsed::Forwarded(_) => vec![],
+                        }).collect();
+                        return Ok(true);
+                    }
+                };
+                let close = request.head.header("Connection").map(|v| v.eq_ignore_ascii_case("close")).unwrap_or(false);
+                let bytes = response.to_bytes();
+                param.send(request).await?;
+                param.tag(tag).await?;
+                param.send_as(Direction::ServerToClient, response).await?;
+                inbound.write_all(&bytes).await?;
+                if close {
+                    inbound.shutdown().await?;
+                    param.sender.send(Capture::Closed { sid: param.sid.clone() }).await?;
+                    return Ok(false);
+                }
+            }
+            let len = inbound.read(&mut bs).await.unwrap_or(0);
+            if len == 0 {
+                param.sender.send(Capture::Closed { sid: param.sid.clone() }).await?;
+                return Ok(false);
+            }
+            parsed.extend(parser.parse(&bs[..len]));
+        }
+    }
+
     async fn handle_http(mut self) -> ProxyResult<()> {
         let http_prefix = b"http://";
         let start_pos = self.param.buffer.filled().windows(http_prefix.len()).position(|b| b == http_prefix).ok_or("获取HTTP地址失败")?;
         let end_pos = self.param.buffer.filled()[start_pos + http_prefix.len()..].iter().position(|b| *b == b'/').ok_or("获取HTTP地址失败")? + start_pos + http_prefix.len();
-        println!("{} {}", start_pos, end_pos);
         //获取真实服务器地址，端口为80的会自动省略
         let addr = String::from_utf8(self.param.buffer.filled()[start_pos + http_prefix.len()..end_pos].to_vec())?;
-        println!("{}", addr);
-        let host = addr.split(":").next().unwrap();
-        let port = match addr.contains(":") {
-            true => addr.split(":").last().ok_or("获取端口失败")?.parse::<u16>()?,
-            false => 80
-        };
+        let (host, port) = split_host_port(&addr, 80)?;
         // 这里我们就拿到了真实的服务器地址
-        println!("{}:{}", host, port);
+        trace!("HTTP代理目标地址：{}:{}", host, port);
+        self.param.server = format!("{}:{}", host, port);
+        //去掉请求行里的http://host，和后面的数据一起在转发的时候处理
+        let mut pending = self.param.buffer[..start_pos].to_vec();
+        pending.extend(&self.param.buffer.filled()[end_pos..]);
+        self.param.pending = pending;
+        let local = self.ctx.connect_later(&host)?;
+        if local && !ProxyStream::answer_local(&mut self.inbound, &mut self.param).await? { return Ok(()); }
+        if let Some(backend) = self.param.map_remote()? { return forward_backend(self.inbound, self.param, &self.ctx, &backend).await; }
         //与真实服务器建立连接，并把两个stream相互复制
-        let mut outbound = TcpStream::connect(format!("{}:{}", host, port)).await?;
-        // outbound.write(&buffer[..len]).await?;
-        if start_pos > 0 {
-            outbound.write_all(&self.param.buffer[..start_pos]).await?;
-        }
-        outbound.write(&self.param.buffer.filled()[end_pos..]).await?;
-        if self.param.data.extend(&self.param.buffer)? {
-            let request=mem::replace(&mut self.param.data, Request::new());
-            self.param.sender.send((Direction::ClientToServer, request)).await?;
-        }
+        let outbound = self.ctx.connect_timed(&host, port, &mut self.param.timing).await?;
         ProxyStream::copy_io(self.inbound, outbound, self.param).await
     }
 
     async fn handle_https(mut self) -> ProxyResult<()> {
+        let start = SystemTime::now();
         let info = String::from_utf8_lossy(self.param.buffer.filled()).to_string();
         let addr = regex_find("CONNECT (.*?) ", info.as_str())?;
         if addr.len() == 0 { return Err("获取HTTPS真实地址失败".into()); }
+        let (host, port) = split_host_port(&addr[0], 443)?;
+        trace!("已解析到CONNECT地址：{}", addr[0]);
+        self.param.server = format!("{}:{}", host, port);
+        //先和真实服务器建立连接，连接失败时告诉客户端；有映射规则的域名等到需要的时候再连接
+        let outbound = match self.ctx.connect_later(&host)? {
+            true => None,
+            false => match self.ctx.connect_timed(&host, port, &mut self.param.timing).await {
+                Ok(res) => Some(res),
+                Err(e) => {
+                    self.inbound.write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n").await?;
+                    return Err(e);
+                }
+            }
+        };
         self.inbound.write(b"HTTP/1.1 200 OK\r\n\r\n").await?;
         self.inbound.flush().await?;
-        //从这里开始，两个stream之间交互的就是真实的https数据了
-        let sni = addr[0].split(":").next().unwrap();
-        trace!("已解析到https地址：{}；SNI：{}",addr[0],sni);
-        let acceptor = gen_acceptor_for_sni(sni)?;
-        let inbound = acceptor.accept(self.inbound).await?;
-        let mut root_ca = RootCertStore::empty();
-        root_ca.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-        let client_config = ClientConfig::builder().with_root_certificates(root_ca).with_no_client_auth();
-        let outbound = TcpStream::connect(&addr[0]).await?;
-        let connector = TlsConnector::from(Arc::new(client_config));
-        let server_name = ServerName::DnsName(DnsName::try_from(sni.to_string())?);
+        self.param.timing.connect = Some(Phase::since(start));
+        //从这里开始，两个stream之间交互的就是真实的数据了
+        self.tunnel(host, outbound).await
+    }
+
+    //CONNECT隧道和socks隧道建立后，根据客户端发送的第一个数据包决定怎么处理，outbound为None时还没有连接服务器
+    pub(crate) async fn tunnel(mut self, host: String, outbound: Option<TcpStream>) -> ProxyResult<()> {
+        let protocol = self.peek_protocol(Some(TUNNEL_SNIFF_WAIT)).await?;
+        trace!("{}隧道内识别到协议：{}", host, protocol);
+        match protocol {
+            Protocol::Tls => {
+                //优先使用ClientHello里的SNI，socks客户端可能只给了IP地址
+                let sni = self.peek_sni().await?.unwrap_or(host);
+                let intercept = self.ctx.intercept.read()?.should_intercept(&sni);
+                if intercept { return self.mitm(sni, outbound).await; }
+                trace!("{}不在解密规则内，直接转发", sni);
+            }
+            //隧道里的明文HTTP，和普通HTTP代理一样抓包
+            Protocol::Http => {
+                if outbound.is_none() {
+                    if !ProxyStream::answer_local(&mut self.inbound, &mut self.param).await? { return Ok(()); }
+                    if let Some(backend) = self.param.map_remote()? { return forward_backend(self.inbound, self.param, &self.ctx, &backend).await; }
+                }
+                let outbound = connect_later(&self.ctx, outbound, &self.param.server, &mut self.param.timing).await?;
+                return ProxyStream::copy_io(self.inbound, outbound, self.param).await;
+            }
+            _ => {}
+        }
+        //严格回放模式不连接服务器，没有解密的数据也没法回放，直接断开
+        if outbound.is_none() && self.ctx.playback.read()?.strict {
+            return Err(format!("回放模式不连接服务器，{}的隧道没有解密，已断开", self.param.server).into());
+        }
+        //不解密或者不认识的协议，只转发不抓包，有网络环境时一样限速
+        let outbound = connect_later(&self.ctx, outbound, &self.param.server, &mut self.param.timing).await?;
+        let (inbound_reader, inbound_writer) = tokio::io::split(self.inbound);
+        let (outbound_reader, outbound_writer) = tokio::io::split(outbound);
+        let response_param = self.param.response();
+        let (r1, r2) = tokio::join!(ProxyStream::pipe(inbound_reader, outbound_writer, self.param).await,
+                                    ProxyStream::pipe(outbound_reader, inbound_writer, response_param).await);
+        r1??;
+        r2??;
+        Ok(())
+    }
+
+    //用自签的证书和客户端握手，再和真实服务器握手，这样中间的数据就是明文了
+    async fn mitm(mut self, sni: String, outbound: Option<TcpStream>) -> ProxyResult<()> {
+        trace!("正在解密{}的HTTPS流量", sni);
+        let acceptor = gen_acceptor_for_sni(sni.as_str(), &self.ctx.ca)?;
+        let mut inbound = acceptor.accept(self.inbound).await?;
+        self.param.tls = true;
+        if outbound.is_none() {
+            if !ProxyStream::answer_local(&mut inbound, &mut self.param).await? { return Ok(()); }
+            if let Some(backend) = self.param.map_remote()? { return forward_backend(inbound, self.param, &self.ctx, &backend).await; }
+        }
+        let outbound = connect_later(&self.ctx, outbound, &self.param.server, &mut self.param.timing).await?;
+        let connector = tls_connector();
+        let server_name = ServerName::try_from(sni)?;
+        let start = SystemTime::now();
         let outbound = connector.connect(server_name, outbound).await?;
+        self.param.timing.tls = Some(Phase::since(start));
         // //这里我们就实现了HTTPS解密，但是我们的根证书还没安装
         // //sudo cp sca.pem /etc/pki/ca-trust/source/anchors/
         // //sudo update-ca-trust
         ProxyStream::copy_io(inbound, outbound, self.param).await
     }
 
-    pub async fn start(mut self) -> ProxyResult<()> {
-        self.param.buffer.reset();
-        self.param.buffer.async_read(&mut self.inbound).await?;
+    //先偷看连接的前几个字节判断协议，数据不够时稍等一下再看，避免把数据读走影响后面的处理
+    pub(crate) async fn peek_protocol(&mut self, wait: Option<Duration>) -> ProxyResult<Protocol> {
+        let mut bs = [0; 16];
+        //先等待第一个字节，隧道里有些协议是服务器先发数据的，这种情况不能一直等下去
+        let first = self.inbound.peek(&mut bs[..1]);
+        let len = match wait {
+            Some(wait) => match timeout(wait, first).await {
+                Ok(res) => res?,
+                Err(_) => return Ok(Protocol::Unknown),
+            },
+            None => first.await?,
+        };
+        if len == 0 { return Err("连接已关闭".into()); }
+        for _ in 0..SNIFF_RETRY {
+            let len = self.inbound.peek(&mut bs).await?;
+            if let Some(protocol) = Protocol::sniff(&bs[..len]) { return Ok(protocol); }
+            sleep(Duration::from_millis(10)).await;
+        }
+        Ok(Protocol::Unknown)
+    }
 
-        if self.param.buffer.filled().starts_with(b"CONNECT") {
-            self.handle_https().await?;
-        } else {
-            self.handle_http().await?;
+    //ClientHello可能分几个TCP包到达，等它接收完整再解析SNI
+    pub(crate) async fn peek_sni(&mut self) -> ProxyResult<Option<String>> {
+        let mut bs = vec![0; 16 * 1024 + 5];
+        for _ in 0..SNIFF_RETRY {
+            let len = self.inbound.peek(&mut bs).await?;
+            if let Ok(sni) = parse_sni(&bs[..len]) { return Ok(sni); }
+            sleep(Duration::from_millis(10)).await;
         }
-        Ok(())
+        Ok(None)
     }
-}
\ No newline at end of file
+
+    //当前监听模式不支持或者无法识别的协议，按照客户端的协议返回一个明确的错误
+    async fn reject(mut self, protocol: Protocol, mode: ListenMode) -> ProxyResult<()> {
+        match protocol {
+            Protocol::Socks4 => self.inbound.write_all(&[0, 0x5B, 0, 0, 0, 0, 0, 0]).await?,
+            Protocol::Socks5 => self.inbound.write_all(&[5, 0xFF]).await?,
+            _ => {
+                let body = format!("不支持的代理协议：{}，当前监听模式：{}", protocol, mode);
+                self.inbound.write_all(&text_response(400, "Bad Request", body)).await?
+            }
+        }
+        self.inbound.shutdown().await?;
+        Err(format!("拒绝{}连接，当前监听模式：{}", protocol, mode).into())
+    }
+
+    pub async fn start(mut self, mode: ListenMode) -> ProxyResult<()> {
+        let protocol = self.peek_protocol(None).await?;
+        trace!("识别到协议：{}", protocol);
+        if !mode.accept(protocol) { return self.reject(protocol, mode).await; }
+        match protocol {
+            Protocol::Socks4 => self.handle_socks4().await,
+            Protocol::Socks5 => self.handle_socks5().await,
+            _ => {
+                self.param.buffer.reset();
+                self.param.buffer.async_read(&mut self.inbound).await?;
+                match protocol {
+                    Protocol::Connect => self.handle_https().await,
+                    _ => self.handle_http().await
+                }
+            }
+        }
+    }
+}
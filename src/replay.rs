@@ -0,0 +1,259 @@
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use log::{debug, error};
+use rustls_pki_types::ServerName;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::runtime::Handle;
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+use uuid::Uuid;
+use crate::context::ProxyContext;
+use crate::data::flow::{Capture, Flow};
+use crate::data::http::{HttpHead, HttpMessage, HttpParser};
+use crate::data::timing::{ConnTiming, Phase};
+use crate::error::ProxyResult;
+use crate::proxy::{tls_connector, Direction};
+use crate::reverse::Backend;
+
+//重放的请求在抓包记录里的标记
+pub const REPLAY_TAG: &str = "replay";
+//重放的请求在抓包记录里的客户端地址
+pub const REPLAY_CLIENT: &str = "replay";
+//等待响应的最长时间
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/*
+    重放抓包记录里的请求，或者在编辑窗口里修改后发送：
+    不经过客户端，按上级代理规则直接连接URL里的服务器，每次一个新连接，
+    结果作为新的记录保存，带有replay标记和原来的记录编号
+ */
+#[derive(Clone, Debug)]
+pub struct ReplayRequest {
+    pub method: String,
+    //完整的URL，决定连接哪里
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    //原样发送，Content-Encoding也保留
+    pub body: Vec<u8>,
+    //从哪条记录重放的
+    pub source: Option<u64>,
+}
+
+impl ReplayRequest {
+    pub fn from_flow(flow: &Flow) -> ReplayRequest {
+        ReplayRequest {
+            method: flow.method().to_string(),
+            url: flow.url(),
+            headers: flow.request.head.headers.clone(),
+            body: flow.request.body.clone(),
+            source: Some(flow.id),
+        }
+    }
+
+    //要连接的地址和发送的请求，Host按URL修改，只给代理的头去掉
+    pub fn to_message(&self) -> ProxyResult<(Backend, HttpMessage)> {
+        let url = self.url.trim();
+        let (scheme, rest) = url.split_once("://").ok_or(format!("重放的URL必须带协议：{}", url))?;
+        let pos = rest.find(['/', '?']).unwrap_or(rest.len());
+        let backend = Backend::parse(format!("{}://{}", scheme, &rest[..pos]))?;
+        let uri = match &rest[pos..] {
+            "" => "/".to_string(),
+            path if path.starts_with('?') => format!("/{}", path),
+            path => path.to_string(),
+        };
+        let method = self.method.trim().to_uppercase();
+        if method.is_empty() || method.contains(char::is_whitespace) { return Err(format!("请求方法错误：{}", self.method).into()); }
+        let mut head = HttpHead { line: format!("{} {} HTTP/1.1", method, uri), headers: vec![] };
+        for (key, value) in &self.headers {
+            let key = key.trim();
+            if key.is_empty() || key.to_lowercase().starts_with("proxy-") { continue; }
+            head.headers.push((key.to_string(), value.trim().to_string()));
+        }
+        head.set_header("Host", backend.host_header());
+        //每次重放都是新连接，发完就关闭
+        head.set_header("Connection", "close");
+        head.remove_header("Transfer-Encoding");
+        if !self.body.is_empty() || head.header("Content-Length").is_some() { head.set_header("Content-Length", self.body.len()); }
+        Ok((backend, HttpMessage::new(head, self.body.clone())))
+    }
+}
+
+//发送一次，连接失败等错误记录在抓包记录里
+pub async fn replay(ctx: Arc<ProxyContext>, request: ReplayRequest) -> ProxyResult<()> {
+    let (backend, message) = request.to_message()?;
+    let sid = Uuid::new_v4().to_string();
+    let server = format!("{}:{}", backend.host, backend.port);
+    debug!("重放{} {}", message.head.method(), request.url);
+    ctx.sender.send(Capture::Message {
+        sid: sid.clone(),
+        client: REPLAY_CLIENT.to_string(),
+        server: server.clone(),
+        tls: backend.tls,
+        direction: Direction::ClientToServer,
+        message: message.clone(),
+    }).await?;
+    ctx.sender.send(Capture::Replay { sid: sid.clone(), source: request.source }).await?;
+    let mut timing = ConnTiming::default();
+    let response = timeout(REPLAY_TIMEOUT, exchange(&ctx, &backend, &message, &mut timing)).await.unwrap_or_else(|_| Err("等待响应超时".into()));
+    ctx.sender.send(Capture::Timing { sid: sid.clone(), timing }).await?;
+    match response {
+        Ok(response) => ctx.sender.send(Capture::Message { sid, client: REPLAY_CLIENT.to_string(), server, tls: backend.tls, direction: Direction::ServerToClient, message: response }).await?,
+        Err(e) => ctx.sender.send(Capture::Failed { sid, error: e.to_string() }).await?,
+    }
+    Ok(())
+}
+
+//重放count次，最多concurrency个同时发送，全部完成后返回
+pub async fn replay_many(ctx: Arc<ProxyContext>, request: ReplayRequest, count: usize, concurrency: usize) -> ProxyResult<()> {
+    request.to_message()?;
+    let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut tasks = vec![];
+    for _ in 0..count {
+        let permit = semaphore.clone().acquire_owned().await?;
+        let (ctx, request) = (ctx.clone(), request.clone());
+        tasks.push(tokio::spawn(async move {
+            let res = replay(ctx, request).await;
+            drop(permit);
+            res
+        }));
+    }
+    for task in tasks { task.await??; }
+    Ok(())
+}
+
+pub(crate) async fn exchange(ctx: &ProxyContext, backend: &Backend, request: &HttpMessage, timing: &mut ConnTiming) -> ProxyResult<HttpMessage> {
+    let outbound = ctx.connect_timed(&backend.host, backend.port, timing).await?;
+    match backend.tls {
+        true => {
+            let server_name = ServerName::try_from(backend.host.trim_start_matches('[').trim_end_matches(']').to_string())?;
+            let start = SystemTime::now();
+            let outbound = tls_connector().connect(server_name, outbound).await?;
+            timing.tls = Some(Phase::since(start));
+            send_request(outbound, request).await
+        }
+        false => send_request(outbound, request).await,
+    }
+}
+
+async fn send_request<S>(mut stream: S, request: &HttpMessage) -> ProxyResult<HttpMessage>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(&request.to_bytes()).await?;
+    //HEAD请求的响应没有body，解析器需要知道请求方法
+    let heads = Arc::new(Mutex::new(VecDeque::from([request.head.clone()])));
+    let mut parser = HttpParser::new(false, heads);
+    let mut bs = vec![0; 16 * 1024];
+    loop {
+        let len = match stream.read(&mut bs).await {
+            Ok(len) => len,
+            //有的服务器关闭TLS连接时不发close_notify
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => 0,
+            Err(e) => return Err(e.into()),
+        };
+        //没有长度的响应到断开时才算完整
+        if len == 0 { return Ok(parser.finish().ok_or("服务器断开了连接，没有收到完整的响应")?); }
+        //100 Continue不是最终的响应
+        let response = parser.feed(&bs[..len]).into_iter().find(|r| !matches!(r.head.status(), Some(status) if status < 200));
+        if let Some(response) = response { return Ok(response); }
+    }
+}
+
+//界面线程里使用，在代理的运行时里后台发送
+#[derive(Clone)]
+pub struct Replayer {
+    ctx: Arc<ProxyContext>,
+    runtime: Handle,
+}
+
+impl Replayer {
+    pub fn new(ctx: Arc<ProxyContext>, runtime: Handle) -> Replayer {
+        Replayer { ctx, runtime }
+    }
+
+    //URL、请求方法格式错误时直接返回错误，发送的结果在抓包记录里
+    pub fn send(&self, request: ReplayRequest, count: usize, concurrency: usize) -> ProxyResult<()> {
+        request.to_message()?;
+        let ctx = self.ctx.clone();
+        self.runtime.spawn(async move {
+            replay_many(ctx, request, count, concurrency).await.unwrap_or_else(|e| error!("重放失败：{}", e.to_string()));
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_replay {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_stream::StreamExt;
+    use crate::data::flow::test_flow;
+    use crate::replay::{replay, replay_many, ReplayRequest};
+    use crate::server::{read_until, ListenMode, ProxyServer};
+
+    #[test]
+    fn test_replay() {
+        let flow = test_flow(7, b"POST /api/login?x=1 HTTP/1.1\r\nHost: www.example.com\r\nProxy-Connection: keep-alive\r\nTransfer-Encoding: chunked\r\n\r\n3\r\na=1\r\n0\r\n\r\n", b"");
+        let request = ReplayRequest::from_flow(&flow);
+        assert_eq!(request.source, Some(7));
+        assert_eq!(request.url, "https://www.example.com/api/login?x=1");
+        let (backend, message) = request.to_message().unwrap();
+        assert_eq!((backend.tls, backend.host.as_str(), backend.port), (true, "www.example.com", 443));
+        assert_eq!(message.head.line, "POST /api/login?x=1 HTTP/1.1");
+        assert_eq!(message.head.header("Content-Length"), Some("3"));
+        assert!(message.head.header("Proxy-Connection").is_none());
+        assert!(message.head.header("Transfer-Encoding").is_none());
+        //编辑后的URL决定连接哪里和Host
+        let mut edited = request.clone();
+        edited.url = "http://127.0.0.1:8080?debug=1".to_string();
+        edited.method = "put".to_string();
+        let (backend, message) = edited.to_message().unwrap();
+        assert_eq!((backend.tls, backend.port), (false, 8080));
+        assert_eq!(message.head.line, "PUT /?debug=1 HTTP/1.1");
+        assert_eq!(message.head.header("Host"), Some("127.0.0.1:8080"));
+        edited.url = "127.0.0.1:8080/".to_string();
+        assert!(edited.to_message().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_replay_proxy() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                let mut bs = vec![0; 1024];
+                let len = stream.read(&mut bs).await.unwrap();
+                let body = String::from_utf8_lossy(&bs[..len]).lines().next().unwrap_or("").to_string();
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        let handle = ProxyServer::builder()
+            .listen("127.0.0.1:0", ListenMode::Http)
+            .mitm(false)
+            .start().await.unwrap();
+        let mut flows = handle.flows();
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+        let request = format!("GET http://{}/hi HTTP/1.1\r\nHost: {}\r\n\r\n", backend_addr, backend_addr);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        read_until(&mut stream, b"HTTP/1.1").await;
+        let flow = flows.next().await.unwrap();
+        //重放两次，结果是新的记录，带有原来的编号
+        replay_many(handle.context(), ReplayRequest::from_flow(&flow), 2, 2).await.unwrap();
+        for _ in 0..2 {
+            let replayed = flows.next().await.unwrap();
+            assert_eq!(replayed.replay_of, Some(flow.id));
+            assert_eq!(replayed.tags, vec!["replay"]);
+            assert_eq!(replayed.response.as_ref().unwrap().body, b"GET /hi HTTP/1.1");
+        }
+        //连接失败的错误记录在新的记录里
+        let mut request = ReplayRequest::from_flow(&flow);
+        request.url = "http://127.0.0.1:1/".to_string();
+        replay(handle.context(), request).await.unwrap();
+        assert!(flows.next().await.unwrap().error.is_some());
+        handle.shutdown();
+    }
+}
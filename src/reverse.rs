@@ -0,0 +1,393 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::SystemTime;
+use log::{debug, trace};
+use rustls_pki_types::ServerName;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use crate::context::ProxyContext;
+use crate::data::http::{text_response, HttpHead, HttpParser, Parsed, MAX_HEAD};
+use crate::data::timing::Phase;
+use crate::error::ProxyResult;
+use crate::cert::gen_acceptor_for_sni;
+use crate::proxy::{tls_connector, ProxyParam, ProxyStream, TcpSocket};
+use crate::rule::HostPattern;
+use crate::sniff::Protocol;
+use crate::upstream::split_host_port;
+
+//反向代理的后端地址，如：http://127.0.0.1:3000、https://api.internal:8443/v1
+#[derive(Clone, Debug)]
+pub struct Backend {
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl Backend {
+    pub fn parse(url: impl AsRef<str>) -> ProxyResult<Backend> {
+        let url = url.as_ref().trim();
+        let (scheme, rest) = url.split_once("://").ok_or(format!("后端地址格式错误：{}", url))?;
+        let tls = match scheme.to_lowercase().as_str() {
+            "http" => false,
+            "https" => true,
+            _ => return Err(format!("不支持的后端协议：{}", scheme).into())
+        };
+        let (addr, path) = match rest.find('/') {
+            Some(pos) => (&rest[..pos], rest[pos..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        let (host, port) = split_host_port(addr, if tls { 443 } else { 80 })?;
+        Ok(Backend { tls, host, port, path: path.to_string() })
+    }
+
+    //默认端口时Host头不带端口
+    pub fn host_header(&self) -> String {
+        match (self.tls, self.port) {
+            (false, 80) | (true, 443) => self.host.clone(),
+            _ => format!("{}:{}", self.host, self.port),
+        }
+    }
+}
+
+//一条反向代理规则：域名+路径前缀 -> 后端地址
+#[derive(Clone, Debug)]
+pub struct ReverseRoute {
+    pub host: HostPattern,
+    pub path_prefix: String,
+    pub backend: Backend,
+    //转发时去掉路径前缀，如：/api/users -> 后端的/users
+    pub strip_prefix: bool,
+    //把Host头改成后端的地址，有些开发服务器会校验Host
+    pub rewrite_host: bool,
+}
+
+impl ReverseRoute {
+    //按路径段匹配前缀，/api匹配/api、/api/users、/api?id=1，不匹配/apiary
+    pub fn matches_path(&self, uri: &str) -> bool {
+        match uri.strip_prefix(self.path_prefix.trim_end_matches('/')) {
+            Some(rest) => rest.is_empty() || rest.starts_with(['/', '?', '#']),
+            None => false,
+        }
+    }
+
+    //把客户端请求的路径转换成后端的路径
+    pub fn rewrite_uri(&self, uri: &str) -> String {
+        let rest = match self.strip_prefix {
+            true => uri.strip_prefix(self.path_prefix.trim_end_matches('/')).unwrap_or(uri),
+            false => uri,
+        };
+        match rest.starts_with('/') {
+            true => format!("{}{}", self.backend.path, rest),
+            false => format!("{}/{}", self.backend.path, rest),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ReverseRoutes {
+    pub routes: Vec<ReverseRoute>,
+}
+
+impl ReverseRoutes {
+    pub fn new() -> ReverseRoutes {
+        ReverseRoutes::default()
+    }
+
+    pub fn has_host(&self, host: impl AsRef<str>) -> bool {
+        self.routes.iter().any(|r| r.host.matches(host.as_ref()))
+    }
+
+    //域名匹配的规则里，选择路径前缀最长的
+    pub fn select(&self, host: impl AsRef<str>, uri: &str) -> Option<&ReverseRoute> {
+        self.routes.iter()
+            .filter(|r| r.host.matches(host.as_ref()) && r.matches_path(uri))
+            .max_by_key(|r| r.path_prefix.len())
+    }
+}
+
+impl ProxyStream {
+    //反向代理，客户端直接访问这个端口，HTTPS按SNI签发证书后解密，然后按Host和路径转发给后端
+    pub async fn start_reverse(mut self) -> ProxyResult<()> {
+        let protocol = self.peek_protocol(None).await?;
+        trace!("反向代理识别到协议：{}", protocol);
+        match protocol {
+            Protocol::Tls => {
+                let sni = self.peek_sni().await?.ok_or("反向代理的HTTPS请求没有SNI")?;
+                if !self.ctx.reverse.read()?.has_host(&sni) {
+                    return Err(format!("{}没有配置反向代理规则", sni).into());
+                }
+                let acceptor = gen_acceptor_for_sni(sni.as_str(), &self.ctx.ca)?;
+                let inbound = acceptor.accept(self.inbound).await?;
+                self.param.tls = true;
+                reverse_forward(inbound, self.param, self.ctx).await
+            }
+            Protocol::Http => reverse_forward(self.inbound, self.param, self.ctx).await,
+            _ => {
+                self.inbound.write_all(&text_response(400, "Bad Request", format!("反向代理不支持的协议：{}", protocol))).await?;
+                Err(format!("反向代理不支持的协议：{}", protocol).into())
+            }
+        }
+    }
+}
+
+async fn reverse_forward<I>(mut inbound: I, mut param: ProxyParam, ctx: Arc<ProxyContext>) -> ProxyResult<()>
+where
+    I: AsyncRead + AsyncWrite + TcpSocket + Send + Unpin + 'static,
+{
+    //请求头可能分几次才能读完
+    let mut received = vec![];
+    let (mut head, head_len) = loop {
+        if let Some(res) = HttpHead::parse(&received) { break res; }
+        if received.len() > MAX_HEAD {
+            inbound.write_all(&text_response(431, "Request Header Fields Too Large", "请求头过长")).await?;
+            return Err("反向代理的请求头过长".into());
+        }
+        param.buffer.reset();
+        param.buffer.async_read(&mut inbound).await?;
+        if param.buffer.len() == 0 { return Err("反向代理读取请求头失败，连接已断开".into()); }
+        received.extend(param.buffer.filled());
+    };
+    let host = head.header("Host").unwrap_or("").to_string();
+    let host = split_host_port(&host, 80).map(|(host, _)| host).unwrap_or_default();
+    let uri = head.uri().to_string();
+    let route = ctx.reverse.read()?.select(&host, &uri).cloned();
+    let route = match route {
+        Some(route) => route,
+        None => {
+            inbound.write_all(&text_response(502, "Bad Gateway", format!("{}{}没有匹配的反向代理规则", host, uri))).await?;
+            return Err(format!("{}{}没有匹配的反向代理规则", host, uri).into());
+        }
+    };
+    let backend = &route.backend;
+    head.set_uri(&route.rewrite_uri(&uri));
+    if route.rewrite_host { head.set_header("Host", backend.host_header()); }
+    //每个连接只转发一个请求，保证同一个连接上的后续请求也能按规则重新选择后端，升级协议的请求要保留Connection: Upgrade
+    if head.header("Upgrade").is_none() { head.set_header("Connection", "close"); }
+    debug!("反向代理：{}{} -> {}:{}{}", host, uri, backend.host, backend.port, head.uri());
+    //改写后的请求在转发的时候处理，抓包记录和拦截处理看到的都是发给后端的请求
+    let (inbound, first) = FirstRequest::new(inbound, &received);
+    let mut data = head.to_bytes();
+    data.extend(&first[head_len..]);
+    param.pending = data;
+    forward_backend(inbound, param, &ctx, backend).await
+}
+
+/*
+   反向代理时包装客户端的连接，第一个请求读完后就当作客户端已经关闭，不再读取后面的数据
+   客户端在同一个连接上接着发送(或者管道化)的请求不会被转发到第一个请求选择的后端，
+   收到Connection: close的响应后客户端会用新的连接重新发送，再按规则选择后端
+   升级协议(如websocket)的请求后面的数据原样转发
+ */
+struct FirstRequest<I> {
+    inner: I,
+    parser: HttpParser,
+    //已经读取还没有返回的数据
+    pending: Vec<u8>,
+    done: bool,
+}
+
+impl<I> FirstRequest<I> {
+    //received是读取请求头时已经读到的数据，返回其中属于第一个请求的部分
+    fn new(inner: I, received: &[u8]) -> (FirstRequest<I>, Vec<u8>) {
+        //所有消息都边接收边转发，原始数据按顺序返回
+        let mut parser = HttpParser::new(true, Default::default()).forward(true);
+        parser.set_policy(Box::new(|_| false));
+        let mut first = FirstRequest { inner, parser, pending: vec![], done: false };
+        let data = first.take(received);
+        (first, data)
+    }
+
+    //返回这次读到的数据里要转发的部分
+    fn take(&mut self, bs: &[u8]) -> Vec<u8> {
+        let mut data = vec![];
+        for item in self.parser.parse(bs) {
+            match item {
+                Parsed::Raw(raw) => data.extend(raw),
+                Parsed::Forwarded(message) | Parsed::Message(message) => {
+                    if message.head.header("Upgrade").is_none() {
+                        self.done = true;
+                        break;
+                    }
+                }
+            }
+        }
+        data
+    }
+}
+
+impl<I: AsyncRead + Unpin> AsyncRead for FirstRequest<I> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        //还没有读到完整的头部时要接着读，不能返回长度为0的数据
+        while this.pending.is_empty() && !this.done {
+            let mut bs = [0; 16 * 1024];
+            let mut read = ReadBuf::new(&mut bs);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                //客户端关闭了，没有接收完整的数据也转发出去
+                this.pending = this.parser.close().into_iter().flat_map(|item| match item {
+                    Parsed::Raw(raw) => raw,
+                    _ => vec![],
+                }).collect();
+                this.done = true;
+                break;
+            }
+            let filled = read.filled().to_vec();
+            this.pending = this.take(&filled);
+        }
+        let len = this.pending.len().min(buf.remaining());
+        buf.put_slice(&this.pending[..len]);
+        this.pending.drain(..len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for FirstRequest<I> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<I: TcpSocket> TcpSocket for FirstRequest<I> {
+    fn tcp(&self) -> &TcpStream {
+        self.inner.tcp()
+    }
+}
+
+//连接后端地址后开始转发，后端是https时再和后端握手，反向代理和远程映射使用
+pub(crate) async fn forward_backend<I>(inbound: I, mut param: ProxyParam, ctx: &ProxyContext, backend: &Backend) -> ProxyResult<()>
+where
+    I: AsyncRead + AsyncWrite + TcpSocket + Send + Unpin + 'static,
+{
+    param.server = format!("{}:{}", backend.host, backend.port);
+    let outbound = ctx.connect_timed(&backend.host, backend.port, &mut param.timing).await?;
+    match backend.tls {
+        true => {
+            let connector = tls_connector();
+            let server_name = ServerName::try_from(backend.host.trim_start_matches('[').trim_end_matches(']').to_string())?;
+            let start = SystemTime::now();
+            let outbound = connector.connect(server_name, outbound).await?;
+            param.timing.tls = Some(Phase::since(start));
+            ProxyStream::copy_io(inbound, outbound, param).await
+        }
+        false => ProxyStream::copy_io(inbound, outbound, param).await,
+    }
+}
+
+#[cfg(test)]
+mod test_reverse {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use crate::reverse::{Backend, ReverseRoute, ReverseRoutes};
+    use crate::rule::HostPattern;
+    use crate::server::{ListenMode, ProxyServer};
+
+    fn route(host: &str, prefix: &str, backend: &str, strip_prefix: bool) -> ReverseRoute {
+        ReverseRoute { host: HostPattern::new(host), path_prefix: prefix.to_string(), backend: Backend::parse(backend).unwrap(), strip_prefix, rewrite_host: true }
+    }
+
+    #[test]
+    fn test_routes() {
+        let routes = ReverseRoutes { routes: vec![
+            route("a.com", "/", "http://127.0.0.1:3000", false),
+            route("a.com", "/api", "http://127.0.0.1:8080/v1", true),
+            route("*.b.com", "/static/", "https://cdn.internal", false),
+        ] };
+        let select = |host, uri| routes.select(host, uri).map(|r| r.backend.port);
+        assert_eq!(select("a.com", "/api/users"), Some(8080));
+        assert_eq!(select("a.com", "/api?id=1"), Some(8080));
+        assert_eq!(select("a.com", "/api"), Some(8080));
+        //前缀要在路径段的边界上
+        assert_eq!(select("a.com", "/apiary"), Some(3000));
+        assert_eq!(select("x.b.com", "/static/app.js"), Some(443));
+        assert_eq!(select("x.b.com", "/static"), Some(443));
+        assert_eq!(select("x.b.com", "/staticfiles"), None);
+        assert_eq!(select("c.com", "/"), None);
+        let api = &routes.routes[1];
+        assert_eq!(api.rewrite_uri("/api/users?id=1"), "/v1/users?id=1");
+        assert_eq!(api.rewrite_uri("/api"), "/v1/");
+        assert_eq!(api.rewrite_uri("/api?id=1"), "/v1/?id=1");
+        assert_eq!(routes.routes[0].rewrite_uri("/a/b"), "/a/b");
+        assert_eq!(routes.routes[2].backend.host_header(), "cdn.internal");
+    }
+
+    //请求头分几次发送也能正常转发
+    #[tokio::test]
+    async fn test_split_head() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut request = vec![];
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut bs = [0; 1024];
+                let len = stream.read(&mut bs).await.unwrap();
+                request.extend_from_slice(&bs[..len]);
+            }
+            let line = String::from_utf8_lossy(&request).lines().next().unwrap().to_string();
+            let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", line.len(), line);
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        let handle = ProxyServer::builder().listen("127.0.0.1:0", ListenMode::Reverse).mitm(false).start().await.unwrap();
+        handle.context().reverse.write().unwrap().routes.push(route("a.com", "/api", &format!("http://{}/v1", backend_addr), true));
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+        stream.write_all(b"GET /api/users HTTP/1.1\r\n").await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        stream.write_all(b"Host: a.com\r\n\r\n").await.unwrap();
+        let mut response = vec![];
+        while !response.ends_with(b"GET /v1/users HTTP/1.1") {
+            let mut bs = [0; 1024];
+            let len = stream.read(&mut bs).await.unwrap();
+            assert!(len > 0);
+            response.extend_from_slice(&bs[..len]);
+        }
+        handle.shutdown();
+    }
+
+    //同一个连接上的第二个请求不会发给第一个请求的后端
+    #[tokio::test]
+    async fn test_first_request() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let backend = tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut request = vec![];
+            while !request.ends_with(b"hello") {
+                let mut bs = [0; 1024];
+                let len = stream.read(&mut bs).await.unwrap();
+                assert!(len > 0);
+                request.extend_from_slice(&bs[..len]);
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok").await.unwrap();
+            stream.shutdown().await.unwrap();
+            //代理关闭连接之前收到的其他数据
+            let mut rest = vec![];
+            stream.read_to_end(&mut rest).await.unwrap();
+            (request, rest)
+        });
+        let handle = ProxyServer::builder().listen("127.0.0.1:0", ListenMode::Reverse).mitm(false).start().await.unwrap();
+        handle.context().reverse.write().unwrap().routes.push(route("a.com", "/api", &format!("http://{}", backend_addr), true));
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+        stream.write_all(b"POST /api/a HTTP/1.1\r\nHost: a.com\r\nContent-Length: 5\r\n\r\nhelloGET /web HTTP/1.1\r\nHost: a.com\r\n\r\n").await.unwrap();
+        //连接没有关闭时不要一直等待
+        let mut response = vec![];
+        tokio::time::timeout(std::time::Duration::from_secs(5), stream.read_to_end(&mut response)).await.unwrap().unwrap();
+        assert!(response.ends_with(b"\r\n\r\nok"));
+        let (request, rest) = backend.await.unwrap();
+        let request = String::from_utf8(request).unwrap();
+        assert!(request.starts_with("POST /a HTTP/1.1\r\n") && request.contains("Connection: close\r\n"));
+        assert!(rest.is_empty());
+        handle.shutdown();
+    }
+}
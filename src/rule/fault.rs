@@ -0,0 +1,232 @@
+use std::time::Duration;
+use tokio::time::sleep;
+use crate::config::FaultConfig;
+use crate::data::http::{reason_phrase, HttpHead, HttpMessage};
+use crate::error::ProxyResult;
+use crate::rule::url_matches;
+use crate::throttle::random;
+
+//注入了故障的请求在抓包记录里的标记
+pub const FAULT_TAG: &str = "fault";
+
+/*
+    故障类型，前两种在请求方向生效，请求不再发给服务器，后面的在响应方向生效：
+    reset     直接断开客户端连接，不返回响应
+    status    返回配置的状态码(默认503)
+    delay     响应的第一个字节延迟delay_ms毫秒
+    close     body发送一半后断开连接，Content-Length还是原来的
+    truncate  body只保留前bytes个字节，同时修改Content-Length，客户端收到的是完整但是不对的响应
+    corrupt   随机修改body里的bytes个字节
+ */
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FaultAction {
+    Reset,
+    Status(u16),
+    Delay(Duration),
+    Close,
+    Truncate(usize),
+    Corrupt(usize),
+}
+
+impl FaultAction {
+    pub fn parse(config: &FaultConfig) -> ProxyResult<FaultAction> {
+        Ok(match config.action.trim().to_lowercase().as_str() {
+            "reset" => FaultAction::Reset,
+            "status" => FaultAction::Status(config.status),
+            "delay" => FaultAction::Delay(Duration::from_millis(config.delay_ms)),
+            "close" => FaultAction::Close,
+            "truncate" => FaultAction::Truncate(config.bytes),
+            "corrupt" => FaultAction::Corrupt(config.bytes),
+            _ => return Err(format!("故障类型{}错误，可选：reset、status、delay、close、truncate、corrupt", config.action).into()),
+        })
+    }
+
+    //请求方向生效的故障，请求不再发给服务器
+    pub fn on_request(&self) -> bool {
+        matches!(self, FaultAction::Reset | FaultAction::Status(_))
+    }
+
+    //status返回的响应
+    pub fn respond(code: u16) -> HttpMessage {
+        let mut response = HttpMessage::response(code, reason_phrase(code), format!("代理注入的故障：{}", code));
+        response.head.set_header("Content-Type", "text/plain; charset=utf-8");
+        response
+    }
+
+    //响应方向的故障，修改响应后返回要发给客户端的数据，以及发送后是否断开连接
+    //message改成客户端实际收到的内容，抓包记录里看到的就是注入故障后的
+    pub async fn apply(&self, message: &mut HttpMessage) -> (Vec<u8>, bool) {
+        match self {
+            FaultAction::Delay(delay) => sleep(*delay).await,
+            FaultAction::Close => {
+                let bytes = message.to_bytes();
+                let rest = message.body.len() - message.body.len() / 2;
+                message.body.truncate(message.body.len() / 2);
+                message.body_size = message.body.len();
+                return (bytes[..bytes.len() - rest].to_vec(), true);
+            }
+            FaultAction::Truncate(len) => {
+                message.body.truncate(*len);
+                message.body_size = message.body.len();
+                message.head.remove_header("Transfer-Encoding");
+                message.head.set_header("Content-Length", message.body.len());
+            }
+            FaultAction::Corrupt(count) if !message.body.is_empty() => {
+                for _ in 0..*count {
+                    let pos = (random() * message.body.len() as f64) as usize % message.body.len();
+                    message.body[pos] ^= 0xff;
+                }
+            }
+            _ => {}
+        }
+        (message.to_bytes(), false)
+    }
+}
+
+//url按通配符匹配完整的URL，和本地映射一样
+#[derive(Clone, Debug)]
+pub struct FaultRule {
+    pub url: String,
+    //为空时不限制
+    pub method: String,
+    pub action: FaultAction,
+    //触发的概率(百分比)
+    pub probability: f64,
+}
+
+impl FaultRule {
+    pub fn new(config: &FaultConfig) -> ProxyResult<FaultRule> {
+        match config.url.split_once("://") {
+            Some((_, rest)) if !rest.is_empty() => {}
+            _ => return Err(format!("故障注入的URL必须带协议，如：https://api.example.com/*：{}", config.url).into()),
+        }
+        if !(0.0..=100.0).contains(&config.probability) {
+            return Err(format!("故障注入{}的概率{}必须在0到100之间", config.url, config.probability).into());
+        }
+        let action = FaultAction::parse(config)?;
+        if let FaultAction::Status(code) = action && !(400..=599).contains(&code) {
+            return Err(format!("故障注入{}的状态码{}必须是4xx或者5xx", config.url, code).into());
+        }
+        Ok(FaultRule { url: config.url.clone(), method: config.method.clone(), action, probability: config.probability })
+    }
+
+    pub fn matches(&self, method: &str, url: &str) -> bool {
+        (self.method.is_empty() || self.method.eq_ignore_ascii_case(method)) && url_matches(&self.url, url)
+    }
+}
+
+//按顺序匹配，每条规则按自己的概率决定是否触发，第一个触发的生效
+#[derive(Default)]
+pub struct FaultRules {
+    pub rules: Vec<FaultRule>,
+}
+
+impl FaultRules {
+    pub fn new() -> FaultRules {
+        FaultRules::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    //这个请求有没有可能注入故障，决定消息是否要接收完整再转发
+    pub fn matches(&self, request: &HttpHead, url: &str, on_request: bool) -> bool {
+        self.rules.iter().any(|rule| rule.action.on_request() == on_request && rule.probability > 0.0 && rule.matches(request.method(), url))
+    }
+
+    //on_request为true时只检查请求方向生效的故障，否则只检查响应方向的
+    pub fn roll(&self, request: &HttpHead, url: &str, on_request: bool) -> Option<FaultAction> {
+        self.rules.iter()
+            .filter(|rule| rule.action.on_request() == on_request && rule.matches(request.method(), url))
+            .find(|rule| random() * 100.0 < rule.probability)
+            .map(|rule| rule.action.clone())
+    }
+}
+
+#[cfg(test)]
+mod test_fault {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use crate::config::FaultConfig;
+    use crate::data::http::{HttpMessage, HttpParser};
+    use crate::rule::fault::{FaultAction, FaultRule, FaultRules};
+    use crate::server::{ListenMode, ProxyServer};
+
+    #[tokio::test]
+    async fn test_fault() {
+        let config = |url: &str, action: &str, probability: f64| FaultConfig { url: url.to_string(), action: action.to_string(), probability, ..FaultConfig::default() };
+        let mut rules = FaultRules::new();
+        rules.rules.push(FaultRule::new(&config("https://api.example.com/never", "reset", 0.0)).unwrap());
+        rules.rules.push(FaultRule::new(&config("https://api.example.com/*", "status", 100.0)).unwrap());
+        rules.rules.push(FaultRule::new(&FaultConfig { bytes: 4, ..config("https://api.example.com/*", "truncate", 100.0) }).unwrap());
+        let request = HttpParser::new(true, Arc::new(Mutex::new(VecDeque::new()))).feed(b"GET /never HTTP/1.1\r\nHost: api.example.com\r\n\r\n").remove(0);
+        //概率为0的不会触发，按顺序找到下一个
+        assert_eq!(rules.roll(&request.head, "https://api.example.com/never", true), Some(FaultAction::Status(503)));
+        assert_eq!(rules.roll(&request.head, "https://api.example.com/never", false), Some(FaultAction::Truncate(4)));
+        assert_eq!(rules.roll(&request.head, "https://www.example.com/", true), None);
+        assert_eq!(FaultAction::respond(503).head.status(), Some(503));
+        //响应方向的故障
+        let response = HttpMessage::response(200, "OK", "0123456789");
+        let (bytes, close) = FaultAction::Truncate(4).apply(&mut response.clone()).await;
+        assert!(!close);
+        assert!(bytes.ends_with(b"Content-Length: 4\r\n\r\n0123"));
+        let mut closed = response.clone();
+        let (bytes, close) = FaultAction::Close.apply(&mut closed).await;
+        assert!(close);
+        assert!(bytes.ends_with(b"Content-Length: 10\r\n\r\n01234"));
+        assert_eq!(closed.body, b"01234");
+        let mut corrupted = response.clone();
+        FaultAction::Corrupt(1).apply(&mut corrupted).await;
+        assert_eq!(corrupted.body.iter().zip(&response.body).filter(|(a, b)| a != b).count(), 1);
+        //命令行的简写
+        let delay = FaultConfig::parse("https://api.example.com/*=delay:100@30").unwrap();
+        assert_eq!((FaultAction::parse(&delay).unwrap(), delay.probability), (FaultAction::Delay(Duration::from_millis(100)), 30.0));
+        assert_eq!(FaultAction::parse(&FaultConfig::parse("https://api.example.com/a?b=1=502").unwrap()).unwrap(), FaultAction::Status(502));
+        assert!(FaultConfig::parse("https://api.example.com/*=delay:abc").is_err());
+        assert!(FaultAction::parse(&config("https://api.example.com/*", "boom", 100.0)).is_err());
+        assert!(FaultRule::new(&config("https://api.example.com/*", "status", 120.0)).is_err());
+        assert!(FaultRule::new(&FaultConfig { status: 200, ..config("https://api.example.com/*", "status", 100.0) }).is_err());
+    }
+
+    //reset让客户端收到RST，close是服务器正常断开，客户端读到的是EOF
+    #[tokio::test]
+    async fn test_reset() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut bs = vec![0; 1024];
+                    let _ = stream.read(&mut bs).await;
+                    let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789").await;
+                    let _ = stream.read(&mut bs).await;
+                });
+            }
+        });
+        let config = |path: &str, action: &str| FaultConfig { url: format!("http://{}/{}", backend_addr, path), action: action.to_string(), probability: 100.0, ..FaultConfig::default() };
+        let handle = ProxyServer::builder()
+            .listen("127.0.0.1:0", ListenMode::Http)
+            .mitm(false)
+            .fault(config("reset", "reset"))
+            .fault(config("close", "close"))
+            .start().await.unwrap();
+        let request = |path: &str| format!("GET http://{}/{} HTTP/1.1\r\nHost: {}\r\n\r\n", backend_addr, path, backend_addr);
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+        stream.write_all(request("reset").as_bytes()).await.unwrap();
+        let mut response = vec![];
+        let err = stream.read_to_end(&mut response).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+        assert!(response.is_empty());
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+        stream.write_all(request("close").as_bytes()).await.unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await.unwrap();
+        assert!(response.ends_with(b"\r\n\r\n01234"));
+        handle.shutdown();
+    }
+}
@@ -0,0 +1,31 @@
+use crate::rule::HostPattern;
+
+//HTTPS解密规则，CONNECT隧道和socks隧道里的TLS流量都按这个规则决定是否解密
+//include为空时表示全部解密，exclude的优先级比include高
+pub struct InterceptRules {
+    pub enabled: bool,
+    pub include: Vec<HostPattern>,
+    pub exclude: Vec<HostPattern>,
+}
+
+impl Default for InterceptRules {
+    fn default() -> Self {
+        InterceptRules::new()
+    }
+}
+
+impl InterceptRules {
+    pub fn new() -> InterceptRules {
+        InterceptRules {
+            enabled: true,
+            include: vec![],
+            exclude: vec![],
+        }
+    }
+
+    pub fn should_intercept(&self, host: impl AsRef<str>) -> bool {
+        if !self.enabled { return false; }
+        if self.exclude.iter().any(|p| p.matches(host.as_ref())) { return false; }
+        self.include.is_empty() || self.include.iter().any(|p| p.matches(host.as_ref()))
+    }
+}
@@ -0,0 +1,213 @@
+use std::path::{Component, Path, PathBuf};
+use crate::data::http::{reason_phrase, HttpHead, HttpMessage};
+use crate::error::ProxyResult;
+use crate::rule::{url_host, url_matches};
+
+//本地映射的请求在抓包记录里的标记
+pub const MAPPED_TAG: &str = "mapped";
+
+//本地映射：URL匹配的请求直接用本地文件响应，不连接服务器
+//url按通配符匹配完整的URL，如：http://localhost:3000/api/*，查询参数可以不写
+//path是文件时所有匹配的请求都返回这个文件；是目录时URL中第一个通配符开始的部分作为目录下的相对路径，
+//如：http://localhost:3000/api/user/1.json -> mock/api/user/1.json，以/结尾的返回index.html
+#[derive(Clone, Debug)]
+pub struct MapLocalRule {
+    pub url: String,
+    pub path: PathBuf,
+    pub status: u16,
+    //额外添加的响应头，同名的会替换自动生成的
+    pub headers: Vec<(String, String)>,
+}
+
+impl MapLocalRule {
+    pub fn new(url: impl Into<String>, path: impl Into<PathBuf>) -> ProxyResult<MapLocalRule> {
+        let url = url.into();
+        match url.split_once("://") {
+            Some((_, rest)) if !rest.is_empty() => {}
+            _ => return Err(format!("本地映射的URL必须带协议，如：http://localhost:3000/api/*：{}", url).into()),
+        }
+        Ok(MapLocalRule { url, path: path.into(), status: 200, headers: vec![] })
+    }
+
+    pub fn matches(&self, url: &str) -> bool {
+        url_matches(&self.url, url)
+    }
+
+    //请求对应的本地文件，路径里有..时返回None
+    fn file(&self, url: &str) -> Option<PathBuf> {
+        if !self.path.is_dir() { return Some(self.path.clone()); }
+        let prefix = self.url.find(['*', '?']).unwrap_or(self.url.len());
+        let path = url.split('?').next().unwrap_or(url);
+        let relative = path.get(prefix..).unwrap_or("");
+        let relative = Path::new(relative.trim_start_matches('/'));
+        if relative.components().any(|c| !matches!(c, Component::Normal(_))) { return None; }
+        let file = self.path.join(relative);
+        match file.is_dir() || relative.as_os_str().is_empty() {
+            true => Some(file.join("index.html")),
+            false => Some(file),
+        }
+    }
+
+    //文件不存在时返回404，同样不会连接服务器
+    pub fn respond(&self, request: &HttpMessage, url: &str) -> HttpMessage {
+        let file = self.file(url);
+        let (status, content_type, body) = match file.as_ref().map(|f| (f, std::fs::read(f))) {
+            Some((file, Ok(body))) => (self.status, content_type(file), body),
+            Some((file, Err(e))) => (404, "text/plain; charset=utf-8", format!("读取本地文件{}失败：{}", file.display(), e).into_bytes()),
+            None => (403, "text/plain; charset=utf-8", format!("不允许访问的路径：{}", url).into_bytes()),
+        };
+        let mut head = HttpHead { line: format!("HTTP/1.1 {} {}", status, reason_phrase(status)), headers: vec![] };
+        head.set_header("Content-Type", content_type);
+        head.set_header("Content-Length", body.len());
+        for (key, value) in &self.headers { head.set_header(key, value); }
+        //HEAD请求只返回头，长度还是文件的长度
+        match request.head.method().eq_ignore_ascii_case("HEAD") {
+            true => HttpMessage::new(head, vec![]),
+            false => HttpMessage::new(head, body),
+        }
+    }
+}
+
+//按扩展名推断Content-Type，不认识的当作二进制
+pub fn content_type(file: &Path) -> &'static str {
+    let ext = file.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+    match ext.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "application/javascript; charset=utf-8",
+        "json" | "map" => "application/json; charset=utf-8",
+        "xml" => "application/xml; charset=utf-8",
+        "txt" | "log" | "md" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "mp4" => "video/mp4",
+        "mp3" => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+//按顺序匹配，第一个匹配的规则生效
+#[derive(Default)]
+pub struct MapLocalRules {
+    pub rules: Vec<MapLocalRule>,
+}
+
+impl MapLocalRules {
+    pub fn new() -> MapLocalRules {
+        MapLocalRules::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn select(&self, url: &str) -> Option<&MapLocalRule> {
+        self.rules.iter().find(|rule| rule.matches(url))
+    }
+
+    //这个域名可能有请求需要本地映射，建立隧道时先不连接服务器
+    pub fn has_host(&self, host: &str) -> bool {
+        self.rules.iter().any(|rule| url_host(&rule.url).matches(host))
+    }
+}
+
+#[cfg(test)]
+mod test_map_local {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_stream::StreamExt;
+    use crate::data::http::HttpParser;
+    use crate::rule::map_local::{MapLocalRule, MapLocalRules};
+    use crate::server::{read_until, ListenMode, ProxyServer};
+
+    #[test]
+    fn test_map_local() {
+        let dir = std::env::temp_dir().join(format!("map-local-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("user")).unwrap();
+        std::fs::write(dir.join("user/1.json"), r#"{"id":1}"#).unwrap();
+        std::fs::write(dir.join("index.html"), "<html></html>").unwrap();
+        let mut rule = MapLocalRule::new("http://localhost:3000/api/*", &dir).unwrap();
+        rule.headers.push(("Access-Control-Allow-Origin".to_string(), "*".to_string()));
+        let mut rules = MapLocalRules::new();
+        rules.rules.push(rule);
+        assert!(rules.has_host("localhost"));
+        assert!(!rules.has_host("example.com"));
+        assert!(rules.select("http://localhost:3000/other").is_none());
+        let request = |bs: &[u8]| HttpParser::new(true, Arc::new(Mutex::new(VecDeque::new()))).feed(bs).remove(0);
+        let get = request(b"GET /api/user/1.json HTTP/1.1\r\n\r\n");
+        let url = "http://localhost:3000/api/user/1.json?t=1";
+        let response = rules.select(url).unwrap().respond(&get, url);
+        assert_eq!(response.head.status(), Some(200));
+        assert_eq!(response.head.header("Content-Type"), Some("application/json; charset=utf-8"));
+        assert_eq!(response.head.header("Access-Control-Allow-Origin"), Some("*"));
+        assert_eq!(response.body, br#"{"id":1}"#);
+        //目录返回index.html，不存在的文件返回404，不能访问目录外面的文件
+        let rule = rules.select("http://localhost:3000/api/").unwrap();
+        assert_eq!(rule.respond(&get, "http://localhost:3000/api/").body, b"<html></html>");
+        assert_eq!(rule.respond(&get, "http://localhost:3000/api/none.js").head.status(), Some(404));
+        assert_eq!(rule.respond(&get, "http://localhost:3000/api/../secret").head.status(), Some(403));
+        //HEAD请求只有长度
+        let head = request(b"HEAD /api/user/1.json HTTP/1.1\r\n\r\n");
+        let response = rule.respond(&head, url);
+        assert!(response.body.is_empty());
+        assert_eq!(response.head.header("Content-Length"), Some("8"));
+        assert!(MapLocalRule::new("localhost/api/*", &dir).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_map_local_proxy() {
+        let dir = std::env::temp_dir().join(format!("map-local-server-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("app.js"), "local()").unwrap();
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            let mut bs = vec![0; 1024];
+            let _ = stream.read(&mut bs).await.unwrap();
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nremote").await.unwrap();
+            let _ = stream.read(&mut bs).await;
+        });
+        let handle = ProxyServer::builder()
+            .listen("127.0.0.1:0", ListenMode::Http)
+            .mitm(false)
+            .map_local(format!("http://{}/static/*", backend_addr), dir.to_string_lossy())
+            .start().await.unwrap();
+        let mut flows = handle.flows();
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+        let request = format!("GET http://{}/static/app.js HTTP/1.1\r\nHost: {}\r\n\r\n", backend_addr, backend_addr);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let response = read_until(&mut stream, b"local()").await;
+        assert!(response.contains("application/javascript"));
+        let flow = flows.next().await.unwrap();
+        assert_eq!(flow.tags, vec!["mapped"]);
+        //本地映射的请求不会连接服务器，后面不匹配的请求才连接
+        assert_eq!(accepted.load(Ordering::SeqCst), 0);
+        let request = format!("GET http://{}/api HTTP/1.1\r\nHost: {}\r\n\r\n", backend_addr, backend_addr);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        read_until(&mut stream, b"remote").await;
+        let flow = flows.next().await.unwrap();
+        assert_eq!(flow.path(), "/api");
+        assert!(flow.tags.is_empty());
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        handle.shutdown();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+use crate::error::ProxyResult;
+use crate::reverse::Backend;
+use crate::rule::{url_host, url_matches};
+
+//改写过地址的请求在抓包记录里的标记
+pub const REMAPPED_TAG: &str = "remapped";
+
+//远程映射：URL匹配的请求改发到另一个地址，协议、域名、端口都可以不同
+//from按通配符匹配完整的URL，其中第一个通配符前面的路径替换成to的路径，如：
+//from = https://api.example.com/v1/*，to = http://127.0.0.1:8080/staging
+//https://api.example.com/v1/users?id=1 -> http://127.0.0.1:8080/staging/users?id=1
+#[derive(Clone, Debug)]
+pub struct MapRemoteRule {
+    pub from: String,
+    pub to: Backend,
+    //保留客户端请求的Host头，默认改成to的地址
+    pub preserve_host: bool,
+}
+
+impl MapRemoteRule {
+    pub fn new(from: impl Into<String>, to: impl AsRef<str>) -> ProxyResult<MapRemoteRule> {
+        let from = from.into();
+        match from.split_once("://") {
+            Some((_, rest)) if !rest.is_empty() => {}
+            _ => return Err(format!("远程映射的URL必须带协议，如：https://api.example.com/*：{}", from).into()),
+        }
+        Ok(MapRemoteRule { from, to: Backend::parse(to)?, preserve_host: false })
+    }
+
+    pub fn matches(&self, url: &str) -> bool {
+        url_matches(&self.from, url)
+    }
+
+    //改写后发给后端的路径
+    pub fn rewrite_uri(&self, url: &str) -> String {
+        let path = match url.split_once("://") {
+            Some((_, rest)) => rest.find('/').map(|pos| &rest[pos..]).unwrap_or("/"),
+            None => url,
+        };
+        let from = self.from.split_once("://").map(|(_, rest)| rest).unwrap_or(&self.from);
+        let from = from.find('/').map(|pos| &from[pos..]).unwrap_or("");
+        let prefix = from[..from.find(['*', '?']).unwrap_or(from.len())].trim_end_matches('/');
+        let uri = format!("{}{}", self.to.path, path.strip_prefix(prefix).unwrap_or(path));
+        match uri.starts_with('/') {
+            true => uri,
+            false => format!("/{}", uri),
+        }
+    }
+
+    //改写后的完整URL，记录在抓包记录里
+    pub fn rewrite_url(&self, url: &str) -> String {
+        let scheme = if self.to.tls { "https" } else { "http" };
+        format!("{}://{}{}", scheme, self.to.host_header(), self.rewrite_uri(url))
+    }
+}
+
+//按顺序匹配，第一个匹配的规则生效
+#[derive(Default)]
+pub struct MapRemoteRules {
+    pub rules: Vec<MapRemoteRule>,
+}
+
+impl MapRemoteRules {
+    pub fn new() -> MapRemoteRules {
+        MapRemoteRules::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn select(&self, url: &str) -> Option<&MapRemoteRule> {
+        self.rules.iter().find(|rule| rule.matches(url))
+    }
+
+    //这个域名可能有请求需要改写地址，建立隧道时先不连接服务器
+    pub fn has_host(&self, host: &str) -> bool {
+        self.rules.iter().any(|rule| url_host(&rule.from).matches(host))
+    }
+}
+
+#[cfg(test)]
+mod test_map_remote {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_stream::StreamExt;
+    use crate::rule::map_remote::{MapRemoteRule, MapRemoteRules};
+    use crate::server::{read_until, ListenMode, ProxyServer};
+
+    #[test]
+    fn test_map_remote() {
+        let mut rules = MapRemoteRules::new();
+        rules.rules.push(MapRemoteRule::new("https://api.example.com/v1/*", "http://127.0.0.1:8080/staging").unwrap());
+        rules.rules.push(MapRemoteRule::new("https://*.example.com/*", "https://backup.example.net").unwrap());
+        assert!(rules.has_host("api.example.com"));
+        assert!(!rules.has_host("example.org"));
+        let url = "https://api.example.com/v1/users?id=1";
+        let rule = rules.select(url).unwrap();
+        assert_eq!(rule.rewrite_uri(url), "/staging/users?id=1");
+        assert_eq!(rule.rewrite_url(url), "http://127.0.0.1:8080/staging/users?id=1");
+        //路径前缀为空时保留原来的路径
+        let url = "https://www.example.com/index.html";
+        let rule = rules.select(url).unwrap();
+        assert_eq!(rule.rewrite_url(url), "https://backup.example.net/index.html");
+        assert!(rules.select("http://www.example.org/").is_none());
+        assert!(MapRemoteRule::new("api.example.com/*", "http://127.0.0.1").is_err());
+        assert!(MapRemoteRule::new("https://api.example.com/*", "ftp://127.0.0.1").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_map_remote_proxy() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut bs = vec![0; 1024];
+            let len = stream.read(&mut bs).await.unwrap();
+            //后端收到的是改写后的路径和Host
+            let request = String::from_utf8_lossy(&bs[..len]).to_string();
+            let body = request.lines().take(2).collect::<Vec<_>>().join("|");
+            let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+            stream.write_all(response.as_bytes()).await.unwrap();
+            let _ = stream.read(&mut bs).await;
+        });
+        let handle = ProxyServer::builder()
+            .listen("127.0.0.1:0", ListenMode::Http)
+            .mitm(false)
+            .map_remote("http://api.invalid/v1/*", format!("http://{}/staging", backend_addr))
+            .start().await.unwrap();
+        let mut flows = handle.flows();
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+        stream.write_all(b"GET http://api.invalid/v1/users?id=1 HTTP/1.1\r\nHost: api.invalid\r\n\r\n").await.unwrap();
+        let expected = format!("GET /staging/users?id=1 HTTP/1.1|Host: {}", backend_addr);
+        read_until(&mut stream, expected.as_bytes()).await;
+        let flow = flows.next().await.unwrap();
+        let remap = flow.remap.as_ref().unwrap();
+        assert_eq!(remap.original, "http://api.invalid/v1/users?id=1");
+        assert_eq!(remap.rewritten, format!("http://{}/staging/users?id=1", backend_addr));
+        assert_eq!(flow.url(), remap.rewritten);
+        assert_eq!(flow.tags, vec!["remapped"]);
+        handle.shutdown();
+    }
+
+    //同一个连接上第一个请求不匹配，后面匹配的请求也要发到映射的地址
+    #[tokio::test]
+    async fn test_map_remote_keep_alive() {
+        let (origin, backend) = (TcpListener::bind("127.0.0.1:0").await.unwrap(), TcpListener::bind("127.0.0.1:0").await.unwrap());
+        let (origin_addr, backend_addr) = (origin.local_addr().unwrap(), backend.local_addr().unwrap());
+        for (listener, name) in [(origin, "origin"), (backend, "backend")] {
+            tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut bs = vec![0; 1024];
+                loop {
+                    let len = stream.read(&mut bs).await.unwrap_or(0);
+                    if len == 0 { break; }
+                    let line = String::from_utf8_lossy(&bs[..len]).lines().next().unwrap_or("").to_string();
+                    let body = format!("{}:{}", name, line);
+                    let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                }
+            });
+        }
+        let handle = ProxyServer::builder()
+            .listen("127.0.0.1:0", ListenMode::Http)
+            .mitm(false)
+            .map_remote(format!("http://{}/v1/*", origin_addr), format!("http://{}/staging", backend_addr))
+            .start().await.unwrap();
+        let mut flows = handle.flows();
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+        let request = format!("GET http://{}/home HTTP/1.1\r\nHost: {}\r\n\r\n", origin_addr, origin_addr);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        read_until(&mut stream, b"origin:GET /home HTTP/1.1").await;
+        let request = format!("GET /v1/users HTTP/1.1\r\nHost: {}\r\n\r\n", origin_addr);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        read_until(&mut stream, b"backend:GET /staging/users HTTP/1.1").await;
+        assert!(flows.next().await.unwrap().remap.is_none());
+        let flow = flows.next().await.unwrap();
+        assert_eq!(flow.remap.as_ref().unwrap().original, format!("http://{}/v1/users", origin_addr));
+        assert_eq!(flow.tags, vec!["remapped"]);
+        handle.shutdown();
+    }
+}
@@ -0,0 +1,82 @@
+pub mod fault;
+pub mod intercept;
+pub mod map_local;
+pub mod map_remote;
+pub mod playback;
+pub mod rewrite;
+
+//域名匹配规则，支持通配符`*`(任意个字符)和`?`(单个字符)，不区分大小写
+//例如：*.baidu.com、api-?.example.com
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HostPattern {
+    pattern: String,
+}
+
+impl HostPattern {
+    pub fn new(pattern: impl AsRef<str>) -> HostPattern {
+        HostPattern { pattern: pattern.as_ref().trim().to_lowercase() }
+    }
+
+    pub fn matches(&self, host: impl AsRef<str>) -> bool {
+        wildcard_match(self.pattern.as_bytes(), host.as_ref().to_lowercase().as_bytes())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+}
+
+//URL规则按通配符匹配完整的URL，不带查询参数也可以匹配，如：http://a.com/app.js匹配http://a.com/app.js?v=1
+pub fn url_matches(pattern: &str, url: &str) -> bool {
+    let path = url.split('?').next().unwrap_or(url);
+    wildcard_match(pattern.as_bytes(), url.as_bytes()) || wildcard_match(pattern.as_bytes(), path.as_bytes())
+}
+
+//URL规则中的域名部分，去掉端口，CONNECT的时候还不知道完整的URL，先按域名判断
+pub fn url_host(pattern: &str) -> HostPattern {
+    let rest = pattern.split_once("://").map(|(_, rest)| rest).unwrap_or(pattern);
+    let host = rest.split('/').next().unwrap_or(rest);
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit() || b == b'*') => host,
+        _ => host,
+    };
+    HostPattern::new(host)
+}
+
+//通配符匹配，遇到`*`时记录位置，后面匹配失败时回退到`*`再多吃一个字符
+pub fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((sp, st)) = star {
+            p = sp + 1;
+            t = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|b| *b == b'*')
+}
+
+#[cfg(test)]
+mod test_rule {
+    use crate::rule::HostPattern;
+
+    #[test]
+    fn test_host_pattern() {
+        assert!(HostPattern::new("*.baidu.com").matches("www.Baidu.com"));
+        assert!(!HostPattern::new("*.baidu.com").matches("baidu.com"));
+        assert!(HostPattern::new("api-?.example.com").matches("api-1.example.com"));
+        assert!(!HostPattern::new("api-?.example.com").matches("api-12.example.com"));
+        assert!(HostPattern::new("*").matches("anything"));
+        assert!(HostPattern::new("a*b*c").matches("aXXbYYc"));
+        assert!(!HostPattern::new("a*b*c").matches("aXXbYY"));
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use log::{debug, warn};
+use serde_json::Value;
+use crate::data::flow::{parse_query, Flow};
+use crate::data::http::{reason_phrase, HttpMessage};
+use crate::error::ProxyResult;
+use crate::import::parse_har;
+use crate::rule::url_host;
+
+//回放的响应在抓包记录里的标记
+pub const PLAYBACK_TAG: &str = "playback";
+//没有匹配的记录、记录用完时返回的状态码
+const PLAYBACK_MISS: u16 = 502;
+
+/*
+    同一个请求被调用的次数比录制的多时怎么办：
+    sequential  按录制的顺序返回，用完后从头开始
+    last        按录制的顺序返回，用完后一直返回最后一个
+    error       按录制的顺序返回，用完后返回502
+ */
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PlaybackStrategy {
+    Sequential,
+    Last,
+    Error,
+}
+
+impl PlaybackStrategy {
+    pub fn parse(value: &str) -> ProxyResult<PlaybackStrategy> {
+        Ok(match value.trim().to_lowercase().as_str() {
+            "sequential" => PlaybackStrategy::Sequential,
+            "last" => PlaybackStrategy::Last,
+            "error" => PlaybackStrategy::Error,
+            _ => return Err(format!("回放策略{}错误，可选：sequential、last、error", value).into()),
+        })
+    }
+}
+
+/*
+    回放模式：用保存的会话响应请求，不连接服务器，集成测试时得到固定的结果
+    会话是--format json输出的记录或者HAR文件，按请求方法、完整的URL匹配，
+    headers里的请求头、body里的字段也要相同，body的字段是JSON的路径(用.分隔)或者表单的参数名，*表示整个body
+    strict为true时没有匹配的请求返回502，否则转发给服务器
+ */
+pub struct PlaybackRules {
+    pub strict: bool,
+    pub strategy: PlaybackStrategy,
+    pub headers: Vec<String>,
+    pub body: Vec<String>,
+    //按匹配键分组的响应，保持录制的顺序
+    entries: HashMap<String, Vec<HttpMessage>>,
+    hosts: HashSet<String>,
+    //每个匹配键已经回放的次数
+    calls: Mutex<HashMap<String, usize>>,
+}
+
+impl Default for PlaybackRules {
+    fn default() -> Self {
+        PlaybackRules::new()
+    }
+}
+
+impl PlaybackRules {
+    pub fn new() -> PlaybackRules {
+        PlaybackRules {
+            strict: false,
+            strategy: PlaybackStrategy::Sequential,
+            headers: vec![],
+            body: vec![],
+            entries: HashMap::new(),
+            hosts: HashSet::new(),
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+
+    //没有加载会话、也不是严格模式时不启用
+    pub fn enabled(&self) -> bool {
+        !self.is_empty() || self.strict
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.values().map(|responses| responses.len()).sum()
+    }
+
+    //先设置headers和body再添加，匹配键是添加时计算的；没有响应的记录跳过
+    pub fn add(&mut self, flow: &Flow) {
+        let response = match &flow.response {
+            Some(response) => response.clone(),
+            None => return,
+        };
+        //远程映射过的记录按客户端请求的URL匹配
+        let url = flow.remap.as_ref().map(|remap| remap.original.clone()).unwrap_or(flow.url());
+        self.hosts.insert(url_host(&url).as_str().to_string());
+        self.entries.entry(self.key(&flow.request, &url)).or_default().push(response);
+    }
+
+    //重新从第一个记录开始回放
+    pub fn reset(&self) {
+        if let Ok(mut calls) = self.calls.lock() { calls.clear(); }
+    }
+
+    //严格模式下所有的请求都不连接服务器
+    pub fn has_host(&self, host: &str) -> bool {
+        self.strict || self.hosts.contains(&host.to_lowercase())
+    }
+
+    fn key(&self, request: &HttpMessage, url: &str) -> String {
+        let mut key = format!("{} {}", request.head.method().to_uppercase(), url);
+        for name in &self.headers {
+            key.push_str(&format!("\n{}: {}", name.to_lowercase(), request.head.header(name).unwrap_or("")));
+        }
+        if self.body.is_empty() { return key; }
+        let body = request.decoded_body();
+        let json: Option<Value> = serde_json::from_slice(&body).ok();
+        let form = match json {
+            Some(_) => vec![],
+            None => parse_query(&String::from_utf8_lossy(&body)),
+        };
+        for field in &self.body {
+            let value = match (field.as_str(), &json) {
+                ("*", _) => String::from_utf8_lossy(&body).to_string(),
+                (_, Some(json)) => field.split('.').try_fold(json, |value, key| match value {
+                    Value::Array(items) => key.parse::<usize>().ok().and_then(|index| items.get(index)),
+                    value => value.get(key),
+                }).map(|value| value.to_string()).unwrap_or_default(),
+                (_, None) => form.iter().find(|(k, _)| k == field).map(|(_, v)| v.clone()).unwrap_or_default(),
+            };
+            key.push_str(&format!("\n{}={}", field, value));
+        }
+        key
+    }
+
+    //返回None时转发给服务器
+    pub fn respond(&self, request: &HttpMessage, url: &str) -> Option<HttpMessage> {
+        let key = self.key(request, url);
+        let responses = match self.entries.get(&key) {
+            Some(responses) => responses,
+            None if self.strict => {
+                warn!("回放模式没有匹配的记录：{} {}", request.head.method(), url);
+                return Some(miss(format!("回放模式没有匹配的记录：{} {}", request.head.method(), url)));
+            }
+            None => return None,
+        };
+        let count = {
+            let mut calls = self.calls.lock().ok()?;
+            let count = calls.entry(key).or_default();
+            *count += 1;
+            *count - 1
+        };
+        let index = match (count < responses.len(), self.strategy) {
+            (true, _) => count,
+            (false, PlaybackStrategy::Sequential) => count % responses.len(),
+            (false, PlaybackStrategy::Last) => responses.len() - 1,
+            (false, PlaybackStrategy::Error) => {
+                warn!("回放模式的记录已经用完：{} {}，录制了{}次，这是第{}次", request.head.method(), url, responses.len(), count + 1);
+                return Some(miss(format!("回放模式的记录已经用完：{} {}，录制了{}次", request.head.method(), url, responses.len())));
+            }
+        };
+        debug!("回放{} {}，第{}个记录", request.head.method(), url, index + 1);
+        let mut response = responses[index].clone();
+        let now = SystemTime::now();
+        (response.start, response.end) = (now, now);
+        Some(response)
+    }
+}
+
+fn miss(message: String) -> HttpMessage {
+    let mut response = HttpMessage::response(PLAYBACK_MISS, reason_phrase(PLAYBACK_MISS), message);
+    response.head.set_header("Content-Type", "text/plain; charset=utf-8");
+    response
+}
+
+//--format json输出的记录(每行一个)或者HAR文件
+pub fn load_session(path: &str) -> ProxyResult<Vec<Flow>> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("读取会话文件{}失败：{}", path, e))?;
+    if serde_json::from_str::<Value>(&text).is_ok_and(|value| value.get("log").is_some()) { return parse_har(&text); }
+    let mut flows = vec![];
+    for (index, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let flow = serde_json::from_str::<Value>(line).map_err(|e| e.to_string()).and_then(|value| Flow::from_json(&value).map_err(|e| e.to_string()))
+            .map_err(|e| format!("会话文件{}第{}行格式错误，需要--format json输出的记录或者HAR文件：{}", path, index + 1, e))?;
+        flows.push(flow);
+    }
+    Ok(flows)
+}
+
+#[cfg(test)]
+mod test_playback {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use crate::data::flow::{test_flow, Flow};
+    use crate::rule::playback::{PlaybackRules, PlaybackStrategy};
+    use crate::server::{read_until, ListenMode, ProxyServer};
+
+    #[test]
+    fn test_playback() {
+        let body = |n: u8| format!("{{\"query\":\"user\",\"variables\":{{\"id\":{}}},\"ts\":{}}}", n, n + 100);
+        let request = |n: u8| format!("POST /graphql HTTP/1.1\r\nHost: api.example.com\r\nAuthorization: a\r\nContent-Length: {}\r\n\r\n{}", body(n).len(), body(n));
+        let flow = |n: u8, text: &str| test_flow(1, request(n).as_bytes(), format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", text.len(), text).as_bytes());
+        let mut rules = PlaybackRules::new();
+        rules.headers = vec!["Authorization".to_string()];
+        rules.body = vec!["variables.id".to_string()];
+        for (n, text) in [(1, "first"), (1, "second"), (2, "other")] { rules.add(&flow(n, text)); }
+        //to_json输出后再读取，和保存的会话文件一样
+        rules.add(&Flow::from_json(&test_flow(2, b"GET /a HTTP/1.1\r\nHost: api.example.com\r\n\r\n", b"HTTP/1.1 204 No Content\r\n\r\n").to_json(true)).unwrap());
+        assert_eq!(rules.len(), 4);
+        let url = "https://api.example.com/graphql";
+        let body = |rules: &PlaybackRules, n: u8| rules.respond(&flow(n, "").request, url).map(|r| String::from_utf8(r.body).unwrap());
+        //ts不参与匹配
+        assert_eq!(body(&rules, 1).as_deref(), Some("first"));
+        assert_eq!(body(&rules, 2).as_deref(), Some("other"));
+        assert_eq!(body(&rules, 1).as_deref(), Some("second"));
+        assert_eq!(body(&rules, 1).as_deref(), Some("first"));
+        rules.strategy = PlaybackStrategy::Last;
+        assert_eq!(body(&rules, 1).as_deref(), Some("second"));
+        rules.strategy = PlaybackStrategy::Error;
+        assert_eq!(rules.respond(&flow(1, "").request, url).unwrap().head.status(), Some(502));
+        rules.reset();
+        assert_eq!(body(&rules, 1).as_deref(), Some("first"));
+        let get = test_flow(3, b"GET /a HTTP/1.1\r\nHost: api.example.com\r\n\r\n", b"");
+        assert_eq!(rules.respond(&get.request, "https://api.example.com/a").unwrap().head.status(), Some(204));
+        //不认识的请求，严格模式返回502，否则转发
+        assert!(rules.respond(&get.request, "https://api.example.com/b").is_none());
+        assert!(rules.has_host("API.example.com") && !rules.has_host("www.example.com"));
+        rules.strict = true;
+        assert_eq!(rules.respond(&get.request, "https://api.example.com/b").unwrap().head.status(), Some(502));
+        assert!(rules.has_host("www.example.com"));
+        assert!(PlaybackStrategy::parse("LAST").is_ok() && PlaybackStrategy::parse("random").is_err());
+    }
+
+    //严格回放模式下不解密的隧道不能连接服务器
+    #[tokio::test]
+    async fn test_playback_strict_tunnel() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let handle = ProxyServer::builder()
+            .listen("127.0.0.1:0", ListenMode::Http)
+            .mitm(false)
+            .playback_strict(true)
+            .start().await.unwrap();
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+        stream.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", backend_addr).as_bytes()).await.unwrap();
+        read_until(&mut stream, b"\r\n\r\n").await;
+        stream.write_all(b"\x00\x01binary").await.unwrap();
+        let mut bs = vec![];
+        let _ = stream.read_to_end(&mut bs).await;
+        assert!(bs.is_empty());
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(200), backend.accept()).await.is_err());
+        handle.shutdown();
+    }
+}
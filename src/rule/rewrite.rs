@@ -0,0 +1,196 @@
+use std::str::FromStr;
+use regex::bytes::Regex;
+use crate::config::RewriteConfig;
+use crate::data::http::{HttpHead, HttpMessage};
+use crate::error::ProxyResult;
+use crate::rule::{wildcard_match, HostPattern};
+use crate::upstream::split_host_port;
+
+//改写过的请求在抓包记录里的标记
+pub const REWRITTEN_TAG: &str = "rewritten";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RewriteStage {
+    Request,
+    Response,
+}
+
+impl FromStr for RewriteStage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "request" => Ok(RewriteStage::Request),
+            "response" => Ok(RewriteStage::Response),
+            _ => Err(format!("改写规则的阶段{}错误，可选：request、response", s)),
+        }
+    }
+}
+
+/*
+   一条改写规则，范围内的条件都满足时按顺序执行：删除头 -> 替换头 -> 添加头 -> 替换body
+   host          域名，支持通配符，为空时不限制
+   path          路径，有通配符时按通配符匹配，否则按前缀匹配，不包括查询参数
+   method        请求方法
+   content_type  当前消息(请求或者响应)的Content-Type包含这个值
+   body的替换按正则匹配解压后的内容，替换后按原来的编码重新压缩并修改长度
+ */
+#[derive(Clone, Debug)]
+pub struct RewriteRule {
+    //原始配置，界面上编辑和显示
+    pub config: RewriteConfig,
+    pub stage: RewriteStage,
+    host: Option<HostPattern>,
+    body: Vec<(Regex, String)>,
+}
+
+impl RewriteRule {
+    pub fn new(config: RewriteConfig) -> ProxyResult<RewriteRule> {
+        let stage = RewriteStage::from_str(&config.stage)?;
+        let host = match config.host.trim().is_empty() {
+            true => None,
+            false => Some(HostPattern::new(&config.host)),
+        };
+        let mut body = vec![];
+        for replace in &config.body {
+            let regex = Regex::new(&replace.find).map_err(|e| format!("改写规则的正则{}错误：{}", replace.find, e))?;
+            body.push((regex, replace.replace.clone()));
+        }
+        Ok(RewriteRule { config, stage, host, body })
+    }
+
+    //request是这次请求的头，message是要修改的消息(请求或者响应)的头
+    pub fn matches(&self, server: &str, request: &HttpHead, message: &HttpHead) -> bool {
+        let content_type = message.header("Content-Type").unwrap_or("").to_lowercase();
+        self.matches_request(server, request) && content_type.contains(&self.config.content_type.to_lowercase())
+    }
+
+    //只按请求判断，响应还没有收到的时候不知道Content-Type
+    pub fn matches_request(&self, server: &str, request: &HttpHead) -> bool {
+        let config = &self.config;
+        if !config.enabled { return false; }
+        if let Some(pattern) = &self.host {
+            let host = request.header("Host").unwrap_or(server);
+            let host = split_host_port(host, 80).map(|(host, _)| host).unwrap_or_default();
+            if !pattern.matches(host) { return false; }
+        }
+        let uri = request.uri();
+        let uri = uri.split_once("://").map(|(_, rest)| rest.find('/').map(|pos| &rest[pos..]).unwrap_or("/")).unwrap_or(uri);
+        let path = uri.split('?').next().unwrap_or(uri);
+        let path_matched = match config.path.contains(['*', '?']) {
+            true => wildcard_match(config.path.as_bytes(), path.as_bytes()),
+            false => path.starts_with(config.path.as_str()),
+        };
+        if !path_matched { return false; }
+        config.method.is_empty() || config.method.eq_ignore_ascii_case(request.method())
+    }
+
+    //返回是否修改过
+    pub fn apply(&self, message: &mut HttpMessage) -> bool {
+        let config = &self.config;
+        let mut changed = false;
+        for key in &config.remove_headers {
+            if message.head.header(key).is_some() {
+                message.head.remove_header(key);
+                changed = true;
+            }
+        }
+        for (key, value) in &config.set_headers {
+            message.head.set_header(key, value);
+            changed = true;
+        }
+        for (key, value) in &config.add_headers {
+            message.head.headers.push((key.clone(), value.clone()));
+            changed = true;
+        }
+        //不认识的压缩格式没法修改内容
+        if self.body.is_empty() || !message.can_decode() || message.body.len() < message.body_size { return changed; }
+        let body = message.decoded_body();
+        let mut replaced = body.clone();
+        for (regex, replace) in &self.body {
+            replaced = regex.replace_all(&replaced, replace.as_bytes()).into_owned();
+        }
+        if replaced != body {
+            message.set_decoded_body(replaced);
+            changed = true;
+        }
+        changed
+    }
+}
+
+//所有匹配的规则按顺序执行
+#[derive(Default)]
+pub struct RewriteRules {
+    pub rules: Vec<RewriteRule>,
+}
+
+impl RewriteRules {
+    pub fn new() -> RewriteRules {
+        RewriteRules::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    //这个阶段有没有可能改写这个请求(或者它的响应)，决定消息是否要接收完整再转发
+    pub fn matches(&self, stage: RewriteStage, server: &str, request: &HttpHead) -> bool {
+        self.rules.iter().any(|rule| rule.stage == stage && rule.matches_request(server, request))
+    }
+
+    //返回是否修改过
+    pub fn apply(&self, stage: RewriteStage, server: &str, request: &HttpHead, message: &mut HttpMessage) -> bool {
+        let mut changed = false;
+        for rule in self.rules.iter().filter(|rule| rule.stage == stage) {
+            if rule.matches(server, request, &message.head) { changed |= rule.apply(message); }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod test_rewrite {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use crate::config::{BodyReplace, RewriteConfig};
+    use crate::data::http::{HttpMessage, HttpParser};
+    use crate::rule::rewrite::{RewriteRule, RewriteRules, RewriteStage};
+
+    #[test]
+    fn test_rewrite() {
+        let mut config = RewriteConfig { host: "*.example.com".to_string(), path: "/api".to_string(), content_type: "json".to_string(), ..RewriteConfig::default() };
+        config.remove_headers.push("Set-Cookie".to_string());
+        config.set_headers.insert("Cache-Control".to_string(), "no-cache".to_string());
+        config.add_headers.insert("X-Rewrite".to_string(), "1".to_string());
+        config.body.push(BodyReplace { find: r#""vip":\s*false"#.to_string(), replace: r#""vip":true"#.to_string() });
+        let mut rules = RewriteRules::new();
+        rules.rules.push(RewriteRule::new(config.clone()).unwrap());
+        let heads = Arc::new(Mutex::new(VecDeque::new()));
+        let request = HttpParser::new(true, heads.clone()).feed(b"GET /api/user?id=1 HTTP/1.1\r\nHost: www.example.com\r\n\r\n").remove(0);
+        let mut response = HttpMessage::response(200, "OK", r#"{"vip": false}"#);
+        response.head.set_header("Content-Type", "application/json");
+        response.head.set_header("Set-Cookie", "a=1");
+        response.head.set_header("Cache-Control", "max-age=60");
+        //请求阶段没有规则
+        assert!(!rules.apply(RewriteStage::Request, "", &request.head, &mut request.clone()));
+        assert!(rules.apply(RewriteStage::Response, "", &request.head, &mut response));
+        assert_eq!(response.body, br#"{"vip":true}"#);
+        assert_eq!(response.head.header("Content-Length"), Some("12"));
+        assert_eq!(response.head.header("Cache-Control"), Some("no-cache"));
+        assert_eq!(response.head.header("X-Rewrite"), Some("1"));
+        assert!(response.head.header("Set-Cookie").is_none());
+        //压缩过的body替换后重新压缩
+        let mut gzip = HttpMessage::response(200, "OK", vec![]);
+        gzip.head.set_header("Content-Type", "application/json");
+        gzip.head.set_header("Content-Encoding", "gzip");
+        gzip.set_decoded_body(br#"{"vip":false}"#.to_vec());
+        assert!(rules.apply(RewriteStage::Response, "", &request.head, &mut gzip));
+        assert_eq!(gzip.decoded_body(), br#"{"vip":true}"#);
+        assert_eq!(gzip.head.header("Content-Length"), Some(gzip.body.len().to_string().as_str()));
+        //不在范围内的不修改
+        let other = HttpParser::new(true, heads).feed(b"GET /static/a.js HTTP/1.1\r\nHost: www.example.com\r\n\r\n").remove(0);
+        assert!(!rules.apply(RewriteStage::Response, "", &other.head, &mut response.clone()));
+        config.stage = "header".to_string();
+        assert!(RewriteRule::new(config).is_err());
+    }
+}
@@ -0,0 +1,521 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use log::{debug, error, info};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use crate::api;
+use crate::config::{ApiConfig, FaultConfig, ListenerConfig, MapLocalConfig, MapRemoteConfig, ProxyConfig, RewriteConfig, ThrottleHostConfig, UpstreamRuleConfig};
+use crate::context::ProxyContext;
+use crate::data::flow::Flow;
+use crate::data::store::{receive_flows, FlowCallback, FlowStore, FlowStream};
+use crate::error::{ProxyError, ProxyResult};
+use crate::handler::{ConnInfo, FlowHandler};
+use crate::proxy::ProxyStream;
+use crate::replay::Replayer;
+use crate::snippet::SnippetOptions;
+use crate::sniff::Protocol;
+use crate::throttle::NetworkProfile;
+use crate::transparent::bind_tproxy;
+
+//监听模式，决定一个端口上可以接受哪些代理协议
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ListenMode {
+    //HTTP代理，包括CONNECT隧道
+    Http,
+    //socks4/socks4a/socks5代理
+    Socks,
+    //单端口同时支持HTTP、CONNECT、socks4、socks5，根据前几个字节自动判断
+    Mixed,
+    //透明代理，iptables REDIRECT过来的连接，通过SO_ORIGINAL_DST获取原始目标地址
+    Transparent,
+    //透明代理，iptables TPROXY过来的连接，本地地址就是原始目标地址
+    Tproxy,
+    //反向代理，按Host和路径把请求转发给固定的后端
+    Reverse,
+}
+
+impl ListenMode {
+    pub fn accept(&self, protocol: Protocol) -> bool {
+        matches!((self, protocol),
+            (ListenMode::Http | ListenMode::Mixed, Protocol::Http | Protocol::Connect) |
+            (ListenMode::Socks | ListenMode::Mixed, Protocol::Socks4 | Protocol::Socks5))
+    }
+}
+
+impl FromStr for ListenMode {
+    type Err = ProxyError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "http" => Ok(ListenMode::Http),
+            "socks" => Ok(ListenMode::Socks),
+            "mixed" => Ok(ListenMode::Mixed),
+            "transparent" => Ok(ListenMode::Transparent),
+            "tproxy" => Ok(ListenMode::Tproxy),
+            "reverse" => Ok(ListenMode::Reverse),
+            _ => Err(format!("不支持的监听模式：{}，可选：http、socks、mixed、transparent、tproxy、reverse", s).into())
+        }
+    }
+}
+
+impl Display for ListenMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenMode::Http => f.write_str("http"),
+            ListenMode::Socks => f.write_str("socks"),
+            ListenMode::Mixed => f.write_str("mixed"),
+            ListenMode::Transparent => f.write_str("transparent"),
+            ListenMode::Tproxy => f.write_str("tproxy"),
+            ListenMode::Reverse => f.write_str("reverse"),
+        }
+    }
+}
+
+//先绑定端口，端口被占用等错误在启动时就能发现
+pub async fn bind_listener(addr: &str, mode: ListenMode) -> ProxyResult<TcpListener> {
+    info!("在本地{}建立一个Tcp端口监听服务，模式：{}", addr, mode);
+    Ok(match mode {
+        ListenMode::Tproxy => bind_tproxy(addr.parse()?)?,
+        _ => TcpListener::bind(addr).await?
+    })
+}
+
+pub async fn start_listener(listen: TcpListener, mode: ListenMode, ctx: Arc<ProxyContext>) -> ProxyResult<()> {
+    let local = listen.local_addr()?;
+    loop {
+        //接受一个新连接
+        let (stream, addr) = listen.accept().await?;
+        debug!("来自{}的新连接", addr);
+        //启动一个线程，避免造成其他连接阻塞，影响网络体验
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            //Broken pipe这个是异常断开，就是我们的浏览器，突然关闭窗口了
+            let mut stream = ProxyStream::new(stream, ctx.clone());
+            //按监听地址选择网络环境
+            stream.param.listener = Some(local);
+            let res = match mode {
+                ListenMode::Transparent | ListenMode::Tproxy => stream.start_transparent(mode == ListenMode::Tproxy, local).await,
+                ListenMode::Reverse => stream.start_reverse().await,
+                _ => stream.start(mode).await,
+            };
+            if let Err(e) = res {
+                error!("{}", e.to_string());
+                //转发开始之前的错误，这时还不知道目标地址
+                let conn = ConnInfo { sid: String::new(), client: addr.to_string(), server: String::new(), tls: false };
+                for handler in ctx.handlers.iter() { handler.on_error(&conn, &e.to_string()).await; }
+            }
+        });
+    }
+}
+
+/*
+    嵌入到其他程序(如集成测试)里使用：
+    let handle = ProxyServer::builder()
+        .listen("127.0.0.1:0", ListenMode::Mixed)
+        .mitm(false)
+        .start().await?;
+    let mut flows = handle.flows();
+    //通过handle.addr()代理发送请求
+    let flow = flows.next().await;
+ */
+pub struct ProxyServer;
+
+impl ProxyServer {
+    pub fn builder() -> ProxyServerBuilder {
+        //默认没有监听地址，也不输出抓包记录，其他的和配置文件的默认值一样
+        let config = ProxyConfig { listeners: vec![], ..ProxyConfig::default() };
+        ProxyServerBuilder { config, output: false, callbacks: vec![], handlers: vec![] }
+    }
+}
+
+pub struct ProxyServerBuilder {
+    config: ProxyConfig,
+    //是否按capture配置输出抓包记录到stdout或者文件
+    output: bool,
+    callbacks: Vec<FlowCallback>,
+    handlers: Vec<Arc<dyn FlowHandler>>,
+}
+
+impl ProxyServerBuilder {
+    //使用完整的配置(配置文件、命令行)，会按配置输出抓包记录，之前设置的都会被替换
+    pub fn config(mut self, config: ProxyConfig) -> Self {
+        self.config = config;
+        self.output = true;
+        self
+    }
+
+    pub fn output(mut self, output: bool) -> Self {
+        self.output = output;
+        self
+    }
+
+    //端口为0时由系统分配，启动后通过ProxyHandle::addrs获取
+    pub fn listen(mut self, addr: impl Into<String>, mode: ListenMode) -> Self {
+        self.config.listeners.push(ListenerConfig { addr: addr.into(), mode: mode.to_string(), throttle: String::new() });
+        self
+    }
+
+    pub fn ca(mut self, cert: impl Into<String>, key: impl Into<String>) -> Self {
+        self.config.ca.cert = cert.into();
+        self.config.ca.key = key.into();
+        self
+    }
+
+    pub fn cert_cache(mut self, dir: impl Into<String>) -> Self {
+        self.config.ca.cache_dir = dir.into();
+        self
+    }
+
+    //是否解密HTTPS，关闭后不需要根证书
+    pub fn mitm(mut self, enabled: bool) -> Self {
+        self.config.intercept.enabled = enabled;
+        self
+    }
+
+    //只解密匹配的域名，可以调用多次，如：*.example.com
+    pub fn intercept(mut self, pattern: impl Into<String>) -> Self {
+        self.config.intercept.include.push(pattern.into());
+        self
+    }
+
+    //不解密匹配的域名，优先于intercept
+    pub fn no_intercept(mut self, pattern: impl Into<String>) -> Self {
+        self.config.intercept.exclude.push(pattern.into());
+        self
+    }
+
+    //默认的上级代理：direct、http://[user:pass@]host:port、socks5://[user:pass@]host:port
+    pub fn upstream(mut self, proxy: impl Into<String>) -> Self {
+        self.config.upstream.default = proxy.into();
+        self
+    }
+
+    pub fn upstream_rule(mut self, host: impl Into<String>, proxy: impl Into<String>) -> Self {
+        self.config.upstream.rules.push(UpstreamRuleConfig { host: host.into(), proxy: proxy.into() });
+        self
+    }
+
+    //匹配的URL直接用本地文件或者目录响应，不连接服务器，如：http://localhost:3000/api/*
+    pub fn map_local(mut self, url: impl Into<String>, path: impl Into<String>) -> Self {
+        self.config.map_local.push(MapLocalConfig { url: url.into(), path: path.into(), status: 200, headers: BTreeMap::new() });
+        self
+    }
+
+    //匹配的URL改发到另一个地址，如：https://api.example.com/* -> http://127.0.0.1:8080
+    pub fn map_remote(mut self, from: impl Into<String>, to: impl Into<String>) -> Self {
+        self.config.map_remote.push(MapRemoteConfig { from: from.into(), to: to.into(), preserve_host: false });
+        self
+    }
+
+    //改写规则，修改范围内的请求或者响应的头和body
+    pub fn rewrite(mut self, rule: RewriteConfig) -> Self {
+        self.config.rewrite.push(rule);
+        self
+    }
+
+    //故障注入规则，匹配的请求按概率断开连接、返回错误、延迟或者破坏响应
+    pub fn fault(mut self, rule: FaultConfig) -> Self {
+        self.config.fault.push(rule);
+        self
+    }
+
+    //回放模式，用保存的会话响应请求，可以调用多次，session是--format json输出的记录或者HAR文件
+    pub fn playback(mut self, session: impl Into<String>) -> Self {
+        self.config.playback.files.push(session.into());
+        self
+    }
+
+    //回放模式下没有匹配记录的请求返回502，不转发给服务器
+    pub fn playback_strict(mut self, strict: bool) -> Self {
+        self.config.playback.strict = strict;
+        self
+    }
+
+    //所有连接默认使用的网络环境：gprs、edge、3g、4g、dsl、wifi或者network_profile添加的
+    pub fn throttle(mut self, profile: impl Into<String>) -> Self {
+        self.config.throttle.profile = profile.into();
+        self
+    }
+
+    //匹配的域名使用指定的网络环境，优先于默认的
+    pub fn throttle_host(mut self, host: impl Into<String>, profile: impl Into<String>) -> Self {
+        self.config.throttle.hosts.push(ThrottleHostConfig { host: host.into(), profile: profile.into() });
+        self
+    }
+
+    //自定义网络环境，同名的替换内置的
+    pub fn network_profile(mut self, profile: NetworkProfile) -> Self {
+        self.config.throttle.profiles.push(profile);
+        self
+    }
+
+    pub fn max_flows(mut self, max_flows: usize) -> Self {
+        self.config.capture.max_flows = max_flows;
+        self
+    }
+
+    //令牌为空时随机生成，启动后通过ProxyHandle::api获取
+    pub fn api(mut self, addr: impl Into<String>, token: impl Into<String>) -> Self {
+        self.config.api = ApiConfig { enabled: true, addr: addr.into(), token: token.into() };
+        self
+    }
+
+    //每个请求完成时调用
+    pub fn on_flow(mut self, callback: impl Fn(&Flow) + Send + Sync + 'static) -> Self {
+        self.callbacks.push(Box::new(callback));
+        self
+    }
+
+    //拦截处理，可以修改、拦截、丢弃请求和响应，按注册的顺序调用
+    pub fn handler(mut self, handler: impl FlowHandler + 'static) -> Self {
+        self.handlers.push(Arc::new(handler));
+        self
+    }
+
+    //检查配置、绑定所有端口后返回，代理在后台的任务里运行
+    pub async fn start(self) -> ProxyResult<ProxyHandle> {
+        let config = self.config;
+        let errors = config.validate();
+        if !errors.is_empty() { return Err(format!("配置错误：\n  {}", errors.join("\n  ")).into()); }
+        let mut listeners = vec![];
+        for (addr, mode) in config.listen_modes()? {
+            let listener = bind_listener(&addr, mode).await.map_err(|e| format!("{}监听失败：{}", addr, e.to_string()))?;
+            listeners.push((listener, mode));
+        }
+        let api = match config.api.enabled {
+            true => Some(TcpListener::bind(&config.api.addr).await.map_err(|e| format!("控制接口{}监听失败：{}", config.api.addr, e))?),
+            false => None,
+        };
+        let (sx, rx) = mpsc::channel(1024);
+        let store = FlowStore::new(config.capture.max_flows);
+        let writer = match self.output {
+            true => config.flow_writer()?,
+            false => None,
+        };
+        let mut tasks = vec![tokio::spawn(receive_flows(rx, store.clone(), writer, self.callbacks))];
+        std::fs::create_dir_all(&config.ca.cache_dir)?;
+        let ctx = ProxyContext::new(sx, config.ca.clone(), Arc::new(self.handlers));
+        *ctx.intercept.write()? = config.intercept_rules();
+        *ctx.upstream.write()? = config.upstream_rules()?;
+        *ctx.reverse.write()? = config.reverse_routes()?;
+        *ctx.map_local.write()? = config.map_local_rules()?;
+        *ctx.map_remote.write()? = config.map_remote_rules()?;
+        *ctx.rewrite.write()? = config.rewrite_rules()?;
+        *ctx.fault.write()? = config.fault_rules()?;
+        *ctx.playback.write()? = config.playback_rules()?;
+        let mut addrs = vec![];
+        for (listener, mode) in &listeners { addrs.push((listener.local_addr()?, *mode)); }
+        *ctx.throttle.write()? = config.throttle_rules(&addrs.iter().map(|(addr, _)| *addr).collect::<Vec<_>>());
+        let names: Vec<String> = addrs.iter().map(|(addr, mode)| format!("{}://{}", mode, addr)).collect();
+        let api = match api {
+            Some(listener) => {
+                let addr = listener.local_addr()?;
+                //没有配置令牌时随机生成一个
+                let token = match config.api.token.is_empty() {
+                    true => uuid::Uuid::new_v4().simple().to_string(),
+                    false => config.api.token.clone(),
+                };
+                let (secret, store, ctx, names) = (token.clone(), store.clone(), ctx.clone(), names.clone());
+                tasks.push(tokio::spawn(async move {
+                    api::start_api(listener, secret, store, ctx, names).await.unwrap_or_else(|e| error!("控制接口退出：{}", e.to_string()));
+                }));
+                Some((addr, token))
+            }
+            None => None,
+        };
+        let mut handles = vec![];
+        for (listener, mode) in listeners {
+            let ctx = ctx.clone();
+            handles.push(tokio::spawn(async move {
+                let addr = listener.local_addr().map(|addr| addr.to_string()).unwrap_or_default();
+                start_listener(listener, mode, ctx).await.unwrap_or_else(|e| error!("{}监听失败：{}", addr, e.to_string()));
+            }));
+        }
+        Ok(ProxyHandle { ctx, store, addrs, api, handles, tasks, runtime: Handle::current() })
+    }
+}
+
+//运行中的代理，drop不会停止代理，需要停止时调用shutdown
+pub struct ProxyHandle {
+    ctx: Arc<ProxyContext>,
+    store: Arc<FlowStore>,
+    addrs: Vec<(SocketAddr, ListenMode)>,
+    api: Option<(SocketAddr, String)>,
+    //监听端口的任务
+    handles: Vec<JoinHandle<()>>,
+    //接收抓包数据、控制接口的任务
+    tasks: Vec<JoinHandle<()>>,
+    //代理运行的运行时，界面线程里重放请求时使用
+    runtime: Handle,
+}
+
+impl ProxyHandle {
+    //实际监听的地址，和添加的顺序一致
+    pub fn addrs(&self) -> &[(SocketAddr, ListenMode)] {
+        &self.addrs
+    }
+
+    //第一个监听地址，至少会有一个
+    pub fn addr(&self) -> SocketAddr {
+        self.addrs[0].0
+    }
+
+    //控制接口的地址和令牌
+    pub fn api(&self) -> Option<&(SocketAddr, String)> {
+        self.api.as_ref()
+    }
+
+    //运行时修改解密、上级代理规则
+    pub fn context(&self) -> Arc<ProxyContext> {
+        self.ctx.clone()
+    }
+
+    //重放请求，可以在运行时以外的线程里调用
+    pub fn replayer(&self) -> Replayer {
+        Replayer::new(self.ctx.clone(), self.runtime.clone())
+    }
+
+    //生成的命令通过第一个HTTP代理端口发送，信任代理的根证书
+    pub fn snippet_options(&self) -> SnippetOptions {
+        let options = SnippetOptions::new().with_cacert(&self.ctx.ca.cert);
+        match self.addrs.iter().find(|(_, mode)| matches!(mode, ListenMode::Http | ListenMode::Mixed)) {
+            Some((addr, _)) => options.with_proxy(*addr),
+            None => options,
+        }
+    }
+
+    pub fn store(&self) -> Arc<FlowStore> {
+        self.store.clone()
+    }
+
+    //订阅之后完成的请求
+    pub fn flows(&self) -> FlowStream {
+        self.store.stream()
+    }
+
+    pub fn shutdown(&self) {
+        for handle in self.handles.iter().chain(self.tasks.iter()) { handle.abort(); }
+    }
+
+    //等待所有监听端口退出，正常情况下会一直运行
+    pub async fn wait(self) -> ProxyResult<()> {
+        for handle in self.handles { handle.await?; }
+        Ok(())
+    }
+}
+
+//测试时从代理读取数据，直到以end结尾
+#[cfg(test)]
+pub(crate) async fn read_until(stream: &mut tokio::net::TcpStream, end: &[u8]) -> String {
+    use tokio::io::AsyncReadExt;
+    let mut response = vec![];
+    let mut bs = vec![0; 1024];
+    while !response.ends_with(end) {
+        let len = stream.read(&mut bs).await.unwrap();
+        assert!(len > 0);
+        response.extend_from_slice(&bs[..len]);
+    }
+    String::from_utf8(response).unwrap()
+}
+
+#[cfg(test)]
+mod test_server {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use async_trait::async_trait;
+    use tokio_stream::StreamExt;
+    use crate::data::http::HttpMessage;
+    use crate::handler::{ConnInfo, FlowHandler, HandlerAction};
+    use crate::server::{read_until, ListenMode, ProxyServer};
+
+    //拦截/mock的请求，其他的请求在响应上加一个头
+    struct MockHandler;
+
+    #[async_trait]
+    impl FlowHandler for MockHandler {
+        async fn on_request(&self, _conn: &ConnInfo, request: &mut HttpMessage) -> HandlerAction {
+            match request.head.uri().ends_with("/mock") {
+                true => HandlerAction::Respond(HttpMessage::response(200, "OK", "mocked")),
+                false => HandlerAction::Continue,
+            }
+        }
+
+        async fn on_response(&self, _conn: &ConnInfo, _request: &HttpMessage, response: &mut HttpMessage) -> HandlerAction {
+            response.head.set_header("X-Handled", "1");
+            response.body = b"changed".to_vec();
+            HandlerAction::Continue
+        }
+    }
+
+    #[tokio::test]
+    async fn test_builder() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut bs = vec![0; 1024];
+            let _ = stream.read(&mut bs).await.unwrap();
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello").await.unwrap();
+        });
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let handle = ProxyServer::builder()
+            .listen("127.0.0.1:0", ListenMode::Http)
+            .mitm(false)
+            .on_flow(move |_| { counter.fetch_add(1, Ordering::SeqCst); })
+            .start().await.unwrap();
+        let mut flows = handle.flows();
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+        let request = format!("GET http://{}/hi HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", backend_addr, backend_addr);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        //客户端不关闭时代理会保持连接，读到完整的响应就可以了
+        read_until(&mut stream, b"hello").await;
+        let flow = flows.next().await.unwrap();
+        assert_eq!(flow.status(), Some(200));
+        assert_eq!(flow.path(), "/hi");
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert_eq!(handle.store().len(), 1);
+        handle.shutdown();
+        //没有监听地址时启动失败
+        assert!(ProxyServer::builder().mitm(false).start().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handler() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut bs = vec![0; 1024];
+            let _ = stream.read(&mut bs).await.unwrap();
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello").await.unwrap();
+            let _ = stream.read(&mut bs).await;
+        });
+        let handle = ProxyServer::builder()
+            .listen("127.0.0.1:0", ListenMode::Http)
+            .mitm(false)
+            .handler(MockHandler)
+            .start().await.unwrap();
+        let mut flows = handle.flows();
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+        let request = format!("GET http://{}/hi HTTP/1.1\r\nHost: {}\r\n\r\n", backend_addr, backend_addr);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let response = read_until(&mut stream, b"changed").await;
+        assert!(response.contains("X-Handled: 1") && response.contains("Content-Length: 7"));
+        //同一个连接上的请求被拦截，不会发给服务器
+        let request = format!("GET http://{}/mock HTTP/1.1\r\nHost: {}\r\n\r\n", backend_addr, backend_addr);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        read_until(&mut stream, b"mocked").await;
+        assert_eq!(flows.next().await.unwrap().response.as_ref().unwrap().body, b"changed");
+        let flow = flows.next().await.unwrap();
+        assert_eq!(flow.path(), "/mock");
+        assert_eq!(flow.response.as_ref().unwrap().body, b"mocked");
+        handle.shutdown();
+    }
+}
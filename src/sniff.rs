@@ -0,0 +1,157 @@
+use std::fmt::{Display, Formatter};
+
+//HTTP请求行中可能出现的方法，CONNECT单独处理
+const HTTP_METHODS: [&[u8]; 8] = [b"GET", b"POST", b"PUT", b"DELETE", b"HEAD", b"OPTIONS", b"PATCH", b"TRACE"];
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Protocol {
+    Http,
+    Connect,
+    Socks4,
+    Socks5,
+    //TLS握手的ClientHello，只会出现在隧道里
+    Tls,
+    Unknown,
+}
+
+impl Protocol {
+    //根据连接的前几个字节判断协议，数据不够判断时返回None，需要等待更多数据
+    pub fn sniff(bs: &[u8]) -> Option<Protocol> {
+        match bs.first()? {
+            //socks的第一个字节就是版本号
+            4 => return Some(Protocol::Socks4),
+            5 => return Some(Protocol::Socks5),
+            //TLS记录层：类型0x16(握手)，版本号的第一个字节为0x03
+            0x16 => return match bs.get(1) {
+                None => None,
+                Some(3) => Some(Protocol::Tls),
+                Some(_) => Some(Protocol::Unknown),
+            },
+            _ => {}
+        }
+        if let Some(res) = Protocol::sniff_method(bs, b"CONNECT", Protocol::Connect) { return res; }
+        let mut need_more = false;
+        for method in HTTP_METHODS {
+            match Protocol::sniff_method(bs, method, Protocol::Http) {
+                Some(Some(res)) => return Some(res),
+                Some(None) => need_more = true,
+                None => {}
+            }
+        }
+        if need_more { None } else { Some(Protocol::Unknown) }
+    }
+
+    //请求行是`METHOD[SPACE]`开头的，数据不完整时返回Some(None)，不匹配时返回None
+    fn sniff_method(bs: &[u8], method: &[u8], protocol: Protocol) -> Option<Option<Protocol>> {
+        if bs.len() <= method.len() {
+            return if method.starts_with(bs) { Some(None) } else { None };
+        }
+        match bs.starts_with(method) && bs[method.len()] == b' ' {
+            true => Some(Some(protocol)),
+            false => None
+        }
+    }
+}
+
+/*
+   TLS ClientHello的结构，SNI在server_name(0x0000)扩展里
+   +------+---------+-----+  +------+-----+---------+--------+------------+---------------+-------------+------------+
+   | 0x16 | VERSION | LEN |  | 0x01 | LEN | VERSION | RANDOM | SESSION_ID | CIPHER_SUITES | COMPRESSION | EXTENSIONS |
+   +------+---------+-----+  +------+-----+---------+--------+------------+---------------+-------------+------------+
+      1        2       2        1      3       2        32       1+N           2+N            1+N          2+N
+ */
+//ClientHello还没接收完整时返回Err，由调用方决定是否继续等待；没有SNI时返回Ok(None)
+pub fn parse_sni(bs: &[u8]) -> Result<Option<String>, &'static str> {
+    let record_len = u16::from_be_bytes([*bs.get(3).ok_or("数据不完整")?, *bs.get(4).ok_or("数据不完整")?]) as usize;
+    let record = bs.get(5..5 + record_len).ok_or("数据不完整")?;
+    if record.first() != Some(&0x01) { return Ok(None); }
+    //跳过握手类型、长度、版本号和随机数
+    let mut pos = 1 + 3 + 2 + 32;
+    let session_len = *record.get(pos).ok_or("数据不完整")? as usize;
+    pos += 1 + session_len;
+    let cipher_len = u16::from_be_bytes([*record.get(pos).ok_or("数据不完整")?, *record.get(pos + 1).ok_or("数据不完整")?]) as usize;
+    pos += 2 + cipher_len;
+    let compression_len = *record.get(pos).ok_or("数据不完整")? as usize;
+    pos += 1 + compression_len;
+    //没有扩展
+    if pos + 2 > record.len() { return Ok(None); }
+    let ext_end = (pos + 2 + u16::from_be_bytes([record[pos], record[pos + 1]]) as usize).min(record.len());
+    pos += 2;
+    while pos + 4 <= ext_end {
+        let ext_type = u16::from_be_bytes([record[pos], record[pos + 1]]);
+        let ext_len = u16::from_be_bytes([record[pos + 2], record[pos + 3]]) as usize;
+        pos += 4;
+        let ext = match record.get(pos..pos + ext_len) {
+            Some(ext) => ext,
+            None => return Ok(None),
+        };
+        //server_name扩展：LIST_LEN(2) NAME_TYPE(1) NAME_LEN(2) NAME
+        if ext_type == 0 && ext.len() > 5 && ext[2] == 0 {
+            let name_len = u16::from_be_bytes([ext[3], ext[4]]) as usize;
+            return match ext.get(5..5 + name_len) {
+                Some(name) => Ok(Some(String::from_utf8_lossy(name).to_string())),
+                None => Ok(None),
+            };
+        }
+        pos += ext_len;
+    }
+    Ok(None)
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Protocol::Http => f.write_str("HTTP"),
+            Protocol::Connect => f.write_str("CONNECT"),
+            Protocol::Socks4 => f.write_str("SOCKS4"),
+            Protocol::Socks5 => f.write_str("SOCKS5"),
+            Protocol::Tls => f.write_str("TLS"),
+            Protocol::Unknown => f.write_str("未知协议"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_sniff {
+    use crate::sniff::{parse_sni, Protocol};
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(Protocol::sniff(b"GET http://a.com/ HTTP/1.1\r\n"), Some(Protocol::Http));
+        assert_eq!(Protocol::sniff(b"CONNECT a.com:443 HTTP/1.1\r\n"), Some(Protocol::Connect));
+        assert_eq!(Protocol::sniff(&[5, 1, 0]), Some(Protocol::Socks5));
+        assert_eq!(Protocol::sniff(&[4, 1, 0, 80]), Some(Protocol::Socks4));
+        //数据还不够判断
+        assert_eq!(Protocol::sniff(b"CONN"), None);
+        assert_eq!(Protocol::sniff(b""), None);
+        assert_eq!(Protocol::sniff(b"\x16\x03\x01\x02\x00"), Some(Protocol::Tls));
+        assert_eq!(Protocol::sniff(b"\x16"), None);
+        assert_eq!(Protocol::sniff(b"GETX / HTTP/1.1"), Some(Protocol::Unknown));
+    }
+
+    #[test]
+    fn test_parse_sni() {
+        let name = b"www.baidu.com";
+        //server_name扩展
+        let mut ext = vec![0, 0];
+        ext.extend((name.len() as u16 + 5).to_be_bytes());
+        ext.extend((name.len() as u16 + 3).to_be_bytes());
+        ext.push(0);
+        ext.extend((name.len() as u16).to_be_bytes());
+        ext.extend(name);
+        let mut hello = vec![3, 3];
+        hello.extend([0; 32]);
+        //session_id、cipher_suites、compression
+        hello.extend([0, 0, 2, 0x13, 0x01, 1, 0]);
+        hello.extend((ext.len() as u16).to_be_bytes());
+        hello.extend(ext);
+        let mut handshake = vec![1, 0];
+        handshake.extend((hello.len() as u16).to_be_bytes());
+        handshake.extend(hello);
+        let mut record = vec![0x16, 3, 1];
+        record.extend((handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+        assert_eq!(parse_sni(&record), Ok(Some("www.baidu.com".to_string())));
+        assert!(parse_sni(&record[..20]).is_err());
+    }
+}
@@ -0,0 +1,545 @@
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use crate::data::flow::Flow;
+use crate::data::http::HttpHead;
+use crate::error::ProxyResult;
+
+/*
+    把抓到的请求生成命令或者代码，复制出去重新发送：
+    文本的body直接写在代码里，二进制的body(包括压缩过的)和上传的文件保存成文件，代码里引用文件路径，
+    浏览器里的fetch不能读文件，用base64写在代码里
+ */
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SnippetKind {
+    Curl,
+    Wget,
+    Httpie,
+    Fetch,
+    Python,
+    Reqrio,
+}
+
+impl SnippetKind {
+    pub fn kinds() -> [SnippetKind; 6] {
+        [SnippetKind::Curl, SnippetKind::Wget, SnippetKind::Httpie, SnippetKind::Fetch, SnippetKind::Python, SnippetKind::Reqrio]
+    }
+
+    pub fn parse(name: &str) -> ProxyResult<SnippetKind> {
+        Ok(match name.trim().to_lowercase().as_str() {
+            "curl" => SnippetKind::Curl,
+            "wget" => SnippetKind::Wget,
+            "httpie" | "http" => SnippetKind::Httpie,
+            "fetch" | "js" => SnippetKind::Fetch,
+            "python" | "requests" => SnippetKind::Python,
+            "reqrio" | "rust" => SnippetKind::Reqrio,
+            _ => return Err(format!("代码类型{}错误，可选：curl、wget、httpie、fetch、python、reqrio", name).into()),
+        })
+    }
+}
+
+impl Display for SnippetKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnippetKind::Curl => f.write_str("cURL"),
+            SnippetKind::Wget => f.write_str("wget"),
+            SnippetKind::Httpie => f.write_str("HTTPie"),
+            SnippetKind::Fetch => f.write_str("fetch"),
+            SnippetKind::Python => f.write_str("Python requests"),
+            SnippetKind::Reqrio => f.write_str("Rust reqrio"),
+        }
+    }
+}
+
+//生成的代码通过这个代理发送，信任代理的根证书
+#[derive(Clone, Debug)]
+pub struct SnippetOptions {
+    //如：http://127.0.0.1:7090，为None时直接连接
+    pub proxy: Option<String>,
+    //根证书的路径，只有https的请求需要
+    pub cacert: Option<String>,
+    //body和上传的文件保存的目录
+    pub dir: PathBuf,
+}
+
+impl Default for SnippetOptions {
+    fn default() -> Self {
+        SnippetOptions::new()
+    }
+}
+
+impl SnippetOptions {
+    pub fn new() -> SnippetOptions {
+        SnippetOptions { proxy: None, cacert: None, dir: std::env::temp_dir().join("proxy") }
+    }
+
+    //监听地址是0.0.0.0时连接本机
+    pub fn with_proxy(mut self, addr: SocketAddr) -> SnippetOptions {
+        let ip = match addr.ip() {
+            ip if ip.is_unspecified() && ip.is_ipv4() => "127.0.0.1".to_string(),
+            ip if ip.is_unspecified() => "[::1]".to_string(),
+            ip if ip.is_ipv6() => format!("[{}]", ip),
+            ip => ip.to_string(),
+        };
+        self.proxy = Some(format!("http://{}:{}", ip, addr.port()));
+        self
+    }
+
+    //相对路径转成绝对路径，命令在哪个目录运行都可以
+    pub fn with_cacert(mut self, path: &str) -> SnippetOptions {
+        let path = fs::canonicalize(path).map(|p| p.to_string_lossy().to_string()).unwrap_or(path.to_string());
+        self.cacert = Some(path);
+        self
+    }
+}
+
+pub struct Snippet {
+    pub code: String,
+    //代码里引用的文件，需要保存后代码才能运行
+    pub files: Vec<(String, Vec<u8>)>,
+}
+
+impl Snippet {
+    pub fn save(&self) -> ProxyResult<()> {
+        for (path, data) in &self.files {
+            if let Some(dir) = Path::new(path).parent() { fs::create_dir_all(dir)?; }
+            fs::write(path, data)?;
+        }
+        Ok(())
+    }
+}
+
+//multipart/form-data中的一项，有文件名的是上传的文件
+struct Part {
+    name: String,
+    filename: Option<String>,
+    content_type: Option<String>,
+    data: Vec<u8>,
+}
+
+enum Body {
+    None,
+    Text(String),
+    //保存的文件路径和原始数据
+    Binary(String, Vec<u8>),
+    Multipart(Vec<Part>),
+}
+
+pub fn generate(kind: SnippetKind, flow: &Flow, options: &SnippetOptions) -> Snippet {
+    let mut snippet = Snippet { code: String::new(), files: vec![] };
+    let dir = options.dir.join(format!("flow-{}", flow.id));
+    let request = &flow.request;
+    let content_type = request.head.header("Content-Type").unwrap_or("");
+    let body = match request.body.is_empty() {
+        true => Body::None,
+        //压缩过的body原样发送
+        false if request.head.header("Content-Encoding").is_some() => Body::Binary(file_path(&dir, "body.bin"), request.body.clone()),
+        false => match (parse_multipart(content_type, &request.body), String::from_utf8(request.body.clone())) {
+            //wget和reqrio不支持表单，原样发送body和Content-Type
+            (Some(parts), _) if !matches!(kind, SnippetKind::Wget | SnippetKind::Reqrio) => Body::Multipart(parts),
+            (_, Ok(text)) => Body::Text(text),
+            (_, Err(e)) => Body::Binary(file_path(&dir, "body.bin"), e.into_bytes()),
+        }
+    };
+    let multipart = matches!(body, Body::Multipart(_));
+    //浏览器里的fetch不需要文件
+    if kind != SnippetKind::Fetch {
+        match &body {
+            Body::Binary(path, data) => snippet.files.push((path.clone(), data.clone())),
+            Body::Multipart(parts) => {
+                for (index, part) in parts.iter().enumerate() {
+                    if let Some(filename) = &part.filename {
+                        snippet.files.push((part_path(&dir, index, filename), part.data.clone()));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    let tls = flow.url().starts_with("https://");
+    let cacert = options.cacert.as_ref().filter(|_| tls && options.proxy.is_some());
+    let args = SnippetArgs { flow, headers: headers(&request.head, multipart), body, dir, proxy: options.proxy.as_ref(), cacert };
+    snippet.code = match kind {
+        SnippetKind::Curl => args.curl(),
+        SnippetKind::Wget => args.wget(),
+        SnippetKind::Httpie => args.httpie(),
+        SnippetKind::Fetch => args.fetch(),
+        SnippetKind::Python => args.python(),
+        SnippetKind::Reqrio => args.reqrio(),
+    };
+    snippet
+}
+
+//上传的文件按原来的文件名保存，HTTPie用文件名作为上传的文件名
+fn part_path(dir: &Path, index: usize, filename: &str) -> String {
+    let name: String = filename.chars().map(|c| if c == '/' || c == '\\' || c.is_control() { '_' } else { c }).collect();
+    let name = if name.is_empty() || name == "." || name == ".." { "file".to_string() } else { name };
+    dir.join(format!("part{}", index)).join(name).to_string_lossy().to_string()
+}
+
+fn file_path(dir: &Path, name: &str) -> String {
+    dir.join(name).to_string_lossy().to_string()
+}
+
+//连接相关的头由客户端自己生成，表单的Content-Type里的boundary会重新生成
+fn headers(head: &HttpHead, multipart: bool) -> Vec<(String, String)> {
+    const SKIP: [&str; 6] = ["host", "content-length", "connection", "keep-alive", "transfer-encoding", "expect"];
+    head.headers.iter().filter(|(k, _)| {
+        let key = k.to_lowercase();
+        !(SKIP.contains(&key.as_str()) || key.starts_with("proxy-") || key.starts_with(':') || (multipart && key == "content-type"))
+    }).cloned().collect()
+}
+
+fn parse_multipart(content_type: &str, body: &[u8]) -> Option<Vec<Part>> {
+    if !content_type.trim().to_lowercase().starts_with("multipart/form-data") { return None; }
+    let boundary = content_type.split(';').filter_map(|s| s.trim().split_once('=')).find(|(k, _)| k.trim().eq_ignore_ascii_case("boundary"))?.1.trim().trim_matches('"');
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let mut parts = vec![];
+    let mut rest = &body[find(body, &delimiter)? + delimiter.len()..];
+    //最后一个分隔符后面是--
+    while !rest.starts_with(b"--") {
+        rest = rest.strip_prefix(b"\r\n")?;
+        let end = find(rest, &[b"\r\n", delimiter.as_slice()].concat())?;
+        let (head, data) = rest[..end].split_at(find(&rest[..end], b"\r\n\r\n")? + 4);
+        let head = String::from_utf8_lossy(head);
+        let mut part = Part { name: String::new(), filename: None, content_type: None, data: data.to_vec() };
+        for (key, value) in head.split("\r\n").filter_map(|l| l.split_once(':')) {
+            match key.trim().to_lowercase().as_str() {
+                "content-disposition" => {
+                    for (k, v) in value.split(';').filter_map(|s| s.trim().split_once('=')) {
+                        let v = v.trim().trim_matches('"').to_string();
+                        match k.trim() {
+                            "name" => part.name = v,
+                            "filename" => part.filename = Some(v),
+                            _ => {}
+                        }
+                    }
+                }
+                "content-type" => part.content_type = Some(value.trim().to_string()),
+                //表单里其他的头命令行工具不支持，不能还原
+                _ => return None,
+            }
+        }
+        //不是文本的字段也当作文件上传
+        if part.filename.is_none() && std::str::from_utf8(&part.data).is_err() { part.filename = Some(part.name.clone()); }
+        parts.push(part);
+        rest = &rest[end + 2 + delimiter.len()..];
+    }
+    Some(parts)
+}
+
+fn find(bs: &[u8], pattern: &[u8]) -> Option<usize> {
+    bs.windows(pattern.len()).position(|w| w == pattern)
+}
+
+//命令行参数用单引号，里面的单引号写成'\''
+fn shell(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+//JSON的字符串同时也是合法的JavaScript和Python字符串
+fn quote(s: &str) -> String {
+    serde_json::to_string(s).unwrap_or_default()
+}
+
+struct SnippetArgs<'a> {
+    flow: &'a Flow,
+    headers: Vec<(String, String)>,
+    body: Body,
+    dir: PathBuf,
+    proxy: Option<&'a String>,
+    cacert: Option<&'a String>,
+}
+
+impl SnippetArgs<'_> {
+    fn method(&self) -> &str {
+        self.flow.method()
+    }
+
+    fn part_path(&self, index: usize, part: &Part) -> String {
+        part_path(&self.dir, index, part.filename.as_deref().unwrap_or(""))
+    }
+
+    /*
+        curl 'https://api.example.com/login' \
+          -X PUT \
+          -H 'Content-Type: application/json' \
+          --data-raw '{"a":1}' \
+          -x http://127.0.0.1:7090 --cacert '/path/sca.pem'
+     */
+    fn curl(&self) -> String {
+        let mut args = vec![format!("curl {}", shell(&self.flow.url()))];
+        let has_body = !matches!(self.body, Body::None);
+        match self.method() {
+            "HEAD" => args.push("--head".to_string()),
+            "GET" if !has_body => {}
+            "POST" if has_body => {}
+            method => args.push(format!("-X {}", method)),
+        }
+        for (k, v) in &self.headers {
+            //值为空的头curl要写成Name;
+            args.push(match v.is_empty() {
+                true => format!("-H {}", shell(&format!("{};", k))),
+                false => format!("-H {}", shell(&format!("{}: {}", k, v))),
+            });
+        }
+        if self.headers.iter().any(|(k, _)| k.eq_ignore_ascii_case("Accept-Encoding")) { args.push("--compressed".to_string()); }
+        match &self.body {
+            Body::None => {}
+            Body::Text(text) => args.push(format!("--data-raw {}", shell(text))),
+            Body::Binary(path, _) => args.push(format!("--data-binary {}", shell(&format!("@{}", path)))),
+            Body::Multipart(parts) => {
+                for (index, part) in parts.iter().enumerate() {
+                    match &part.filename {
+                        Some(filename) => {
+                            let mut value = format!("{}=@\"{}\";filename=\"{}\"", part.name, self.part_path(index, part), filename.replace('"', "\\\""));
+                            if let Some(content_type) = &part.content_type { value.push_str(&format!(";type={}", content_type)); }
+                            args.push(format!("-F {}", shell(&value)));
+                        }
+                        //--form-string不会把@和<开头的值当作文件
+                        None => args.push(format!("--form-string {}", shell(&format!("{}={}", part.name, String::from_utf8_lossy(&part.data))))),
+                    }
+                }
+            }
+        }
+        if let Some(proxy) = self.proxy { args.push(format!("-x {}", proxy)); }
+        if let Some(cacert) = self.cacert { args.push(format!("--cacert {}", shell(cacert))); }
+        args.join(" \\\n  ")
+    }
+
+    fn wget(&self) -> String {
+        let mut args = vec!["wget -O -".to_string(), format!("--method={}", self.method())];
+        for (k, v) in &self.headers { args.push(format!("--header={}", shell(&format!("{}: {}", k, v)))); }
+        match &self.body {
+            Body::None | Body::Multipart(_) => {}
+            Body::Text(text) => args.push(format!("--body-data={}", shell(text))),
+            Body::Binary(path, _) => args.push(format!("--body-file={}", shell(path))),
+        }
+        if let Some(proxy) = self.proxy {
+            args.push(format!("-e use_proxy=yes -e http_proxy={} -e https_proxy={}", proxy, proxy));
+        }
+        if let Some(cacert) = self.cacert { args.push(format!("--ca-certificate={}", shell(cacert))); }
+        args.push(shell(&self.flow.url()));
+        args.join(" \\\n  ")
+    }
+
+    fn httpie(&self) -> String {
+        let mut args = vec!["http".to_string()];
+        if matches!(self.body, Body::Multipart(_)) { args.push("--multipart".to_string()); }
+        if let Some(proxy) = self.proxy { args.push(format!("--proxy=http:{} --proxy=https:{}", proxy, proxy)); }
+        if let Some(cacert) = self.cacert { args.push(format!("--verify={}", shell(cacert))); }
+        if let Body::Text(text) = &self.body { args.push(format!("--raw {}", shell(text))); }
+        args.push(format!("{} {}", self.method(), shell(&self.flow.url())));
+        for (k, v) in &self.headers {
+            args.push(match v.is_empty() {
+                true => shell(&format!("{};", k)),
+                false => shell(&format!("{}:{}", k, v)),
+            });
+        }
+        match &self.body {
+            Body::Multipart(parts) => {
+                for (index, part) in parts.iter().enumerate() {
+                    match &part.content_type {
+                        Some(content_type) if part.filename.is_some() => args.push(shell(&format!("{}@{};type={}", part.name, self.part_path(index, part), content_type))),
+                        _ if part.filename.is_some() => args.push(shell(&format!("{}@{}", part.name, self.part_path(index, part)))),
+                        _ => args.push(shell(&format!("{}={}", part.name, String::from_utf8_lossy(&part.data)))),
+                    }
+                }
+            }
+            Body::Binary(path, _) => args.push(format!("< {}", shell(path))),
+            _ => {}
+        }
+        args.join(" \\\n  ")
+    }
+
+    fn fetch(&self) -> String {
+        let bytes = |data: &[u8]| format!("Uint8Array.from(atob({}), c => c.charCodeAt(0))", quote(&STANDARD.encode(data)));
+        let mut code = String::new();
+        //浏览器里不能指定代理，需要在系统或者浏览器里设置
+        if let Some(proxy) = self.proxy { code.push_str(&format!("// 浏览器的代理设置为{}，并信任代理的根证书\n", proxy)); }
+        if let Body::Multipart(parts) = &self.body {
+            code.push_str("const form = new FormData();\n");
+            for part in parts {
+                match &part.filename {
+                    Some(filename) => {
+                        let content_type = part.content_type.as_deref().unwrap_or("application/octet-stream");
+                        code.push_str(&format!("form.append({}, new Blob([{}], {{ type: {} }}), {});\n", quote(&part.name), bytes(&part.data), quote(content_type), quote(filename)));
+                    }
+                    None => code.push_str(&format!("form.append({}, {});\n", quote(&part.name), quote(&String::from_utf8_lossy(&part.data)))),
+                }
+            }
+        }
+        code.push_str(&format!("fetch({}, {{\n  \"method\": {},\n  \"headers\": {{\n", quote(&self.flow.url()), quote(self.method())));
+        let headers: Vec<String> = self.headers.iter().map(|(k, v)| format!("    {}: {}", quote(k), quote(v))).collect();
+        if !headers.is_empty() { code.push_str(&format!("{}\n", headers.join(",\n"))); }
+        code.push_str("  }");
+        match &self.body {
+            Body::None => {}
+            Body::Text(text) => code.push_str(&format!(",\n  \"body\": {}", quote(text))),
+            Body::Binary(_, data) => code.push_str(&format!(",\n  \"body\": {}", bytes(data))),
+            Body::Multipart(_) => code.push_str(",\n  \"body\": form"),
+        }
+        code.push_str("\n});");
+        code
+    }
+
+    fn python(&self) -> String {
+        let mut code = vec!["import requests".to_string(), String::new()];
+        let mut args = vec![quote(self.method()), quote(&self.flow.url())];
+        let headers: Vec<String> = self.headers.iter().map(|(k, v)| format!("    {}: {},", quote(k), quote(v))).collect();
+        code.push(format!("headers = {{\n{}\n}}", headers.join("\n")));
+        args.push("headers=headers".to_string());
+        match &self.body {
+            Body::None => {}
+            Body::Text(text) => {
+                code.push(format!("data = {}.encode(\"utf-8\")", quote(text)));
+                args.push("data=data".to_string());
+            }
+            Body::Binary(path, _) => {
+                code.push(format!("with open({}, \"rb\") as f:\n    data = f.read()", quote(path)));
+                args.push("data=data".to_string());
+            }
+            Body::Multipart(parts) => {
+                let mut data = vec![];
+                let mut files = vec![];
+                for (index, part) in parts.iter().enumerate() {
+                    match &part.filename {
+                        Some(filename) => {
+                            let content_type = part.content_type.as_ref().map(|c| format!(", {}", quote(c))).unwrap_or_default();
+                            files.push(format!("    ({}, ({}, open({}, \"rb\"){})),", quote(&part.name), quote(filename), quote(&self.part_path(index, part)), content_type));
+                        }
+                        None => data.push(format!("    ({}, {}),", quote(&part.name), quote(&String::from_utf8_lossy(&part.data)))),
+                    }
+                }
+                //同名的字段可以有多个，用列表
+                code.push(format!("data = [\n{}\n]", data.join("\n")));
+                code.push(format!("files = [\n{}\n]", files.join("\n")));
+                args.push("data=data".to_string());
+                args.push("files=files".to_string());
+            }
+        }
+        if let Some(proxy) = self.proxy {
+            code.push(format!("proxies = {{\"http\": {}, \"https\": {}}}", quote(proxy), quote(proxy)));
+            args.push("proxies=proxies".to_string());
+        }
+        if let Some(cacert) = self.cacert { args.push(format!("verify={}", quote(cacert))); }
+        code.push(format!("response = requests.request(\n    {},\n)", args.join(",\n    ")));
+        code.push("print(response.status_code)\nprint(response.text)".to_string());
+        code.join("\n")
+    }
+
+    /*
+        reqrio 0.0.6的ScReq：with_url的时候就会连接，代理要在这之前设置；
+        Method只有GET、POST、PUT、DELETE、HEAD、OPTIONS这几个，set_text会把body当作JSON字符串加上引号，
+        所以有body或者其他方法的请求拼好原始的HTTP/1.1请求用h1_io发送
+     */
+    fn reqrio(&self) -> String {
+        const METHODS: [&str; 6] = ["GET", "POST", "PUT", "DELETE", "HEAD", "OPTIONS"];
+        let url = self.flow.url();
+        let raw = !matches!(self.body, Body::None) || !METHODS.contains(&self.method());
+        let mut imports = vec!["Method", "Proxy", "ReqExt", "ScReq"];
+        imports.retain(|name| match *name {
+            "Method" | "ReqExt" => !raw,
+            "Proxy" => self.proxy.is_some(),
+            _ => true,
+        });
+        let mut code = vec![format!("use reqrio::{{{}}};", imports.join(", ")), String::new()];
+        code.push("fn main() -> Result<(), Box<dyn std::error::Error>> {".to_string());
+        //只信任编译进去的webpki根证书
+        if let Some(cacert) = self.cacert { code.push(format!("    // reqrio不能指定根证书，代理解密的HTTPS会握手失败，需要把域名加到不解密的列表里，或者信任{}", cacert)); }
+        code.push("    let mut req = ScReq::new()".to_string());
+        if let Some(proxy) = self.proxy { code.push(format!("        .with_proxy(Proxy::try_from({:?})?)", proxy)); }
+        code.push(format!("        .with_url({:?})?;", url));
+        match raw {
+            true => {
+                let rest = url.split_once("://").map(|(_, rest)| rest).unwrap_or(&url);
+                let (host, path) = match rest.find('/') {
+                    Some(pos) => (&rest[..pos], &rest[pos..]),
+                    None => (rest, "/"),
+                };
+                let host = self.flow.request.head.header("Host").unwrap_or(host);
+                let (body, len) = match &self.body {
+                    Body::None | Body::Multipart(_) => (None, 0),
+                    Body::Text(text) => (Some(format!("{:?}.as_bytes()", text)), text.len()),
+                    Body::Binary(path, data) => (Some(format!("&std::fs::read({:?})?", path)), data.len()),
+                };
+                let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", self.method(), path, host);
+                for (k, v) in &self.headers { head.push_str(&format!("{}: {}\r\n", k, v)); }
+                if body.is_some() || self.method() != "GET" { head.push_str(&format!("Content-Length: {}\r\n", len)); }
+                head.push_str("\r\n");
+                code.push(format!("    let mut request = {:?}.as_bytes().to_vec();", head));
+                if let Some(body) = body { code.push(format!("    request.extend_from_slice({});", body)); }
+                code.push("    let response = req.h1_io(request)?;".to_string());
+            }
+            false => {
+                for (k, v) in &self.headers { code.push(format!("    req.insert_header({:?}, {:?})?;", k, v)); }
+                code.push(format!("    let response = req.send_check(Method::{})?;", self.method()));
+            }
+        }
+        code.push("    println!(\"{}\", response.to_string()?);".to_string());
+        code.push("    Ok(())".to_string());
+        code.push("}".to_string());
+        code.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod test_snippet {
+    use std::path::PathBuf;
+    use crate::data::flow::test_flow;
+    use crate::snippet::{generate, SnippetKind, SnippetOptions};
+
+    #[test]
+    fn test_snippet() {
+        let options = SnippetOptions { proxy: None, cacert: None, dir: PathBuf::from("/tmp/proxy") }.with_proxy("0.0.0.0:7090".parse().unwrap());
+        let options = SnippetOptions { cacert: Some("/etc/sca.pem".to_string()), ..options };
+        let flow = test_flow(3, b"POST /login HTTP/1.1\r\nHost: api.example.com\r\nContent-Type: application/json\r\nProxy-Connection: keep-alive\r\nContent-Length: 15\r\n\r\n{\"name\":\"o'k\"}\n", b"");
+        let curl = generate(SnippetKind::Curl, &flow, &options);
+        assert!(curl.files.is_empty());
+        assert_eq!(curl.code, "curl 'https://api.example.com/login' \\\n  -H 'Content-Type: application/json' \\\n  --data-raw '{\"name\":\"o'\\''k\"}\n' \\\n  -x http://127.0.0.1:7090 \\\n  --cacert '/etc/sca.pem'");
+        let python = generate(SnippetKind::Python, &flow, &options).code;
+        assert!(python.contains("data = \"{\\\"name\\\":\\\"o'k\\\"}\\n\".encode(\"utf-8\")"));
+        assert!(python.contains("verify=\"/etc/sca.pem\""));
+        //上传的文件保存后引用，文本字段直接写
+        let body = b"--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\n@home\r\n--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.png\"\r\nContent-Type: image/png\r\n\r\n\x89PNG\r\n--XyZ--\r\n";
+        let mut request = format!("PUT /upload HTTP/1.1\r\nHost: api.example.com\r\nContent-Type: multipart/form-data; boundary=XyZ\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+        request.extend(body);
+        let flow = test_flow(4, &request, b"");
+        let curl = generate(SnippetKind::Curl, &flow, &options);
+        assert_eq!(curl.files, vec![("/tmp/proxy/flow-4/part1/a.png".to_string(), b"\x89PNG".to_vec())]);
+        assert!(curl.code.contains("-X PUT"));
+        assert!(!curl.code.contains("Content-Type"));
+        assert!(curl.code.contains("--form-string 'title=@home'"));
+        assert!(curl.code.contains("-F 'file=@\"/tmp/proxy/flow-4/part1/a.png\";filename=\"a.png\";type=image/png'"));
+        assert!(generate(SnippetKind::Httpie, &flow, &options).code.contains("'file@/tmp/proxy/flow-4/part1/a.png;type=image/png'"));
+        //wget不支持表单，原样保存body
+        let wget = generate(SnippetKind::Wget, &flow, &options);
+        assert_eq!(wget.files, vec![("/tmp/proxy/flow-4/body.bin".to_string(), body.to_vec())]);
+        assert!(wget.code.contains("--header='Content-Type: multipart/form-data; boundary=XyZ'"));
+        let fetch = generate(SnippetKind::Fetch, &flow, &options);
+        assert!(fetch.files.is_empty());
+        assert!(fetch.code.contains("form.append(\"file\", new Blob([Uint8Array.from(atob(\"iVBORw==\"), c => c.charCodeAt(0))], { type: \"image/png\" }), \"a.png\");"));
+        assert!(SnippetKind::parse("CURL").is_ok() && SnippetKind::parse("java").is_err());
+        //reqrio有body的请求拼成原始请求用h1_io发送，没有body的用send_check
+        let reqrio = generate(SnippetKind::Reqrio, &test_flow(3, b"POST /login HTTP/1.1\r\nHost: api.example.com\r\nContent-Type: application/json\r\nContent-Length: 4\r\n\r\n{\"a\"", b""), &options).code;
+        assert!(reqrio.starts_with("use reqrio::{Proxy, ScReq};"));
+        assert!(reqrio.contains("ScReq::new()\n        .with_proxy(Proxy::try_from(\"http://127.0.0.1:7090\")?)\n        .with_url(\"https://api.example.com/login\")?;"));
+        assert!(reqrio.contains("let mut request = \"POST /login HTTP/1.1\\r\\nHost: api.example.com\\r\\nContent-Type: application/json\\r\\nContent-Length: 4\\r\\n\\r\\n\".as_bytes().to_vec();\n    request.extend_from_slice(\"{\\\"a\\\"\".as_bytes());\n    let response = req.h1_io(request)?;"));
+        let flow = test_flow(5, b"GET /users?id=1 HTTP/1.1\r\nHost: api.example.com\r\nAccept: */*\r\n\r\n", b"");
+        assert_eq!(generate(SnippetKind::Reqrio, &flow, &SnippetOptions::new()).code, [
+            "use reqrio::{Method, ReqExt, ScReq};",
+            "",
+            "fn main() -> Result<(), Box<dyn std::error::Error>> {",
+            "    let mut req = ScReq::new()",
+            "        .with_url(\"https://api.example.com/users?id=1\")?;",
+            "    req.insert_header(\"Accept\", \"*/*\")?;",
+            "    let response = req.send_check(Method::GET)?;",
+            "    println!(\"{}\", response.to_string()?);",
+            "    Ok(())",
+            "}",
+        ].join("\n"));
+    }
+}
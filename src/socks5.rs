@@ -1,72 +1,231 @@
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs};
+use log::{debug, trace};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpStream;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::SystemTime;
+use crate::data::timing::Phase;
 use crate::error::ProxyResult;
+use crate::proxy::ProxyStream;
 
-async fn start_socks5_server() -> ProxyResult<()> {
-    let listen = TcpListener::bind("127.0.0.1:7091").await?;
+//socks4的应答码，0x5A为允许，0x5B为拒绝或失败
+const SOCKS4_GRANTED: u8 = 0x5A;
+const SOCKS4_REJECTED: u8 = 0x5B;
+//userid和socks4a的域名都是以0结尾的字符串，这里限制一下长度，防止恶意客户端一直发送数据
+const SOCKS4_MAX_FIELD: usize = 255;
+
+//读取一个以0结尾的字符串，socks4的userid和socks4a的域名都是这种格式
+async fn read_cstring(inbound: &mut TcpStream) -> ProxyResult<String> {
+    let mut bs = vec![];
     loop {
-        //接受一个新连接
-        let (stream, addr) = listen.accept().await?;
-        println!("{}", addr);
-        //启动一个线程，避免造成其他连接阻塞，影响网络体验
-        tokio::spawn(async move {
-            handle_socks5_client(stream).await.unwrap(); //Broken pipe这个是异常断开，就是我们的浏览器，突然关闭窗口了
-        });
+        let b = inbound.read_u8().await?;
+        if b == 0 { break; }
+        if bs.len() >= SOCKS4_MAX_FIELD { return Err("socks4字段过长".into()); }
+        bs.push(b);
     }
+    Ok(String::from_utf8(bs)?)
 }
 
-async fn handle_socks5_client(mut inbound: TcpStream) -> ProxyResult<()> {
-    let mut buffer = [0; 1024];
-    let len = inbound.read(&mut buffer).await?;
-    //这里还是和之前的一样，先看一下数据
-    println!("{:?}", buffer[..len].to_vec());
-    //我们这里给客户端返回不认证
-    inbound.write(&[5, 0]).await?;
-    inbound.flush().await?;
-    //因为我们选择了不认证，所以这里直接跳过认证环节，客户端会发送请求数据
-    let mut buffer = [0; 1024];
-    let len = inbound.read(&mut buffer).await?;
-    //这里先看一下客户端发过的来数据
-    println!("{:?}", buffer[..len].to_vec());
-    //现在来解析地址
-    let addr = match buffer[3] {
-        //IPv4
-        0x01 => {
-            let host = u32::from_be_bytes(buffer[4..8].try_into().unwrap());
-            let port = u16::from_be_bytes(buffer[8..10].try_into().unwrap());
-            let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(host), port));
-            addr
+impl ProxyStream {
+    /*
+       BYTE       1    1      2        4        N      1     N      1
+              +----+----+---------+--------+--------+----+--------+----+
+       CLIENT | VN | CD | DSTPORT | DSTIP  | USERID | 0  | DOMAIN | 0  |
+              +----+----+---------+--------+--------+----+--------+----+
+       socks4a中DSTIP为0.0.0.x(x不为0)时，USERID后面会跟一个域名
+     */
+    pub(crate) async fn handle_socks4(mut self) -> ProxyResult<()> {
+        let start = SystemTime::now();
+        let mut header = [0; 8];
+        self.inbound.read_exact(&mut header).await?;
+        let cmd = header[1];
+        let port = u16::from_be_bytes([header[2], header[3]]);
+        let ip = Ipv4Addr::new(header[4], header[5], header[6], header[7]);
+        //userid我们这里不做认证，只记录一下
+        let userid = self.socks4_field().await?;
+        trace!("socks4 userid: {}", userid);
+        let octets = ip.octets();
+        let host = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+            //socks4a，由代理来解析域名
+            self.socks4_field().await?
+        } else {
+            ip.to_string()
+        };
+        //socks4只支持CONNECT(0x01)和BIND(0x02)，这里只实现CONNECT
+        if cmd != 0x01 {
+            self.socks4_reply(SOCKS4_REJECTED).await?;
+            return Err(format!("socks4不支持的命令: {}", cmd).into());
+        }
+        debug!("socks4目标地址: {}:{}", host, port);
+        self.param.server = format!("{}:{}", host, port);
+        //有映射规则或者回放记录的域名等到需要的时候再连接，和CONNECT隧道一样
+        let outbound = match self.ctx.connect_later(&host)? {
+            true => None,
+            false => match self.ctx.connect_timed(&host, port, &mut self.param.timing).await {
+                Ok(res) => Some(res),
+                Err(e) => {
+                    self.socks4_reply(SOCKS4_REJECTED).await?;
+                    return Err(e);
+                }
+            }
+        };
+        self.socks4_reply(SOCKS4_GRANTED).await?;
+        self.param.timing.connect = Some(Phase::since(start));
+        self.tunnel(host, outbound).await
+    }
+
+    //过长或者不是UTF-8的字段要先告诉客户端拒绝再断开
+    async fn socks4_field(&mut self) -> ProxyResult<String> {
+        match read_cstring(&mut self.inbound).await {
+            Ok(field) => Ok(field),
+            Err(e) => {
+                //客户端可能已经断开了，这时发送失败不用管，返回原来的错误
+                let _ = self.socks4_reply(SOCKS4_REJECTED).await;
+                Err(e)
+            }
         }
-        //域名，第一个字节为长度
-        0x03 => {
-            let len = buffer[4] as usize;
-            let domain = String::from_utf8(buffer[5..len + 5].to_vec())?;
-            let port = u16::from_be_bytes(buffer[len + 5..len + 7].try_into().unwrap());
-            //这里做了一个域名解析，我们以IPv4为例，暂不支持IPv6
-            let addr = format!("{}:{}", domain, port).to_socket_addrs()?.find(|x| x.is_ipv4()).ok_or("解析IPv4地址失败")?;
-            addr
+    }
+
+    //socks4的应答，VN为0，后面的端口和地址客户端会忽略
+    async fn socks4_reply(&mut self, code: u8) -> ProxyResult<()> {
+        self.inbound.write_all(&[0, code, 0, 0, 0, 0, 0, 0]).await?;
+        self.inbound.flush().await?;
+        if code != SOCKS4_GRANTED { self.inbound.shutdown().await?; }
+        Ok(())
+    }
+
+    pub(crate) async fn handle_socks5(mut self) -> ProxyResult<()> {
+        let start = SystemTime::now();
+        let mut header = [0; 2];
+        self.inbound.read_exact(&mut header).await?;
+        let mut methods = vec![0; header[1] as usize];
+        self.inbound.read_exact(&mut methods).await?;
+        //这里还是和之前的一样，先看一下数据
+        trace!("socks5认证方法: {:?}", methods);
+        //我们这里只支持不认证，客户端没有提供不认证时返回0xFF
+        if !methods.contains(&0) {
+            self.inbound.write_all(&[5, 0xFF]).await?;
+            self.inbound.shutdown().await?;
+            return Err("客户端不支持无认证的socks5".into());
+        }
+        self.inbound.write_all(&[5, 0]).await?;
+        self.inbound.flush().await?;
+        //因为我们选择了不认证，所以这里直接跳过认证环节，客户端会发送请求数据
+        let mut request = [0; 4];
+        self.inbound.read_exact(&mut request).await?;
+        //现在来解析地址
+        let host = match request[3] {
+            //IPv4
+            0x01 => {
+                let mut ip = [0; 4];
+                self.inbound.read_exact(&mut ip).await?;
+                Ipv4Addr::from(ip).to_string()
+            }
+            //域名，第一个字节为长度
+            0x03 => {
+                let len = self.inbound.read_u8().await? as usize;
+                let mut domain = vec![0; len];
+                self.inbound.read_exact(&mut domain).await?;
+                String::from_utf8(domain)?
+            }
+            //IPv6
+            0x04 => {
+                let mut ip = [0; 16];
+                self.inbound.read_exact(&mut ip).await?;
+                format!("[{}]", Ipv6Addr::from(ip))
+            }
+            _ => {
+                //这里我们做一个不支持的地址类型
+                self.socks5_reply(8).await?;
+                return Err("地址类型不支持".into());
+            }
+        };
+        let port = self.inbound.read_u16().await?;
+        if request[1] != 0x01 {
+            self.socks5_reply(7).await?;
+            return Err(format!("socks5不支持的命令: {}", request[1]).into());
         }
-        _ => {
-            //这里我们做一个不支持的地址类型
-            inbound.write(&[5, 8, 0, 1, 127, 0, 0, 1, 0, 80]).await?; //这里的127,0,0,1,0,80为地址127.0.0.1:80无意义
-            inbound.shutdown().await?;
-            return Err("地址类型不支持".into());
+        debug!("socks5目标地址: {}:{}", host, port);
+        self.param.server = format!("{}:{}", host, port);
+        //建立连接，并返回
+        let outbound = match self.ctx.connect_later(&host)? {
+            true => None,
+            false => match self.ctx.connect_timed(&host, port, &mut self.param.timing).await {
+                Ok(res) => Some(res),
+                Err(e) => {
+                    //连接目标地址失败
+                    self.socks5_reply(5).await?;
+                    return Err(e);
+                }
+            }
+        };
+        self.socks5_reply(0).await?;
+        self.param.timing.connect = Some(Phase::since(start));
+        //这里我们就完成了socks5代理的建立，后面和CONNECT隧道一样处理
+        self.tunnel(host, outbound).await
+    }
+
+    //这里的127,0,0,1,0,80为地址127.0.0.1:80无意义
+    async fn socks5_reply(&mut self, rep: u8) -> ProxyResult<()> {
+        self.inbound.write_all(&[5, rep, 0, 1, 127, 0, 0, 1, 0, 80]).await?;
+        self.inbound.flush().await?;
+        if rep != 0 { self.inbound.shutdown().await?; }
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod test_socks5 {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use crate::server::{ListenMode, ProxyServer, ProxyHandle};
+
+    async fn start_socks() -> ProxyHandle {
+        ProxyServer::builder().listen("127.0.0.1:0", ListenMode::Socks).mitm(false).start().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_socks4() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = backend.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut bs = vec![0; 1024];
+            let _ = stream.read(&mut bs).await.unwrap();
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello").await.unwrap();
+            let _ = stream.read(&mut bs).await;
+        });
+        let handle = start_socks().await;
+        //socks4a：地址是0.0.0.1，userid后面跟着由代理解析的域名
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+        let mut request = vec![4, 1];
+        request.extend_from_slice(&port.to_be_bytes());
+        request.extend_from_slice(&[0, 0, 0, 1]);
+        request.extend_from_slice(b"user\0localhost\0");
+        stream.write_all(&request).await.unwrap();
+        let mut reply = [0; 8];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [0, 0x5A]);
+        stream.write_all(b"GET /hi HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut response = vec![];
+        while !response.ends_with(b"hello") {
+            let mut bs = [0; 1024];
+            let len = stream.read(&mut bs).await.unwrap();
+            assert!(len > 0);
+            response.extend_from_slice(&bs[..len]);
         }
-    };
-    println!("{}", addr);
-    //建立连接，并返回
-    let outbound = match TcpStream::connect(addr).await {
-        Ok(res) => res,
-        Err(e) => {
-            //连接目标地址失败
-            inbound.write(&[5, 5, 0, 1, 127, 0, 0, 1, 0, 80]).await?;
-            inbound.shutdown().await?;
-            return Err(e.into());
+        //超过长度的userid、不是UTF-8的域名返回拒绝后断开，不会连接服务器
+        for (ip, fields) in [([127, 0, 0, 1], [vec![b'u'; 300], vec![0]].concat()), ([0, 0, 0, 1], b"user\0\xff\xfe\0".to_vec())] {
+            let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+            let mut request = vec![4, 1];
+            request.extend_from_slice(&port.to_be_bytes());
+            request.extend_from_slice(&ip);
+            request.extend_from_slice(&fields);
+            stream.write_all(&request).await.unwrap();
+            let mut reply = [0; 8];
+            stream.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply[..2], [0, 0x5B]);
         }
-    };
-    inbound.write(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80]).await?; //这里的127,0,0,1,0,80为地址127.0.0.1:80无意义
-    //这里我们就完成了socks5代理的建立
-    copy_io(inbound, outbound).await?;
-    Ok(())
-}
\ No newline at end of file
+        handle.shutdown();
+    }
+}
@@ -0,0 +1,236 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+use tokio::time::sleep;
+use uuid::Uuid;
+use crate::proxy::Direction;
+use crate::rule::HostPattern;
+use crate::upstream::split_host_port;
+
+//限速时按一个TCP报文的大小分块转发，丢包也是按块计算的
+pub const THROTTLE_CHUNK: usize = 1460;
+//超过这个时间没有数据算作空闲，之后的第一块数据加上延迟
+const IDLE: Duration = Duration::from_millis(100);
+
+/*
+    网络环境，模拟手机等慢速网络：
+    down_kbps/up_kbps  下行/上行带宽，单位kbps(1000位每秒)，为0时不限速
+    latency_ms         增加的往返延迟，两个方向各一半，只加在空闲后的第一块数据上
+    jitter_ms          延迟的随机抖动，0到jitter_ms之间
+    loss               丢包率(百分比)，每块数据按这个概率停顿stall_ms，模拟丢包重传
+ */
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkProfile {
+    pub name: String,
+    #[serde(default)]
+    pub down_kbps: u32,
+    #[serde(default)]
+    pub up_kbps: u32,
+    #[serde(default)]
+    pub latency_ms: u32,
+    #[serde(default)]
+    pub jitter_ms: u32,
+    #[serde(default)]
+    pub loss: f64,
+    #[serde(default = "default_stall")]
+    pub stall_ms: u32,
+}
+
+fn default_stall() -> u32 { 1000 }
+
+impl NetworkProfile {
+    pub fn new(name: impl Into<String>, down_kbps: u32, up_kbps: u32, latency_ms: u32) -> NetworkProfile {
+        NetworkProfile { name: name.into(), down_kbps, up_kbps, latency_ms, jitter_ms: 0, loss: 0.0, stall_ms: default_stall() }
+    }
+
+    //内置的网络环境，配置文件里同名的会替换
+    pub fn presets() -> Vec<NetworkProfile> {
+        vec![
+            NetworkProfile::new("gprs", 50, 20, 500),
+            NetworkProfile::new("edge", 240, 200, 400),
+            NetworkProfile::new("3g", 780, 330, 200),
+            NetworkProfile::new("4g", 9000, 9000, 85),
+            NetworkProfile::new("dsl", 2000, 256, 50),
+            NetworkProfile::new("wifi", 30000, 15000, 2),
+        ]
+    }
+
+    //每秒的字节数，客户端发出的数据是上行
+    fn rate(&self, direction: &Direction) -> f64 {
+        let kbps = match direction {
+            Direction::ClientToServer => self.up_kbps,
+            Direction::ServerToClient => self.down_kbps,
+        };
+        kbps as f64 * 1000.0 / 8.0
+    }
+}
+
+//按监听地址、域名选择网络环境，界面上选择的最优先，然后是域名
+pub struct ThrottleRules {
+    //为false时所有连接都不限速，界面上切换
+    pub enabled: bool,
+    //界面上选择的网络环境，所有的连接都使用，不管域名和监听地址的配置
+    pub forced: Option<String>,
+    pub profiles: Vec<NetworkProfile>,
+    //没有匹配的监听地址和域名时使用，为空时不限速
+    pub default: Option<String>,
+    pub listeners: Vec<(SocketAddr, String)>,
+    pub hosts: Vec<(HostPattern, String)>,
+}
+
+impl ThrottleRules {
+    pub fn new() -> ThrottleRules {
+        ThrottleRules { enabled: true, forced: None, profiles: NetworkProfile::presets(), default: None, listeners: vec![], hosts: vec![] }
+    }
+
+    pub fn profile(&self, name: &str) -> Option<&NetworkProfile> {
+        self.profiles.iter().find(|profile| profile.name.eq_ignore_ascii_case(name))
+    }
+
+    //同名的替换内置的
+    pub fn add_profile(&mut self, profile: NetworkProfile) {
+        match self.profiles.iter_mut().find(|p| p.name.eq_ignore_ascii_case(&profile.name)) {
+            Some(p) => *p = profile,
+            None => self.profiles.push(profile),
+        }
+    }
+
+    //server是目标地址host:port，listener是连接进来的监听地址
+    pub fn select(&self, listener: Option<SocketAddr>, server: &str) -> Option<NetworkProfile> {
+        if !self.enabled { return None; }
+        let host = split_host_port(server, 80).map(|(host, _)| host).unwrap_or_default();
+        let name = self.forced.as_ref()
+            .or_else(|| self.hosts.iter().find(|(pattern, _)| pattern.matches(&host)).map(|(_, name)| name))
+            .or_else(|| self.listeners.iter().find(|(addr, _)| Some(*addr) == listener).map(|(_, name)| name))
+            .or(self.default.as_ref())?;
+        self.profile(name).cloned()
+    }
+}
+
+impl Default for ThrottleRules {
+    fn default() -> Self {
+        ThrottleRules::new()
+    }
+}
+
+//每个连接的每个方向一个，令牌桶限速
+#[derive(Default)]
+pub struct Throttle {
+    //可以发送的字节数，最多是一个突发的大小
+    tokens: f64,
+    //上一次发送完的时间，判断是否空闲
+    last: Option<Instant>,
+}
+
+impl Throttle {
+    pub fn new() -> Throttle {
+        Throttle::default()
+    }
+
+    //发送len字节之前调用，按网络环境等待
+    pub async fn wait(&mut self, profile: &NetworkProfile, direction: &Direction, len: usize) {
+        let now = Instant::now();
+        let idle = self.last.map(|last| now.duration_since(last) >= IDLE).unwrap_or(true);
+        let mut delay = Duration::ZERO;
+        if idle {
+            let jitter = random() * profile.jitter_ms as f64;
+            delay += Duration::from_secs_f64((profile.latency_ms as f64 / 2.0 + jitter) / 1000.0);
+        }
+        if profile.loss > 0.0 && random() * 100.0 < profile.loss { delay += Duration::from_millis(profile.stall_ms as u64); }
+        let rate = profile.rate(direction);
+        if rate > 0.0 {
+            //突发的大小是100毫秒的数据量，至少一块
+            let capacity = (rate / 10.0).max(THROTTLE_CHUNK as f64);
+            self.tokens = match self.last {
+                Some(last) if !idle => (self.tokens + now.duration_since(last).as_secs_f64() * rate).min(capacity),
+                _ => capacity,
+            };
+            self.tokens -= len as f64;
+            //不够的部分按速率等待，等待完正好用完
+            if self.tokens < 0.0 {
+                delay += Duration::from_secs_f64(-self.tokens / rate);
+                self.tokens = 0.0;
+            }
+        }
+        if !delay.is_zero() { sleep(delay).await; }
+        self.last = Some(Instant::now());
+    }
+}
+
+//0到1之间的随机数，丢包、抖动和故障注入用，不需要很好的随机性
+pub(crate) fn random() -> f64 {
+    (Uuid::new_v4().as_u64_pair().0 >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod test_throttle {
+    use std::time::Instant;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use crate::proxy::Direction;
+    use crate::rule::HostPattern;
+    use crate::server::{read_until, ListenMode, ProxyServer};
+    use crate::throttle::{NetworkProfile, Throttle, ThrottleRules};
+
+    #[tokio::test]
+    async fn test_throttle() {
+        let mut rules = ThrottleRules::new();
+        let listener = "127.0.0.1:7090".parse().unwrap();
+        rules.listeners.push((listener, "3g".to_string()));
+        rules.hosts.push((HostPattern::new("*.example.com"), "edge".to_string()));
+        rules.add_profile(NetworkProfile::new("3G", 100, 80, 0));
+        assert_eq!(rules.select(Some(listener), "api.example.com:443").unwrap().name, "edge");
+        assert_eq!(rules.select(Some(listener), "example.org:80").unwrap().down_kbps, 100);
+        assert!(rules.select(None, "example.org:80").is_none());
+        rules.default = Some("wifi".to_string());
+        assert_eq!(rules.select(None, "example.org").unwrap().name, "wifi");
+        //界面上选择的比域名和监听地址的配置优先
+        rules.forced = Some("wifi".to_string());
+        assert_eq!(rules.select(Some(listener), "api.example.com:443").unwrap().name, "wifi");
+        rules.enabled = false;
+        assert!(rules.select(Some(listener), "api.example.com:443").is_none());
+        //80kbps是每秒10000字节，第一块用完突发的1460字节，后面的4000字节需要等待400毫秒
+        let profile = rules.profile("3g").unwrap().clone();
+        let mut throttle = Throttle::new();
+        let start = Instant::now();
+        throttle.wait(&profile, &Direction::ClientToServer, 1460).await;
+        for _ in 0..4 { throttle.wait(&profile, &Direction::ClientToServer, 1000).await; }
+        let elapsed = start.elapsed().as_millis();
+        assert!((380..800).contains(&elapsed), "{}", elapsed);
+        //空闲后的第一块数据加上一半的往返延迟
+        let start = Instant::now();
+        Throttle::new().wait(&NetworkProfile::new("slow", 0, 0, 200), &Direction::ServerToClient, 100).await;
+        assert!(start.elapsed().as_millis() >= 100);
+    }
+
+    //不解密的隧道只转发，也要按网络环境限速
+    #[tokio::test]
+    async fn test_throttle_tunnel() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut data = vec![0; 5460];
+            stream.read_exact(&mut data).await.unwrap();
+            stream.write_all(&data).await.unwrap();
+            let _ = stream.read(&mut data).await;
+        });
+        let handle = ProxyServer::builder()
+            .listen("127.0.0.1:0", ListenMode::Http)
+            .mitm(false)
+            .network_profile(NetworkProfile::new("slow", 80, 80, 0))
+            .throttle("slow")
+            .start().await.unwrap();
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+        stream.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", backend_addr).as_bytes()).await.unwrap();
+        read_until(&mut stream, b"\r\n\r\n").await;
+        //80kbps是每秒10000字节，除了突发的1460字节，每个方向的4000字节都要等待400毫秒
+        let start = Instant::now();
+        stream.write_all(&[0xAA; 5460]).await.unwrap();
+        let mut data = vec![0; 5460];
+        stream.read_exact(&mut data).await.unwrap();
+        assert!(start.elapsed().as_millis() >= 700, "{:?}", start.elapsed());
+        handle.shutdown();
+    }
+}
@@ -0,0 +1,138 @@
+use std::net::SocketAddr;
+use log::debug;
+use tokio::net::{TcpListener, TcpStream};
+use crate::error::ProxyResult;
+use crate::proxy::ProxyStream;
+
+/*
+   透明代理，客户端不需要配置代理，由iptables/nftables把流量转发到这里
+   REDIRECT: iptables -t nat -A PREROUTING -p tcp --dport 443 -j REDIRECT --to-ports 7092
+             连接的目标地址被改成了本机，原始目标地址要通过SO_ORIGINAL_DST获取
+   TPROXY:   iptables -t mangle -A PREROUTING -p tcp --dport 443 -j TPROXY --on-port 7093 --tproxy-mark 1
+             ip rule add fwmark 1 lookup 100 && ip route add local 0.0.0.0/0 dev lo table 100
+             连接的本地地址就是原始目标地址，但是监听端口需要设置IP_TRANSPARENT
+ */
+#[cfg(target_os = "linux")]
+pub fn original_dst(stream: &TcpStream) -> ProxyResult<SocketAddr> {
+    use std::os::fd::AsRawFd;
+    let level = match stream.local_addr()?.is_ipv4() {
+        true => libc::SOL_IP,
+        false => libc::SOL_IPV6,
+    };
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    //IPv4和IPv6的选项值都是80，只是level不一样
+    let res = unsafe { libc::getsockopt(stream.as_raw_fd(), level, libc::SO_ORIGINAL_DST, &mut addr as *mut _ as *mut libc::c_void, &mut len) };
+    if res != 0 { return Err(format!("获取原始目标地址失败：{}", std::io::Error::last_os_error()).into()); }
+    sockaddr_to_addr(&addr)
+}
+
+//getsockopt返回的地址，端口和IPv4地址都是网络字节序
+#[cfg(target_os = "linux")]
+fn sockaddr_to_addr(addr: &libc::sockaddr_storage) -> ProxyResult<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+    match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            Ok(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(addr.sin6_port), 0, 0)))
+        }
+        family => Err(format!("不支持的地址类型：{}", family).into())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn original_dst(_stream: &TcpStream) -> ProxyResult<SocketAddr> {
+    Err("透明代理只支持Linux".into())
+}
+
+//TPROXY需要在bind之前给监听端口设置IP_TRANSPARENT，这个需要CAP_NET_ADMIN权限
+#[cfg(target_os = "linux")]
+pub fn bind_tproxy(addr: SocketAddr) -> ProxyResult<TcpListener> {
+    use std::os::fd::AsRawFd;
+    let socket = match addr {
+        SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6()?,
+    };
+    let (level, name) = match addr {
+        SocketAddr::V4(_) => (libc::SOL_IP, libc::IP_TRANSPARENT),
+        SocketAddr::V6(_) => (libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+    };
+    let enable: libc::c_int = 1;
+    let res = unsafe {
+        libc::setsockopt(socket.as_raw_fd(), level, name, &enable as *const _ as *const libc::c_void, size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if res != 0 { return Err(format!("设置IP_TRANSPARENT失败(需要root或CAP_NET_ADMIN)：{}", std::io::Error::last_os_error()).into()); }
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    Ok(socket.listen(1024)?)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind_tproxy(_addr: SocketAddr) -> ProxyResult<TcpListener> {
+    Err("TPROXY只支持Linux".into())
+}
+
+impl ProxyStream {
+    //透明代理没有CONNECT和绝对地址，只能通过原始目标地址连接服务器，后面和隧道的处理一样(按SNI解密或者抓取明文HTTP)
+    pub async fn start_transparent(mut self, tproxy: bool, listen: SocketAddr) -> ProxyResult<()> {
+        let dst = match tproxy {
+            true => self.inbound.local_addr()?,
+            false => original_dst(&self.inbound)?,
+        };
+        //直接访问透明代理端口时原始地址就是自己，不处理的话会自己连自己一直循环下去
+        if dst.port() == listen.port() && (listen.ip().is_unspecified() || dst.ip() == listen.ip()) {
+            return Err(format!("{}不是重定向过来的连接，透明代理端口不能直接访问", dst).into());
+        }
+        debug!("透明代理原始目标地址：{}", dst);
+        let host = match dst {
+            SocketAddr::V4(addr) => addr.ip().to_string(),
+            SocketAddr::V6(addr) => format!("[{}]", addr.ip()),
+        };
+        self.param.server = dst.to_string();
+        let outbound = self.ctx.connect_timed(&host, dst.port(), &mut self.param.timing).await?;
+        self.tunnel(host, Some(outbound)).await
+    }
+}
+
+#[cfg(test)]
+mod test_transparent {
+    use tokio::net::{TcpListener, TcpStream};
+    use crate::transparent::original_dst;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_sockaddr() {
+        use std::net::SocketAddr;
+        use crate::transparent::sockaddr_to_addr;
+        let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let addr = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+        addr.sin_family = libc::AF_INET as libc::sa_family_t;
+        addr.sin_port = 443u16.to_be();
+        addr.sin_addr.s_addr = u32::from_be_bytes([93, 184, 216, 34]).to_be();
+        assert_eq!(sockaddr_to_addr(&storage).unwrap(), "93.184.216.34:443".parse::<SocketAddr>().unwrap());
+        let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let addr = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+        addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+        addr.sin6_port = 8443u16.to_be();
+        addr.sin6_addr.s6_addr = "2001:db8::1".parse::<std::net::Ipv6Addr>().unwrap().octets();
+        assert_eq!(sockaddr_to_addr(&storage).unwrap(), "[2001:db8::1]:8443".parse::<SocketAddr>().unwrap());
+        storage.ss_family = libc::AF_UNIX as libc::sa_family_t;
+        assert!(sockaddr_to_addr(&storage).is_err());
+    }
+
+    //没有经过iptables重定向的连接拿不到原始地址，非Linux系统直接报错
+    #[tokio::test]
+    async fn test_original_dst() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        assert!(original_dst(&stream).is_err());
+        #[cfg(not(target_os = "linux"))]
+        assert!(crate::transparent::bind_tproxy("127.0.0.1:0".parse().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,294 @@
+use std::sync::Arc;
+use std::time::Duration;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph, Row, Table, TableState, Tabs, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use crate::data::flow::{format_size, Flow};
+use crate::data::store::FlowStore;
+use crate::data::ui::{ProxyTab, TabContent};
+use crate::error::ProxyResult;
+use crate::filter::Filter;
+
+//没有按键时的刷新间隔，新的抓包记录会在这个时间内显示出来
+const REFRESH: Duration = Duration::from_millis(200);
+//PgUp/PgDn一次移动的行数
+const PAGE: i64 = 20;
+
+const HELP: &str = " ↑↓/jk 选择  PgUp/PgDn 翻页  Tab/←→ 切换标签  J/K 滚动详情  / 过滤  f 跟随  c 清空  q 退出";
+
+#[derive(Eq, PartialEq)]
+enum Focus {
+    List,
+    //正在编辑过滤表达式
+    Filter,
+}
+
+/*
+   终端界面，布局和ProxyView一样：
+   ---------------------------------------------
+   | 过滤表达式                                 |
+   ---------------------------------------------
+   | 请求列表            | 标头|负载|预览|...    |
+   |                    | [对应页面]            |
+   ---------------------------------------------
+ */
+pub struct ProxyTui {
+    store: Arc<FlowStore>,
+    //标题栏显示的监听地址
+    listeners: String,
+    //当前过滤后的记录，每次刷新时从store重新获取
+    flows: Vec<Arc<Flow>>,
+    //按id记录选中的请求，列表变化时选中的还是同一个
+    selected: Option<u64>,
+    table: TableState,
+    //自动选中最新的请求
+    follow: bool,
+    filter: Filter,
+    filter_text: String,
+    input: String,
+    filter_error: Option<String>,
+    focus: Focus,
+    view_tab: ProxyTab,
+    detail_scroll: u16,
+    quit: bool,
+}
+
+//会阻塞当前线程，直到按q退出
+pub fn run(store: Arc<FlowStore>, listeners: String) -> ProxyResult<()> {
+    let mut terminal = ratatui::init();
+    let mut tui = ProxyTui::new(store, listeners);
+    let res = tui.run(&mut terminal);
+    //不管是否出错都要恢复终端，否则退出后终端没法正常使用
+    ratatui::restore();
+    res
+}
+
+impl ProxyTui {
+    pub fn new(store: Arc<FlowStore>, listeners: String) -> ProxyTui {
+        ProxyTui {
+            store,
+            listeners,
+            flows: vec![],
+            selected: None,
+            table: TableState::default(),
+            follow: true,
+            filter: Filter::All,
+            filter_text: String::new(),
+            input: String::new(),
+            filter_error: None,
+            focus: Focus::List,
+            view_tab: ProxyTab::Header,
+            detail_scroll: 0,
+            quit: false,
+        }
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> ProxyResult<()> {
+        while !self.quit {
+            self.refresh();
+            terminal.draw(|frame| self.draw(frame))?;
+            if !event::poll(REFRESH)? { continue; }
+            if let Event::Key(key) = event::read()? && key.kind == KeyEventKind::Press { self.on_key(key); }
+        }
+        Ok(())
+    }
+
+    fn refresh(&mut self) {
+        self.flows = self.store.list(&self.filter);
+        if self.follow || self.selected.is_none() { self.selected = self.flows.last().map(|flow| flow.id); }
+        self.table.select(self.selected_index());
+    }
+
+    fn selected_index(&self) -> Option<usize> {
+        let id = self.selected?;
+        self.flows.iter().position(|flow| flow.id == id)
+    }
+
+    fn selected_flow(&self) -> Option<&Arc<Flow>> {
+        self.flows.get(self.selected_index()?)
+    }
+
+    fn move_selection(&mut self, delta: i64) {
+        if self.flows.is_empty() { return; }
+        let last = self.flows.len() as i64 - 1;
+        let index = self.selected_index().map(|i| i as i64).unwrap_or(last);
+        let index = (index + delta).clamp(0, last);
+        self.selected = Some(self.flows[index as usize].id);
+        //移动到最后一个时继续跟随最新的请求
+        self.follow = index == last;
+        self.detail_scroll = 0;
+    }
+
+    fn switch_tab(&mut self, delta: i64) {
+        let tabs = ProxyTab::tabs();
+        let index = tabs.iter().position(|tab| *tab == self.view_tab).unwrap_or(0) as i64;
+        let index = (index + delta).rem_euclid(tabs.len() as i64) as usize;
+        self.view_tab = tabs[index].clone();
+        self.detail_scroll = 0;
+    }
+
+    fn on_key(&mut self, key: KeyEvent) {
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            self.quit = true;
+            return;
+        }
+        match self.focus {
+            Focus::Filter => self.on_filter_key(key),
+            Focus::List => self.on_list_key(key),
+        }
+    }
+
+    fn on_filter_key(&mut self, key: KeyEvent) {
+        match key.code {
+            KeyCode::Enter => match Filter::parse(&self.input) {
+                Ok(filter) => {
+                    self.filter = filter;
+                    self.filter_text = self.input.clone();
+                    self.filter_error = None;
+                    self.focus = Focus::List;
+                }
+                Err(e) => self.filter_error = Some(e.to_string()),
+            },
+            KeyCode::Esc => {
+                self.filter_error = None;
+                self.focus = Focus::List;
+            }
+            KeyCode::Backspace => { self.input.pop(); }
+            KeyCode::Char(c) => self.input.push(c),
+            _ => {}
+        }
+    }
+
+    fn on_list_key(&mut self, key: KeyEvent) {
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+            KeyCode::Down | KeyCode::Char('j') => self.move_selection(1),
+            KeyCode::Up | KeyCode::Char('k') => self.move_selection(-1),
+            KeyCode::PageDown => self.move_selection(PAGE),
+            KeyCode::PageUp => self.move_selection(-PAGE),
+            KeyCode::Home | KeyCode::Char('g') => self.move_selection(i64::MIN / 2),
+            KeyCode::End | KeyCode::Char('G') => self.move_selection(i64::MAX / 2),
+            KeyCode::Tab | KeyCode::Right | KeyCode::Char('l') => self.switch_tab(1),
+            KeyCode::BackTab | KeyCode::Left | KeyCode::Char('h') => self.switch_tab(-1),
+            KeyCode::Char('J') => self.detail_scroll = self.detail_scroll.saturating_add(1),
+            KeyCode::Char('K') => self.detail_scroll = self.detail_scroll.saturating_sub(1),
+            KeyCode::Char('f') => self.follow = !self.follow,
+            KeyCode::Char('c') => {
+                self.store.clear();
+                self.selected = None;
+            }
+            KeyCode::Char('/') => {
+                self.input = self.filter_text.clone();
+                self.focus = Focus::Filter;
+            }
+            KeyCode::Char(c) if c.is_ascii_digit() => {
+                let tabs = ProxyTab::tabs();
+                if let Some(tab) = c.to_digit(10).and_then(|i| tabs.get((i as usize).wrapping_sub(1))) {
+                    self.view_tab = tab.clone();
+                    self.detail_scroll = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [top, main, bottom] = Layout::vertical([Constraint::Length(3), Constraint::Min(5), Constraint::Length(1)]).areas(frame.area());
+        let [left, right] = Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(main);
+        self.draw_filter(frame, top);
+        self.draw_list(frame, left);
+        self.draw_detail(frame, right);
+        frame.render_widget(Paragraph::new(HELP).style(Style::new().fg(Color::DarkGray)), bottom);
+    }
+
+    fn draw_filter(&self, frame: &mut Frame, area: Rect) {
+        let title = format!(" Proxy {} | {}/{}{} ", self.listeners, self.flows.len(), self.store.len(), if self.follow { " | 跟随" } else { "" });
+        let mut spans = match self.focus {
+            Focus::Filter => vec![Span::raw(self.input.as_str()), Span::raw("_").add_modifier(Modifier::SLOW_BLINK)],
+            Focus::List if self.filter_text.is_empty() => vec![Span::raw("按 / 输入过滤表达式，如：host:*.example.com status>=400").fg(Color::DarkGray)],
+            Focus::List => vec![Span::raw(self.filter_text.as_str())],
+        };
+        if let Some(error) = &self.filter_error { spans.push(Span::raw(format!("  {}", error)).fg(Color::Red)); }
+        let border = match self.focus {
+            Focus::Filter => Style::new().fg(Color::Yellow),
+            Focus::List => Style::new(),
+        };
+        let block = Block::bordered().title(title).border_style(border);
+        frame.render_widget(Paragraph::new(Line::from(spans)).block(block), area);
+    }
+
+    fn draw_list(&mut self, frame: &mut Frame, area: Rect) {
+        let rows = self.flows.iter().map(|flow| {
+            let status = match (flow.status(), &flow.error) {
+                (Some(status), _) => status.to_string(),
+                (None, Some(_)) => "ERR".to_string(),
+                (None, None) => "-".to_string(),
+            };
+            let color = match flow.status() {
+                Some(200..300) => Color::Green,
+                Some(300..400) => Color::Cyan,
+                Some(400..500) => Color::Yellow,
+                Some(_) => Color::Red,
+                None if flow.error.is_some() => Color::Red,
+                None => Color::Reset,
+            };
+            let content_type = flow.content_type().split(';').next().unwrap_or("").trim();
+            let content_type = content_type.rsplit('/').next().unwrap_or("").to_string();
+            let size = flow.response.as_ref().map(|r| format_size(r.body_size)).unwrap_or("-".to_string());
+            let duration = flow.duration().map(|d| format!("{}ms", d.as_millis())).unwrap_or("-".to_string());
+            //代理自己处理过的请求在URL前面显示标记
+            let tags: String = flow.tags.iter().map(|tag| format!("[{}] ", tag)).collect();
+            Row::new(vec![
+                flow.id.to_string(),
+                status,
+                flow.method().to_string(),
+                format!("{}{}{}", tags, flow.host(), flow.path()),
+                content_type,
+                size,
+                duration,
+            ]).style(Style::new().fg(color))
+        });
+        let widths = [Constraint::Length(5), Constraint::Length(4), Constraint::Length(7), Constraint::Fill(1),
+            Constraint::Length(10), Constraint::Length(9), Constraint::Length(7)];
+        let header = Row::new(["编号", "状态", "方法", "URL", "类型", "大小", "耗时"]).add_modifier(Modifier::BOLD);
+        let table = Table::new(rows, widths).header(header)
+            .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+            .block(Block::bordered().title(" 请求列表 "));
+        frame.render_stateful_widget(table, area, &mut self.table);
+    }
+
+    fn draw_detail(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title(" 详情 ");
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+        let [tabs_area, content_area] = Layout::vertical([Constraint::Length(2), Constraint::Min(1)]).areas(inner);
+        let tabs = ProxyTab::tabs();
+        let index = tabs.iter().position(|tab| *tab == self.view_tab).unwrap_or(0);
+        let titles = tabs.iter().enumerate().map(|(i, tab)| format!("{}.{}", i + 1, tab));
+        let tabs = Tabs::new(titles).select(index).highlight_style(Style::new().fg(Color::Yellow).add_modifier(Modifier::BOLD));
+        frame.render_widget(tabs, tabs_area);
+        let flow = match self.selected_flow() {
+            Some(flow) => flow,
+            None => return frame.render_widget(Paragraph::new("还没有抓到请求").fg(Color::DarkGray), content_area),
+        };
+        let lines = match self.view_tab.content(flow) {
+            TabContent::Sections(sections) => {
+                let mut lines = vec![];
+                for (title, items) in sections {
+                    lines.push(Line::from(title).add_modifier(Modifier::BOLD).fg(Color::Blue));
+                    for (key, value) in items {
+                        lines.push(Line::from(vec![Span::raw(format!("  {}: ", key)).fg(Color::Cyan), Span::raw(value)]));
+                    }
+                    lines.push(Line::default());
+                }
+                lines
+            }
+            TabContent::Text(text) => text.lines().map(|line| Line::from(line.to_string())).collect(),
+        };
+        let paragraph = Paragraph::new(lines).wrap(Wrap { trim: false }).scroll((self.detail_scroll, 0));
+        frame.render_widget(paragraph, content_area);
+    }
+}